@@ -0,0 +1,164 @@
+use crate::az_impl::az_lmdb::LmdbAzContext;
+use crate::runtime_wrapper::spawn_blocking;
+use crate::v_authorization::common::AuthorizationContext;
+use crossbeam_channel::{bounded, Receiver, Sender};
+use std::io::{Error, ErrorKind};
+
+// Authorization for async callers: every call runs on the blocking pool of the runtime and takes
+// its own context from a pool, so concurrent requests are not serialized on a single mutex; without a tokio feature the call
+// blocks the executor (see runtime_wrapper::spawn_blocking)
+pub struct AsyncAzContext<T = LmdbAzContext> {
+    sender: Sender<T>,
+    receiver: Receiver<T>,
+    size: usize,
+}
+
+impl<T> Clone for AsyncAzContext<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            receiver: self.receiver.clone(),
+            size: self.size,
+        }
+    }
+}
+
+impl AsyncAzContext<LmdbAzContext> {
+    pub fn new(pool_size: usize, max_read_counter: u64) -> Self {
+        Self::from_contexts((0..pool_size.max(1)).map(|_| LmdbAzContext::new(max_read_counter)).collect())
+    }
+}
+
+impl<T: AuthorizationContext + Send + 'static> AsyncAzContext<T> {
+    pub fn from_contexts(contexts: Vec<T>) -> Self {
+        let size = contexts.len();
+        let (sender, receiver) = bounded(size);
+        for az in contexts {
+            let _ = sender.send(az);
+        }
+        Self {
+            sender,
+            receiver,
+            size,
+        }
+    }
+
+    pub fn pool_size(&self) -> usize {
+        self.size
+    }
+
+    pub async fn authorize(&self, uri: &str, user_uri: &str, request_access: u8) -> Result<u8, Error> {
+        if self.size == 0 {
+            return Err(Error::new(ErrorKind::Other, "AsyncAzContext: pool is empty"));
+        }
+
+        let sender = self.sender.clone();
+        let receiver = self.receiver.clone();
+        let uri = uri.to_owned();
+        let user_uri = user_uri.to_owned();
+
+        spawn_blocking(move || {
+            let az = receiver.recv().map_err(|e| Error::new(ErrorKind::Other, format!("AsyncAzContext: fail take context, err={:?}", e)))?;
+            let mut pooled = PooledContext {
+                az: Some(az),
+                sender,
+            };
+            pooled.az.as_mut().unwrap().authorize(&uri, &user_uri, request_access, false)
+        })
+        .await?
+    }
+}
+
+// Returns the context to the pool when dropped, also while unwinding from a panic of authorize, so a panic does not
+// shrink the pool and leave the next callers waiting for a context forever
+struct PooledContext<T> {
+    az: Option<T>,
+    sender: Sender<T>,
+}
+
+impl<T> Drop for PooledContext<T> {
+    fn drop(&mut self) {
+        if let Some(az) = self.az.take() {
+            let _ = self.sender.send(az);
+        }
+    }
+}
+
+#[cfg(all(test, any(feature = "tokio_0_2", feature = "tokio_1")))]
+mod tests {
+    use super::*;
+    use crate::runtime_wrapper::RuntimeWrapper;
+    use futures::future::join_all;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use v_authorization::common::Trace;
+
+    struct SlowContext {
+        active: Arc<AtomicUsize>,
+        max_active: Arc<AtomicUsize>,
+    }
+
+    impl AuthorizationContext for SlowContext {
+        fn authorize(&mut self, _uri: &str, _user_uri: &str, request_access: u8, _is_check_for_reload: bool) -> Result<u8, Error> {
+            let now = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_active.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(50));
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            Ok(request_access)
+        }
+
+        fn authorize_and_trace(&mut self, uri: &str, user_uri: &str, request_access: u8, is_check_for_reload: bool, _trace: &mut Trace) -> Result<u8, Error> {
+            self.authorize(uri, user_uri, request_access, is_check_for_reload)
+        }
+    }
+
+    struct PanickingContext;
+
+    impl AuthorizationContext for PanickingContext {
+        fn authorize(&mut self, uri: &str, _user_uri: &str, request_access: u8, _is_check_for_reload: bool) -> Result<u8, Error> {
+            if uri == "d:panic" {
+                panic!("authorize {}", uri);
+            }
+            Ok(request_access)
+        }
+
+        fn authorize_and_trace(&mut self, uri: &str, user_uri: &str, request_access: u8, is_check_for_reload: bool, _trace: &mut Trace) -> Result<u8, Error> {
+            self.authorize(uri, user_uri, request_access, is_check_for_reload)
+        }
+    }
+
+    #[test]
+    fn test_panic_returns_context_to_pool() {
+        let az = AsyncAzContext::from_contexts(vec![PanickingContext]);
+
+        let mut rt = RuntimeWrapper::new();
+        assert!(rt.block_on(az.authorize("d:panic", "td:User1", 2)).is_err());
+        assert_eq!(rt.block_on(az.authorize("d:doc_1", "td:User1", 2)).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_concurrent_authorize_uses_pool() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let az = AsyncAzContext::from_contexts(
+            (0..4)
+                .map(|_| SlowContext {
+                    active: active.clone(),
+                    max_active: max_active.clone(),
+                })
+                .collect(),
+        );
+
+        let mut rt = RuntimeWrapper::new();
+        let res = rt.block_on(join_all((0..16).map(|n| {
+            let az = az.clone();
+            async move { az.authorize(&format!("d:doc_{}", n), "td:User1", 2).await }
+        })));
+
+        assert!(res.iter().all(|r| matches!(r, Ok(2))));
+        assert!(max_active.load(Ordering::SeqCst) > 1);
+        assert!(max_active.load(Ordering::SeqCst) <= az.pool_size());
+    }
+}
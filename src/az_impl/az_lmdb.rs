@@ -1,127 +1,589 @@
-use crate::az_impl::formats::{decode_filter, decode_rec_to_rights, decode_rec_to_rightset};
+#[cfg(any(test, feature = "test_utils"))]
+use crate::az_impl::formats::{encode_record, RECOMMENDED_INDEX_FORMAT_VERSION};
+use crate::az_impl::context_ext::AuthorizationContextExt;
+use crate::az_impl::formats::{decode_filter, decode_rec_to_rights_with, decode_rec_to_rightset_with, report_decode_error, validate_index_record};
 use crate::v_authorization::common::AuthorizationContext;
 use chrono::{DateTime, Utc};
 use io::Error;
 use lmdb_rs_m::core::{Database, EnvCreateNoLock, EnvCreateNoMetaSync, EnvCreateNoSync, EnvCreateReadOnly};
 use lmdb_rs_m::{DbFlags, EnvBuilder, Environment, MdbError};
-use std::cmp::PartialEq;
+use rand::{thread_rng, Rng};
 use std::io::ErrorKind;
 use std::path::PathBuf;
-use std::time;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use std::{io, thread};
-use v_authorization::common::{Storage, Trace};
+use v_authorization::common::{Storage, Trace, FILTER_PREFIX, MEMBERSHIP_PREFIX, PERMISSION_PREFIX};
 use v_authorization::*;
 
 const DB_PATH: &str = "./data/acl-indexes/";
 const CACHE_DB_PATH: &str = "./data/acl-cache-indexes/";
+const ACCESS_CAN_ALL: u8 = 15;
+// Generation of the data, written by the indexer to the main and to the cache databases
+pub const CACHE_GENERATION_KEY: &str = "_cache_generation";
 
-use crate::az_impl::stat_manager::StatPub;
+use crate::az_impl::stat_manager::{new_stat_sink, NullStatSink, StatMode, StatPub, StatSink};
 use crate::module::module_impl::Module;
+use crate::runtime_wrapper::spawn_blocking;
+use std::future::Future;
 
-#[derive(Debug, Eq, PartialEq, Clone)]
-enum StatMode {
-    Full,
-    Minimal,
-    None,
+// Access of a user to an object with the groups and acl records it was granted through
+#[derive(Debug, Default, PartialEq)]
+pub struct EffectiveAccess {
+    pub access: u8,
+    // object and subject groups of the matched acl records, in the order they were found
+    pub via_groups: Vec<String>,
+    // matched acl records as "object_group;subject;predicate"
+    pub matched_acls: Vec<String>,
 }
 
-struct Stat {
-    point: StatPub,
-    mode: StatMode,
+#[derive(Debug, Default)]
+pub struct IntegrityReport {
+    pub total: usize,
+    // key of the record and the reason it can not be decoded
+    pub malformed: Vec<(String, String)>,
 }
 
+// Every context opens its own environments, nothing is shared between contexts: a reopen (by max_authorize_counter,
+// after an error or by force_reopen) only replaces the environments of this context, so other threads are not affected
 pub struct LmdbAzContext {
     env: Environment,
     cache_env: Option<Environment>,
     authorize_counter: u64,
     max_authorize_counter: u64,
-    stat: Option<Stat>,
+    stat: Box<dyn StatSink>,
+    check_expiry: bool,
+    db_path: String,
+    cache_db_path: String,
+    reopen_count: u64,
+    check_cache_generation: bool,
+    stale_cache_bypass_count: u64,
 }
 
-fn open(max_read_counter: u64, stat_collector_url: Option<String>, stat_mode: StatMode, use_cache: Option<bool>) -> LmdbAzContext {
-    let env_builder = EnvBuilder::new().flags(EnvCreateNoLock | EnvCreateReadOnly | EnvCreateNoMetaSync | EnvCreateNoSync);
+fn open_env(path: &str) -> Result<Environment, MdbError> {
+    EnvBuilder::new().flags(EnvCreateNoLock | EnvCreateReadOnly | EnvCreateNoMetaSync | EnvCreateNoSync).open(path, 0o644)
+}
 
-    loop {
-        let path: PathBuf = PathBuf::from(format!("{}{}", DB_PATH, "data.mdb"));
+// Settings of LmdbAzContext, filled by the positional constructor from properties or by LmdbAzContextBuilder
+#[derive(Debug, Clone, PartialEq)]
+pub struct LmdbAzConfig {
+    max_read_counter: u64,
+    stat_collector_url: Option<String>,
+    stat_mode: StatMode,
+    stat_batch_size: usize,
+    stat_batch_timeout: Duration,
+    use_cache: bool,
+    // delay before the second attempt to open the database, it doubles on every next attempt up to open_max_retry_delay
+    open_retry_delay: Duration,
+    open_max_retry_delay: Duration,
+    // None retries until the database is opened
+    open_max_attempts: Option<u32>,
+    db_path: String,
+    cache_db_path: String,
+}
 
-        if !path.exists() {
-            error!("LIB_AZ: Database does not exist at path: {}", path.display());
-            thread::sleep(time::Duration::from_secs(3));
-            error!("Retrying database connection...");
-            continue;
+impl Default for LmdbAzConfig {
+    fn default() -> Self {
+        LmdbAzConfig {
+            max_read_counter: u64::MAX,
+            stat_collector_url: None,
+            stat_mode: StatMode::Full,
+            // by default every sample is sent at once
+            stat_batch_size: 1,
+            stat_batch_timeout: Duration::default(),
+            use_cache: false,
+            open_retry_delay: Duration::from_secs(3),
+            open_max_retry_delay: Duration::from_secs(60),
+            open_max_attempts: None,
+            db_path: DB_PATH.to_owned(),
+            cache_db_path: CACHE_DB_PATH.to_owned(),
         }
+    }
+}
 
-        match env_builder.open(DB_PATH, 0o644) {
-            Ok(env) => {
-                info!("LIB_AZ: Opened environment at path: {}", DB_PATH);
+fn stat_mode_from_str(v: &str) -> StatMode {
+    match v.to_lowercase().as_str() {
+        "full" => StatMode::Full,
+        "minimal" => StatMode::Minimal,
+        "off" => StatMode::None,
+        "none" => StatMode::None,
+        _ => StatMode::Full,
+    }
+}
 
-                let stat_ctx = stat_collector_url.clone().and_then(|s| StatPub::new(&s).ok()).map(|p| Stat {
-                    point: p,
-                    mode: stat_mode.clone(),
-                });
+// Only the LMDB backend is built into this crate, mdbx and unknown values are not supported
+fn is_supported_az_db_type(v: &str) -> bool {
+    v.trim().eq_ignore_ascii_case("lmdb")
+}
 
-                if let Some(_stat) = &stat_ctx {
-                    info!("LIB_AZ: Stat collector URL: {:?}", stat_collector_url);
-                    info!("LIB_AZ: Stat mode: {:?}", &stat_mode);
-                }
+fn open(config: &LmdbAzConfig) -> LmdbAzContext {
+    try_open(config, &config.db_path, &config.cache_db_path, &mut thread::sleep, &mut random_jitter).unwrap_or_else(|e| panic!("LIB_AZ: {}", e))
+}
 
-                return if use_cache.unwrap_or(false) {
-                    let cache_env_builder = EnvBuilder::new().flags(EnvCreateNoLock | EnvCreateReadOnly | EnvCreateNoMetaSync | EnvCreateNoSync);
-                    let cache_env = match cache_env_builder.open(CACHE_DB_PATH, 0o644) {
-                        Ok(env) => {
-                            info!("LIB_AZ: Opened cache environment at path: {}", CACHE_DB_PATH);
-                            Some(env)
-                        },
-                        Err(e) => {
-                            warn!("LIB_AZ: Error opening cache environment: {:?}. Proceeding without cache.", e);
-                            None
-                        },
-                    };
-
-                    LmdbAzContext {
-                        env,
-                        cache_env,
-                        authorize_counter: 0,
-                        max_authorize_counter: max_read_counter,
-                        stat: stat_ctx,
-                    }
-                } else {
-                    LmdbAzContext {
-                        env,
-                        cache_env: None,
-                        authorize_counter: 0,
-                        max_authorize_counter: max_read_counter,
-                        stat: stat_ctx,
+// Exponential backoff with jitter, so many workers started together do not retry at the same moment;
+// jitter in [0, 1) picks the delay between a half and the whole of the backoff step
+fn open_retry_delay(config: &LmdbAzConfig, attempt: u32, jitter: f64) -> Duration {
+    let step = config.open_retry_delay.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1))).min(config.open_max_retry_delay);
+    step / 2 + step.mul_f64(jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+fn random_jitter() -> f64 {
+    thread_rng().gen::<f64>()
+}
+
+// sleep and jitter are taken from the caller, so a test neither waits nor depends on random delays
+fn try_open(
+    config: &LmdbAzConfig,
+    db_path: &str,
+    cache_db_path: &str,
+    sleep: &mut dyn FnMut(Duration),
+    jitter: &mut dyn FnMut() -> f64,
+) -> Result<LmdbAzContext, Error> {
+    let env_builder = EnvBuilder::new().flags(EnvCreateNoLock | EnvCreateReadOnly | EnvCreateNoMetaSync | EnvCreateNoSync);
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let path: PathBuf = PathBuf::from(db_path).join("data.mdb");
+
+        let err = if !path.exists() {
+            format!("Database does not exist at path: {}", path.display())
+        } else {
+            match env_builder.open(db_path, 0o644) {
+                Ok(env) => {
+                    info!("LIB_AZ: Opened environment at path: {}", db_path);
+
+                    let stat_point = config.stat_collector_url.as_ref().and_then(|s| StatPub::new(s).ok()).map(|mut p| {
+                        p.set_batch(config.stat_batch_size, config.stat_batch_timeout);
+                        p
+                    });
+
+                    if stat_point.is_some() {
+                        info!("LIB_AZ: Stat collector URL: {:?}", config.stat_collector_url);
+                        info!("LIB_AZ: Stat mode: {:?}", &config.stat_mode);
                     }
-                };
-            },
-            Err(e) => {
-                error!("Authorize: Error opening environment: {:?}. Retrying in 3 seconds...", e);
-                thread::sleep(time::Duration::from_secs(3));
-            },
+                    let stat_ctx = new_stat_sink(stat_point, config.stat_mode.clone());
+
+                    return Ok(if config.use_cache {
+                        let cache_env_builder = EnvBuilder::new().flags(EnvCreateNoLock | EnvCreateReadOnly | EnvCreateNoMetaSync | EnvCreateNoSync);
+                        let cache_env = match cache_env_builder.open(cache_db_path, 0o644) {
+                            Ok(env) => {
+                                info!("LIB_AZ: Opened cache environment at path: {}", cache_db_path);
+                                Some(env)
+                            },
+                            Err(e) => {
+                                warn!("LIB_AZ: Error opening cache environment: {:?}. Proceeding without cache.", e);
+                                None
+                            },
+                        };
+
+                        LmdbAzContext {
+                            env,
+                            cache_env,
+                            authorize_counter: 0,
+                            max_authorize_counter: config.max_read_counter,
+                            stat: stat_ctx,
+                            check_expiry: false,
+                            db_path: db_path.to_owned(),
+                            cache_db_path: cache_db_path.to_owned(),
+                            reopen_count: 0,
+                            check_cache_generation: false,
+                            stale_cache_bypass_count: 0,
+                        }
+                    } else {
+                        LmdbAzContext {
+                            env,
+                            cache_env: None,
+                            authorize_counter: 0,
+                            max_authorize_counter: config.max_read_counter,
+                            stat: stat_ctx,
+                            check_expiry: false,
+                            db_path: db_path.to_owned(),
+                            cache_db_path: cache_db_path.to_owned(),
+                            reopen_count: 0,
+                            check_cache_generation: false,
+                            stale_cache_bypass_count: 0,
+                        }
+                    });
+                },
+                Err(e) => format!("Error opening environment: {:?}", e),
+            }
+        };
+
+        if let Some(max_attempts) = config.open_max_attempts {
+            if attempt >= max_attempts {
+                error!("LIB_AZ: {}, attempt {}, give up", err, attempt);
+                return Err(Error::new(ErrorKind::NotFound, format!("fail open database after {} attempts: {}", attempt, err)));
+            }
         }
+
+        let delay = open_retry_delay(config, attempt, jitter());
+        error!("LIB_AZ: {}, attempt {}, retrying in {} ms...", err, attempt, delay.as_millis());
+        sleep(delay);
     }
 }
 
 impl LmdbAzContext {
     pub fn new(max_read_counter: u64) -> LmdbAzContext {
-        let mode = if let Some(v) = Module::get_property::<String>("stat_mode") {
-            match v.to_lowercase().as_str() {
-                "full" => StatMode::Full,
-                "minimal" => StatMode::Minimal,
-                "off" => StatMode::None,
-                "none" => StatMode::None,
-                _ => StatMode::Full,
+        let stat_mode = Module::get_property::<String>("stat_mode").unwrap_or_default();
+
+        let mut config = config_from_args(max_read_counter, Module::get_property("stat_collector_url"), &stat_mode, Module::get_property("use_authorization_cache"));
+        config.stat_batch_size = Module::get_property("stat_batch_size").unwrap_or(config.stat_batch_size);
+        config.stat_batch_timeout = Module::get_property("stat_batch_timeout_ms").map(Duration::from_millis).unwrap_or(config.stat_batch_timeout);
+        paths_from_properties(&mut config);
+
+        open(&config)
+    }
+
+    pub fn new_with_config(max_read_counter: u64, stat_collector_url: Option<String>, stat_mode_str: &str, use_cache: Option<bool>) -> LmdbAzContext {
+        let mut config = config_from_args(max_read_counter, stat_collector_url, stat_mode_str, use_cache);
+        paths_from_properties(&mut config);
+
+        open(&config)
+    }
+
+    // Every context opens its own environment, so contexts over different paths never share one
+    pub fn new_with_paths(max_read_counter: u64, db_path: &str, cache_db_path: &str) -> LmdbAzContext {
+        LmdbAzContext::builder().max_read(max_read_counter).paths(db_path, cache_db_path).build()
+    }
+
+    // Reads the backend type, stat, cache and path settings from properties, as new does; any az_db_type
+    // other than lmdb falls back to it
+    pub fn from_config(max_read_counter: u64) -> LmdbAzContext {
+        if let Some(db_type) = Module::get_property::<String>("az_db_type") {
+            if !is_supported_az_db_type(&db_type) {
+                warn!("LIB_AZ: az_db_type={} is not supported, use lmdb", db_type);
             }
+        }
+
+        LmdbAzContext::new(max_read_counter)
+    }
+
+    pub fn builder() -> LmdbAzContextBuilder {
+        LmdbAzContextBuilder::default()
+    }
+}
+
+// acl_indexes_path and acl_cache_indexes_path replace ./data/acl-indexes/ and ./data/acl-cache-indexes/
+fn paths_from_properties(config: &mut LmdbAzConfig) {
+    if let Some(path) = Module::get_property::<String>("acl_indexes_path") {
+        config.db_path = path;
+    }
+    if let Some(path) = Module::get_property::<String>("acl_cache_indexes_path") {
+        config.cache_db_path = path;
+    }
+}
+
+fn config_from_args(max_read_counter: u64, stat_collector_url: Option<String>, stat_mode_str: &str, use_cache: Option<bool>) -> LmdbAzConfig {
+    LmdbAzConfig {
+        max_read_counter,
+        stat_collector_url,
+        stat_mode: stat_mode_from_str(stat_mode_str),
+        use_cache: use_cache.unwrap_or(false),
+        ..Default::default()
+    }
+}
+
+// LmdbAzContext::builder().max_read(1000).cache(true).build()
+#[derive(Default)]
+pub struct LmdbAzContextBuilder {
+    config: LmdbAzConfig,
+}
+
+impl LmdbAzContextBuilder {
+    pub fn max_read(mut self, max_read_counter: u64) -> Self {
+        self.config.max_read_counter = max_read_counter;
+        self
+    }
+
+    pub fn stat_collector_url(mut self, url: &str) -> Self {
+        self.config.stat_collector_url = Some(url.to_owned());
+        self
+    }
+
+    // "full", "minimal", "none" or "off"
+    pub fn stat_mode(mut self, mode: &str) -> Self {
+        self.config.stat_mode = stat_mode_from_str(mode);
+        self
+    }
+
+    // Samples are sent as one message per size of them, or earlier once the timeout passed; a zero timeout is no timeout
+    pub fn stat_batch(mut self, size: usize, timeout: Duration) -> Self {
+        self.config.stat_batch_size = size;
+        self.config.stat_batch_timeout = timeout;
+        self
+    }
+
+    pub fn cache(mut self, use_cache: bool) -> Self {
+        self.config.use_cache = use_cache;
+        self
+    }
+
+    // max_attempts None keeps retrying until the database appears
+    pub fn open_retry(mut self, delay: Duration, max_delay: Duration, max_attempts: Option<u32>) -> Self {
+        self.config.open_retry_delay = delay;
+        self.config.open_max_retry_delay = max_delay;
+        self.config.open_max_attempts = max_attempts;
+        self
+    }
+
+    pub fn paths(mut self, db_path: &str, cache_db_path: &str) -> Self {
+        self.config.db_path = db_path.to_owned();
+        self.config.cache_db_path = cache_db_path.to_owned();
+        self
+    }
+
+    pub fn config(&self) -> &LmdbAzConfig {
+        &self.config
+    }
+
+    pub fn build(self) -> LmdbAzContext {
+        open(&self.config)
+    }
+
+    // Like build, but returns an error when the database is not opened within the configured attempts
+    pub fn try_build(self) -> Result<LmdbAzContext, Error> {
+        try_open(&self.config, &self.config.db_path, &self.config.cache_db_path, &mut thread::sleep, &mut random_jitter)
+    }
+}
+
+// Context over a database opened for writing, so tests can seed acl records without the indexer
+#[cfg(any(test, feature = "test_utils"))]
+impl LmdbAzContext {
+    pub fn open_writable(path: &str) -> Result<LmdbAzContext, Error> {
+        std::fs::create_dir_all(path)?;
+        let env = EnvBuilder::new().open(path, 0o644).map_err(|e| Error::new(ErrorKind::Other, format!("Authorize: Err opening environment: {:?}", e)))?;
+
+        Ok(LmdbAzContext {
+            env,
+            cache_env: None,
+            authorize_counter: 0,
+            max_authorize_counter: u64::MAX,
+            stat: new_stat_sink(None, StatMode::None),
+            check_expiry: false,
+            db_path: path.to_owned(),
+            cache_db_path: String::new(),
+            reopen_count: 0,
+            check_cache_generation: false,
+            stale_cache_bypass_count: 0,
+        })
+    }
+
+    // Stores the rights under the key (with the prefix of permission, membership or filter) in the recommended index format
+    pub fn put_rights(&mut self, key: &str, rights: &ACLRecordSet) -> Result<(), Error> {
+        self.put(key, &encode_record(None, rights, RECOMMENDED_INDEX_FORMAT_VERSION))
+    }
+
+    pub fn put(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        let to_io_err = |e: MdbError| Error::new(ErrorKind::Other, format!("Authorize: put {}, err={:?}", key, e));
+
+        let db_handle = self.env.get_default_db(DbFlags::empty()).map_err(to_io_err)?;
+        let txn = self.env.new_transaction().map_err(to_io_err)?;
+        txn.bind(&db_handle).set(&key, &value).map_err(to_io_err)?;
+        txn.commit().map_err(to_io_err)
+    }
+}
+
+impl LmdbAzContext {
+    // Opens the environments again without waiting for max_authorize_counter, so the next authorize sees
+    // the data committed after they were opened
+    pub fn force_reopen(&mut self) -> Result<(), std::io::Error> {
+        self.authorize_counter = 0;
+
+        self.env = open_env(&self.db_path).map_err(|e| Error::new(ErrorKind::Other, format!("Authorize: Err opening environment: {:?}", e)))?;
+
+        if self.cache_env.is_some() {
+            self.cache_env = match open_env(&self.cache_db_path) {
+                Ok(env) => Some(env),
+                Err(e) => {
+                    warn!("LIB_AZ: Error reopening cache environment: {:?}. Proceeding without cache.", e);
+                    None
+                },
+            };
+        }
+
+        info!("LIB_AZ: reopened environment at path: {}", self.db_path);
+        Ok(())
+    }
+
+    // Like authorize, also tells whether the index has any record (membership or permission) for the uri,
+    // to tell "not indexed yet" from "denied"
+    pub fn authorize_with_presence(&mut self, uri: &str, user_uri: &str, request_access: u8) -> Result<(u8, bool), std::io::Error> {
+        let access = self.authorize(uri, user_uri, request_access, false)?;
+        Ok((access, self.has_acl_records(uri)?))
+    }
+
+    fn has_acl_records(&self, uri: &str) -> Result<bool, std::io::Error> {
+        let to_io_err = |e: MdbError| Error::new(ErrorKind::Other, format!("Authorize: read acl records of {}, err={:?}", uri, e));
+
+        let db_handle = self.env.get_default_db(DbFlags::empty()).map_err(to_io_err)?;
+        let txn = self.env.get_reader().map_err(to_io_err)?;
+        let db = txn.bind(&db_handle);
+
+        for prefix in [MEMBERSHIP_PREFIX, PERMISSION_PREFIX] {
+            match db.get::<Vec<u8>>(&(prefix.to_owned() + uri)) {
+                Ok(_) => return Ok(true),
+                Err(MdbError::NotFound) => {},
+                Err(e) => return Err(to_io_err(e)),
+            }
+        }
+        Ok(false)
+    }
+
+    // Count of reopens made by authorize: every max_authorize_counter calls and after db errors
+    pub fn reopen_count(&self) -> u64 {
+        self.reopen_count
+    }
+
+    // When set, index records dated before the current day are treated as expired and grant nothing,
+    // otherwise the date of a record is informational only
+    pub fn set_check_expiry(&mut self, check_expiry: bool) {
+        self.check_expiry = check_expiry;
+    }
+
+    // When set, the cache is used only if its CACHE_GENERATION_KEY equals the one of the main database,
+    // a main database without the key is not versioned and the cache is always used
+    pub fn set_check_cache_generation(&mut self, check_cache_generation: bool) {
+        self.check_cache_generation = check_cache_generation;
+    }
+
+    // Count of authorize calls that read the main database only because the cache was of another generation
+    pub fn stale_cache_bypass_count(&self) -> u64 {
+        self.stale_cache_bypass_count
+    }
+
+    // Same walk as authorize_and_trace over all access bits, but the result is returned as structured data
+    pub fn effective_access(&mut self, uri: &str, user_uri: &str) -> Result<EffectiveAccess, std::io::Error> {
+        let mut acl = String::new();
+        let mut group = String::new();
+        let mut info = String::new();
+
+        let mut trace = Trace {
+            acl: &mut acl,
+            is_acl: true,
+            group: &mut group,
+            is_group: false,
+            info: &mut info,
+            is_info: false,
+            str_num: 0,
+        };
+
+        let access = self.authorize_and_trace(uri, user_uri, ACCESS_CAN_ALL, false, &mut trace)?;
+
+        let mut res = EffectiveAccess {
+            access,
+            ..Default::default()
+        };
+
+        for line in acl.lines() {
+            let fields: Vec<&str> = line.split(';').collect();
+            if fields.len() != 3 {
+                continue;
+            }
+            for gr in &fields[0..2] {
+                if *gr != uri && *gr != user_uri && !res.via_groups.iter().any(|el| el == gr) {
+                    res.via_groups.push(gr.to_string());
+                }
+            }
+            if !res.matched_acls.iter().any(|el| el == line) {
+                res.matched_acls.push(line.to_owned());
+            }
+        }
+
+        Ok(res)
+    }
+
+    // Reads every record of the index and reports the ones that can not be decoded
+    pub fn scan_integrity(&mut self) -> Result<IntegrityReport, std::io::Error> {
+        let mut report = IntegrityReport::default();
+
+        self.scan_prefix("", |key, val| {
+            report.total += 1;
+
+            let val = match std::str::from_utf8(val) {
+                Ok(v) => v,
+                Err(e) => {
+                    report.malformed.push((key.to_owned(), format!("value is not utf8: {}", e)));
+                    return;
+                },
+            };
+
+            // a filter may be stored as a bare id
+            if key.starts_with(FILTER_PREFIX) && !val.contains(';') {
+                return;
+            }
+
+            if let Err(e) = validate_index_record(val) {
+                report.malformed.push((key.to_owned(), e));
+            }
+        })
+        .map_err(|e| Error::new(ErrorKind::Other, format!("fail scan acl db, err={:?}", e)))?;
+
+        Ok(report)
+    }
+
+    // Direct members of the group: subjects and objects whose membership record lists it
+    pub fn group_members(&mut self, group_uri: &str) -> Vec<String> {
+        let mut members = vec![];
+        let res = self.scan_prefix(MEMBERSHIP_PREFIX, |key, val| {
+            let mut groups = vec![];
+            decode_rec_to_rights_with(&String::from_utf8_lossy(val), &mut groups, |e| report_decode_error(key, &e));
+            if groups.iter().any(|gr| gr.id == group_uri) {
+                members.push(key.to_owned());
+            }
+        });
+
+        if let Err(e) = res {
+            error!("fail read members of group {}, err={:?}", group_uri, e);
+        }
+        members
+    }
+
+    // Calls f(key without prefix, raw value) for every record of the index starting with prefix
+    fn scan_prefix<F>(&self, prefix: &str, mut f: F) -> Result<(), MdbError>
+    where
+        F: FnMut(&str, &[u8]),
+    {
+        let db_handle = self.env.get_default_db(DbFlags::empty())?;
+        let txn = self.env.get_reader()?;
+        let db = txn.bind(&db_handle);
+        let mut cursor = db.new_cursor()?;
+
+        let res = if prefix.is_empty() {
+            cursor.to_first()
         } else {
-            StatMode::Full
+            cursor.to_gte_key(&prefix)
         };
+        match res {
+            Ok(()) => {},
+            Err(MdbError::NotFound) => return Ok(()),
+            Err(e) => return Err(e),
+        }
 
-        let stat_collector_url = Module::get_property("stat_collector_url");
-        let use_authorization_cache = Module::get_property("use_authorization_cache");
+        loop {
+            let key = cursor.get_key::<Vec<u8>>()?;
+            let key = String::from_utf8_lossy(&key);
+            let id = if let Some(id) = key.strip_prefix(prefix) {
+                id
+            } else {
+                break;
+            };
+            let val = cursor.get_value::<Vec<u8>>()?;
+            f(id, &val);
 
-        open(max_read_counter, stat_collector_url, mode, use_authorization_cache)
+            match cursor.to_next_item() {
+                Ok(()) => {},
+                Err(MdbError::NotFound) => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Checks several access bits in one pass over the index and returns the granted part of them,
+    // e.g. modes = CanRead | CanUpdate answers "can the user read or update"
+    pub fn authorize_any(&mut self, uri: &str, user_uri: &str, modes: u8) -> Result<u8, std::io::Error> {
+        Ok(self.authorize(uri, user_uri, modes, false)? & modes)
     }
 }
 
@@ -147,85 +609,74 @@ impl AuthorizationContext for LmdbAzContext {
 
         let r = self.authorize_and_trace(uri, user_uri, request_access, _is_check_for_reload, &mut t);
 
-        if let Some(stat) = &mut self.stat {
-            if stat.mode == StatMode::Full || stat.mode == StatMode::Minimal {
-                let elapsed = start_time.elapsed().unwrap_or_default();
-                stat.point.set_duration(elapsed);
-                if let Err(e) = stat.point.flush() {
-                    warn!("fail flush stat, err={:?}", e);
-                }
-            }
-        }
+        self.stat.flush(start_time.elapsed().unwrap_or_default());
 
         r
     }
 
     fn authorize_and_trace(&mut self, uri: &str, user_uri: &str, request_access: u8, _is_check_for_reload: bool, trace: &mut Trace) -> Result<u8, std::io::Error> {
-        self.authorize_counter += 1;
-        //info!("az counter={}", self.authorize_counter);
-        if self.authorize_counter >= self.max_authorize_counter {
-            //info!("az reopen, counter > {}", self.max_authorize_counter);
-            self.authorize_counter = 0;
-            let env_builder = EnvBuilder::new().flags(EnvCreateNoLock | EnvCreateReadOnly | EnvCreateNoMetaSync | EnvCreateNoSync);
-
-            match env_builder.open(DB_PATH, 0o644) {
-                Ok(env1) => {
-                    self.env = env1;
-                },
-                Err(e1) => {
-                    return Err(Error::new(ErrorKind::Other, format!("Authorize: Err opening environment: {:?}", e1)));
-                },
-            }
-        }
+        self.count_authorize(1)?;
 
         match self.authorize_use_db(uri, user_uri, request_access, _is_check_for_reload, trace) {
             Ok(r) => {
                 return Ok(r);
             },
-            Err(e) => {
-                info!("reopen");
-                let env_builder = EnvBuilder::new().flags(EnvCreateNoLock | EnvCreateReadOnly | EnvCreateNoMetaSync | EnvCreateNoSync);
-
-                match env_builder.open(DB_PATH, 0o644) {
-                    Ok(env1) => {
-                        self.env = env1;
-                    },
-                    Err(e1) => {
-                        error!("Authorize: Err opening environment: {:?}", e1);
-                        return Err(e);
-                    },
-                }
-            },
+            Err(e) => self.reopen_after_error(e)?,
         }
         // retry authorization if db err
         self.authorize_use_db(uri, user_uri, request_access, _is_check_for_reload, trace)
     }
 }
 
+// All requests of the batch are checked inside one read transaction
+impl AuthorizationContextExt for LmdbAzContext {
+    fn authorize_batch(&mut self, reqs: &[(&str, &str, u8)]) -> Result<Vec<u8>, Error> {
+        let start_time = SystemTime::now();
+
+        let r = self.count_authorize(reqs.len() as u64).and_then(|_| match self.authorize_batch_use_db(reqs) {
+            Ok(r) => Ok(r),
+            // retry authorization if db err
+            Err(e) => self.reopen_after_error(e).and_then(|_| self.authorize_batch_use_db(reqs)),
+        });
+
+        self.stat.flush(start_time.elapsed().unwrap_or_default());
+
+        r
+    }
+}
+
 pub struct AzLmdbStorage<'a> {
     db: &'a Database<'a>,
     cache_db: Option<&'a Database<'a>>,
-    stat: &'a mut Option<Stat>,
+    stat: &'a mut dyn StatSink,
+    check_expiry: bool,
+    // key of the last record read, the authorization decodes the value of a get right after it
+    last_key: String,
 }
 
-fn message(key: &str, use_cache: bool, from_cache: bool) -> String {
-    match (use_cache, from_cache) {
-        (true, true) => format!("{}/C", key),
-        (true, false) => format!("{}/cB", key),
-        (false, _) => format!("{}/B", key),
+fn is_expired(date: Option<DateTime<Utc>>) -> bool {
+    if let Some(d) = date {
+        return d.date_naive() < Utc::now().date_naive();
+    }
+    false
+}
+
+fn is_stale_cache(db: &Database, cache_db: &Database) -> bool {
+    match db.get::<String>(&CACHE_GENERATION_KEY) {
+        Ok(generation) => cache_db.get::<String>(&CACHE_GENERATION_KEY).map(|cache_generation| cache_generation != generation).unwrap_or(true),
+        Err(_) => false,
     }
 }
 
 impl<'a> Storage for AzLmdbStorage<'a> {
     fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.last_key.clear();
+        self.last_key.push_str(key);
+
         if let Some(cache_db) = self.cache_db {
             match cache_db.get::<String>(&key) {
                 Ok(val) => {
-                    if let Some(stat) = self.stat {
-                        if stat.mode == StatMode::Full {
-                            stat.point.collect(message(key, true, true));
-                        }
-                    }
+                    self.stat.collect_read(key, true, true);
                     debug!("@cache val={}", val);
                     return Ok(Some(val));
                 },
@@ -240,11 +691,7 @@ impl<'a> Storage for AzLmdbStorage<'a> {
 
         match self.db.get::<String>(&key) {
             Ok(val) => {
-                if let Some(stat) = self.stat {
-                    if stat.mode == StatMode::Full {
-                        stat.point.collect(message(key, self.cache_db.is_some(), false));
-                    }
-                }
+                self.stat.collect_read(key, self.cache_db.is_some(), false);
                 debug!("@db val={}", val);
                 Ok(Some(val))
             },
@@ -258,11 +705,35 @@ impl<'a> Storage for AzLmdbStorage<'a> {
     fn fiber_yield(&self) {}
 
     fn decode_rec_to_rights(&self, src: &str, result: &mut Vec<ACLRecord>) -> (bool, Option<DateTime<Utc>>) {
-        decode_rec_to_rights(src, result)
+        let problem = |e: String| report_decode_error(&self.last_key, &e);
+        if !self.check_expiry {
+            return decode_rec_to_rights_with(src, result, problem);
+        }
+
+        let mut rights = vec![];
+        let (res, date) = decode_rec_to_rights_with(src, &mut rights, problem);
+        if is_expired(date) {
+            debug!("skip expired record {}", src);
+        } else {
+            result.append(&mut rights);
+        }
+        (res, date)
     }
 
     fn decode_rec_to_rightset(&self, src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
-        decode_rec_to_rightset(src, new_rights)
+        let problem = |e: String| report_decode_error(&self.last_key, &e);
+        if !self.check_expiry {
+            return decode_rec_to_rightset_with(src, new_rights, problem);
+        }
+
+        let mut rights = ACLRecordSet::new();
+        let (res, date) = decode_rec_to_rightset_with(src, &mut rights, problem);
+        if is_expired(date) {
+            debug!("skip expired record {}", src);
+        } else {
+            new_rights.extend(rights);
+        }
+        (res, date)
     }
 
     fn decode_filter(&self, filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
@@ -271,47 +742,490 @@ impl<'a> Storage for AzLmdbStorage<'a> {
 }
 
 impl LmdbAzContext {
+    // Counts authorize calls, the environment is reopened every max_authorize_counter calls
+    fn count_authorize(&mut self, count: u64) -> Result<(), Error> {
+        self.authorize_counter += count;
+        //info!("az counter={}", self.authorize_counter);
+        if self.authorize_counter >= self.max_authorize_counter {
+            //info!("az reopen, counter > {}", self.max_authorize_counter);
+            self.authorize_counter = 0;
+            self.reopen_count += 1;
+
+            match open_env(&self.db_path) {
+                Ok(env1) => {
+                    self.env = env1;
+                },
+                Err(e1) => {
+                    return Err(Error::new(ErrorKind::Other, format!("Authorize: Err opening environment: {:?}", e1)));
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // Reopens the environment after a db error, the error is returned if it can not be reopened
+    fn reopen_after_error(&mut self, e: Error) -> Result<(), Error> {
+        info!("reopen");
+        self.reopen_count += 1;
+
+        match open_env(&self.db_path) {
+            Ok(env1) => {
+                self.env = env1;
+                Ok(())
+            },
+            Err(e1) => {
+                error!("Authorize: Err opening environment: {:?}", e1);
+                Err(e)
+            },
+        }
+    }
+
     fn authorize_use_db(&mut self, uri: &str, user_uri: &str, request_access: u8, _is_check_for_reload: bool, trace: &mut Trace) -> Result<u8, std::io::Error> {
-        let db_handle = match self.env.get_default_db(DbFlags::empty()) {
+        self.with_storage(|storage| authorize(uri, user_uri, request_access, storage, trace))
+    }
+
+    fn authorize_batch_use_db(&mut self, reqs: &[(&str, &str, u8)]) -> Result<Vec<u8>, std::io::Error> {
+        self.with_storage(|storage| {
+            let mut trace = Trace {
+                acl: &mut String::new(),
+                is_acl: false,
+                group: &mut String::new(),
+                is_group: false,
+                info: &mut String::new(),
+                is_info: false,
+                str_num: 0,
+            };
+            reqs.iter().map(|(uri, user_uri, request_access)| authorize(uri, user_uri, *request_access, storage, &mut trace)).collect()
+        })
+    }
+
+    // Runs f over the storage of one read transaction of the main (and the cache) database
+    fn with_storage<R, F>(&mut self, f: F) -> Result<R, std::io::Error>
+    where
+        F: FnOnce(&mut AzLmdbStorage) -> Result<R, std::io::Error>,
+    {
+        let (r, is_stale_cache_bypassed) =
+            with_env_storage(&self.env, self.cache_env.as_ref(), self.check_cache_generation, self.check_expiry, self.stat.as_mut(), f)?;
+        if is_stale_cache_bypassed {
+            self.stale_cache_bypass_count += 1;
+        }
+        Ok(r)
+    }
+
+    // Authorizes on the blocking pool of the runtime, so the executor is not blocked while LMDB is read.
+    // The environments are shared with the context instead of being reopened, the call is not counted
+    // by max_read_counter and not sent to the stat collector. The future does not borrow the context:
+    // a context behind a lock can be released before awaiting it. Without a tokio feature the reading is done in place and
+    // blocks the executor (see runtime_wrapper::spawn_blocking)
+    pub fn authorize_async(
+        &self,
+        uri: &str,
+        user_uri: &str,
+        request_access: u8,
+        _is_check_for_reload: bool,
+    ) -> impl Future<Output = Result<u8, Error>> + Send + 'static {
+        let env = self.env.clone();
+        let cache_env = self.cache_env.clone();
+        let check_cache_generation = self.check_cache_generation;
+        let check_expiry = self.check_expiry;
+        let uri = uri.to_owned();
+        let user_uri = user_uri.to_owned();
+
+        async move {
+            spawn_blocking(move || {
+                let mut trace = Trace {
+                    acl: &mut String::new(),
+                    is_acl: false,
+                    group: &mut String::new(),
+                    is_group: false,
+                    info: &mut String::new(),
+                    is_info: false,
+                    str_num: 0,
+                };
+                with_env_storage(&env, cache_env.as_ref(), check_cache_generation, check_expiry, &mut NullStatSink, |storage| {
+                    authorize(&uri, &user_uri, request_access, storage, &mut trace)
+                })
+                .map(|(r, _)| r)
+            })
+            .await?
+        }
+    }
+}
+
+// Runs f over the storage of one read transaction of the main (and the cache) database,
+// the flag of the result is set when a stale cache was bypassed
+fn with_env_storage<R, F>(
+    env: &Environment,
+    cache_env: Option<&Environment>,
+    check_cache_generation: bool,
+    check_expiry: bool,
+    stat: &mut dyn StatSink,
+    f: F,
+) -> Result<(R, bool), std::io::Error>
+where
+    F: FnOnce(&mut AzLmdbStorage) -> Result<R, std::io::Error>,
+{
+    let db_handle = match env.get_default_db(DbFlags::empty()) {
+        Ok(db_handle_res) => db_handle_res,
+        Err(e) => {
+            return Err(Error::new(ErrorKind::Other, format!("Authorize: Err opening db handle: {:?}", e)));
+        },
+    };
+    let txn = match env.get_reader() {
+        Ok(txn1) => txn1,
+        Err(e) => {
+            return Err(Error::new(ErrorKind::Other, format!("Authorize:CREATING TRANSACTION {:?}", e)));
+        },
+    };
+    let db = txn.bind(&db_handle);
+
+    let mut is_stale_cache_bypassed = false;
+    let txn_cache;
+    let cache_db = if let Some(env) = cache_env {
+        let db_handle = match env.get_default_db(DbFlags::empty()) {
             Ok(db_handle_res) => db_handle_res,
             Err(e) => {
                 return Err(Error::new(ErrorKind::Other, format!("Authorize: Err opening db handle: {:?}", e)));
             },
         };
-        let txn = match self.env.get_reader() {
+        txn_cache = match env.get_reader() {
             Ok(txn1) => txn1,
             Err(e) => {
                 return Err(Error::new(ErrorKind::Other, format!("Authorize:CREATING TRANSACTION {:?}", e)));
             },
         };
-        let db = txn.bind(&db_handle);
-
-        let txn_cache;
-        let cache_db = if let Some(env) = &self.cache_env {
-            let db_handle = match env.get_default_db(DbFlags::empty()) {
-                Ok(db_handle_res) => db_handle_res,
-                Err(e) => {
-                    return Err(Error::new(ErrorKind::Other, format!("Authorize: Err opening db handle: {:?}", e)));
-                },
-            };
-            txn_cache = match env.get_reader() {
-                Ok(txn1) => txn1,
-                Err(e) => {
-                    return Err(Error::new(ErrorKind::Other, format!("Authorize:CREATING TRANSACTION {:?}", e)));
-                },
-            };
-            let cache_db = txn_cache.bind(&db_handle);
-            Some(cache_db)
-        } else {
+        let cache_db = txn_cache.bind(&db_handle);
+        if check_cache_generation && is_stale_cache(&db, &cache_db) {
+            debug!("LIB_AZ: cache generation differs from the main database, bypass cache");
+            is_stale_cache_bypassed = true;
             None
-        };
+        } else {
+            Some(cache_db)
+        }
+    } else {
+        None
+    };
 
-        let mut storage = AzLmdbStorage {
-            db: &db,
-            cache_db: cache_db.as_ref(),
-            stat: &mut self.stat,
-        };
+    let mut storage = AzLmdbStorage {
+        db: &db,
+        cache_db: cache_db.as_ref(),
+        stat,
+        check_expiry,
+        last_key: String::new(),
+    };
+
+    Ok((f(&mut storage)?, is_stale_cache_bypassed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::az_impl::formats::with_quiet_decode_errors;
+    #[cfg(any(feature = "tokio_0_2", feature = "tokio_1"))]
+    use crate::runtime_wrapper::RuntimeWrapper;
+    use std::env;
+    use std::fs;
+    use v_authorization::common::Access;
+
+    fn open_test_context(name: &str, records: &[(&str, &str)]) -> LmdbAzContext {
+        let path = env::temp_dir().join(format!("az-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        put_records(path.to_str().unwrap(), records);
+
+        LmdbAzContext {
+            env: open_env(path.to_str().unwrap()).unwrap(),
+            cache_env: None,
+            authorize_counter: 0,
+            max_authorize_counter: u64::MAX,
+            stat: new_stat_sink(None, StatMode::None),
+            check_expiry: false,
+            db_path: path.to_str().unwrap().to_owned(),
+            cache_db_path: String::new(),
+            reopen_count: 0,
+            check_cache_generation: false,
+            stale_cache_bypass_count: 0,
+        }
+    }
+
+    fn put_records(path: &str, records: &[(&str, &str)]) {
+        let env = EnvBuilder::new().open(path, 0o644).unwrap();
+        let db_handle = env.get_default_db(DbFlags::empty()).unwrap();
+        let txn = env.new_transaction().unwrap();
+        {
+            let db = txn.bind(&db_handle);
+            for (key, val) in records {
+                db.set(key, val).unwrap();
+            }
+        }
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn test_authorize_any() {
+        let mut az = open_test_context(
+            "authorize-any",
+            &[("Pd:doc_r", "td:User1;R;"), ("Pd:doc_ru", "td:User1;RU;"), ("Pd:doc_u", "td:User1;U;"), ("Pd:doc_p", "td:User1;P;")],
+        );
+
+        let modes = Access::CanRead as u8 | Access::CanUpdate as u8;
+        for uri in ["d:doc_r", "d:doc_ru", "d:doc_u", "d:doc_p"] {
+            let expected = az.authorize(uri, "td:User1", Access::CanRead as u8, false).unwrap() | az.authorize(uri, "td:User1", Access::CanUpdate as u8, false).unwrap();
+            assert_eq!(az.authorize_any(uri, "td:User1", modes).unwrap(), expected, "uri={}", uri);
+        }
+
+        assert_eq!(az.authorize_any("d:doc_ru", "td:User1", modes).unwrap(), modes);
+        assert_eq!(az.authorize_any("d:doc_p", "td:User1", modes).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_check_expiry() {
+        let mut az = open_test_context("check-expiry", &[("Pd:doc_past", "T200101,td:User1;R;"), ("Pd:doc_future", "T681231,td:User1;R;")]);
+        let read = Access::CanRead as u8;
+
+        // by default the date is informational
+        assert_eq!(az.authorize("d:doc_past", "td:User1", read, false).unwrap(), read);
+        assert_eq!(az.authorize("d:doc_future", "td:User1", read, false).unwrap(), read);
+
+        az.set_check_expiry(true);
+        assert_eq!(az.authorize("d:doc_past", "td:User1", read, false).unwrap(), 0);
+        assert_eq!(az.authorize("d:doc_future", "td:User1", read, false).unwrap(), read);
+    }
+
+    #[test]
+    fn test_malformed_record_reported_with_key() {
+        let mut az = open_test_context("malformed-key", &[("Pd:doc1", "td:User1;2Z;"), ("Pd:doc2", "td:User1;R;")]);
+        let read = Access::CanRead as u8;
+
+        let (res, keys) = with_quiet_decode_errors(|| az.authorize("d:doc1", "td:User1", read, false).unwrap());
+        assert_eq!(res, read);
+        assert_eq!(keys, vec!["Pd:doc1"]);
+
+        let (res, keys) = with_quiet_decode_errors(|| az.authorize("d:doc2", "td:User1", read, false).unwrap());
+        assert_eq!(res, read);
+        assert!(keys.is_empty());
+    }
+
+    #[test]
+    fn test_effective_access() {
+        let mut az = open_test_context(
+            "effective-access",
+            &[
+                ("Mtd:User1", "cfg:Group1;F;"),
+                ("Md:doc1", "d:Folder1;F;"),
+                ("Pd:Folder1", "cfg:Group1;RU;"),
+                ("Pd:doc1", "td:User2;R;"),
+            ],
+        );
+
+        let res = az.effective_access("d:doc1", "td:User1").unwrap();
+        assert_eq!(res.access, Access::CanRead as u8 | Access::CanUpdate as u8);
+        assert_eq!(res.via_groups, vec!["d:Folder1".to_owned(), "cfg:Group1".to_owned()]);
+        assert_eq!(res.matched_acls, vec!["d:Folder1;cfg:Group1;v-s:canRead".to_owned(), "d:Folder1;cfg:Group1;v-s:canUpdate".to_owned()]);
+
+        let res = az.effective_access("d:doc1", "td:User3").unwrap();
+        assert_eq!(res, EffectiveAccess::default());
+    }
+
+    #[test]
+    fn test_group_members() {
+        let mut az = open_test_context(
+            "group-members",
+            &[
+                ("Mtd:User1", "cfg:Group1;F;cfg:Group2;F;"),
+                ("Mtd:User2", "cfg:Group2;F;"),
+                ("Mcfg:Group2", "cfg:Group1;F;"),
+                ("Md:doc1", "d:Folder1;F;"),
+                ("Pcfg:Group1", "td:User2;R;"),
+            ],
+        );
+
+        assert_eq!(az.group_members("cfg:Group1"), vec!["cfg:Group2".to_owned(), "td:User1".to_owned()]);
+        assert_eq!(az.group_members("cfg:Group2"), vec!["td:User1".to_owned(), "td:User2".to_owned()]);
+        assert!(az.group_members("cfg:Group3").is_empty());
+    }
+
+    #[test]
+    fn test_force_reopen() {
+        let mut az = open_test_context("force-reopen", &[("Pd:doc1", "td:User1;R;")]);
+        let read = Access::CanRead as u8;
+        assert_eq!(az.authorize("d:doc2", "td:User1", read, false).unwrap(), 0);
+
+        put_records(&az.db_path.clone(), &[("Pd:doc2", "td:User1;R;")]);
+        az.force_reopen().unwrap();
+
+        assert_eq!(az.authorize("d:doc2", "td:User1", read, false).unwrap(), read);
+        assert_eq!(az.authorize("d:doc1", "td:User1", read, false).unwrap(), read);
+    }
+
+    #[test]
+    fn test_scan_integrity() {
+        let mut az = open_test_context(
+            "scan-integrity",
+            &[("Mtd:User1", "cfg:Group1;F;"), ("Pd:doc1", "T240115,td:User1;R2U;"), ("Pd:doc2", "td:User1;2Z;"), ("Fd:doc1", "v-s:Filter1")],
+        );
+
+        let report = az.scan_integrity().unwrap();
+        assert_eq!(report.total, 4);
+        assert_eq!(report.malformed.len(), 1);
+        assert_eq!(report.malformed[0].0, "Pd:doc2");
+    }
+
+    #[test]
+    fn test_builder_config() {
+        let builder = LmdbAzContext::builder().max_read(1000).stat_collector_url("tcp://127.0.0.1:9999").stat_mode("minimal").cache(true);
+        assert_eq!(builder.config(), &config_from_args(1000, Some("tcp://127.0.0.1:9999".to_owned()), "minimal", Some(true)));
+
+        assert_eq!(LmdbAzContext::builder().config(), &config_from_args(u64::MAX, None, "", None));
+        assert_eq!(LmdbAzContext::builder().stat_mode("off").config().stat_mode, StatMode::None);
+        assert_eq!(LmdbAzContext::builder().config().db_path, DB_PATH);
+    }
+
+    #[test]
+    fn test_az_db_type() {
+        assert!(is_supported_az_db_type("lmdb"));
+        assert!(is_supported_az_db_type(" LMDB "));
+        assert!(!is_supported_az_db_type("mdbx"));
+        assert!(!is_supported_az_db_type("tarantool"));
+        assert!(!is_supported_az_db_type(""));
+    }
+
+    #[test]
+    fn test_new_with_paths() {
+        let path = env::temp_dir().join(format!("az-with-paths-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        put_records(path.to_str().unwrap(), &[("Pd:doc1", "td:User1;R;")]);
+
+        // the path is accepted without the trailing slash
+        let mut az = LmdbAzContext::new_with_paths(1000, path.to_str().unwrap(), "");
+        assert_eq!(az.db_path, path.to_str().unwrap());
+        assert_eq!(az.authorize("d:doc1", "td:User1", Access::CanRead as u8, false).unwrap(), Access::CanRead as u8);
+
+        fs::remove_dir_all(&path).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_authorize_batch() {
+        let mut az = open_test_context(
+            "authorize-batch",
+            &[("Pd:doc1", "td:User1;R;"), ("Pd:doc2", "td:User1;RU;td:User2;R;"), ("Mtd:User3", "td:Group1;R;"), ("Pd:doc3", "td:Group1;R;")],
+        );
+        let read = Access::CanRead as u8;
+        let update = Access::CanUpdate as u8;
+
+        let reqs = [
+            ("d:doc2", "td:User1", update),
+            ("d:doc1", "td:User1", read),
+            ("d:doc1", "td:User2", read),
+            ("d:doc3", "td:User3", read),
+            ("d:doc2", "td:User2", update),
+            ("d:doc_absent", "td:User1", read),
+        ];
+        let expected: Vec<u8> = reqs.iter().map(|(uri, user_uri, access)| az.authorize(uri, user_uri, *access, false).unwrap()).collect();
+        assert_eq!(expected, vec![update, read, 0, read, 0, 0]);
+
+        let counter = az.authorize_counter;
+        assert_eq!(az.authorize_batch(&reqs).unwrap(), expected);
+        assert_eq!(az.authorize_counter, counter + reqs.len() as u64);
+        assert_eq!(az.authorize_batch(&[]).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    #[cfg(any(feature = "tokio_0_2", feature = "tokio_1"))]
+    fn test_authorize_async() {
+        let mut az = open_test_context("authorize-async", &[("Pd:doc1", "td:User1;R;"), ("Mtd:User3", "td:Group1;R;"), ("Pd:doc3", "td:Group1;R;")]);
+        let read = Access::CanRead as u8;
+        let counter = az.authorize_counter;
+
+        let reqs = [("d:doc1", "td:User1"), ("d:doc1", "td:User2"), ("d:doc3", "td:User3"), ("d:doc_absent", "td:User1")];
+        let mut rt = RuntimeWrapper::new();
+        for (uri, user_uri) in reqs {
+            let authorized = az.authorize_async(uri, user_uri, read, false);
+            assert_eq!(rt.block_on(authorized).unwrap(), az.authorize(uri, user_uri, read, false).unwrap());
+        }
+        assert_eq!(az.authorize_counter, counter + reqs.len() as u64);
+    }
+
+    #[test]
+    fn test_open_writable() {
+        let path = env::temp_dir().join(format!("az-writable-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let mut az = LmdbAzContext::open_writable(path.to_str().unwrap()).unwrap();
+
+        let mut rights = ACLRecordSet::new();
+        let mut right = ACLRecord::new("td:User1");
+        right.access = Access::CanRead as u8 | Access::CanUpdate as u8;
+        rights.insert("td:User1".to_owned(), right);
+        az.put_rights("Pd:doc1", &rights).unwrap();
+
+        assert_eq!(az.authorize("d:doc1", "td:User1", Access::CanRead as u8, false).unwrap(), Access::CanRead as u8);
+        assert_eq!(az.authorize("d:doc1", "td:User1", Access::CanDelete as u8, false).unwrap(), 0);
+        assert_eq!(az.authorize("d:doc1", "td:User2", Access::CanRead as u8, false).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_stale_cache_is_bypassed() {
+        let read = Access::CanRead as u8;
+        let mut az = open_test_context("stale-cache", &[(CACHE_GENERATION_KEY, "2"), ("Pd:doc1", "td:User1;R;")]);
+
+        let cache_path = env::temp_dir().join(format!("az-stale-cache-c-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&cache_path);
+        fs::create_dir_all(&cache_path).unwrap();
+        put_records(cache_path.to_str().unwrap(), &[(CACHE_GENERATION_KEY, "1"), ("Pd:doc1", "td:User2;R;")]);
+        az.cache_env = Some(open_env(cache_path.to_str().unwrap()).unwrap());
+
+        // without the check the stale cache answers
+        assert_eq!(az.authorize("d:doc1", "td:User1", read, false).unwrap(), 0);
+        assert_eq!(az.stale_cache_bypass_count(), 0);
+
+        az.set_check_cache_generation(true);
+        assert_eq!(az.authorize("d:doc1", "td:User1", read, false).unwrap(), read);
+        assert_eq!(az.authorize("d:doc1", "td:User2", read, false).unwrap(), 0);
+        assert_eq!(az.stale_cache_bypass_count(), 2);
+
+        put_records(cache_path.to_str().unwrap(), &[(CACHE_GENERATION_KEY, "2")]);
+        assert_eq!(az.authorize("d:doc1", "td:User2", read, false).unwrap(), read);
+        assert_eq!(az.stale_cache_bypass_count(), 2);
+    }
+
+    #[test]
+    fn test_open_retry_backoff() {
+        let config = LmdbAzContext::builder().open_retry(Duration::from_millis(100), Duration::from_millis(300), Some(5)).config().clone();
+
+        assert_eq!(open_retry_delay(&config, 1, 0.0), Duration::from_millis(50));
+        assert_eq!(open_retry_delay(&config, 2, 0.0), Duration::from_millis(100));
+        assert_eq!(open_retry_delay(&config, 3, 0.99999), Duration::from_millis(300).mul_f64(0.99999 / 2.0) + Duration::from_millis(150));
+        assert_eq!(open_retry_delay(&config, 10, 0.0), Duration::from_millis(150));
+
+        let missing = env::temp_dir().join(format!("az-missing-{}/", std::process::id())).to_str().unwrap().to_owned();
+        let mut delays = vec![];
+        let res = try_open(&config, &missing, &missing, &mut |d| delays.push(d), &mut || 0.5);
+
+        assert_eq!(res.err().map(|e| e.kind()), Some(ErrorKind::NotFound));
+        // steps of 100, 200, 300 and 300 ms, each taken at 3/4 with the jitter of 0.5
+        assert_eq!(delays, [75, 150, 225, 225].iter().map(|ms| Duration::from_millis(*ms)).collect::<Vec<Duration>>());
+    }
+
+    #[test]
+    fn test_reopen_count() {
+        let mut az = open_test_context("reopen-count", &[("Pd:doc1", "td:User1;R;")]);
+        az.max_authorize_counter = 3;
+
+        for _ in 0..10 {
+            assert_eq!(az.authorize("d:doc1", "td:User1", Access::CanRead as u8, false).unwrap(), Access::CanRead as u8);
+        }
+        assert_eq!(az.reopen_count(), 3);
+    }
+
+    #[test]
+    fn test_authorize_with_presence() {
+        let mut az = open_test_context("authorize-with-presence", &[("Pd:doc1", "td:User2;R;")]);
+        let read = Access::CanRead as u8;
 
-        authorize(uri, user_uri, request_access, &mut storage, trace)
+        assert_eq!(az.authorize_with_presence("d:doc1", "td:User1", read).unwrap(), (0, true));
+        assert_eq!(az.authorize_with_presence("d:doc1", "td:User2", read).unwrap(), (read, true));
+        assert_eq!(az.authorize_with_presence("d:doc2", "td:User1", read).unwrap(), (0, false));
     }
 }
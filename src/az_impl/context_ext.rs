@@ -0,0 +1,124 @@
+use crate::v_authorization::common::AuthorizationContext;
+use std::collections::HashMap;
+use std::io::Error;
+
+// Authorization of many requests at once; AuthorizationContext is defined in v_authorization, so the batch method
+// lives in an extension trait, by default the requests are authorized one by one
+pub trait AuthorizationContextExt: AuthorizationContext {
+    // reqs are (uri, user_uri, request_access), the results are in the same order
+    fn authorize_batch(&mut self, reqs: &[(&str, &str, u8)]) -> Result<Vec<u8>, Error> {
+        reqs.iter().map(|(uri, user_uri, request_access)| self.authorize(uri, user_uri, *request_access, false)).collect()
+    }
+}
+
+// Authorizes found ids while the results are collected: the next batch is the ids after the last authorized one, as many as
+// readable ids are still needed, so the matches past the returned page are not authorized. If a batch fails, its ids are
+// authorized one by one and an id whose authorization fails is denied
+pub struct BatchAuthorizer<'a> {
+    ids: Vec<&'a str>,
+    user: &'a str,
+    access: u8,
+    need: usize,
+    next: usize,
+    readable: HashMap<&'a str, bool>,
+}
+
+impl<'a> BatchAuthorizer<'a> {
+    pub fn new(ids: Vec<&'a str>, user: &'a str, access: u8, top: usize) -> Self {
+        BatchAuthorizer {
+            ids,
+            user,
+            access,
+            need: top,
+            next: 0,
+            readable: HashMap::new(),
+        }
+    }
+
+    pub fn is_readable<A: AuthorizationContextExt + ?Sized>(&mut self, az: &mut A, id: &str) -> bool {
+        while !self.readable.contains_key(id) && self.next < self.ids.len() {
+            let end = (self.next + self.need.max(1)).min(self.ids.len());
+            let reqs: Vec<(&str, &str, u8)> = self.ids[self.next..end].iter().map(|id| (*id, self.user, self.access)).collect();
+            let res = az.authorize_batch(&reqs).unwrap_or_else(|e| {
+                error!("fail authorization {}, err={}", self.user, e);
+                reqs.iter().map(|(uri, user_uri, access)| az.authorize(uri, user_uri, *access, false).unwrap_or(0)).collect()
+            });
+            for ((id, _, _), granted) in reqs.iter().zip(res) {
+                self.readable.insert(id, granted == self.access);
+            }
+            self.next = end;
+        }
+
+        let is_readable = self.readable.get(id) == Some(&true);
+        if is_readable {
+            self.need = self.need.saturating_sub(1);
+        }
+        is_readable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+    use v_authorization::common::Trace;
+
+    // grants CanRead to ids ending with an even digit, fails a batch of more than `max_batch` ids
+    struct RecordingContext {
+        batches: Vec<usize>,
+        max_batch: usize,
+    }
+
+    impl AuthorizationContext for RecordingContext {
+        fn authorize(&mut self, uri: &str, _user_uri: &str, _request_access: u8, _is_check_for_reload: bool) -> Result<u8, Error> {
+            match uri.chars().last().and_then(|c| c.to_digit(10)) {
+                Some(n) if n % 2 == 0 => Ok(2),
+                Some(_) => Ok(0),
+                None => Err(Error::new(ErrorKind::Other, "bad uri")),
+            }
+        }
+
+        fn authorize_and_trace(&mut self, uri: &str, user_uri: &str, request_access: u8, is_check_for_reload: bool, _trace: &mut Trace) -> Result<u8, Error> {
+            self.authorize(uri, user_uri, request_access, is_check_for_reload)
+        }
+    }
+
+    impl AuthorizationContextExt for RecordingContext {
+        fn authorize_batch(&mut self, reqs: &[(&str, &str, u8)]) -> Result<Vec<u8>, Error> {
+            self.batches.push(reqs.len());
+            if reqs.len() > self.max_batch {
+                return Err(Error::new(ErrorKind::Other, "batch is too large"));
+            }
+            reqs.iter().map(|(uri, user_uri, request_access)| self.authorize(uri, user_uri, *request_access, false)).collect()
+        }
+    }
+
+    #[test]
+    fn test_batches_follow_remaining_top() {
+        let ids: Vec<String> = (0..100).map(|n| format!("d:doc_{}", n)).collect();
+        let mut az = RecordingContext {
+            batches: vec![],
+            max_batch: 100,
+        };
+        let mut ba = BatchAuthorizer::new(ids.iter().map(|id| id.as_str()).collect(), "td:User1", 2, 3);
+
+        let readable: Vec<&String> = ids.iter().filter(|id| ba.is_readable(&mut az, id)).take(3).collect();
+        assert_eq!(readable, vec!["d:doc_0", "d:doc_2", "d:doc_4"]);
+        // 3 ids give 2 readable ones, then 1 is still needed for each next batch
+        assert_eq!(az.batches, vec![3, 1, 1]);
+    }
+
+    #[test]
+    fn test_failed_batch_is_authorized_by_id() {
+        let ids = vec!["d:doc_1", "d:doc_2", "d:doc_x", "d:doc_4"];
+        let mut az = RecordingContext {
+            batches: vec![],
+            max_batch: 2,
+        };
+        let mut ba = BatchAuthorizer::new(ids.clone(), "td:User1", 2, 10);
+
+        let readable: Vec<&str> = ids.iter().copied().filter(|id| ba.is_readable(&mut az, id)).collect();
+        assert_eq!(readable, vec!["d:doc_2", "d:doc_4"]);
+        assert_eq!(az.batches, vec![4]);
+    }
+}
@@ -1,5 +1,6 @@
 use chrono::Utc;
 use chrono::{DateTime, NaiveDate};
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use string_builder::Builder;
 use v_authorization::common::{Access, ACCESS_8_FULL_LIST, ACCESS_C_FULL_LIST, M_IGNORE_EXCLUSIVE, M_IS_EXCLUSIVE};
@@ -84,6 +85,30 @@ fn encode_value_v2(right: &ACLRecord, outbuff: &mut Builder) {
     //println!("{} -> {}", access_to_pretty_string(right.access), outbuff);
 }
 
+// Version 2 keeps per-access counters and is the one to use for new indexes, version 1 is read for old databases only
+pub const RECOMMENDED_INDEX_FORMAT_VERSION: u8 = 2;
+
+// Encodes in the given version of the index format, or in the recommended one when the version is not set or unknown
+pub fn encode_record_auto(date: Option<DateTime<Utc>>, new_rights: &ACLRecordSet, target_version: Option<u8>) -> String {
+    let version = match target_version {
+        Some(v) if v == 1 || v == 2 => v,
+        Some(v) => {
+            warn!("unknown version of index format {}, use {}", v, RECOMMENDED_INDEX_FORMAT_VERSION);
+            RECOMMENDED_INDEX_FORMAT_VERSION
+        },
+        None => RECOMMENDED_INDEX_FORMAT_VERSION,
+    };
+
+    encode_record(date, new_rights, version)
+}
+
+// Decodes a record stored in any version of the index format and encodes it again in the target version
+pub fn reencode_record(src: &str, target_version: u8) -> String {
+    let mut rights = ACLRecordSet::new();
+    let (_, date) = decode_rec_to_rightset(src, &mut rights);
+    encode_record_auto(date, &rights, Some(target_version))
+}
+
 pub fn encode_record(date: Option<DateTime<Utc>>, new_rights: &ACLRecordSet, version_of_index_format: u8) -> String {
     let mut builder = Builder::new(16);
 
@@ -124,8 +149,19 @@ pub fn encode_record(date: Option<DateTime<Utc>>, new_rights: &ACLRecordSet, ver
     }
 }
 
-fn decode_value_v2(value: &str, rr: &mut ACLRecord, with_count: bool) {
+// Counter of an access tag, 1 if it is not set; a counter that is not a number is read as 1 and the reason is kept in res
+fn parse_counter(val: &str, res: &mut Result<(), String>) -> u16 {
+    val.parse::<u16>().unwrap_or_else(|_| {
+        if !val.is_empty() && res.is_ok() {
+            *res = Err(format!("invalid counter {}", val));
+        }
+        1
+    })
+}
+
+fn decode_value_v2(value: &str, rr: &mut ACLRecord, with_count: bool) -> Result<(), String> {
     let mut access = 0;
+    let mut res = Ok(());
 
     let mut tag: Option<char> = None;
     let mut val = String::new();
@@ -139,9 +175,10 @@ fn decode_value_v2(value: &str, rr: &mut ACLRecord, with_count: bool) {
                 }
             }
 
-            if with_count {
-                if let Some(t) = tag {
-                    rr.counters.insert(t, val.parse::<u16>().unwrap_or(1));
+            if let Some(t) = tag {
+                let count = parse_counter(&val, &mut res);
+                if with_count {
+                    rr.counters.insert(t, count);
                 }
             }
 
@@ -152,18 +189,48 @@ fn decode_value_v2(value: &str, rr: &mut ACLRecord, with_count: bool) {
         }
     }
 
-    if with_count {
-        if let Some(t) = tag {
-            rr.counters.insert(t, val.parse::<u16>().unwrap_or(1));
+    if let Some(t) = tag {
+        let count = parse_counter(&val, &mut res);
+        if with_count {
+            rr.counters.insert(t, count);
         }
     }
 
     rr.access = access;
+    res
+}
+
+thread_local! {
+    static QUIET_DECODE_ERRORS: Cell<bool> = Cell::new(false);
+    static DECODE_ERROR_KEYS: RefCell<Vec<String>> = RefCell::new(vec![]);
+}
+
+// Logs a part of the record stored under db_key that the decoder had to skip or guess, unless the decode errors are quiet
+pub fn report_decode_error(db_key: &str, problem: &str) {
+    DECODE_ERROR_KEYS.with(|k| k.borrow_mut().push(db_key.to_owned()));
+    if !QUIET_DECODE_ERRORS.with(|q| q.get()) {
+        warn!("fail decode index record, key={}, {}", db_key, problem);
+    }
+}
+
+// Runs f without logging each malformed record, for bulk scans; returns the result and the keys of the malformed records met,
+// once per problem
+pub fn with_quiet_decode_errors<R, F: FnOnce() -> R>(f: F) -> (R, Vec<String>) {
+    let prev_quiet = QUIET_DECODE_ERRORS.with(|q| q.replace(true));
+    let prev_keys = DECODE_ERROR_KEYS.with(|k| k.replace(vec![]));
+
+    let res = f();
+
+    QUIET_DECODE_ERRORS.with(|q| q.set(prev_quiet));
+    let keys = DECODE_ERROR_KEYS.with(|k| k.replace(prev_keys));
+
+    (res, keys)
 }
 
-fn decode_value_v1(value: &str, rr: &mut ACLRecord, with_count: bool) {
+fn decode_value_v1(value: &str, rr: &mut ACLRecord, with_count: bool) -> Result<(), String> {
     let mut access = 0;
     let mut marker = 0 as char;
+    let mut res = Ok(());
 
     // format value, ver 1
     let mut shift = 0;
@@ -172,13 +239,16 @@ fn decode_value_v1(value: &str, rr: &mut ACLRecord, with_count: bool) {
             marker = c;
         } else {
             match c.to_digit(16) {
-                Some(v) => access |= v << shift,
+                // digits past the width of access are dropped, as the high bits are cut to u8 below
+                Some(v) => access |= v.checked_shl(shift).unwrap_or(0),
                 None => {
-                    eprintln!("ERR! decode_value_v1, fail parse, access is not hex digit {}", value);
+                    if res.is_ok() {
+                        res = Err(format!("access is not hex digit {}", c));
+                    }
                     continue;
                 },
             }
-            shift += 4;
+            shift = shift.saturating_add(4);
         }
     }
 
@@ -194,6 +264,8 @@ fn decode_value_v1(value: &str, rr: &mut ACLRecord, with_count: bool) {
             }
         }
     }
+
+    res
 }
 
 fn extract_date(s: &str) -> (Option<DateTime<Utc>>, String) {
@@ -210,30 +282,57 @@ fn extract_date(s: &str) -> (Option<DateTime<Utc>>, String) {
 }
 
 pub fn decode_filter(filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
+    let (filters_set, date) = decode_filter_all(filter_value);
+    (filters_set.into_iter().next(), date)
+}
+
+pub fn decode_filter_all(filter_value: String) -> (Vec<ACLRecord>, Option<DateTime<Utc>>) {
     let (date, filter_value) = extract_date(&filter_value);
 
     if filter_value.len() < 3 {
-        return (Some(ACLRecord::new_with_access("", 0)), date);
+        return (vec![ACLRecord::new_with_access("", 0)], date);
     }
 
     let mut filters_set: Vec<ACLRecord> = Vec::new();
     decode_rec_to_rights(&filter_value, &mut filters_set);
 
     if filters_set.is_empty() {
-        (Some(ACLRecord::new_with_access(&filter_value, 0)), date)
+        (vec![ACLRecord::new_with_access(&filter_value, 0)], date)
     } else {
-        let el = &mut filters_set[0];
-        (Some(ACLRecord::new_with_access(&el.id.clone(), el.access)), date)
+        (filters_set.iter().map(|el| ACLRecord::new_with_access(&el.id, el.access)).collect(), date)
     }
 }
 
-fn decode_index_record<F>(src: &str, with_counter: bool, mut drain: F) -> (bool, Option<DateTime<Utc>>)
+// Decodes the record and returns the first thing the decoder had to skip or guess, Ok if there was none
+pub fn validate_index_record(src: &str) -> Result<(), String> {
+    let mut res = Ok(());
+    decode_index_record(
+        src,
+        true,
+        |_key, _right| {},
+        |e| {
+            if res.is_ok() {
+                res = Err(e);
+            }
+        },
+    );
+    res
+}
+
+// The record is decoded as far as possible, the parts that are skipped or guessed are passed to problem
+fn decode_index_record<F, P>(src: &str, with_counter: bool, mut drain: F, mut problem: P) -> (bool, Option<DateTime<Utc>>)
 where
     F: FnMut(&str, ACLRecord),
+    P: FnMut(String),
 {
     let (date, rest) = extract_date(src);
 
+    if src.starts_with('T') && date.is_none() {
+        problem("invalid date".to_owned());
+    }
+
     if rest.is_empty() {
+        problem("empty record".to_owned());
         return (false, date);
     }
 
@@ -245,18 +344,26 @@ where
             let key = tokens[idx];
             let value = tokens[idx + 1];
 
-            if !value.is_empty() {
-                let mut rr = ACLRecord::new(key);
+            // a record without id can not match any subject or object, it is skipped
+            match (key.is_empty(), value.chars().next()) {
+                (true, _) => problem("empty id".to_owned()),
+                (false, None) => problem(format!("{}: empty access", key)),
+                (false, Some(first)) => {
+                    let mut rr = ACLRecord::new(key);
 
-                if access_from_char(value.chars().next().unwrap()).is_none() {
-                    decode_value_v1(value, &mut rr, with_counter);
-                } else {
-                    decode_value_v2(value, &mut rr, with_counter);
-                }
+                    let res = if access_from_char(first).is_none() {
+                        decode_value_v1(value, &mut rr, with_counter)
+                    } else {
+                        decode_value_v2(value, &mut rr, with_counter)
+                    };
+                    if let Err(e) = res {
+                        problem(format!("{}: {}", key, e));
+                    }
 
-                //println!("{} -> {}", value, access_to_pretty_string(rr.access));
+                    //println!("{} -> {}", value, access_to_pretty_string(rr.access));
 
-                drain(key, rr);
+                    drain(key, rr);
+                },
             }
         } else {
             break;
@@ -268,19 +375,43 @@ where
         }
     }
 
+    if tokens.len() % 2 == 1 && tokens.last().map_or(false, |t| !t.is_empty()) && rest != "X" {
+        problem("record is not terminated by ';'".to_owned());
+    }
+
     (true, date)
 }
 
 pub fn decode_rec_to_rights(src: &str, result: &mut Vec<ACLRecord>) -> (bool, Option<DateTime<Utc>>) {
-    decode_index_record(src, false, |_key, right| {
-        result.push(right);
-    })
+    decode_rec_to_rights_with(src, result, |_| {})
+}
+
+// As decode_rec_to_rights, the parts of the record that are skipped or guessed are passed to problem
+pub fn decode_rec_to_rights_with<P: FnMut(String)>(src: &str, result: &mut Vec<ACLRecord>, problem: P) -> (bool, Option<DateTime<Utc>>) {
+    decode_index_record(
+        src,
+        false,
+        |_key, right| {
+            result.push(right);
+        },
+        problem,
+    )
 }
 
 pub fn decode_rec_to_rightset(src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
-    decode_index_record(src, true, |key, right| {
-        new_rights.insert(key.to_owned(), right);
-    })
+    decode_rec_to_rightset_with(src, new_rights, |_| {})
+}
+
+// As decode_rec_to_rightset, the parts of the record that are skipped or guessed are passed to problem
+pub fn decode_rec_to_rightset_with<P: FnMut(String)>(src: &str, new_rights: &mut ACLRecordSet, problem: P) -> (bool, Option<DateTime<Utc>>) {
+    decode_index_record(
+        src,
+        true,
+        |key, right| {
+            new_rights.insert(key.to_owned(), right);
+        },
+        problem,
+    )
 }
 
 pub fn update_counters(counters: &mut HashMap<char, u16>, prev_access: u8, cur_access: u8, is_deleted: bool, is_drop_count: bool) -> u8 {
@@ -301,13 +432,19 @@ pub fn update_counters(counters: &mut HashMap<char, u16>, prev_access: u8, cur_a
                     } else {
                         if is_deleted {
                             if prev_access & check_bit > 0 {
-                                *cc -= 1;
+                                if *cc == 0 {
+                                    warn!("update_counters: counter of access {} is already zero", access_c);
+                                }
+                                *cc = cc.saturating_sub(1);
                                 if *cc == 0 {
                                     out_access &= !check_bit;
                                 }
                             }
                         } else {
-                            *cc += 1;
+                            if *cc == u16::MAX {
+                                warn!("update_counters: counter of access {} reached max value {}", access_c, u16::MAX);
+                            }
+                            *cc = cc.saturating_add(1);
                             out_access |= check_bit;
                         }
                     }
@@ -329,3 +466,163 @@ pub fn update_counters(counters: &mut HashMap<char, u16>, prev_access: u8, cur_a
 
     out_access
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_malformed_v1_value_is_reported() {
+        let mut rights = vec![];
+        let mut problems = vec![];
+        decode_rec_to_rights_with("td:User1;2Z;", &mut rights, |e| problems.push(e));
+        assert_eq!(rights[0].access, Access::CanRead as u8);
+        assert_eq!(problems, vec!["td:User1: access is not hex digit Z"]);
+
+        let (_, keys) = with_quiet_decode_errors(|| {
+            let mut rights = vec![];
+            decode_rec_to_rights_with("td:User2;2Z;td:User3;Q;", &mut rights, |e| report_decode_error("Md:doc1", &e));
+        });
+        assert_eq!(keys, vec!["Md:doc1", "Md:doc1"]);
+
+        // the keys met inside are not reported to the outer scan
+        let (_, keys) = with_quiet_decode_errors(|| {
+            report_decode_error("Pd:doc1", "td:User1: access is not hex digit Z");
+            with_quiet_decode_errors(|| report_decode_error("Pd:doc2", "td:User1: access is not hex digit Z"))
+        });
+        assert_eq!(keys, vec!["Pd:doc1"]);
+    }
+
+    #[test]
+    fn test_decode_filter_all() {
+        let (filters, date) = decode_filter_all("T240115,v-s:Filter1;R;v-s:Filter2;RU;v-s:Filter3;2;".to_owned());
+
+        assert_eq!(date, NaiveDate::from_ymd_opt(2024, 1, 15).and_then(|d| d.and_hms_opt(0, 0, 0)).map(|dt| DateTime::from_naive_utc_and_offset(dt, Utc)));
+        assert_eq!(filters.len(), 3);
+        assert_eq!((filters[0].id.as_str(), filters[0].access), ("v-s:Filter1", Access::CanRead as u8));
+        assert_eq!((filters[1].id.as_str(), filters[1].access), ("v-s:Filter2", Access::CanRead as u8 | Access::CanUpdate as u8));
+        assert_eq!((filters[2].id.as_str(), filters[2].access), ("v-s:Filter3", Access::CanRead as u8));
+
+        let (first, _) = decode_filter("v-s:Filter1;R;v-s:Filter2;RU;".to_owned());
+        assert_eq!(first.map(|f| f.id), Some("v-s:Filter1".to_owned()));
+    }
+
+    #[test]
+    fn test_reencode_v1_to_v2() {
+        let src = "T240115,td:User1;6;td:User2;F;td:Group1;2N;";
+
+        let dst = reencode_record(src, 2);
+        assert!(dst.starts_with("T240115,"));
+
+        let mut v1 = ACLRecordSet::new();
+        let mut v2 = ACLRecordSet::new();
+        assert_eq!(decode_rec_to_rightset(src, &mut v1).1, decode_rec_to_rightset(&dst, &mut v2).1);
+
+        assert_eq!(v2.len(), 3);
+        for (id, right) in v1.iter() {
+            let r2 = v2.get(id).unwrap();
+            assert_eq!(r2.access, right.access, "id={}", id);
+            assert_eq!(r2.marker, right.marker, "id={}", id);
+        }
+        assert_eq!(v2.get("td:User1").unwrap().access, Access::CanRead as u8 | Access::CanUpdate as u8);
+
+        // version 2 records survive a round trip through version 1
+        let mut v1_again = ACLRecordSet::new();
+        decode_rec_to_rightset(&reencode_record(&dst, 1), &mut v1_again);
+        assert_eq!(v1_again.get("td:User2").unwrap().access, 15);
+    }
+
+    #[test]
+    fn test_update_counters_saturation() {
+        let read = Access::CanRead as u8;
+
+        let mut counters = HashMap::new();
+        counters.insert('R', u16::MAX - 1);
+        for _ in 0..3 {
+            assert_eq!(update_counters(&mut counters, 0, read, false, false), read);
+        }
+        assert_eq!(counters.get(&'R'), Some(&u16::MAX));
+
+        let mut counters = HashMap::new();
+        counters.insert('R', 0);
+        assert_eq!(update_counters(&mut counters, read, read, true, false), 0);
+        assert_eq!(counters.get(&'R'), Some(&0));
+        assert_eq!(update_counters(&mut counters, read, read, true, false), 0);
+        assert_eq!(counters.get(&'R'), Some(&0));
+    }
+
+    #[test]
+    fn test_decode_malformed_records() {
+        let decode = |src: &str| {
+            let mut rights = ACLRecordSet::new();
+            let mut problems: Vec<String> = vec![];
+            decode_rec_to_rightset_with(src, &mut rights, |e| problems.push(e));
+            let mut res: Vec<(String, u8)> = rights.into_iter().map(|(k, r)| (k, r.access)).collect();
+            res.sort();
+            (res, problems)
+        };
+        let read = Access::CanRead as u8;
+
+        // empty tokens
+        assert_eq!(decode("").0, vec![]);
+        assert_eq!(decode(";").0, vec![]);
+        assert_eq!(decode(";;;;").0, vec![]);
+        assert_eq!(decode(";R;td:User1;R;").0, vec![("td:User1".to_owned(), read)]);
+        assert_eq!(decode("td:User1;;td:User2;R;").0, vec![("td:User2".to_owned(), read)]);
+        assert_eq!(decode("T240115,").0, vec![]);
+
+        // unterminated records
+        assert_eq!(decode("td:User1;R").0, vec![("td:User1".to_owned(), read)]);
+        assert_eq!(decode("td:User1;R;td:User2").0, vec![("td:User1".to_owned(), read)]);
+        assert_eq!(decode("T2401").0, vec![]);
+
+        // a long v1 value used to overflow the shift
+        assert_eq!(decode("td:User1;2222222222222222222;"), (vec![("td:User1".to_owned(), 0x22)], vec![]));
+        assert_eq!(decode("td:User1;R65535999999999999;").0, vec![("td:User1".to_owned(), read)]);
+    }
+
+    #[test]
+    fn test_validate_index_record() {
+        for src in ["X", "T240115,X", "td:User1;R2U;", "T240115,td:User1;F;td:User2;2N;", "td:User1;R"] {
+            assert_eq!(validate_index_record(src), Ok(()), "src={}", src);
+        }
+
+        assert_eq!(validate_index_record("T2401,td:User1;R;"), Err("invalid date".to_owned()));
+        assert_eq!(validate_index_record(""), Err("empty record".to_owned()));
+        assert_eq!(validate_index_record(";R;td:User1;R;"), Err("empty id".to_owned()));
+        assert_eq!(validate_index_record("td:User1;;"), Err("td:User1: empty access".to_owned()));
+        assert_eq!(validate_index_record("td:User1;R;td:User2"), Err("record is not terminated by ';'".to_owned()));
+        assert_eq!(validate_index_record("td:User1;2Z;"), Err("td:User1: access is not hex digit Z".to_owned()));
+        assert_eq!(validate_index_record("td:User1;R65536;"), Err("td:User1: invalid counter 65536".to_owned()));
+        assert_eq!(validate_index_record("td:User1;R2Z;"), Err("td:User1: invalid counter 2Z".to_owned()));
+
+        // the malformed values met while validating are returned, not reported
+        let (_, keys) = with_quiet_decode_errors(|| validate_index_record("td:User1;2Z;"));
+        assert!(keys.is_empty());
+    }
+
+    #[test]
+    fn test_decode_never_panics() {
+        use rand::rngs::StdRng;
+        use rand::seq::SliceRandom;
+        use rand::{Rng, SeedableRng};
+
+        let alphabet: Vec<char> = "TXMRUPmrupNE0123456789ABCDEFabcdef;,:-_ é\u{0}\u{10ffff}".chars().collect();
+        let mut rng = StdRng::seed_from_u64(748);
+
+        for _ in 0..10000 {
+            let len = rng.gen_range(0, 40);
+            let src: String = (0..len).map(|_| *alphabet.choose(&mut rng).unwrap()).collect();
+
+            let res = std::panic::catch_unwind(|| {
+                let mut rightset = ACLRecordSet::new();
+                decode_rec_to_rightset(&src, &mut rightset);
+                let mut rights = vec![];
+                decode_rec_to_rights(&src, &mut rights);
+                let _ = validate_index_record(&src);
+                let _ = decode_filter_all(src.clone());
+            });
+            assert!(res.is_ok(), "panic on record [{}]", src);
+        }
+    }
+}
@@ -1,3 +1,9 @@
+pub mod async_az;
 pub mod az_lmdb;
+pub mod context_ext;
 pub mod formats;
 mod stat_manager;
+pub mod storage_ext;
+pub mod trace_render;
+
+pub use stat_manager::split_stat_message;
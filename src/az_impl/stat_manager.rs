@@ -1,39 +1,140 @@
 use nng::{Protocol, Socket};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use std::collections::VecDeque;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-pub(crate) struct StatPub {
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub(crate) enum StatMode {
+    Full,
+    Minimal,
+    None,
+}
+
+// Receiver of authorization statistics: keys read during authorize (collect_read) and the duration of the call (flush)
+pub(crate) trait StatSink: Send {
+    fn collect_read(&mut self, key: &str, use_cache: bool, from_cache: bool);
+    fn flush(&mut self, duration: Duration);
+}
+
+pub(crate) struct NullStatSink;
+
+impl StatSink for NullStatSink {
+    #[inline(always)]
+    fn collect_read(&mut self, _key: &str, _use_cache: bool, _from_cache: bool) {}
+
+    #[inline(always)]
+    fn flush(&mut self, _duration: Duration) {}
+}
+
+struct PubStatSink {
+    point: StatPub,
+    mode: StatMode,
+}
+
+impl StatSink for PubStatSink {
+    fn collect_read(&mut self, key: &str, use_cache: bool, from_cache: bool) {
+        if self.mode == StatMode::Full {
+            self.point.collect(message(key, use_cache, from_cache));
+        }
+    }
+
+    fn flush(&mut self, duration: Duration) {
+        self.point.set_duration(duration);
+        if let Err(e) = self.point.flush() {
+            warn!("fail flush stat, err={:?}", e);
+        }
+    }
+}
+
+fn message(key: &str, use_cache: bool, from_cache: bool) -> String {
+    match (use_cache, from_cache) {
+        (true, true) => format!("{}/C", key),
+        (true, false) => format!("{}/cB", key),
+        (false, _) => format!("{}/B", key),
+    }
+}
+
+pub(crate) fn new_stat_sink(point: Option<StatPub>, mode: StatMode) -> Box<dyn StatSink> {
+    match (point, mode) {
+        (Some(point), StatMode::Full) => Box::new(PubStatSink {
+            point,
+            mode: StatMode::Full,
+        }),
+        (Some(point), StatMode::Minimal) => Box::new(PubStatSink {
+            point,
+            mode: StatMode::Minimal,
+        }),
+        _ => Box::new(NullStatSink),
+    }
+}
+
+pub(crate) trait StatTransport: Send {
+    fn send(&mut self, data: &[u8]) -> Result<(), nng::Error>;
+}
+
+struct NngTransport {
     socket: Socket,
     url: String,
     is_connected: bool,
+}
+
+impl StatTransport for NngTransport {
+    fn send(&mut self, data: &[u8]) -> Result<(), nng::Error> {
+        if !self.is_connected {
+            self.socket.dial(&self.url)?;
+            self.is_connected = true;
+        }
+        self.socket.send(data).map_err(|(_, e)| e)
+    }
+}
+
+pub(crate) struct StatPub {
+    transport: Box<dyn StatTransport>,
     message_buffer: VecDeque<String>,
     sender_id: String,
     duration: Duration,
+    samples: Vec<String>,
+    batch_size: usize,
+    batch_timeout: Duration,
+    last_send: Instant,
 }
 
 impl StatPub {
     pub(crate) fn new(url: &str) -> Result<Self, nng::Error> {
         let socket = Socket::new(Protocol::Pub0)?;
 
-        let sender_id: String = thread_rng().sample_iter(&Alphanumeric).take(8).map(char::from).collect();
-
-        info!("StatManager: id={}, connected to {}", sender_id, url);
+        info!("StatManager: connected to {}", url);
 
-        Ok(Self {
+        Ok(Self::new_with_transport(Box::new(NngTransport {
             socket,
             url: url.to_string(),
             is_connected: false,
+        })))
+    }
+
+    pub(crate) fn new_with_transport(transport: Box<dyn StatTransport>) -> Self {
+        let sender_id: String = thread_rng().sample_iter(&Alphanumeric).take(8).map(char::from).collect();
+
+        info!("StatManager: id={}", sender_id);
+
+        Self {
+            transport,
             message_buffer: VecDeque::new(),
             sender_id,
             duration: Duration::default(),
-        })
+            samples: vec![],
+            batch_size: 1,
+            batch_timeout: Duration::default(),
+            last_send: Instant::now(),
+        }
     }
 
-    fn connect(&mut self) -> Result<(), nng::Error> {
-        self.socket.dial(&self.url)?;
-        self.is_connected = true;
-        Ok(())
+    // Samples are kept until batch_size of them are collected or batch_timeout passed since the previous send, a zero
+    // timeout is no timeout; batch_size = 1 sends every sample as soon as it is flushed. There is no timer: the timeout is
+    // checked when the next sample is completed, so the samples of an idle context wait for its next authorize call or for the drop
+    pub(crate) fn set_batch(&mut self, batch_size: usize, batch_timeout: Duration) {
+        self.batch_size = batch_size.max(1);
+        self.batch_timeout = batch_timeout;
     }
 
     pub(crate) fn collect(&mut self, message: String) {
@@ -44,28 +145,144 @@ impl StatPub {
         self.duration = duration;
     }
 
+    // Completes the current sample, the samples are sent in batches
     pub(crate) fn flush(&mut self) -> Result<(), nng::Error> {
-        if !self.is_connected {
-            self.connect()?;
-        }
-
-        //if self.message_buffer.is_empty() {
-        //    return Ok(());
-        //}
-
         // Объединяем все сообщения в одну строку, используя точку с запятой в качестве разделителя
         let combined_message = self.message_buffer.iter().map(|s| s.as_str()).collect::<Vec<&str>>().join(";");
 
         // Формируем строку с датой, идентификатором отправителя и объединенными сообщениями,
         // используя запятую в качестве разделителя между элементами
-        let message_with_timestamp = format!("{},{},{}", self.sender_id, self.duration.as_micros(), combined_message);
+        self.samples.push(format!("{},{},{}", self.sender_id, self.duration.as_micros(), combined_message));
 
-        // Отправляем сообщение
-        self.socket.send(message_with_timestamp.as_bytes())?;
-
-        // Очищаем буфер после отправки
+        // Очищаем буфер сообщений текущего замера
         self.message_buffer.clear();
 
+        if self.samples.len() >= self.batch_size || (!self.batch_timeout.is_zero() && self.last_send.elapsed() >= self.batch_timeout) {
+            self.send_batch()?;
+        }
+
         Ok(())
     }
+
+    // Sends the collected samples as one message of lines, a sample per line, see split_stat_message;
+    // the samples of a failed send are dropped
+    fn send_batch(&mut self) -> Result<(), nng::Error> {
+        self.last_send = Instant::now();
+
+        if self.samples.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::take(&mut self.samples).join("\n");
+        self.transport.send(batch.as_bytes())
+    }
+}
+
+// Samples of a message received from StatPub, a message without batching holds one sample
+pub fn split_stat_message(data: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(data).lines().filter(|l| !l.is_empty()).map(|l| l.to_owned()).collect()
+}
+
+impl Drop for StatPub {
+    fn drop(&mut self) {
+        if let Err(e) = self.send_batch() {
+            warn!("fail send stat on drop, err={:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct CountingTransport {
+        sent: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl StatTransport for CountingTransport {
+        fn send(&mut self, data: &[u8]) -> Result<(), nng::Error> {
+            self.sent.lock().unwrap().push(String::from_utf8_lossy(data).to_string());
+            Ok(())
+        }
+    }
+
+    fn send_samples(batch_size: usize, batch_timeout: Duration, count: u64) -> (Vec<usize>, Arc<Mutex<Vec<String>>>) {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let mut stat = StatPub::new_with_transport(Box::new(CountingTransport {
+            sent: sent.clone(),
+        }));
+        stat.set_batch(batch_size, batch_timeout);
+
+        let mut sent_after_flush = vec![];
+        for n in 0..count {
+            stat.collect(format!("Md:doc_{}/B", n));
+            stat.set_duration(Duration::from_micros(n));
+            stat.flush().unwrap();
+            sent_after_flush.push(sent.lock().unwrap().len());
+        }
+        drop(stat);
+        (sent_after_flush, sent)
+    }
+
+    #[test]
+    fn test_samples_sent_in_batches() {
+        let (sent_after_flush, sent) = send_samples(10, Duration::from_secs(3600), 25);
+
+        // nothing is sent until a batch is full, then the batch is sent as one message
+        assert!(sent_after_flush[..9].iter().all(|n| *n == 0));
+        assert_eq!(sent_after_flush[9], 1);
+        assert_eq!(sent_after_flush[24], 2);
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 3);
+        let samples: Vec<String> = sent.iter().flat_map(|m| split_stat_message(m.as_bytes())).collect();
+        assert_eq!(samples.len(), 25);
+        assert_eq!(split_stat_message(sent[2].as_bytes()).len(), 5);
+        assert!(samples[24].ends_with(",24,Md:doc_24/B"));
+    }
+
+    #[test]
+    fn test_batch_size_without_timeout() {
+        let (sent_after_flush, sent) = send_samples(10, Duration::default(), 25);
+
+        assert!(sent_after_flush[..9].iter().all(|n| *n == 0));
+        assert_eq!(sent_after_flush[9], 1);
+        assert_eq!(sent_after_flush[24], 2);
+        assert_eq!(sent.lock().unwrap().len(), 3);
+
+        // without batching every sample is a message of one line
+        let (sent_after_flush, sent) = send_samples(1, Duration::default(), 3);
+        assert_eq!(sent_after_flush, vec![1, 2, 3]);
+        assert!(sent.lock().unwrap().iter().all(|m| split_stat_message(m.as_bytes()).len() == 1 && !m.contains('\n')));
+    }
+
+    fn run_sink(mode: StatMode) -> Vec<String> {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let point = StatPub::new_with_transport(Box::new(CountingTransport {
+            sent: sent.clone(),
+        }));
+
+        let mut sink = new_stat_sink(Some(point), mode);
+        for n in 0..10 {
+            sink.collect_read(&format!("Md:doc_{}", n), false, false);
+            sink.flush(Duration::from_micros(n));
+        }
+        drop(sink);
+
+        let sent = sent.lock().unwrap();
+        sent.clone()
+    }
+
+    #[test]
+    fn test_none_mode_records_nothing() {
+        assert!(run_sink(StatMode::None).is_empty());
+
+        let minimal = run_sink(StatMode::Minimal);
+        assert_eq!(minimal.len(), 10);
+        assert!(minimal.iter().all(|s| s.ends_with(',')));
+
+        let full = run_sink(StatMode::Full);
+        assert_eq!(full.len(), 10);
+        assert!(full[3].ends_with(",3,Md:doc_3/B"));
+    }
 }
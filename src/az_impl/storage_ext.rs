@@ -0,0 +1,64 @@
+use std::io::{Error, ErrorKind, Result};
+use std::str::FromStr;
+use v_authorization::common::Storage;
+
+// Typed reads over any az Storage; Storage is defined in v_authorization, so the getters live in an extension trait
+pub trait StorageExt: Storage {
+    // Ok(None) if the key is absent, an InvalidData error if the value does not parse
+    fn get_parsed<T: FromStr>(&mut self, key: &str) -> Result<Option<T>> {
+        match self.get(key)? {
+            Some(val) => val.trim().parse::<T>().map(Some).map_err(|_| Error::new(ErrorKind::InvalidData, format!("Authorize: fail parse value of {}: [{}]", key, val))),
+            None => Ok(None),
+        }
+    }
+
+    fn get_u64(&mut self, key: &str) -> Result<Option<u64>> {
+        self.get_parsed::<u64>(key)
+    }
+}
+
+impl<S: Storage + ?Sized> StorageExt for S {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::az_impl::formats::{decode_filter, decode_rec_to_rights, decode_rec_to_rightset};
+    use chrono::{DateTime, Utc};
+    use std::collections::HashMap;
+    use v_authorization::{ACLRecord, ACLRecordSet};
+
+    struct MapStorage(HashMap<String, String>);
+
+    impl Storage for MapStorage {
+        fn get(&mut self, key: &str) -> Result<Option<String>> {
+            Ok(self.0.get(key).cloned())
+        }
+
+        fn fiber_yield(&self) {}
+
+        fn decode_rec_to_rights(&self, src: &str, result: &mut Vec<ACLRecord>) -> (bool, Option<DateTime<Utc>>) {
+            decode_rec_to_rights(src, result)
+        }
+
+        fn decode_rec_to_rightset(&self, src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
+            decode_rec_to_rightset(src, new_rights)
+        }
+
+        fn decode_filter(&self, filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
+            decode_filter(filter_value)
+        }
+    }
+
+    #[test]
+    fn test_get_parsed() {
+        let mut storage = MapStorage(hashmap! {"_cache_generation".to_owned() => "42".to_owned(), "Pd:doc1".to_owned() => "td:User1;R;".to_owned()});
+
+        assert_eq!(storage.get_u64("_cache_generation").unwrap(), Some(42));
+        assert_eq!(storage.get_parsed::<i32>("_cache_generation").unwrap(), Some(42));
+        assert_eq!(storage.get_u64("_absent").unwrap(), None);
+        assert_eq!(storage.get_u64("Pd:doc1").unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let dyn_storage: &mut dyn Storage = &mut storage;
+        assert_eq!(dyn_storage.get_u64("_cache_generation").unwrap(), Some(42));
+    }
+}
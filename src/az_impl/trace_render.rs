@@ -0,0 +1,90 @@
+use v_authorization::common::Trace;
+
+// Which parts of the trace to render
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceLevel {
+    Acl,
+    Group,
+    Full,
+}
+
+// Trace is declared in v_authorization, so the pretty-printer is provided as an extension
+pub trait TraceRender {
+    fn render(&self, level: TraceLevel) -> String;
+}
+
+impl<'a> TraceRender for Trace<'a> {
+    fn render(&self, level: TraceLevel) -> String {
+        let mut out = String::new();
+
+        if level == TraceLevel::Group || level == TraceLevel::Full {
+            out.push_str("object groups:\n");
+            for gr in self.group.lines().filter(|l| !l.is_empty()) {
+                out.push_str("  ");
+                out.push_str(gr);
+                out.push('\n');
+            }
+        }
+
+        if level == TraceLevel::Acl || level == TraceLevel::Full {
+            out.push_str("granted by:\n");
+            for line in self.acl.lines().filter(|l| !l.is_empty()) {
+                let fields: Vec<&str> = line.split(';').collect();
+                if let [object_group, subject, predicate] = fields[..] {
+                    out.push_str(&format!("  {} -> {} : {}\n", subject, object_group, predicate));
+                } else {
+                    out.push_str("  ");
+                    out.push_str(line);
+                    out.push('\n');
+                }
+            }
+        }
+
+        if level == TraceLevel::Full {
+            out.push_str("walk:\n");
+            // info lines are numbered as "N text", the continuation of a step is indented deeper
+            for line in self.info.lines().filter(|l| !l.is_empty()) {
+                match line.split_once(' ') {
+                    Some((n, text)) if n.parse::<u32>().is_ok() => out.push_str(&format!("  {:>3}. {}\n", n, text)),
+                    _ => out.push_str(&format!("       {}\n", line)),
+                }
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut acl = "d:Folder1;cfg:Group1;v-s:canRead\nd:Folder1;cfg:Group1;v-s:canUpdate\n".to_owned();
+        let mut group = "v-s:AllResourcesGroup\nd:doc1\nd:Folder1\n".to_owned();
+        let mut info = "1 authorize uri=d:doc1, user=td:User1, request_access=R\n2 found permission S:[cfg:Group1], O:[d:Folder1], access=RU\n".to_owned();
+
+        let trace = Trace {
+            acl: &mut acl,
+            is_acl: true,
+            group: &mut group,
+            is_group: true,
+            info: &mut info,
+            is_info: true,
+            str_num: 2,
+        };
+
+        let acl_only = trace.render(TraceLevel::Acl);
+        assert_eq!(acl_only, "granted by:\n  cfg:Group1 -> d:Folder1 : v-s:canRead\n  cfg:Group1 -> d:Folder1 : v-s:canUpdate\n");
+
+        let group_only = trace.render(TraceLevel::Group);
+        assert!(group_only.contains("  d:Folder1\n"));
+        assert!(!group_only.contains("granted by"));
+
+        let full = trace.render(TraceLevel::Full);
+        assert!(full.contains("  v-s:AllResourcesGroup\n"));
+        assert!(full.contains("  cfg:Group1 -> d:Folder1 : v-s:canUpdate\n"));
+        assert!(full.contains("    2. found permission S:[cfg:Group1], O:[d:Folder1], access=RU\n"));
+    }
+}
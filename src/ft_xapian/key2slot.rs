@@ -12,15 +12,13 @@ pub struct Key2Slot {
     data: HashMap<String, u32>,
     last_size_key2slot: usize,
     modified: SystemTime,
+    // the file the slots are loaded from and stored to
+    path: String,
 }
 
 impl Default for Key2Slot {
     fn default() -> Self {
-        Key2Slot {
-            data: Default::default(),
-            last_size_key2slot: 0,
-            modified: SystemTime::now(),
-        }
+        Key2Slot::new(SystemTime::now())
     }
 }
 
@@ -30,9 +28,21 @@ impl Key2Slot {
             data: Default::default(),
             last_size_key2slot: 0,
             modified: t,
+            path: XAPIAN_INFO_PATH.to_owned() + "/key2slot",
         }
     }
 
+    // Slots stored to and reloaded from the file instead of the one under XAPIAN_INFO_PATH
+    pub fn new_with_path(path: &str) -> Self {
+        let mut key2slot = Key2Slot::default();
+        key2slot.path = path.to_owned();
+        key2slot
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
     pub fn is_empty(&self) -> bool {
         self.data.is_empty()
     }
@@ -78,17 +88,20 @@ impl Key2Slot {
     }
 
     pub fn is_need_reload(&mut self) -> Result<bool, XError> {
-        let fname = XAPIAN_INFO_PATH.to_owned() + "/key2slot";
-        let cur_modified = fs::metadata(fname)?.modified()?;
+        let cur_modified = fs::metadata(&self.path)?.modified()?;
         Ok(cur_modified != self.modified)
     }
 
     pub fn load() -> Result<Key2Slot, XError> {
-        let fname = XAPIAN_INFO_PATH.to_owned() + "/key2slot";
+        Key2Slot::load_from(&(XAPIAN_INFO_PATH.to_owned() + "/key2slot"))
+    }
+
+    pub fn load_from(fname: &str) -> Result<Key2Slot, XError> {
         let mut ff = OpenOptions::new().read(true).open(fname)?;
         ff.seek(SeekFrom::Start(0))?;
 
         let mut key2slot = Key2Slot::new(ff.metadata()?.modified()?);
+        key2slot.path = fname.to_owned();
 
         let mut hash_in_file = String::default();
 
@@ -138,7 +151,7 @@ impl Key2Slot {
             return Ok(());
         }
 
-        let mut ff = OpenOptions::new().write(true).truncate(true).create(true).open(XAPIAN_INFO_PATH.to_owned() + "/key2slot")?;
+        let mut ff = OpenOptions::new().write(true).truncate(true).create(true).open(&self.path)?;
         ff.write_all(format!("\"{}\",{}\n{}", hash, data.len(), data).as_bytes())?;
 
         Ok(())
@@ -2,6 +2,7 @@ use std::collections::HashMap;
 
 pub mod index_schema;
 pub mod key2slot;
+pub mod snippet;
 pub mod vql;
 pub mod xapian_reader;
 pub mod xapian_vql;
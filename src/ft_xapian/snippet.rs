@@ -0,0 +1,172 @@
+use crate::ft_xapian::vql::TTA;
+use crate::onto::individual::Individual;
+use crate::onto::resource::Value;
+
+pub const SNIPPET_MARK_START: &str = "<b>";
+pub const SNIPPET_MARK_END: &str = "</b>";
+pub const SNIPPET_ELLIPSIS: &str = "...";
+
+// words of the text kept before and after the first match
+const SNIPPET_CONTEXT_WORDS: usize = 8;
+
+// Pairs (field, word) of the full-text clauses of the query, a word may start or end with '*'; clauses over ids and uris are skipped
+pub fn query_terms(tta: &TTA) -> Vec<(String, String)> {
+    let mut terms = vec![];
+    collect_query_terms(tta, &mut terms);
+    terms
+}
+
+fn collect_query_terms(tta: &TTA, terms: &mut Vec<(String, String)>) {
+    if let (Some(l), Some(r)) = (&tta.l, &tta.r) {
+        let is_leaf = |t: &TTA| t.l.is_none() && t.r.is_none();
+        if is_leaf(l) && is_leaf(r) {
+            let is_text = r.op.chars().any(char::is_whitespace) || !r.op.contains(':');
+            if (tta.op == "==" || tta.op == "===" || tta.op == "~") && l.op != "@" && is_text {
+                for word in r.op.split(|c: char| !c.is_alphanumeric() && c != '*') {
+                    if !word.trim_matches('*').is_empty() {
+                        terms.push((l.op.to_owned(), word.to_lowercase()));
+                    }
+                }
+            }
+        } else {
+            collect_query_terms(l, terms);
+            collect_query_terms(r, terms);
+        }
+    }
+}
+
+fn term_matches(word: &str, term: &str) -> bool {
+    let core = term.trim_matches('*');
+    match (term.starts_with('*'), term.ends_with('*')) {
+        (false, false) => word == core,
+        (false, true) => word.starts_with(core),
+        (true, false) => word.ends_with(core),
+        (true, true) => word.contains(core),
+    }
+}
+
+fn push_escaped(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+}
+
+// Excerpt of the text around the first word matched by one of the terms, every matched word of the excerpt is wrapped in
+// SNIPPET_MARK_START/SNIPPET_MARK_END; the text itself is HTML-escaped, so only the marks are markup; None if no word matches
+pub fn highlight(text: &str, terms: &[&str]) -> Option<String> {
+    let mut words = vec![];
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                words.push((s, i));
+                start = None;
+            },
+            _ => {},
+        }
+    }
+    if let Some(s) = start {
+        words.push((s, text.len()));
+    }
+
+    let matched: Vec<bool> = words.iter().map(|(s, e)| terms.iter().any(|t| term_matches(&text[*s..*e].to_lowercase(), t))).collect();
+    let first = matched.iter().position(|m| *m)?;
+
+    let from = first.saturating_sub(SNIPPET_CONTEXT_WORDS);
+    let to = (first + SNIPPET_CONTEXT_WORDS).min(words.len() - 1);
+
+    let mut out = String::new();
+    if words[from].0 > 0 {
+        out.push_str(SNIPPET_ELLIPSIS);
+    }
+    let mut pos = words[from].0;
+    for (i, (s, e)) in words.iter().copied().enumerate().take(to + 1).skip(from) {
+        push_escaped(&mut out, &text[pos..s]);
+        if matched[i] {
+            out.push_str(SNIPPET_MARK_START);
+            push_escaped(&mut out, &text[s..e]);
+            out.push_str(SNIPPET_MARK_END);
+        } else {
+            push_escaped(&mut out, &text[s..e]);
+        }
+        pos = e;
+    }
+    if words[to].1 < text.len() {
+        out.push_str(SNIPPET_ELLIPSIS);
+    }
+    Some(out)
+}
+
+// Snippet of the first text value of the queried fields that contains a term, '*' stands for all fields of the individual;
+// empty if there is none
+pub fn make_snippet(indv: &mut Individual, terms: &[(String, String)]) -> String {
+    let mut fields: Vec<String> = vec![];
+    for (field, _) in terms {
+        if field == "*" {
+            for p in indv.get_predicates() {
+                if !fields.contains(&p) {
+                    fields.push(p);
+                }
+            }
+        } else if !fields.contains(field) {
+            fields.push(field.to_owned());
+        }
+    }
+
+    for field in fields.iter() {
+        let field_terms: Vec<&str> = terms.iter().filter(|(f, _)| f == field || f == "*").map(|(_, t)| t.as_str()).collect();
+        for r in indv.get_resources(field).unwrap_or_default() {
+            if let Value::Str(text, _) = &r.value {
+                if let Some(s) = highlight(text, &field_terms) {
+                    return s;
+                }
+            }
+        }
+    }
+    String::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onto::datatype::Lang;
+
+    #[test]
+    fn test_snippet_marks_query_terms() {
+        let tta = TTA::parse_expr("'rdf:type' === 'v-s:Contract' && 'v-s:description' == 'supply deliver*'").unwrap();
+        let terms = query_terms(&tta);
+        assert_eq!(terms, vec![("v-s:description".to_owned(), "supply".to_owned()), ("v-s:description".to_owned(), "deliver*".to_owned())]);
+
+        let mut indv = Individual::default();
+        indv.set_id("d:contract_1");
+        indv.add_uri("rdf:type", "v-s:Contract");
+        indv.add_string(
+            "v-s:description",
+            "The parties agreed on the terms of the Supply of equipment, the goods are delivered by the seller within thirty days after the payment of the invoice",
+            Lang::none(),
+        );
+
+        let snippet = make_snippet(&mut indv, &terms);
+        assert_eq!(snippet, "The parties agreed on the terms of the <b>Supply</b> of equipment, the goods are <b>delivered</b> by the...");
+
+        assert_eq!(make_snippet(&mut indv, &[("v-s:description".to_owned(), "invoices".to_owned())]), "");
+        assert_eq!(highlight("один два", &["два"]), Some("один <b>два</b>".to_owned()));
+    }
+
+    #[test]
+    fn test_snippet_escapes_html() {
+        assert_eq!(
+            highlight("Supply \"<script>alert('x')</script>\" R&D", &["supply"]),
+            Some("<b>Supply</b> &quot;&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;&quot; R&amp;D".to_owned())
+        );
+        assert_eq!(highlight("a <b>supply</b> now", &["b"]), Some("a &lt;<b>b</b>&gt;supply&lt;/<b>b</b>&gt; now".to_owned()));
+    }
+}
@@ -5,9 +5,13 @@ use std::str::from_utf8;
 //  "==", "!="
 //  "===" : поиск в подклассах
 //  "=*" : полнотекстовый поиск
+//  "~" : слова рядом друг с другом (фраза)
 //  "&&", "||",
 //  ">", "<", ">=", "<=",
 
+// Deeper expressions are rejected instead of overflowing the stack of the recursive transform and drop of the tree
+pub(crate) const MAX_VQL_DEPTH: i32 = 512;
+
 #[derive(Debug, Eq, PartialEq)]
 pub enum Decor {
     NONE,
@@ -21,6 +25,8 @@ pub struct TTA {
     pub(crate) token_decor: Decor,
     pub(crate) l: Option<Box<TTA>>,
     pub(crate) r: Option<Box<TTA>>,
+    // levels of operators below and including this one, 0 for an operand
+    pub(crate) depth: i32,
     //count: i32,
 }
 
@@ -39,6 +45,10 @@ impl fmt::Display for TTA {
 
 impl TTA {
     pub fn new(op: &str, l: Option<TTA>, r: Option<TTA>, token_decor: Decor) -> Self {
+        let depth = match (&l, &r) {
+            (None, None) => 0,
+            _ => 1 + l.as_ref().map_or(0, |t| t.depth).max(r.as_ref().map_or(0, |t| t.depth)),
+        };
         let l1 = l.map(Box::new);
         let r1 = r.map(Box::new);
 
@@ -47,6 +57,7 @@ impl TTA {
             token_decor,
             l: l1,
             r: r1,
+            depth,
             //count: 0,
         }
     }
@@ -69,7 +80,7 @@ impl TTA {
                                 break;
                             }
                             if let Some(p) = op.pop() {
-                                process_op(&mut st, p);
+                                process_op(&mut st, p)?;
                             }
                         }
                     }
@@ -93,7 +104,7 @@ impl TTA {
                                 }
                             }
                             if let Some(p) = op.pop() {
-                                process_op(&mut st, p);
+                                process_op(&mut st, p)?;
                             }
                         }
                         op.push(cur_op);
@@ -181,7 +192,7 @@ impl TTA {
         }
         while !op.is_empty() {
             if let Some(operand) = op.pop() {
-                process_op(&mut st, operand);
+                process_op(&mut st, operand)?;
             }
         }
 
@@ -193,16 +204,23 @@ fn delim(c: u8) -> bool {
     c == b' ' || c == b'\t' || c == b'\r' || c == b'\n'
 }
 
-fn process_op(st: &mut Vec<TTA>, op: &str) {
+// None if the new node is deeper than MAX_VQL_DEPTH
+fn process_op(st: &mut Vec<TTA>, op: &str) -> Option<()> {
     let r = st.pop();
     let l = st.pop();
 
     match op {
-        "<" | ">" | "==" | "===" | "!=" | "=*" | "=+" | ">=" | "<=" | "||" | "&&" => {
-            st.push(TTA::new(op, l, r, Decor::NONE));
+        "<" | ">" | "==" | "===" | "!=" | "=*" | "=+" | "~" | ">=" | "<=" | "||" | "&&" => {
+            let tta = TTA::new(op, l, r, Decor::NONE);
+            if tta.depth > MAX_VQL_DEPTH {
+                warn!("expression is deeper than {}", MAX_VQL_DEPTH);
+                return None;
+            }
+            st.push(tta);
         },
         _ => {},
     }
+    Some(())
 }
 
 fn is_op(c: &[u8]) -> &str {
@@ -215,6 +233,10 @@ fn is_op(c: &[u8]) -> &str {
             if c[0] == b'<' {
                 return "<";
             }
+
+            if c[0] == b'~' {
+                return "~";
+            }
         },
         2 => match (c[0], c[1]) {
             (b'>', b'=') => return ">=",
@@ -233,6 +255,10 @@ fn is_op(c: &[u8]) -> &str {
                 if c[0] == b'<' && c[1] != b'=' {
                     return "<";
                 }
+
+                if c[0] == b'~' {
+                    return "~";
+                }
             },
         },
         3 => {
@@ -252,7 +278,7 @@ fn priority(op: &str) -> i32 {
         return 4;
     }
 
-    if op == "==" || op == "!=" || op == "=*" || op == "=+" || op == "===" {
+    if op == "==" || op == "!=" || op == "=*" || op == "=+" || op == "===" || op == "~" {
         return 3;
     }
 
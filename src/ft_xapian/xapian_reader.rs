@@ -2,8 +2,9 @@ use crate::az_impl::az_lmdb::LmdbAzContext;
 use crate::ft_xapian::index_schema::IndexerSchema;
 use crate::ft_xapian::init_db_path;
 use crate::ft_xapian::key2slot::Key2Slot;
+use crate::ft_xapian::snippet::{make_snippet, query_terms};
 use crate::ft_xapian::vql::TTA;
-use crate::ft_xapian::xapian_vql::{exec_xapian_query_and_queue_authorize, get_sorter, transform_vql_to_xapian, AuxContext};
+use crate::ft_xapian::xapian_vql::{exec_xapian_query_and_queue_authorize, is_bad_query_error, set_sort, transform_vql_to_xapian, AuxContext};
 use crate::module::common::load_onto;
 use crate::module::info::ModuleInfo;
 use crate::onto::individual::Individual;
@@ -14,14 +15,17 @@ use crate::storage::async_storage::{get_individual_from_db, AStorage};
 use crate::storage::common::VStorage;
 use crate::v_api::obj::{OptAuthorize, ResultCode};
 use futures::executor::block_on;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::io::{Error, ErrorKind};
-use std::time::Instant;
+use std::path::Path;
+use std::time::{Duration, Instant};
 use std::time::SystemTime;
 use xapian_rusty::*;
 
 const MAX_WILDCARD_EXPANSION: i32 = 20_000;
 const BASE_PATH: &str = "./data";
+const DEFAULT_MODIFIED_RETRIES: u32 = 2;
+const DEFAULT_QUERY_CACHE_SIZE: usize = 128;
 
 pub struct DatabaseQueryParser {
     db: Database,
@@ -37,11 +41,81 @@ impl DatabaseQueryParser {
     }
 }
 
+// Cost of a query known before it is executed: score grows with the estimated number of matches and with the number of
+// terms the wildcards of the query may expand to
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QueryCost {
+    pub estimated_matches: i64,
+    pub wildcard_breadth: i64,
+    pub score: i64,
+}
+
+impl QueryCost {
+    fn new(estimated_matches: i64, wildcard_breadth: i64) -> Self {
+        QueryCost {
+            estimated_matches,
+            wildcard_breadth,
+            score: estimated_matches.saturating_add(wildcard_breadth),
+        }
+    }
+}
+
+struct CachedQuery {
+    db_names: Vec<String>,
+    query: Query,
+}
+
+// Transformed queries keyed by stemmer language, requested databases and query text, the least recently used query is evicted
+// when the cache is full
+struct QueryCache<V> {
+    capacity: usize,
+    queries: HashMap<(String, String, String), V>,
+    order: VecDeque<(String, String, String)>,
+}
+
+impl<V> QueryCache<V> {
+    fn new(capacity: usize) -> Self {
+        QueryCache {
+            capacity,
+            queries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    // A taken query is put back with insert when it is executed
+    fn remove(&mut self, key: &(String, String, String)) -> Option<V> {
+        let v = self.queries.remove(key)?;
+        self.order.retain(|k| k != key);
+        Some(v)
+    }
+
+    fn insert(&mut self, key: (String, String, String), v: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.queries.contains_key(&key) {
+            self.order.retain(|k| *k != key);
+        } else if self.queries.len() >= self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.queries.remove(&old);
+            }
+        }
+        self.order.push_back(key.clone());
+        self.queries.insert(key, v);
+    }
+
+    fn clear(&mut self) {
+        self.queries.clear();
+        self.order.clear();
+    }
+}
+
 pub struct XapianReader {
     pub index_schema: IndexerSchema,
     pub onto: Onto,
     pub onto_modified: SystemTime,
-    using_dbqp: HashMap<Vec<String>, DatabaseQueryParser>,
+    // query parsers by stemmer language and databases
+    using_dbqp: HashMap<(String, Vec<String>), DatabaseQueryParser>,
     opened_db: HashMap<String, Database>,
     xapian_stemmer: Stem,
     xapian_lang: String,
@@ -50,6 +124,12 @@ pub struct XapianReader {
     db2path: HashMap<String, String>,
     committed_op_id: i64,
     az: LmdbAzContext,
+    slow_query_threshold: Option<Duration>,
+    estimate_authorized: bool,
+    strict_fields: bool,
+    modified_retries: u32,
+    query_cache: QueryCache<CachedQuery>,
+    proximity_window: u32,
 }
 
 impl XapianReader {
@@ -60,23 +140,10 @@ impl XapianReader {
             return None;
         }
 
-        let mut onto = Onto::default();
-        load_onto(storage, &mut onto);
-
-        let mut xr = XapianReader {
-            using_dbqp: Default::default(),
-            opened_db: Default::default(),
-            xapian_stemmer: Stem::new(lang).unwrap(),
-            xapian_lang: lang.to_string(),
-            index_schema: Default::default(),
-            mdif: indexer_module_info.unwrap(),
-            key2slot: Key2Slot::load().unwrap_or_default(),
-            onto,
-            db2path: init_db_path(),
-            committed_op_id: 0,
-            onto_modified: SystemTime::now(),
-            az: LmdbAzContext::default(),
-        };
+        let key2slot = Key2Slot::load().unwrap_or_default();
+        let mut xr = XapianReader::from_parts(lang, indexer_module_info.unwrap(), key2slot, init_db_path(), LmdbAzContext::default());
+        load_onto(storage, &mut xr.onto);
+        xr.onto_modified = SystemTime::now();
 
         xr.load_index_schema(storage);
 
@@ -90,22 +157,43 @@ impl XapianReader {
             return None;
         }
 
-        let xr = XapianReader {
+        let key2slot = Key2Slot::load().unwrap_or_default();
+        Some(XapianReader::from_parts(lang, indexer_module_info.unwrap(), key2slot, init_db_path(), LmdbAzContext::default()))
+    }
+
+    // Reader over the databases of db2path with the module info, key2slot and acl indexes under base_path instead of ./data
+    #[cfg(test)]
+    pub(crate) fn new_for_test(lang: &str, base_path: &str, db2path: HashMap<String, String>) -> Self {
+        XapianReader::from_parts(
+            lang,
+            ModuleInfo::new(base_path, "fulltext_indexer", true).unwrap(),
+            Key2Slot::load_from(&format!("{}/key2slot", base_path)).unwrap(),
+            db2path,
+            LmdbAzContext::open_writable(&format!("{}/acl-indexes", base_path)).unwrap(),
+        )
+    }
+
+    fn from_parts(lang: &str, mdif: ModuleInfo, key2slot: Key2Slot, db2path: HashMap<String, String>, az: LmdbAzContext) -> Self {
+        XapianReader {
             using_dbqp: Default::default(),
             opened_db: Default::default(),
             xapian_stemmer: Stem::new(lang).unwrap(),
             xapian_lang: lang.to_string(),
             index_schema: Default::default(),
-            mdif: indexer_module_info.unwrap(),
-            key2slot: Key2Slot::load().unwrap_or_default(),
+            mdif,
+            key2slot,
             onto: Onto::default(),
-            db2path: init_db_path(),
+            db2path,
             committed_op_id: 0,
             onto_modified: SystemTime::UNIX_EPOCH,
-            az: LmdbAzContext::default(),
-        };
-
-        Some(xr)
+            az,
+            slow_query_threshold: None,
+            estimate_authorized: false,
+            strict_fields: false,
+            modified_retries: DEFAULT_MODIFIED_RETRIES,
+            query_cache: QueryCache::new(DEFAULT_QUERY_CACHE_SIZE),
+            proximity_window: 0,
+        }
     }
 
     pub fn query_use_authorize(&mut self, request: FTQuery, storage: &mut VStorage, op_auth: OptAuthorize, reopen: bool) -> QueryResult {
@@ -115,27 +203,131 @@ impl XapianReader {
             }
         }
 
-        let mut res_out_list = vec![];
         fn add_out_element(id: &str, ctx: &mut Vec<String>) {
             ctx.push(id.to_owned());
         }
 
+        self.refresh_onto_and_schema(storage);
+
+        let mut res = retry_if_modified(self.modified_retries, |attempt| {
+            if attempt > 0 {
+                info!("database modified during query, reopen and retry, attempt={}", attempt);
+                if let Err(e) = self.reopen_dbs() {
+                    error!("fail reopen xapian databases: {:?}", e);
+                }
+            }
+
+            let mut res_out_list = vec![];
+            if let Ok(mut res) = block_on(self.query_use_collect_fn(&request, add_out_element, op_auth, &mut res_out_list)) {
+                res.result = res_out_list;
+                debug!("res={:?}", res);
+                return res;
+            }
+            QueryResult::default()
+        });
+        add_facets_and_snippets(&mut res, &request, storage);
+        res
+    }
+
+    fn refresh_onto_and_schema(&mut self, storage: &mut VStorage) {
         if let Some(t) = OntoIndex::get_modified() {
             if t > self.onto_modified {
                 load_onto(storage, &mut self.onto);
                 self.onto_modified = t;
+                self.query_cache.clear();
             }
         }
         if self.index_schema.is_empty() {
             self.load_index_schema(storage);
         }
+    }
 
-        if let Ok(mut res) = block_on(self.query_use_collect_fn(&request, add_out_element, op_auth, &mut res_out_list)) {
-            res.result = res_out_list;
-            debug!("res={:?}", res);
-            return res;
+    // Runs the query with the stemmer of each language and merges the authorized results, a document keeps its best position.
+    // The languages match overlapping sets of documents, so estimated, processed and cursor have no merged value and stay 0;
+    // denied_count and skipped_empty_ids are summed over the languages
+    pub fn query_multilang(&mut self, request: FTQuery, langs: &[String], storage: &mut VStorage) -> QueryResult {
+        fn add_out_element(id: &str, ctx: &mut Vec<String>) {
+            ctx.push(id.to_owned());
+        }
+
+        let mut merged = QueryResult::default();
+        if langs.is_empty() {
+            merged.result_code = ResultCode::BadRequest;
+            return merged;
+        }
+
+        self.refresh_onto_and_schema(storage);
+
+        let total_time = Instant::now();
+        let mut results = vec![];
+
+        for lang in langs {
+            let mut res_out_list = vec![];
+            match block_on(self.query_lang_use_collect_fn(&request, lang, add_out_element, OptAuthorize::YES, &mut res_out_list)) {
+                Ok(res) => {
+                    if res.result_code != ResultCode::Ok {
+                        return res;
+                    }
+                    merged.authorize_time += res.authorize_time;
+                    merged.denied_count += res.denied_count;
+                    merged.skipped_empty_ids += res.skipped_empty_ids;
+                    results.push(res_out_list);
+                },
+                Err(e) => {
+                    warn!("fail query with stemmer [{}], err={:?}", lang, e);
+                },
+            }
+        }
+
+        if results.is_empty() {
+            merged.result_code = ResultCode::InternalServerError;
+            return merged;
         }
-        QueryResult::default()
+
+        merged.result = merge_ranked(results);
+        merged.count = merged.result.len() as i64;
+        merged.result_code = ResultCode::Ok;
+        merged.total_time = total_time.elapsed().as_millis() as i64;
+        merged.query_time = merged.total_time - merged.authorize_time;
+        add_facets_and_snippets(&mut merged, &request, storage);
+        merged
+    }
+
+    // Size of the cache of transformed queries, 0 turns the cache off
+    pub fn with_query_cache(mut self, capacity: usize) -> Self {
+        self.query_cache = QueryCache::new(capacity);
+        self
+    }
+
+    pub fn set_slow_query_threshold(&mut self, threshold: Option<Duration>) {
+        self.slow_query_threshold = threshold;
+    }
+
+    // Fill QueryResult.estimated_authorized, the xapian estimate is counted before authorization and over-counts for restricted users
+    pub fn set_estimate_authorized(&mut self, enabled: bool) {
+        self.estimate_authorized = enabled;
+    }
+
+    // Names accepted in FTQuery.databases, sorted
+    pub fn available_databases(&self) -> Vec<String> {
+        database_names(&self.db2path)
+    }
+
+    // How many times a query that failed with DatabaseModifiedError is run again over the reopened databases
+    pub fn set_database_modified_retries(&mut self, retries: u32) {
+        self.modified_retries = retries;
+    }
+
+    // Answer BadRequest to a query over a field that has no slot in the index, by default such a clause is skipped
+    pub fn set_strict_fields(&mut self, strict: bool) {
+        self.strict_fields = strict;
+        self.query_cache.clear();
+    }
+
+    // Words of a `~` clause must be at most `window` positions apart, by default (0) they must form a phrase
+    pub fn set_proximity_window(&mut self, window: u32) {
+        self.proximity_window = window;
+        self.query_cache.clear();
     }
 
     pub fn query(&mut self, request: FTQuery, storage: &mut VStorage) -> QueryResult {
@@ -148,32 +340,33 @@ impl XapianReader {
         add_out_element: fn(uri: &str, ctx: &mut T),
         op_auth: OptAuthorize,
         out_list: &mut T,
+    ) -> Result<QueryResult> {
+        let lang = self.xapian_lang.clone();
+        self.query_lang_use_collect_fn(request, &lang, add_out_element, op_auth, out_list).await
+    }
+
+    async fn query_lang_use_collect_fn<T>(
+        &mut self,
+        request: &FTQuery,
+        lang: &str,
+        add_out_element: fn(uri: &str, ctx: &mut T),
+        op_auth: OptAuthorize,
+        out_list: &mut T,
     ) -> Result<QueryResult> {
         let total_time = Instant::now();
         let mut sr = QueryResult::default();
 
-        let wtta = TTA::parse_expr(&request.query);
-
-        if wtta.is_none() {
-            error!("fail parse query (phase 1) [{}], tta is empty", request.query);
-            sr.result_code = ResultCode::BadRequest;
-            return Ok(sr);
-        }
+        let cache_key = (lang.to_owned(), request.databases.trim().to_owned(), request.query.trim().to_owned());
 
         if self.key2slot.is_need_reload()? {
-            self.key2slot = Key2Slot::load()?;
+            self.key2slot = Key2Slot::load_from(self.key2slot.path())?;
+            self.query_cache.clear();
         }
 
-        let mut tta = wtta.unwrap();
-
-        let db_names = self.get_dn_names(&tta, &request.databases);
-
-        debug!("db_names={:?}", db_names);
         debug!(
             "user_uri=[{}] query=[{}] str_sort=[{}], db_names=[{:?}], from=[{}], top=[{}], limit=[{}]",
             request.user, request.query, request.sort, request.databases, request.from, request.top, request.limit
         );
-        debug!("TTA [{}]", tta);
 
         if let Some((_, new_committed_op_id)) = self.mdif.read_info() {
             if new_committed_op_id > self.committed_op_id {
@@ -185,18 +378,57 @@ impl XapianReader {
             }
         }
 
-        self.open_dbqp_if_need(&db_names)?;
-
-        let mut query = Query::new()?;
-        if let Some(dbqp) = self.using_dbqp.get_mut(&db_names) {
-            let mut _rd: f64 = 0.0;
-            let mut ctx = AuxContext {
-                key2slot: &self.key2slot,
-                qp: &mut dbqp.qp,
-                onto: &self.onto,
+        let (db_names, mut query) = if let Some(cached) = self.query_cache.remove(&cache_key) {
+            debug!("use cached query [{}]", request.query);
+            (cached.db_names, cached.query)
+        } else {
+            let mut tta = if let Some(t) = TTA::parse_expr(&request.query) {
+                t
+            } else {
+                error!("fail parse query (phase 1) [{}], tta is empty", request.query);
+                sr.result_code = ResultCode::BadRequest;
+                return Ok(sr);
             };
-            transform_vql_to_xapian(&mut ctx, &mut tta, None, None, &mut query, &mut _rd, 0)?;
-        }
+
+            let db_names = self.get_dn_names(&tta, &request.databases);
+
+            if !request.databases.is_empty() {
+                if let Some(name) = find_unknown_database(&db_names, &self.db2path) {
+                    warn!("unknown database [{}] in query, databases=[{}], available={:?}", name, request.databases, self.available_databases());
+                    sr.result_code = ResultCode::BadRequest;
+                    return Ok(sr);
+                }
+            }
+
+            debug!("db_names={:?}", db_names);
+            debug!("TTA [{}]", tta);
+
+            self.open_dbqp_if_need(lang, &db_names)?;
+
+            let mut query = Query::new()?;
+            if let Some(dbqp) = self.using_dbqp.get_mut(&(lang.to_owned(), db_names.clone())) {
+                let mut _rd: f64 = 0.0;
+                let mut ctx = AuxContext {
+                    key2slot: &self.key2slot,
+                    qp: &mut dbqp.qp,
+                    onto: &self.onto,
+                    strict_fields: self.strict_fields,
+                    proximity_window: self.proximity_window,
+                };
+                if let Err(e) = transform_vql_to_xapian(&mut ctx, &mut tta, None, None, &mut query, &mut _rd, 0) {
+                    if is_bad_query_error(&e) {
+                        warn!("reject query [{}]: {:?}", request.query, e);
+                        sr.result_code = ResultCode::BadRequest;
+                        return Ok(sr);
+                    }
+                    return Err(e);
+                }
+            }
+            (db_names, query)
+        };
+
+        self.open_dbqp_if_need(lang, &db_names)?;
+        let dbqp_key = (lang.to_owned(), db_names);
 
         debug!("query={:?}", query.get_description());
 
@@ -206,25 +438,79 @@ impl XapianReader {
             return Ok(sr);
         }
 
-        if let Some(dbqp) = self.using_dbqp.get_mut(&db_names) {
+        if let Some(dbqp) = self.using_dbqp.get_mut(&dbqp_key) {
             let mut xapian_enquire = dbqp.db.new_enquire()?;
 
             xapian_enquire.set_query(&mut query)?;
 
-            if let Some(s) = get_sorter(&request.sort, &self.key2slot)? {
-                xapian_enquire.set_sort_by_key(s, true)?;
+            if let Err(e) = set_sort(&mut xapian_enquire, &request.sort, &self.key2slot) {
+                if is_bad_query_error(&e) {
+                    warn!("reject query [{}]: {:?}", request.query, e);
+                    sr.result_code = ResultCode::BadRequest;
+                    return Ok(sr);
+                }
+                return Err(e);
             }
 
             sr = exec_xapian_query_and_queue_authorize(request, &mut xapian_enquire, add_out_element, op_auth, out_list, &mut self.az).await;
+            if self.estimate_authorized && sr.result_code == ResultCode::Ok {
+                sr.set_estimated_authorized();
+            }
         }
 
+        self.query_cache.insert(
+            cache_key,
+            CachedQuery {
+                db_names: dbqp_key.1,
+                query,
+            },
+        );
+
         debug!("res={:?}", sr);
         sr.total_time = total_time.elapsed().as_millis() as i64;
         sr.query_time = sr.total_time - sr.authorize_time;
+        sr.log_if_slow(self.slow_query_threshold, "xapian", &request.user, &request.query);
 
         Ok(sr)
     }
 
+    // Parses the query and counts the matches on an empty mset, without reading documents or authorizing them
+    pub fn estimate_cost(&mut self, request: &FTQuery) -> Result<QueryCost> {
+        let mut tta = match TTA::parse_expr(&request.query) {
+            Some(t) => t,
+            None => return Err(XError::from(Error::new(ErrorKind::InvalidInput, format!("fail parse query [{}]", request.query)))),
+        };
+
+        if self.key2slot.is_need_reload()? {
+            self.key2slot = Key2Slot::load_from(self.key2slot.path())?;
+            self.query_cache.clear();
+        }
+
+        let db_names = self.get_dn_names(&tta, &request.databases);
+        if !request.databases.is_empty() {
+            if let Some(name) = find_unknown_database(&db_names, &self.db2path) {
+                return Err(XError::from(Error::new(ErrorKind::InvalidInput, format!("unknown database [{}]", name))));
+            }
+        }
+
+        let lang = self.xapian_lang.clone();
+        self.open_dbqp_if_need(&lang, &db_names)?;
+        let dbqp_key = (lang, db_names);
+
+        if let Some(dbqp) = self.using_dbqp.get_mut(&dbqp_key) {
+            let mut ctx = AuxContext {
+                key2slot: &self.key2slot,
+                qp: &mut dbqp.qp,
+                onto: &self.onto,
+                strict_fields: self.strict_fields,
+                proximity_window: self.proximity_window,
+            };
+            return query_cost(&mut ctx, &mut dbqp.db, &mut tta);
+        }
+
+        Ok(QueryCost::new(0, tta_wildcard_breadth(&tta)))
+    }
+
     pub fn load_index_schema(&mut self, storage: &mut VStorage) {
         fn add_out_element(id: &str, ctx: &mut Vec<String>) {
             ctx.push(id.to_owned());
@@ -258,6 +544,7 @@ impl XapianReader {
                 },
             },
         }
+        self.query_cache.clear();
     }
 
     pub async fn c_load_index_schema(&mut self, storage: &AStorage) {
@@ -292,9 +579,14 @@ impl XapianReader {
         }
 
         info!("load index schema, size={}", self.index_schema.len());
+        self.query_cache.clear();
     }
 
+    // Called only through &mut self, before the query is parsed, so it never runs while get_mset of the same reader is in progress.
+    // A reader is not shared between threads (the xapian handles are not Send), concurrent searches use a reader each
     fn reopen_dbs(&mut self) -> Result<()> {
+        self.query_cache.clear();
+
         for (_, el) in self.using_dbqp.iter_mut() {
             el.db.reopen()?;
             el.qp.set_database(&mut el.db)?;
@@ -322,7 +614,7 @@ impl XapianReader {
     fn open_db_if_need(&mut self, db_name: &str) -> Result<()> {
         if !self.opened_db.contains_key(db_name) {
             if let Some(path) = self.db2path.get(db_name) {
-                let db = Database::new_with_path(&("./".to_owned() + path), UNKNOWN)?;
+                let db = Database::new_with_path(&Path::new(".").join(path).to_string_lossy(), UNKNOWN)?;
                 self.opened_db.insert(db_name.to_owned(), db);
             } else {
                 return Err(XError::from(Error::new(ErrorKind::Other, "db2path invalid")));
@@ -331,8 +623,9 @@ impl XapianReader {
         Ok(())
     }
 
-    fn open_dbqp_if_need(&mut self, db_names: &[String]) -> Result<()> {
-        if !self.using_dbqp.contains_key(db_names) {
+    fn open_dbqp_if_need(&mut self, lang: &str, db_names: &[String]) -> Result<()> {
+        let key = (lang.to_owned(), db_names.to_vec());
+        if !self.using_dbqp.contains_key(&key) {
             for el in db_names {
                 self.open_db_if_need(el)?;
             }
@@ -349,13 +642,13 @@ impl XapianReader {
 
             dbqp.qp.set_max_wildcard_expansion(MAX_WILDCARD_EXPANSION)?;
 
-            self.xapian_stemmer = Stem::new(&self.xapian_lang)?;
+            self.xapian_stemmer = Stem::new(lang)?;
 
             dbqp.qp.set_stemmer(&mut self.xapian_stemmer)?;
 
             dbqp.qp.set_database(&mut dbqp.db)?;
 
-            self.using_dbqp.insert(db_names.to_vec(), dbqp);
+            self.using_dbqp.insert(key, dbqp);
         }
         /*
            committed_op_id = get_info().committed_op_id;
@@ -363,35 +656,30 @@ impl XapianReader {
         Ok(())
     }
 
+    // db_names_str is a comma separated list, names prefixed with '+' are added to the autodetected ones: "+deleted"
     fn get_dn_names(&self, tta: &TTA, db_names_str: &str) -> Vec<String> {
+        combine_db_names(db_names_str, || self.autodetect_db_names(tta))
+    }
+
+    fn autodetect_db_names(&self, tta: &TTA) -> Vec<String> {
         let mut db_names = vec![];
 
-        if db_names_str.is_empty() {
-            let mut databases = HashMap::new();
-            self.db_names_from_tta(tta, &mut databases);
+        let mut databases = HashMap::new();
+        self.db_names_from_tta(tta, &mut databases);
 
-            for (key, value) in databases.iter() {
-                if !(*value) {
-                    if key != "not-indexed" {
-                        db_names.push(key.to_owned());
-                    }
+        for (key, value) in databases.iter() {
+            if !(*value) {
+                if key != "not-indexed" {
+                    db_names.push(key.to_owned());
+                }
 
-                    // при автоопределении баз, если находится база deleted, то другие базы исключаются
-                    if key == "deleted" {
-                        db_names.clear();
-                        db_names.push(key.to_owned());
-                        break;
-                    }
+                // при автоопределении баз, если находится база deleted, то другие базы исключаются
+                if key == "deleted" {
+                    db_names.clear();
+                    db_names.push(key.to_owned());
+                    break;
                 }
             }
-        } else {
-            for el in db_names_str.split(',') {
-                db_names.push(String::from(el).trim().to_owned());
-            }
-        }
-
-        if db_names.is_empty() {
-            db_names.push("base".to_owned());
         }
 
         db_names
@@ -421,3 +709,300 @@ impl XapianReader {
         tta.op.to_owned()
     }
 }
+
+fn database_names(db2path: &HashMap<String, String>) -> Vec<String> {
+    let mut names: Vec<String> = db2path.keys().cloned().collect();
+    names.sort();
+    names
+}
+
+fn combine_db_names<F>(db_names_str: &str, autodetect: F) -> Vec<String>
+where
+    F: FnOnce() -> Vec<String>,
+{
+    let mut explicit = vec![];
+    let mut additional = vec![];
+    for el in db_names_str.split(',').map(|el| el.trim()).filter(|el| !el.is_empty()) {
+        if let Some(name) = el.strip_prefix('+') {
+            additional.push(name.trim().to_owned());
+        } else {
+            explicit.push(el.to_owned());
+        }
+    }
+
+    let mut db_names = if explicit.is_empty() {
+        autodetect()
+    } else {
+        explicit
+    };
+
+    if db_names.is_empty() {
+        db_names.push("base".to_owned());
+    }
+
+    for name in additional {
+        if !db_names.contains(&name) {
+            db_names.push(name);
+        }
+    }
+
+    db_names
+}
+
+fn find_unknown_database<'a>(db_names: &'a [String], db2path: &HashMap<String, String>) -> Option<&'a String> {
+    db_names.iter().find(|name| !db2path.contains_key(name.as_str()))
+}
+
+// The xapian binding reads neither the value slots nor the term positions of a document, facet values and snippets are taken
+// from the authorized individuals of the result, so a request without them reads nothing more
+fn add_facets_and_snippets(res: &mut QueryResult, request: &FTQuery, storage: &mut VStorage) {
+    if (request.facets.is_empty() && !request.with_snippets) || res.result_code != ResultCode::Ok {
+        return;
+    }
+    let terms = if request.with_snippets {
+        TTA::parse_expr(&request.query).map(|tta| query_terms(&tta)).unwrap_or_default()
+    } else {
+        vec![]
+    };
+
+    let ids = std::mem::take(&mut res.result);
+    for id in ids.iter() {
+        let mut indv = Individual::default();
+        let is_found = storage.get_individual(id, &mut indv) == ResultCode::Ok;
+        if is_found && !request.facets.is_empty() {
+            res.count_facets(&mut indv, &request.facets);
+        }
+        if request.with_snippets {
+            res.snippets.push(if is_found {
+                make_snippet(&mut indv, &terms)
+            } else {
+                String::new()
+            });
+        }
+    }
+    res.result = ids;
+}
+
+// The index was rewritten while the query was running, the attempt number passed to run_query is above 0 for a repeated run
+fn retry_if_modified<F>(retries: u32, mut run_query: F) -> QueryResult
+where
+    F: FnMut(u32) -> QueryResult,
+{
+    let mut attempt = 0;
+    loop {
+        let res = run_query(attempt);
+        if res.result_code != ResultCode::DatabaseModifiedError || attempt >= retries {
+            return res;
+        }
+        attempt += 1;
+    }
+}
+
+// A wildcard may expand to at most MAX_WILDCARD_EXPANSION terms, the fewer literal characters it has the closer it gets to that limit
+fn wildcard_breadth(token: &str) -> i64 {
+    if !token.contains('*') {
+        return 0;
+    }
+    let literal = token.chars().filter(|c| *c != '*' && *c != '+').count() as i64;
+    MAX_WILDCARD_EXPANSION as i64 / ((literal + 1) * (literal + 1))
+}
+
+fn tta_wildcard_breadth(tta: &TTA) -> i64 {
+    let mut breadth = 0;
+    if let Some(l) = &tta.l {
+        breadth += tta_wildcard_breadth(l);
+    }
+    if let Some(r) = &tta.r {
+        if r.l.is_none() && r.r.is_none() {
+            breadth += wildcard_breadth(&r.op);
+        } else {
+            breadth += tta_wildcard_breadth(r);
+        }
+    }
+    breadth
+}
+
+fn query_cost(ctx: &mut AuxContext, db: &mut Database, tta: &mut TTA) -> Result<QueryCost> {
+    let wildcard_breadth = tta_wildcard_breadth(tta);
+
+    let mut query = Query::new()?;
+    let mut _rd: f64 = 0.0;
+    transform_vql_to_xapian(ctx, tta, None, None, &mut query, &mut _rd, 0)?;
+
+    let mut estimated_matches = 0;
+    if !query.is_empty() {
+        let mut xapian_enquire = db.new_enquire()?;
+        xapian_enquire.set_query(&mut query)?;
+        estimated_matches = xapian_enquire.get_mset(0, 0)?.get_matches_estimated()? as i64;
+    }
+
+    Ok(QueryCost::new(estimated_matches, wildcard_breadth))
+}
+
+// Union of ranked id lists without duplicates, ordered by the best position of an id; on equal positions the earlier list goes first
+fn merge_ranked(results: Vec<Vec<String>>) -> Vec<String> {
+    let mut best: HashMap<String, (usize, usize)> = HashMap::new();
+    for (list_idx, ids) in results.into_iter().enumerate() {
+        for (pos, id) in ids.into_iter().enumerate() {
+            let rank = best.entry(id).or_insert((pos, list_idx));
+            if (pos, list_idx) < *rank {
+                *rank = (pos, list_idx);
+            }
+        }
+    }
+    let mut merged: Vec<(String, (usize, usize))> = best.into_iter().collect();
+    merged.sort_by(|a, b| a.1.cmp(&b.1));
+    merged.into_iter().map(|(id, _)| id).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_ranked_bilingual() {
+        // matches of the same query parsed with the english and the russian stemmers
+        let en = vec!["d:doc_en_1".to_owned(), "d:doc_mixed".to_owned(), "d:doc_en_2".to_owned()];
+        let ru = vec!["d:doc_mixed".to_owned(), "d:doc_ru_1".to_owned()];
+
+        let merged = merge_ranked(vec![en, ru]);
+
+        assert_eq!(merged, vec!["d:doc_en_1".to_owned(), "d:doc_mixed".to_owned(), "d:doc_ru_1".to_owned(), "d:doc_en_2".to_owned()]);
+        assert!(merge_ranked(vec![vec![], vec![]]).is_empty());
+    }
+
+    #[test]
+    fn test_wildcard_query_costs_more() {
+        let mut key2slot = Key2Slot::default();
+        let slot = key2slot.get_slot_and_set_if_not_found("v-s:title");
+
+        let path = std::env::temp_dir().join(format!("xapian-cost-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        {
+            let mut wdb = WritableDatabase::new(path, DB_CREATE_OR_OVERWRITE, CHERT).unwrap();
+            let mut tg = TermGenerator::new().unwrap();
+            let docs = [("d:doc_1", "supply contract"), ("d:doc_2", "service contract"), ("d:doc_3", "cooperation agreement"), ("d:doc_4", "invoice")];
+            for (id, title) in docs {
+                let mut doc = Document::new().unwrap();
+                tg.set_document(&mut doc).unwrap();
+                tg.index_text_with_prefix(title, &format!("X{}X", slot)).unwrap();
+                doc.set_data(id).unwrap();
+                let id_term = format!("Q{}", id);
+                doc.add_boolean_term(&id_term).unwrap();
+                wdb.replace_document(&id_term, &mut doc).unwrap();
+            }
+            wdb.commit().unwrap();
+        }
+        let mut db = Database::new_with_path(path, UNKNOWN).unwrap();
+        let mut qp = QueryParser::new().unwrap();
+        qp.set_database(&mut db).unwrap();
+        qp.set_max_wildcard_expansion(MAX_WILDCARD_EXPANSION).unwrap();
+        let onto = Onto::default();
+
+        let mut cost = |query: &str| {
+            let mut ctx = AuxContext {
+                key2slot: &key2slot,
+                qp: &mut qp,
+                onto: &onto,
+                strict_fields: false,
+                proximity_window: 0,
+            };
+            query_cost(&mut ctx, &mut db, &mut TTA::parse_expr(query).unwrap()).unwrap()
+        };
+
+        let specific = cost("'v-s:title' == 'contract'");
+        let broad = cost("'v-s:title' == 'co*'");
+
+        assert!(specific.estimated_matches > 0);
+        assert_eq!(specific.wildcard_breadth, 0);
+        assert!(broad.estimated_matches > 0);
+        assert!(broad.score > specific.score);
+        assert!(cost("'*' == '*'").score > broad.score);
+        assert!(cost("'*' == 'co*' || '*' == 'ab*'").score > broad.score);
+
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_query_cache_evicts_least_recently_used() {
+        let key = |q: &str| ("english".to_owned(), String::new(), q.to_owned());
+        let mut cache = QueryCache::new(2);
+
+        cache.insert(key("'v-s:title' == 'a'"), 1);
+        cache.insert(key("'v-s:title' == 'b'"), 2);
+
+        // a query taken for execution and put back becomes the most recent one
+        let v = cache.remove(&key("'v-s:title' == 'a'")).unwrap();
+        cache.insert(key("'v-s:title' == 'a'"), v);
+        cache.insert(key("'v-s:title' == 'c'"), 3);
+
+        assert_eq!(cache.remove(&key("'v-s:title' == 'b'")), None);
+        assert_eq!(cache.remove(&key("'v-s:title' == 'a'")), Some(1));
+        assert_eq!(cache.remove(&key("'v-s:title' == 'c'")), Some(3));
+
+        cache.insert(key("'v-s:title' == 'a'"), 1);
+        cache.clear();
+        assert_eq!(cache.remove(&key("'v-s:title' == 'a'")), None);
+
+        let mut disabled = QueryCache::new(0);
+        disabled.insert(key("'v-s:title' == 'a'"), 1);
+        assert_eq!(disabled.remove(&key("'v-s:title' == 'a'")), None);
+    }
+
+    #[test]
+    fn test_database_names() {
+        let db2path = init_db_path();
+        let names = database_names(&db2path);
+
+        assert_eq!(names, vec!["az".to_owned(), "base".to_owned(), "deleted".to_owned(), "system".to_owned()]);
+        assert!(names.iter().all(|n| db2path.contains_key(n)));
+    }
+
+    #[test]
+    fn test_find_unknown_database() {
+        let db2path = init_db_path();
+
+        assert_eq!(find_unknown_database(&["base".to_owned(), "system".to_owned()], &db2path), None);
+        assert_eq!(find_unknown_database(&["base".to_owned(), "archive".to_owned()], &db2path), Some(&"archive".to_owned()));
+    }
+
+    #[test]
+    fn test_combine_db_names() {
+        let autodetect = || vec!["base".to_owned(), "system".to_owned()];
+
+        assert_eq!(combine_db_names("", autodetect), vec!["base".to_owned(), "system".to_owned()]);
+        assert_eq!(combine_db_names("+deleted", autodetect), vec!["base".to_owned(), "system".to_owned(), "deleted".to_owned()]);
+        assert_eq!(combine_db_names("+deleted, +base", autodetect), vec!["base".to_owned(), "system".to_owned(), "deleted".to_owned()]);
+        assert_eq!(combine_db_names("az, +deleted", autodetect), vec!["az".to_owned(), "deleted".to_owned()]);
+        assert_eq!(combine_db_names("+deleted", Vec::new), vec!["base".to_owned(), "deleted".to_owned()]);
+    }
+
+    #[test]
+    fn test_retry_if_modified() {
+        let mut attempts = vec![];
+        let res = retry_if_modified(2, |attempt| {
+            attempts.push(attempt);
+            let mut res = QueryResult::default();
+            if attempt == 0 {
+                res.result_code = ResultCode::DatabaseModifiedError;
+            } else {
+                res.result_code = ResultCode::Ok;
+                res.result = vec!["d:doc_1".to_owned()];
+            }
+            res
+        });
+        assert_eq!(attempts, vec![0, 1]);
+        assert_eq!(res.result_code, ResultCode::Ok);
+        assert_eq!(res.result, vec!["d:doc_1".to_owned()]);
+
+        let mut runs = 0;
+        let res = retry_if_modified(2, |_| {
+            runs += 1;
+            let mut res = QueryResult::default();
+            res.result_code = ResultCode::DatabaseModifiedError;
+            res
+        });
+        assert_eq!(runs, 3);
+        assert_eq!(res.result_code, ResultCode::DatabaseModifiedError);
+    }
+}
@@ -1,17 +1,17 @@
 use crate::az_impl::az_lmdb::LmdbAzContext;
+use crate::az_impl::context_ext::BatchAuthorizer;
 use crate::ft_xapian::key2slot::Key2Slot;
 use crate::ft_xapian::to_lower_and_replace_delimiters;
-use crate::ft_xapian::vql::{Decor, TTA};
+use crate::ft_xapian::vql::{Decor, MAX_VQL_DEPTH, TTA};
 use crate::onto::onto_impl::Onto;
 use crate::search::common::{FTQuery, QueryResult};
 use crate::v_api::obj::{OptAuthorize, ResultCode};
-use crate::v_authorization::common::AuthorizationContext;
 use chrono::NaiveDateTime;
 use regex::Regex;
 use std::collections::HashSet;
+use std::fmt;
 use std::io::{Error, ErrorKind};
 use stopwatch::Stopwatch;
-use v_authorization::common::Access;
 use xapian_rusty::*;
 
 #[derive(Debug, PartialEq)]
@@ -77,68 +77,146 @@ async fn exec<T>(
         query.limit
     };
 
-    let mut read_count = 0;
-
     let mut matches = xapian_enquire.get_mset(query.from, limit)?;
-    let mut processed: i32 = 0;
 
     sr.estimated = matches.get_matches_estimated()? as i64;
 
     let mut it = matches.iterator()?;
+    let subject_ids: Vec<Result<String>> = std::iter::from_fn(|| match it.is_next() {
+        Ok(true) => {
+            let subject_id = it.get_document_data();
+            if let Err(e) = it.next() {
+                return Some(Err(e));
+            }
+            Some(subject_id)
+        },
+        Ok(false) => None,
+        Err(e) => Some(Err(e)),
+    })
+    .collect();
 
     let mut auth_sw = Stopwatch::new();
 
-    while it.is_next()? {
+    let ids: Vec<String> = subject_ids.iter().flatten().filter(|id| !id.is_empty()).cloned().collect();
+    let mut authorizer = BatchAuthorizer::new(ids.iter().map(|id| id.as_str()).collect(), &query.user, query.request_access(), top as usize);
+    let is_readable = |subject_id: &str| {
+        if op_auth == OptAuthorize::NO {
+            return true;
+        }
+        auth_sw.start();
+        let is_readable = authorizer.is_readable(az, subject_id);
+        auth_sw.stop();
+        if is_readable {
+            debug!("subject_id=[{}] authorized for user_id=[{}]", subject_id, query.user);
+        }
+        is_readable
+    };
+
+    collect_authorized(subject_ids.into_iter(), query, top, add_out_element, out_list, is_readable, &mut sr)?;
+
+    sr.authorize_time = auth_sw.elapsed_ms();
+
+    Ok(sr)
+}
+
+// Walks over the found subject ids, passes readable ones to add_out_element and fills the counters of the query result
+fn collect_authorized<T, I, F>(
+    subject_ids: I,
+    query: &FTQuery,
+    top: i32,
+    add_out_element: fn(uri: &str, ctx: &mut T),
+    out_list: &mut T,
+    mut is_readable: F,
+    sr: &mut QueryResult,
+) -> Result<()>
+where
+    I: Iterator<Item = Result<String>>,
+    F: FnMut(&str) -> bool,
+{
+    let mut read_count = 0;
+    let mut processed: i32 = 0;
+    let is_return_denied = query.is_return_denied();
+
+    for subject_id in subject_ids {
+        let subject_id = subject_id?;
+
         processed += 1;
         if (processed % 1000) == 0 {
             info!("processed {}", processed);
         }
 
-        let subject_id = it.get_document_data()?;
-
-        if subject_id.is_empty() {
-            it.next()?;
+        if sr.skip_empty_id(&subject_id) {
             continue;
         }
 
-        let mut is_passed = true;
-
-        if op_auth == OptAuthorize::YES {
-            async {
-                auth_sw.start();
-                if az.authorize(&subject_id, &query.user, Access::CanRead as u8, true).unwrap_or(0) != Access::CanRead as u8 {
-                    is_passed = false;
-                } else {
-                    debug!("subject_id=[{}] authorized for user_id=[{}]", subject_id, query.user);
-                }
-                auth_sw.stop();
-            }
-            .await;
-        }
-
-        if is_passed {
+        if is_readable(&subject_id) {
             add_out_element(&subject_id, out_list);
             read_count += 1;
             if read_count >= top {
                 break;
             }
+        } else {
+            sr.denied_count += 1;
+            if is_return_denied {
+                sr.denied.push(subject_id);
+            }
         }
-        it.next()?;
     }
 
     sr.result_code = ResultCode::Ok;
     sr.processed = processed as i64;
     sr.count = read_count as i64;
-    sr.cursor = (query.from + processed) as i64;
-    sr.authorize_time = auth_sw.elapsed_ms();
+    sr.set_cursor(query.from);
 
-    Ok(sr)
+    Ok(())
 }
 
 pub(crate) struct AuxContext<'a> {
     pub(crate) key2slot: &'a Key2Slot,
     pub(crate) qp: &'a mut QueryParser,
     pub(crate) onto: &'a Onto,
+    // reject a clause over a field without slot instead of skipping it
+    pub(crate) strict_fields: bool,
+    // window of the `~` operator, 0 requires the words to be adjacent
+    pub(crate) proximity_window: u32,
+}
+
+impl<'a> AuxContext<'a> {
+    fn get_slot(&self, key: &str, field: &str) -> Result<Option<u32>> {
+        let slot = self.key2slot.get_slot(key);
+        if slot.is_none() && self.strict_fields {
+            return Err(bad_query_error(BadQuery::NotIndexedField(field.to_owned())));
+        }
+        Ok(slot)
+    }
+}
+
+// A query the client has to change, the caller answers it with BadRequest instead of an internal error
+#[derive(Debug)]
+pub(crate) enum BadQuery {
+    // a field of a strict query has no slot in the index
+    NotIndexedField(String),
+    // a sort order the xapian binding can not apply
+    UnsupportedSort(String),
+}
+
+impl fmt::Display for BadQuery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BadQuery::NotIndexedField(field) => write!(f, "field [{}] is not indexed", field),
+            BadQuery::UnsupportedSort(sort) => write!(f, "sort [{}] is not supported", sort),
+        }
+    }
+}
+
+impl std::error::Error for BadQuery {}
+
+pub(crate) fn bad_query_error(reason: BadQuery) -> XError {
+    XError::from(Error::new(ErrorKind::InvalidInput, reason))
+}
+
+pub(crate) fn is_bad_query_error(e: &XError) -> bool {
+    matches!(e, XError::Io(e) if e.get_ref().map_or(false, |inner| inner.is::<BadQuery>()))
 }
 
 pub(crate) fn transform_vql_to_xapian(
@@ -150,6 +228,10 @@ pub(crate) fn transform_vql_to_xapian(
     _rd: &mut f64,
     _level: i32,
 ) -> Result<String> {
+    if _level > MAX_VQL_DEPTH {
+        return Err(XError::from(Error::new(ErrorKind::Other, format!("transform_vql_to_xapian, expression is deeper than {}", MAX_VQL_DEPTH))));
+    }
+
     let mut query_r = Query::new()?;
     let mut query_l = Query::new()?;
     let mut rd = 0.0;
@@ -232,9 +314,9 @@ pub(crate) fn transform_vql_to_xapian(
                     };
 
                     let wslot = if !rs.is_empty() && rs_first_byte == b'*' && is_good_token(&rs) {
-                        ctx.key2slot.get_slot(&(ls + "#F"))
+                        ctx.get_slot(&(ls.clone() + "#F"), &ls)?
                     } else {
-                        ctx.key2slot.get_slot(&ls)
+                        ctx.get_slot(&ls, &ls)?
                     };
 
                     if let Some(slot) = wslot {
@@ -346,6 +428,30 @@ pub(crate) fn transform_vql_to_xapian(
                 }
             }
         }
+    } else if tta.op == "~" {
+        if tta.l.is_none() || tta.r.is_none() {
+            return Err(XError::from(Error::new(ErrorKind::Other, format!("transform_vql_to_xapian, invalid tta=[{}]", tta))));
+        }
+
+        let mut ls = String::new();
+        if let Some(l) = &mut tta.l {
+            ls = transform_vql_to_xapian(ctx, l, None, None, &mut query_l, &mut ld, _level + 1)?;
+        }
+
+        let mut rs = String::new();
+        if let Some(r) = &mut tta.r {
+            rs = transform_vql_to_xapian(ctx, r, None, None, &mut query_r, &mut rd, _level + 1)?;
+        }
+
+        let query_str = proximity_query_str(&rs, ctx.proximity_window);
+        if !query_str.is_empty() {
+            let flags = FeatureFlag::FlagDefault as i16 | FeatureFlag::FlagPhrase as i16;
+            if ls == "*" {
+                *query = parse_query(ctx.qp, &query_str, flags)?;
+            } else if let Some(slot) = ctx.get_slot(&ls, &ls)? {
+                *query = parse_query_with_prefix(ctx.qp, &query_str, flags, &format!("X{}X", slot))?;
+            }
+        }
     } else if tta.op == "&&" {
         let mut t_op_l = String::new();
         let mut t_op_r = String::new();
@@ -394,7 +500,7 @@ pub(crate) fn transform_vql_to_xapian(
                 c_to = ld;
             }
 
-            if let Some(slot) = ctx.key2slot.get_slot(&token_l) {
+            if let Some(slot) = ctx.get_slot(&token_l, &token_l)? {
                 query_r = Query::new_range(XapianOp::OpValueRange, slot, c_from, c_to)?;
 
                 if query_l.is_empty() {
@@ -456,32 +562,84 @@ pub(crate) fn transform_vql_to_xapian(
     Ok(Default::default())
 }
 
-pub fn get_sorter(sort: &str, key2slot: &Key2Slot) -> Result<Option<MultiValueKeyMaker>> {
-    if !sort.is_empty() {
-        let fields: Vec<&str> = sort.split(',').collect();
-        for f in fields {
-            let el: Vec<&str> = f.trim().split(' ').collect();
-
-            if el.len() == 2 {
-                let key = el.first().unwrap().replace('\'', " ");
-
-                let direction = el.get(1).unwrap().trim();
-                let asc_desc = direction != "desc";
-
-                if let Some(slot) = key2slot.get_slot(key.trim()) {
-                    debug!("use sort {} {}", key, asc_desc);
-                    let mut sorter = MultiValueKeyMaker::new()?;
-                    sorter.add_value(slot, asc_desc)?;
-                    return Ok(Some(sorter));
-                }
-            } else {
-                warn!("ignore invalid sort [{}]", f);
+// Words of the value of a `~` clause for the query parser: a phrase (OpPhrase) for window 0, otherwise the words joined
+// with NEAR (OpNear); the words are lowercased so that none of them is taken for an operator of the parser
+fn proximity_query_str(value: &str, window: u32) -> String {
+    let words: Vec<String> = value.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()).map(|w| w.to_lowercase()).collect();
+    if words.len() < 2 {
+        words.join("")
+    } else if window == 0 {
+        format!("\"{}\"", words.join(" "))
+    } else {
+        words.join(&format!(" NEAR/{} ", window))
+    }
+}
+
+// FTQuery.sort key for the relevance order of Xapian; a value key after it as a tiebreaker, "_relevance, 'v-s:created' desc",
+// is rejected, xapian-rusty does not bind Enquire::set_sort_by_relevance_then_key
+pub const RELEVANCE_SORT_KEY: &str = "_relevance";
+
+#[derive(Debug, PartialEq)]
+pub enum SortOrder {
+    Relevance,
+    Key(u32, bool),
+    RelevanceThenKey(u32, bool),
+}
+
+// Only the first value key is used, the relevance is the default if no key is found
+pub fn parse_sort(sort: &str, key2slot: &Key2Slot) -> SortOrder {
+    let mut is_relevance_first = false;
+    for (idx, f) in sort.split(',').enumerate() {
+        if f.trim().is_empty() {
+            continue;
+        }
+        let el: Vec<&str> = f.trim().split(' ').collect();
+
+        if el.len() == 1 && el[0] == RELEVANCE_SORT_KEY {
+            is_relevance_first |= idx == 0;
+        } else if el.len() == 2 {
+            let key = el.first().unwrap().replace('\'', " ");
+
+            let direction = el.get(1).unwrap().trim();
+            let asc_desc = direction != "desc";
+
+            if let Some(slot) = key2slot.get_slot(key.trim()) {
+                debug!("use sort {} {}", key, asc_desc);
+                return if is_relevance_first {
+                    SortOrder::RelevanceThenKey(slot, asc_desc)
+                } else {
+                    SortOrder::Key(slot, asc_desc)
+                };
             }
+        } else {
+            warn!("ignore invalid sort [{}]", f);
         }
     }
+    SortOrder::Relevance
+}
+
+pub fn get_sorter(sort: &str, key2slot: &Key2Slot) -> Result<Option<MultiValueKeyMaker>> {
+    if let SortOrder::Key(slot, asc_desc) = parse_sort(sort, key2slot) {
+        let mut sorter = MultiValueKeyMaker::new()?;
+        sorter.add_value(slot, asc_desc)?;
+        return Ok(Some(sorter));
+    }
     Ok(None)
 }
 
+pub(crate) fn set_sort(xapian_enquire: &mut Enquire, sort: &str, key2slot: &Key2Slot) -> Result<()> {
+    match parse_sort(sort, key2slot) {
+        SortOrder::Relevance => {},
+        SortOrder::Key(slot, asc_desc) => {
+            let mut sorter = MultiValueKeyMaker::new()?;
+            sorter.add_value(slot, asc_desc)?;
+            xapian_enquire.set_sort_by_key(sorter, true)?;
+        },
+        SortOrder::RelevanceThenKey(..) => return Err(bad_query_error(BadQuery::UnsupportedSort(sort.to_owned()))),
+    }
+    Ok(())
+}
+
 fn add_subclasses_to_query(rs: &str, onto: &Onto) -> Option<String> {
     if rs.find(':').is_some() && rs.find(',').is_none() {
         let mut new_rs = rs.to_string();
@@ -533,9 +691,11 @@ fn get_token_type(token_in: &str) -> (TokenType, f64) {
             return (TokenType::Date, nv.and_utc().timestamp() as f64);
         }
     } else if token.len() == 24 && token[4] == b'-' && token[7] == b'-' && token[10] == b'T' && token[13] == b':' && token[16] == b':' && token[19] == b'.' {
-        let (token, _) = token_in.split_at(token_in.len() - 1);
-        if let Ok(nv) = NaiveDateTime::parse_from_str(token, "%Y-%m-%dT%H:%M:%S%.f") {
-            return (TokenType::Date, nv.and_utc().timestamp() as f64);
+        // the last char (the zone) is dropped, get keeps a multibyte char at the end from panicking
+        if let Some(token) = token_in.trim().get(..token.len() - 1) {
+            if let Ok(nv) = NaiveDateTime::parse_from_str(token, "%Y-%m-%dT%H:%M:%S%.f") {
+                return (TokenType::Date, nv.and_utc().timestamp() as f64);
+            }
         }
     }
 
@@ -582,3 +742,356 @@ fn normalize_if_uri(str: &str) -> Option<String> {
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::common::check_cursor_paging;
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+    use std::panic::{self, AssertUnwindSafe};
+
+    fn add_out_element(id: &str, ctx: &mut Vec<String>) {
+        ctx.push(id.to_owned());
+    }
+
+    // collects the matches d:doc_0..d:doc_<count-1>
+    fn collect_docs<F: FnMut(&str) -> bool>(query: &FTQuery, count: usize, is_readable: F, sr: &mut QueryResult) -> Vec<String> {
+        let mut out_list = vec![];
+        collect_authorized((0..count).map(|n| Ok(format!("d:doc_{}", n))), query, 10000, add_out_element, &mut out_list, is_readable, sr).unwrap();
+        out_list
+    }
+
+    #[test]
+    fn test_all_matches_denied() {
+        let query = FTQuery::new_with_user("cfg:Guest", "'rdf:type' === 'v-s:Document'");
+
+        let mut sr = QueryResult::default();
+        let out_list = collect_docs(&query, 5, |_| false, &mut sr);
+
+        assert_eq!(sr.result_code, ResultCode::Ok);
+        assert_eq!(sr.count, 0);
+        assert_eq!(sr.processed, 5);
+        assert_eq!(sr.denied_count, sr.processed);
+        assert!(out_list.is_empty());
+    }
+
+    #[test]
+    fn test_return_denied_only_for_privileged() {
+        let mut query = FTQuery::new_with_user("cfg:Guest", "'rdf:type' === 'v-s:Document'");
+        query.return_denied = true;
+
+        let is_readable = |id: &str| id != "d:doc_1" && id != "d:doc_3";
+
+        let mut sr = QueryResult::default();
+        collect_docs(&query, 5, is_readable, &mut sr);
+        assert_eq!(sr.denied_count, 2);
+        assert!(sr.denied.is_empty());
+
+        query.privileged = true;
+        let mut sr = QueryResult::default();
+        let out_list = collect_docs(&query, 5, is_readable, &mut sr);
+        assert_eq!(sr.denied_count, 2);
+        assert_eq!(sr.denied, vec!["d:doc_1".to_owned(), "d:doc_3".to_owned()]);
+        assert_eq!(out_list, vec!["d:doc_0".to_owned(), "d:doc_2".to_owned(), "d:doc_4".to_owned()]);
+    }
+
+    #[test]
+    fn test_cursor_advances_across_pages() {
+        check_cursor_paging(|from, page| {
+            let mut query = FTQuery::new_with_user("cfg:Guest", "'rdf:type' === 'v-s:Document'");
+            query.from = from;
+            let mut out_list = vec![];
+            let mut sr = QueryResult::default();
+            collect_authorized(page.iter().map(|id| Ok(id.to_owned())), &query, 10000, add_out_element, &mut out_list, |id| id != "d:doc_3", &mut sr).unwrap();
+            sr.result = out_list;
+            sr
+        });
+    }
+
+    #[test]
+    fn test_estimated_authorized_scaled_by_denied_share() {
+        let query = FTQuery::new_with_user("cfg:Guest", "'rdf:type' === 'v-s:Document'");
+
+        let mut sr = QueryResult::default();
+        sr.estimated = 1000;
+        collect_docs(&query, 100, |id| id.ends_with(['0', '2', '4', '6', '8']), &mut sr);
+        sr.set_estimated_authorized();
+
+        assert_eq!(sr.count, 50);
+        assert_eq!(sr.denied_count, 50);
+        assert_eq!(sr.estimated, 1000);
+        assert!((sr.estimated_authorized - 500).abs() <= 1);
+    }
+
+    #[test]
+    fn test_parse_sort_relevance() {
+        let key2slot = Key2Slot::default();
+
+        assert_eq!(parse_sort("", &key2slot), SortOrder::Relevance);
+        assert_eq!(parse_sort("_relevance", &key2slot), SortOrder::Relevance);
+        assert_eq!(parse_sort("'#12' desc", &key2slot), SortOrder::Key(12, false));
+        assert_eq!(parse_sort("_relevance, '#12' asc", &key2slot), SortOrder::RelevanceThenKey(12, true));
+        assert_eq!(parse_sort("'#12' asc, _relevance", &key2slot), SortOrder::Key(12, true));
+        assert_eq!(parse_sort("_relevance, 'v-s:unknown' asc", &key2slot), SortOrder::Relevance);
+
+        let path = std::env::temp_dir().join(format!("xapian-sort-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        WritableDatabase::new(path, DB_CREATE_OR_OVERWRITE, CHERT).unwrap().commit().unwrap();
+        let mut db = Database::new_with_path(path, UNKNOWN).unwrap();
+        let mut enquire = db.new_enquire().unwrap();
+
+        assert!(set_sort(&mut enquire, "_relevance", &key2slot).is_ok());
+        assert!(is_bad_query_error(&set_sort(&mut enquire, "_relevance, '#12' asc", &key2slot).unwrap_err()));
+
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_strict_fields_rejects_not_indexed_field() {
+        let key2slot = Key2Slot::default();
+        let onto = Onto::default();
+        let mut qp = QueryParser::new().unwrap();
+
+        let mut transform = |strict_fields: bool| {
+            let mut ctx = AuxContext {
+                key2slot: &key2slot,
+                qp: &mut qp,
+                onto: &onto,
+                strict_fields,
+                proximity_window: 0,
+            };
+            let mut tta = TTA::parse_expr("'v-s:unindexed' == 'value'").unwrap();
+            let mut query = Query::new().unwrap();
+            let mut rd = 0.0;
+            transform_vql_to_xapian(&mut ctx, &mut tta, None, None, &mut query, &mut rd, 0)
+        };
+
+        assert!(transform(false).is_ok());
+
+        let err = transform(true).unwrap_err();
+        assert!(is_bad_query_error(&err));
+        assert!(!is_bad_query_error(&XError::from(Error::new(ErrorKind::InvalidInput, "fail open database"))));
+        match err {
+            XError::Io(e) => assert!(e.to_string().contains("v-s:unindexed")),
+            _ => panic!("unexpected error"),
+        }
+    }
+
+    // queries known to hit the edge cases of the parser, the random ones below are built from their fragments
+    const VQL_SEED_CORPUS: &[&str] = &[
+        "",
+        " ",
+        "(",
+        ")",
+        "()",
+        "((",
+        "'",
+        "`",
+        "[",
+        "=",
+        "==",
+        "===",
+        "!",
+        "-",
+        "&&",
+        "||",
+        "a)",
+        "a')",
+        "'a'==",
+        "=='a'",
+        "'v-s:created' > '2020-01-01T00:00:00.00é'",
+        "'v-s:created' < '2020-01-01T00:00:00.000Z\u{3000}'",
+        "'v-s:created' == [2020-01-01T00:00:00, 2020-12-31T23:59:59]",
+        "'v-s:created' == [,]",
+        "'v-s:created' == [1,2,3]",
+        "'v-s:created' > 2020-01-01T00:00:00 && 'v-s:created' < 2021-01-01T00:00:00",
+        "'rdf:type' === 'v-s:Document' && ('v-s:title' == '*док*' || 'v-s:title' != '+тест*')",
+        "'*' == 'абв' && '@' == 'd:doc_1'",
+        "'v-s:flag' == true || 'v-s:flag' != false",
+        "'v-s:number' =* 'ё' && 'v-s:number' =+ 'я'",
+        "'a' == 'b' == 'c' && || ( ) 'd'",
+        "'v-s:name' == '\u{0}\u{ffff}'",
+    ];
+
+    const VQL_FRAGMENTS: &[&str] = &[
+        "'",
+        "`",
+        "[",
+        "]",
+        "(",
+        ")",
+        ",",
+        " ",
+        "==",
+        "===",
+        "!=",
+        "=*",
+        "=+",
+        ">",
+        "<",
+        ">=",
+        "<=",
+        "&&",
+        "||",
+        "*",
+        "+",
+        "@",
+        "-",
+        ":",
+        "rdf:type",
+        "v-s:created",
+        "2020-01-01T00:00:00",
+        ".000Z",
+        "42",
+        "true",
+        "абв",
+        "é",
+        "\u{3000}",
+    ];
+
+    fn check_never_panics(key2slot: &Key2Slot, onto: &Onto, qp: &mut QueryParser, query: &str) {
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            if let Some(mut tta) = TTA::parse_expr(query) {
+                let mut ctx = AuxContext {
+                    key2slot,
+                    qp,
+                    onto,
+                    strict_fields: false,
+                    proximity_window: 0,
+                };
+                let mut xquery = Query::new().unwrap();
+                let mut rd = 0.0;
+                let _ = transform_vql_to_xapian(&mut ctx, &mut tta, None, None, &mut xquery, &mut rd, 0);
+            }
+        }));
+        assert!(res.is_ok(), "panic on query [{}]", query);
+    }
+
+    #[test]
+    fn test_vql_never_panics() {
+        let mut key2slot = Key2Slot::default();
+        for field in ["rdf:type", "v-s:created", "v-s:title", "v-s:flag", "v-s:number", "v-s:name", "v-s:title#F"] {
+            key2slot.get_slot_and_set_if_not_found(field);
+        }
+        let onto = Onto::default();
+        let mut qp = QueryParser::new().unwrap();
+
+        for query in VQL_SEED_CORPUS {
+            check_never_panics(&key2slot, &onto, &mut qp, query);
+        }
+
+        let mut rng = StdRng::seed_from_u64(747);
+        for _ in 0..5000 {
+            let len = rng.gen_range(1, 16);
+            let query: String = (0..len).map(|_| *VQL_FRAGMENTS.choose(&mut rng).unwrap()).collect();
+            check_never_panics(&key2slot, &onto, &mut qp, &query);
+        }
+    }
+
+    #[test]
+    fn test_proximity_query_str() {
+        assert_eq!(proximity_query_str("Supply contract", 0), "\"supply contract\"");
+        assert_eq!(proximity_query_str("supply, contract", 5), "supply NEAR/5 contract");
+        assert_eq!(proximity_query_str("contract", 5), "contract");
+        assert_eq!(proximity_query_str("supply OR \"contract", 0), "\"supply or contract\"");
+        assert_eq!(proximity_query_str(" - ", 0), "");
+
+        let tta = TTA::parse_expr("'v-s:title' ~ 'supply contract' && 'rdf:type' === 'v-s:Document'").unwrap();
+        assert_eq!(tta.op, "&&");
+        assert_eq!(tta.l.unwrap().op, "~");
+    }
+
+    fn find_by_vql(db: &mut Database, key2slot: &Key2Slot, query: &str, proximity_window: u32) -> Vec<String> {
+        let onto = Onto::default();
+        let mut qp = QueryParser::new().unwrap();
+        let mut ctx = AuxContext {
+            key2slot,
+            qp: &mut qp,
+            onto: &onto,
+            strict_fields: false,
+            proximity_window,
+        };
+        let mut tta = TTA::parse_expr(query).unwrap();
+        let mut xquery = Query::new().unwrap();
+        let mut rd = 0.0;
+        transform_vql_to_xapian(&mut ctx, &mut tta, None, None, &mut xquery, &mut rd, 0).unwrap();
+
+        let mut enquire = db.new_enquire().unwrap();
+        enquire.set_query(&mut xquery).unwrap();
+        let mut matches = enquire.get_mset(0, 10).unwrap();
+        let mut it = matches.iterator().unwrap();
+        let mut ids = vec![];
+        while it.is_next().unwrap() {
+            ids.push(it.get_document_data().unwrap());
+            it.next().unwrap();
+        }
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_phrase_matches_adjacent_words() {
+        let mut key2slot = Key2Slot::default();
+        let slot = key2slot.get_slot_and_set_if_not_found("v-s:title");
+
+        let path = std::env::temp_dir().join(format!("xapian-phrase-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        {
+            let mut wdb = WritableDatabase::new(path, DB_CREATE_OR_OVERWRITE, CHERT).unwrap();
+            let mut tg = TermGenerator::new().unwrap();
+            let docs = [
+                ("d:adjacent", "supply contract signed"),
+                ("d:near", "supply of the contract"),
+                ("d:scattered", "contract signed after a long delay with the supply department"),
+            ];
+            for (id, title) in docs {
+                let mut doc = Document::new().unwrap();
+                tg.set_document(&mut doc).unwrap();
+                tg.index_text_with_prefix(title, &format!("X{}X", slot)).unwrap();
+                doc.set_data(id).unwrap();
+                let id_term = format!("Q{}", id);
+                doc.add_boolean_term(&id_term).unwrap();
+                wdb.replace_document(&id_term, &mut doc).unwrap();
+            }
+            wdb.commit().unwrap();
+        }
+        let mut db = Database::new_with_path(path, UNKNOWN).unwrap();
+
+        assert_eq!(find_by_vql(&mut db, &key2slot, "'v-s:title' == 'supply'", 0), vec!["d:adjacent", "d:near", "d:scattered"]);
+        assert_eq!(find_by_vql(&mut db, &key2slot, "'v-s:title' ~ 'supply contract'", 0), vec!["d:adjacent"]);
+        assert_eq!(find_by_vql(&mut db, &key2slot, "'v-s:title' ~ 'supply contract'", 4), vec!["d:adjacent", "d:near"]);
+
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_deep_expression_is_rejected() {
+        let key2slot = Key2Slot::default();
+        let onto = Onto::default();
+        let mut qp = QueryParser::new().unwrap();
+        let mut ctx = AuxContext {
+            key2slot: &key2slot,
+            qp: &mut qp,
+            onto: &onto,
+            strict_fields: false,
+            proximity_window: 0,
+        };
+
+        let query = |terms: usize| vec!["'v-s:title' == 'a'"; terms].join(" && ");
+        assert_eq!(TTA::parse_expr(&query(MAX_VQL_DEPTH as usize)).map(|t| t.depth), Some(MAX_VQL_DEPTH));
+        assert!(TTA::parse_expr(&query(MAX_VQL_DEPTH as usize + 1)).is_none());
+        assert!(TTA::parse_expr(&format!("{}{}{}", "(".repeat(1000), query(3), ")".repeat(1000))).is_some());
+
+        // a tree built without the parser is still checked by the transform
+        let eq = || TTA::new("==", Some(TTA::new("v-s:title", None, None, Decor::QUOTED)), Some(TTA::new("a", None, None, Decor::QUOTED)), Decor::NONE);
+        let mut tta = eq();
+        for _ in 0..MAX_VQL_DEPTH + 10 {
+            tta = TTA::new("&&", Some(tta), Some(eq()), Decor::NONE);
+        }
+        let mut xquery = Query::new().unwrap();
+        let mut rd = 0.0;
+        assert!(transform_vql_to_xapian(&mut ctx, &mut tta, None, None, &mut xquery, &mut rd, 0).is_err());
+    }
+}
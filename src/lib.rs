@@ -23,4 +23,5 @@ pub use v_queue;
 
 pub mod runtime_wrapper;
 
+#[cfg(any(feature = "tokio_0_2", feature = "tokio_1"))]
 pub use runtime_wrapper::RuntimeWrapper;
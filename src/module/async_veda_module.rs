@@ -0,0 +1,183 @@
+use crate::module::common::{lock_consumer, sys_sig_listener};
+use crate::module::module_impl::{init_log, BatchCallbacks, Module, PrepareError, MAIN_QUEUE_NAME};
+use crate::onto::individual::Individual;
+use crate::runtime_wrapper::spawn_blocking;
+use nng::{Protocol, Socket};
+use std::thread;
+use std::time::{Duration, Instant};
+use v_queue::consumer::Consumer;
+
+// Same callbacks as VedaQueueModule, for modules that call async clients while preparing the queue
+#[allow(async_fn_in_trait)]
+pub trait AsyncVedaQueueModule {
+    // a new size of the batch, None keeps the size found in the queue
+    async fn before_batch(&mut self, _size_batch: u32) -> Option<u32> {
+        None
+    }
+    async fn prepare(&mut self, queue_element: &mut Individual) -> Result<bool, PrepareError>;
+    // Ok(true) commits the queue position after the batch
+    async fn after_batch(&mut self, _prepared_batch_size: u32) -> Result<bool, PrepareError> {
+        Ok(false)
+    }
+    async fn heartbeat(&mut self) -> Result<(), PrepareError> {
+        Ok(())
+    }
+    async fn before_start(&mut self) {}
+    async fn before_exit(&mut self) {}
+}
+
+impl Module {
+    // The async counterpart of prepare_queue, must be run inside the runtime (see RuntimeWrapper::block_on);
+    // waiting on the notify channel and the pauses between batches are moved to the blocking pool. Without a tokio feature
+    // they block the executor (see runtime_wrapper::spawn_blocking), so the loop must not share its thread with other tasks
+    pub async fn prepare_queue_async<M: AsyncVedaQueueModule>(&mut self, veda_module: &mut M) {
+        init_log(&self.name);
+
+        let _consumer_lock = lock_consumer(self.queue_base_path(), MAIN_QUEUE_NAME, &self.name).unwrap_or_else(|e| panic!("!!!!!!!!! FAIL QUEUE: {}", e));
+        let queue_consumer = &mut self.open_queue_consumer().expect("!!!!!!!!! FAIL QUEUE");
+
+        if let Ok(ch) = sys_sig_listener() {
+            self.syssig_ch = Some(ch);
+        }
+
+        let mut soc = Socket::new(Protocol::Sub0).unwrap();
+        let mut count_timeout_error = 0;
+
+        let mut prev_batch_time = Instant::now();
+        veda_module.before_start().await;
+        loop {
+            if let Some(qq) = &self.syssig_ch {
+                if qq.try_recv().is_ok() {
+                    info!("Exit");
+                    veda_module.before_exit().await;
+                    std::process::exit(exitcode::OK);
+                }
+            }
+
+            if let Err(PrepareError::Fatal) = veda_module.heartbeat().await {
+                error!("heartbeat: found fatal error, stop listen queue");
+                break;
+            }
+
+            if let Some(s) = self.connect_to_notify_channel() {
+                soc = s;
+            }
+
+            let (size_batch, prepared_batch_size) = match self.prepare_batch_async(queue_consumer, veda_module, None).await {
+                Ok(sizes) => sizes,
+                Err(PrepareError::Fatal) => return,
+                Err(_) => continue,
+            };
+
+            if prepared_batch_size == size_batch {
+                let soc_clone = soc.clone();
+                let res = spawn_blocking(move || soc_clone.recv()).await;
+                // a failed blocking task is not a timeout of the channel
+                let res = res.map_or(Ok(()), |r| r.map(|_| ()));
+                self.on_notify_recv(res, size_batch, &mut count_timeout_error);
+            }
+
+            if let Some(t) = self.max_timeout_between_batches {
+                let delta = prev_batch_time.elapsed().as_millis() as u64;
+                let need_sleep = match self.min_batch_size_to_cancel_timeout {
+                    Some(c) => prepared_batch_size < c && delta < t,
+                    None => delta < t,
+                };
+                if need_sleep {
+                    let _ = spawn_blocking(move || thread::sleep(Duration::from_millis(t - delta))).await;
+                    info!("sleep {} ms", t - delta);
+                }
+            }
+
+            prev_batch_time = Instant::now();
+        }
+    }
+
+    // Reads and prepares one batch of the queue, returns the size of the batch found in the queue and the count of prepared records
+    pub(crate) async fn prepare_batch_async<M: AsyncVedaQueueModule>(
+        &mut self,
+        queue_consumer: &mut Consumer,
+        veda_module: &mut M,
+        max_records: Option<u32>,
+    ) -> Result<(u32, u32), PrepareError> {
+        self.prepare_batch_with(queue_consumer, &mut AsyncModule(veda_module), max_records).await
+    }
+}
+
+struct AsyncModule<'a, M>(&'a mut M);
+
+impl<M: AsyncVedaQueueModule> BatchCallbacks for AsyncModule<'_, M> {
+    async fn before_batch(&mut self, size_batch: u32) -> Option<u32> {
+        self.0.before_batch(size_batch).await
+    }
+
+    async fn prepare(&mut self, queue_element: &mut Individual, _queue_consumer: &Consumer) -> Result<bool, PrepareError> {
+        self.0.prepare(queue_element).await
+    }
+
+    async fn after_batch(&mut self, prepared_batch_size: u32) -> Result<bool, PrepareError> {
+        self.0.after_batch(prepared_batch_size).await
+    }
+}
+
+#[cfg(all(test, any(feature = "tokio_0_2", feature = "tokio_1")))]
+mod tests {
+    use super::*;
+    use crate::onto::individual2msgpack::to_msgpack;
+    use crate::runtime_wrapper::RuntimeWrapper;
+    use std::env;
+    use v_queue::queue::Queue;
+    use v_queue::record::{Mode, MsgType};
+
+    struct AsyncCountModule {
+        prepared: Vec<String>,
+        batches: Vec<u32>,
+    }
+
+    impl AsyncVedaQueueModule for AsyncCountModule {
+        async fn prepare(&mut self, queue_element: &mut Individual) -> Result<bool, PrepareError> {
+            let id = queue_element.get_id().to_owned();
+            let id = spawn_blocking(move || id).await.unwrap();
+            self.prepared.push(id);
+            Ok(true)
+        }
+
+        async fn after_batch(&mut self, prepared_batch_size: u32) -> Result<bool, PrepareError> {
+            self.batches.push(prepared_batch_size);
+            Ok(false)
+        }
+    }
+
+    #[test]
+    fn test_prepare_batch_async() {
+        let base_path = env::temp_dir().join(format!("veda-async-module-{}", std::process::id())).to_str().unwrap().to_owned();
+
+        let mut queue = Queue::new(&base_path, MAIN_QUEUE_NAME, Mode::ReadWrite).unwrap();
+        for n in 0..3 {
+            let mut indv = Individual::default();
+            indv.set_id(&format!("d:doc_{}", n));
+            let mut raw = vec![];
+            to_msgpack(&indv, &mut raw).unwrap();
+            queue.push(&raw, MsgType::Object).unwrap();
+        }
+
+        let mut module = Module::new_for_test();
+        module.set_queue_base_path(&base_path);
+        let mut consumer = module.open_queue_consumer().unwrap();
+        let mut async_module = AsyncCountModule {
+            prepared: vec![],
+            batches: vec![],
+        };
+
+        let mut rt = RuntimeWrapper::new();
+        let res = rt.block_on(module.prepare_batch_async(&mut consumer, &mut async_module, None));
+
+        assert!(matches!(res, Ok((3, 3))));
+        assert_eq!(async_module.prepared, (0..3).map(|n| format!("d:doc_{}", n)).collect::<Vec<String>>());
+        assert_eq!(async_module.batches, vec![3]);
+        assert_eq!(consumer.count_popped, 3);
+
+        drop(consumer);
+        std::fs::remove_dir_all(&base_path).unwrap_or_default();
+    }
+}
@@ -2,18 +2,21 @@ use crossbeam_channel::{bounded, Receiver};
 use signal_hook::consts::signal::*;
 use signal_hook::consts::{SIGCONT, SIGTSTP};
 use std::collections::HashSet;
-use std::io::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind};
 use std::os::raw::c_int;
 use std::thread;
 #[cfg(feature = "extended-siginfo")]
 type Signals = signal_hook::iterator::SignalsInfo<signal_hook::iterator::exfiltrator::origin::WithOrigin>;
+use crate::module::module_impl::MAIN_QUEUE_NAME;
 use crate::onto::individual::Individual;
 use crate::onto::onto_impl::Onto;
 use crate::onto::onto_index::OntoIndex;
 use crate::storage::async_storage::{get_individual_from_db, AStorage};
 use crate::storage::common::VStorage;
 use crate::v_api::obj::ResultCode;
-use chrono::Utc;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use fs2::FileExt;
 #[cfg(not(feature = "extended-siginfo"))]
 use signal_hook::iterator::Signals;
 use signal_hook::low_level;
@@ -106,7 +109,82 @@ pub fn sys_sig_listener() -> Result<Receiver<i32>, Error> {
     Ok(receiver)
 }
 
-const MAIN_QUEUE_NAME: &str = "individuals-flow";
+// Reads a date-time field stored as a datetime or integer value (seconds since unix epoch) or as a string
+pub fn get_first_datetime(indv: &mut Individual, field: &str) -> Option<DateTime<Utc>> {
+    if let Some(t) = indv.get_first_datetime(field) {
+        return Utc.timestamp_opt(t, 0).single();
+    }
+    if let Some(t) = indv.get_first_integer(field) {
+        return Utc.timestamp_opt(t, 0).single();
+    }
+    indv.get_first_literal(field).and_then(|s| parse_datetime_str(&s))
+}
+
+pub fn parse_datetime_str(s: &str) -> Option<DateTime<Utc>> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Some(t.with_timezone(&Utc));
+    }
+    NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f").ok().map(|t| t.and_utc())
+}
+
+// Veda ticks count 100ns intervals from 0001-01-01T00:00:00Z (as .NET ticks), this is the tick of the unix epoch
+pub const UNIX_EPOCH_TICKS: i64 = 621_355_968_000_000_000;
+const TICKS_PER_SECOND: i64 = 10_000_000;
+
+// Ticks out of the range of DateTime<Utc> give ErrorKind::InvalidData
+pub fn ticks_to_datetime(ticks: i64) -> Result<DateTime<Utc>, Error> {
+    let out_of_range = || Error::new(ErrorKind::InvalidData, format!("ticks {} are out of the datetime range", ticks));
+    let unix_ticks = ticks.checked_sub(UNIX_EPOCH_TICKS).ok_or_else(out_of_range)?;
+    let nanos = (unix_ticks.rem_euclid(TICKS_PER_SECOND) * 100) as u32;
+    Utc.timestamp_opt(unix_ticks.div_euclid(TICKS_PER_SECOND), nanos).single().ok_or_else(out_of_range)
+}
+
+pub fn datetime_to_ticks(dt: DateTime<Utc>) -> i64 {
+    UNIX_EPOCH_TICKS + dt.timestamp() * TICKS_PER_SECOND + (dt.timestamp_subsec_nanos() / 100) as i64
+}
+
+// Exclusive use of a consumer name on a queue, released on drop. The lock taken inside Consumer::new is released before it returns,
+// so two processes with the same consumer name would overwrite the position of each other
+pub struct ConsumerLock {
+    _file: File,
+}
+
+pub fn lock_consumer(base_path: &str, queue_name: &str, consumer_name: &str) -> Result<ConsumerLock, Error> {
+    std::fs::create_dir_all(base_path)?;
+    let path = format!("{}/{}_consumer_{}.lock", base_path, queue_name, consumer_name);
+    let file = OpenOptions::new().read(true).write(true).create(true).open(&path)?;
+    if let Err(e) = file.try_lock_exclusive() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("consumer [{}] of queue [{}] is already in use by another process, lock file {}, err={}", consumer_name, queue_name, path, e),
+        ));
+    }
+    Ok(ConsumerLock {
+        _file: file,
+    })
+}
+
+// Rewinds the consumer to the first record of part 0 and saves the position, the records are read again from the start of the queue.
+// `confirm` must be true, it guards against an accidental full reprocessing
+pub fn reset_consumer_position(consumer: &mut Consumer, confirm: bool) -> Result<(), Error> {
+    if !confirm {
+        return Err(Error::new(ErrorKind::PermissionDenied, format!("reset position of consumer [{}] is not confirmed", consumer.name)));
+    }
+
+    let base_path = consumer.queue.base_path.clone();
+    let queue_name = consumer.queue.name.clone();
+    let consumer_name = consumer.name.clone();
+
+    let info_pop_file_name = format!("{}/{}_info_pop_{}", base_path, queue_name, consumer_name);
+    std::fs::write(&info_pop_file_name, format!("{};{};{};{};{}\n", queue_name, consumer_name, 0, 0, 0))?;
+
+    // reopen the consumer, it reads the saved position and seeks the queue part to it
+    *consumer = Consumer::new(&base_path, &consumer_name, &queue_name)
+        .map_err(|e| Error::new(ErrorKind::Other, format!("fail reopen consumer [{}] after reset, err={}", consumer_name, e.as_str())))?;
+
+    warn!("consumer [{}] of queue [{}] is reset to the start", consumer_name, queue_name);
+    Ok(())
+}
 
 pub fn get_queue_status(id: &str) -> Individual {
     let mut out_indv = Individual::default();
@@ -140,3 +218,103 @@ macro_rules! init_module_log {
         info!("{} {} {}", $module_name, version!(), git_version!());
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onto::datatype::Lang;
+
+    fn pop_string(consumer: &mut Consumer) -> Option<String> {
+        if !consumer.pop_header() {
+            return None;
+        }
+        let mut msg = vec![0; consumer.header.msg_length as usize];
+        consumer.pop_body(&mut msg).ok()?;
+        consumer.commit();
+        String::from_utf8(msg).ok()
+    }
+
+    #[test]
+    fn test_reset_consumer_position() {
+        let base_path = std::env::temp_dir().join(format!("veda-consumer-reset-{}", std::process::id())).to_str().unwrap().to_owned();
+
+        let mut queue = v_queue::queue::Queue::new(&base_path, MAIN_QUEUE_NAME, Mode::ReadWrite).unwrap();
+        for n in 0..10 {
+            queue.push(n.to_string().as_bytes(), v_queue::record::MsgType::String).unwrap();
+        }
+
+        let mut consumer = Consumer::new(&base_path, "scripts_main", MAIN_QUEUE_NAME).unwrap();
+        for n in 0..3 {
+            assert_eq!(pop_string(&mut consumer), Some(n.to_string()));
+        }
+
+        assert_eq!(reset_consumer_position(&mut consumer, false).err().map(|e| e.kind()), Some(ErrorKind::PermissionDenied));
+        assert_eq!(pop_string(&mut consumer), Some("3".to_owned()));
+
+        reset_consumer_position(&mut consumer, true).unwrap();
+        assert_eq!(consumer.count_popped, 0);
+        assert_eq!(pop_string(&mut consumer), Some("0".to_owned()));
+
+        drop(consumer);
+        std::fs::remove_dir_all(&base_path).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_lock_consumer_twice() {
+        let base_path = std::env::temp_dir().join(format!("veda-consumer-lock-{}", std::process::id())).to_str().unwrap().to_owned();
+
+        let lock = lock_consumer(&base_path, MAIN_QUEUE_NAME, "fulltext_indexer").unwrap();
+        let err = lock_consumer(&base_path, MAIN_QUEUE_NAME, "fulltext_indexer").err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert!(err.to_string().contains("fulltext_indexer"));
+        assert!(lock_consumer(&base_path, MAIN_QUEUE_NAME, "scripts_main").is_ok());
+
+        drop(lock);
+        assert!(lock_consumer(&base_path, MAIN_QUEUE_NAME, "fulltext_indexer").is_ok());
+
+        std::fs::remove_dir_all(&base_path).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_get_first_datetime() {
+        let expected = Utc.with_ymd_and_hms(2024, 1, 15, 10, 20, 30).unwrap();
+
+        let mut indv = Individual::default();
+        indv.add_string("ticket:when", "2024-01-15T10:20:30.000000", Lang::none());
+        indv.add_integer("v-s:epoch", expected.timestamp());
+        indv.add_datetime("v-s:created", expected.timestamp());
+        indv.add_string("v-s:rfc", "2024-01-15T13:20:30+03:00", Lang::none());
+        indv.add_string("v-s:bad", "15.01.2024", Lang::none());
+
+        assert_eq!(get_first_datetime(&mut indv, "ticket:when"), Some(expected));
+        assert_eq!(get_first_datetime(&mut indv, "v-s:epoch"), Some(expected));
+        assert_eq!(get_first_datetime(&mut indv, "v-s:created"), Some(expected));
+        assert_eq!(get_first_datetime(&mut indv, "v-s:rfc"), Some(expected));
+        assert_eq!(get_first_datetime(&mut indv, "v-s:bad"), None);
+        assert_eq!(get_first_datetime(&mut indv, "v-s:none"), None);
+    }
+
+    #[test]
+    fn test_ticks_round_trip() {
+        let unix_epoch = Utc.timestamp_opt(0, 0).unwrap();
+        assert_eq!(datetime_to_ticks(unix_epoch), 621_355_968_000_000_000);
+        assert_eq!(ticks_to_datetime(621_355_968_000_000_000).unwrap(), unix_epoch);
+        assert_eq!(ticks_to_datetime(0).unwrap(), Utc.with_ymd_and_hms(1, 1, 1, 0, 0, 0).unwrap());
+
+        let before_epoch = Utc.timestamp_opt(-1, 999_999_900).unwrap();
+        assert_eq!(datetime_to_ticks(before_epoch), 621_355_967_999_999_999);
+        assert_eq!(ticks_to_datetime(621_355_967_999_999_999).unwrap(), before_epoch);
+
+        for ticks in [0, 1, 621_355_968_000_000_001, 638_409_312_301_234_567] {
+            assert_eq!(datetime_to_ticks(ticks_to_datetime(ticks).unwrap()), ticks);
+        }
+
+        for ticks in [i64::MIN, i64::MIN + UNIX_EPOCH_TICKS - 1] {
+            assert_eq!(ticks_to_datetime(ticks).unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+        assert!(ticks_to_datetime(i64::MAX).is_ok() && ticks_to_datetime(i64::MIN + UNIX_EPOCH_TICKS).is_ok());
+
+        let now = Utc::now();
+        assert_eq!(datetime_to_ticks(now), UNIX_EPOCH_TICKS + now.timestamp_millis() * 10_000 + (now.timestamp_subsec_nanos() % 1_000_000 / 100) as i64);
+    }
+}
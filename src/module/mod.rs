@@ -1,3 +1,4 @@
+pub mod async_veda_module;
 pub mod common;
 pub mod info;
 pub mod module_impl;
@@ -9,13 +9,17 @@ use crate::v_api::obj::ResultCode;
 use chrono::Local;
 use crossbeam_channel::{select, tick, Receiver};
 use env_logger::Builder;
+use futures::executor::block_on;
 use ini::Ini;
 use nng::options::protocol::pubsub::Subscribe;
 use nng::options::Options;
 use nng::options::RecvTimeout;
 use nng::{Protocol, Socket};
+use std::collections::HashMap;
 use std::io::Write;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use std::time::Instant;
 use std::{env, thread, time};
@@ -29,6 +33,39 @@ pub enum PrepareError {
 }
 
 const NOTIFY_CHANNEL_RECONNECT_TIMEOUT: u64 = 300;
+const PROPERTIES_FILE: &str = "veda.properties";
+pub const DEFAULT_QUEUE_BASE_PATH: &str = "./data/queue";
+pub(crate) const MAIN_QUEUE_NAME: &str = "individuals-flow";
+
+lazy_static! {
+    // filled on first access and cleared by Module::reload_properties
+    static ref PROPERTIES: PropertiesCache = PropertiesCache::default();
+}
+
+// Parsed properties files, keyed by path
+#[derive(Default)]
+struct PropertiesCache {
+    files: RwLock<HashMap<String, Arc<Ini>>>,
+    load_count: AtomicUsize,
+}
+
+impl PropertiesCache {
+    // The file is read and parsed without holding the lock, a file that can not be loaded is not cached and leaves the cache usable
+    fn load(&self, file: &str) -> Result<Arc<Ini>, ini::Error> {
+        if let Some(conf) = self.files.read().unwrap().get(file) {
+            return Ok(conf.clone());
+        }
+
+        let conf = Arc::new(Ini::load_from_file(file)?);
+        self.load_count.fetch_add(1, Ordering::SeqCst);
+        // another thread may have loaded the file meanwhile, the copy cached first is kept
+        Ok(self.files.write().unwrap().entry(file.to_owned()).or_insert(conf).clone())
+    }
+
+    fn clear(&self) {
+        self.files.write().unwrap().clear();
+    }
+}
 
 pub struct Module {
     pub(crate) queue_prepared_count: i64,
@@ -38,10 +75,73 @@ pub struct Module {
     pub(crate) max_timeout_between_batches: Option<u64>,
     pub(crate) min_batch_size_to_cancel_timeout: Option<u32>,
     pub max_batch_size: Option<u32>,
+    // commit the queue position every N processed records and at the end of a batch instead of after every record;
+    // delivery stays at-least-once, after a crash up to N-1 records are processed again
+    pub commit_interval: Option<u32>,
     pub(crate) subsystem_id: Option<i64>,
     pub(crate) syssig_ch: Option<Receiver<i32>>,
     pub(crate) name: String,
     onto_types: Vec<String>,
+    // stop listening the queue if the position of the consumer could not be saved
+    pub(crate) stop_on_commit_error: bool,
+    counters: ModuleCounters,
+    queue_base_path: String,
+}
+
+// Counters of the queue listener, read with Module::metrics_snapshot
+#[derive(Debug, Clone, Default)]
+pub struct ModuleMetrics {
+    pub prepared_count: i64,
+    pub batch_count: u64,
+    pub last_batch_size: u32,
+    pub notify_reconnect_count: u64,
+    pub commit_error_count: u64,
+    pub uptime: Duration,
+    // prepared records per second since the module was created
+    pub throughput: f64,
+}
+
+struct ModuleCounters {
+    started: Instant,
+    batch_count: u64,
+    last_batch_size: u32,
+    notify_reconnect_count: u64,
+    commit_error_count: u64,
+}
+
+impl ModuleCounters {
+    fn new() -> Self {
+        ModuleCounters {
+            started: Instant::now(),
+            batch_count: 0,
+            last_batch_size: 0,
+            notify_reconnect_count: 0,
+            commit_error_count: 0,
+        }
+    }
+
+    fn on_batch(&mut self, prepared_batch_size: u32) {
+        self.batch_count += 1;
+        self.last_batch_size = prepared_batch_size;
+    }
+
+    fn snapshot(&self, prepared_count: i64) -> ModuleMetrics {
+        let uptime = self.started.elapsed();
+        let secs = uptime.as_secs_f64();
+        ModuleMetrics {
+            prepared_count,
+            batch_count: self.batch_count,
+            last_batch_size: self.last_batch_size,
+            notify_reconnect_count: self.notify_reconnect_count,
+            commit_error_count: self.commit_error_count,
+            uptime,
+            throughput: if secs > 0.0 {
+                prepared_count as f64 / secs
+            } else {
+                0.0
+            },
+        }
+    }
 }
 
 impl Default for Module {
@@ -63,6 +163,9 @@ impl Module {
         let mut min_batch_size_to_cancel_timeout = None;
         let mut max_batch_size = None;
         let mut notify_channel_read_timeout = None;
+        let mut stop_on_commit_error = false;
+        let mut commit_interval = None;
+        let mut queue_base_path = DEFAULT_QUEUE_BASE_PATH.to_owned();
 
         for el in args.iter() {
             if el.starts_with("--max_timeout_between_batches") {
@@ -89,6 +192,22 @@ impl Module {
                     notify_channel_read_timeout = Some(v);
                     info!("use {} = {} ms", p[0], v);
                 }
+            } else if el.starts_with("--commit_interval") {
+                let p: Vec<&str> = el.split('=').collect();
+                if let Ok(v) = p[1].parse::<u32>() {
+                    commit_interval = Some(v);
+                    info!("use {} = {}", p[0], v);
+                }
+            } else if el.starts_with("--queue_base_path") {
+                let p: Vec<&str> = el.split('=').collect();
+                if let Some(v) = p.get(1) {
+                    queue_base_path = v.to_string();
+                    info!("use {} = {}", p[0], v);
+                }
+            } else if el.starts_with("--stop_on_commit_error") {
+                let p: Vec<&str> = el.split('=').collect();
+                stop_on_commit_error = p.get(1).map(|v| *v == "true").unwrap_or(true);
+                info!("use {} = {}", p[0], stop_on_commit_error);
             } else if el.starts_with("--notify_channel_url") {
                 let p: Vec<&str> = el.split('=').collect();
                 notify_channel_url = p[1].to_owned();
@@ -125,11 +244,37 @@ impl Module {
             max_timeout_between_batches,
             min_batch_size_to_cancel_timeout,
             max_batch_size,
+            commit_interval,
             subsystem_id: module_id,
             notify_channel_read_timeout,
             syssig_ch: None,
             name: module_name.to_owned(),
             onto_types: onto_types.iter().map(|x| x.to_string()).collect(),
+            stop_on_commit_error,
+            counters: ModuleCounters::new(),
+            queue_base_path,
+        }
+    }
+
+    // Module with the default settings that does not read veda.properties
+    #[cfg(test)]
+    pub(crate) fn new_for_test() -> Module {
+        Module {
+            queue_prepared_count: 0,
+            notify_channel_url: String::default(),
+            is_ready_notify_channel: false,
+            notify_channel_read_timeout: None,
+            max_timeout_between_batches: None,
+            min_batch_size_to_cancel_timeout: None,
+            max_batch_size: None,
+            commit_interval: None,
+            subsystem_id: None,
+            syssig_ch: None,
+            name: "test".to_owned(),
+            onto_types: vec![],
+            stop_on_commit_error: false,
+            counters: ModuleCounters::new(),
+            queue_base_path: DEFAULT_QUEUE_BASE_PATH.to_owned(),
         }
     }
 
@@ -137,14 +282,59 @@ impl Module {
         Module::create(None, "")
     }
 
+    pub fn queue_base_path(&self) -> &str {
+        &self.queue_base_path
+    }
+
+    pub fn set_queue_base_path(&mut self, path: &str) {
+        self.queue_base_path = path.to_owned();
+    }
+
+    // Consumer of the main queue named after the module
+    pub fn open_queue_consumer(&self) -> Result<Consumer, ErrorQueue> {
+        Consumer::new(&self.queue_base_path, &self.name, MAIN_QUEUE_NAME)
+    }
+
+    pub fn metrics_snapshot(&self) -> ModuleMetrics {
+        self.counters.snapshot(self.queue_prepared_count)
+    }
+
+    pub(crate) fn commit_queue(&mut self, queue_consumer: &mut Consumer) -> Result<(), PrepareError> {
+        let res = check_commit(queue_consumer.commit(), self.stop_on_commit_error, &queue_consumer.name);
+        if res.is_err() {
+            self.counters.commit_error_count += 1;
+        }
+        res
+    }
+
+    // Drops the cached contents of veda.properties, the next get_property call reads the file again
+    pub fn reload_properties() {
+        PROPERTIES.clear();
+    }
+
     // A function that retrieves a property value from a configuration file
     // The function takes an input parameter as an argument and returns an Option<String>
     pub fn get_property<T: FromStr>(in_param: &str) -> Option<T> {
-        // Load the configuration file "veda.properties" using the Ini library and panic if it fails
-        let conf = Ini::load_from_file("veda.properties").expect("fail load veda.properties file");
+        Module::get_property_from_file(PROPERTIES_FILE, in_param)
+    }
+
+    pub(crate) fn get_property_from_file<T: FromStr>(file: &str, in_param: &str) -> Option<T> {
+        // Take the parsed configuration file, it is read from disk only once and then served from the cache
+        let conf = match PROPERTIES.load(file) {
+            Ok(conf) => conf,
+            Err(e) => {
+                error!("fail load {} file, err={:?}", file, e);
+                return None;
+            },
+        };
 
-        // Extract the [alias] section from the configuration file and panic if it fails
-        let aliases = conf.section(Some("alias")).expect("fail parse veda.properties, section [alias]");
+        // Extract the [alias] section from the configuration file
+        let aliases = if let Some(aliases) = conf.section(Some("alias")) {
+            aliases
+        } else {
+            error!("fail parse {}, section [alias]", file);
+            return None;
+        };
 
         // Collect command line arguments into a vector of strings
         let args: Vec<String> = env::args().collect();
@@ -304,6 +494,9 @@ impl Module {
         heartbeat: &mut fn(&mut Backend, &mut T) -> Result<(), PrepareError>,
         backend: &mut Backend,
     ) {
+        let prepare_raw = prepare_raw.map(|f| *f);
+        let prepare_indv = prepare_indv.map(|f| *f);
+
         if let Ok(ch) = sys_sig_listener() {
             self.syssig_ch = Some(ch);
         }
@@ -340,162 +533,340 @@ impl Module {
                 }
             }
 
-            // read queue current part info
-            if let Err(e) = queue_consumer.queue.get_info_of_part(queue_consumer.id, true) {
-                error!("{} get_info_of_part {}: {}", self.queue_prepared_count, queue_consumer.id, e.as_str());
-                continue;
-            }
-
-            let size_batch = queue_consumer.get_batch_size();
-
-            let mut max_size_batch = size_batch;
-            if let Some(m) = self.max_batch_size {
-                max_size_batch = m;
-            }
+            let (size_batch, prepared_batch_size) = match self.prepare_batch(queue_consumer, module_context, *before_batch, prepare_raw, prepare_indv, *after_batch, backend, None) {
+                Ok(v) => v,
+                Err(PrepareError::Recoverable) => continue,
+                Err(PrepareError::Fatal) => return,
+            };
 
-            if size_batch > 0 {
-                debug!("queue: batch size={}", size_batch);
-                if let Some(new_size) = before_batch(backend, module_context, size_batch) {
-                    max_size_batch = new_size;
+            if prepared_batch_size == size_batch {
+                if let Some(s) = &soc {
+                    let res = s.recv().map(|_| ());
+                    self.on_notify_recv(res, size_batch, &mut count_timeout_error);
                 }
             }
 
-            let mut prepared_batch_size = 0;
-            for _it in 0..max_size_batch {
-                // пробуем взять из очереди заголовок сообщения
-                if !queue_consumer.pop_header() {
-                    break;
-                }
-
-                let mut raw = RawObj::new(vec![0; (queue_consumer.header.msg_length) as usize]);
-
-                // заголовок взят успешно, занесем содержимое сообщения в структуру Individual
-                if let Err(e) = queue_consumer.pop_body(&mut raw.data) {
-                    match e {
-                        ErrorQueue::FailReadTailMessage => {
-                            break;
-                        },
-                        ErrorQueue::InvalidChecksum => {
-                            error!("[module] consumer:pop_body: invalid CRC, attempt seek next record");
-                            queue_consumer.seek_next_pos();
-                            break;
-                        },
-                        _ => {
-                            error!("{} get msg from queue: {}", self.queue_prepared_count, e.as_str());
-                            break;
-                        },
+            if let Some(t) = self.max_timeout_between_batches {
+                let delta = prev_batch_time.elapsed().as_millis() as u64;
+                if let Some(c) = self.min_batch_size_to_cancel_timeout {
+                    if prepared_batch_size < c && delta < t {
+                        thread::sleep(time::Duration::from_millis(t - delta));
+                        info!("sleep {} ms", t - delta);
                     }
+                } else if delta < t {
+                    thread::sleep(time::Duration::from_millis(t - delta));
+                    info!("sleep {} ms", t - delta);
                 }
+            }
 
-                let mut need_commit = true;
-
-                if let Some(&mut f) = prepare_raw {
-                    match f(backend, module_context, &raw, queue_consumer) {
-                        Err(e) => {
-                            if let PrepareError::Fatal = e {
-                                warn!("prepare: found fatal error, stop listen queue");
-                                return;
-                            }
-                        },
-                        Ok(b) => {
-                            need_commit = b;
-                        },
-                    }
-                }
+            prev_batch_time = Instant::now();
+        }
+    }
 
-                if let Some(&mut f) = prepare_indv {
-                    let mut queue_element = Individual::new_raw(raw);
-                    if parse_raw(&mut queue_element).is_ok() {
-                        let mut is_processed = true;
-                        if let Some(assigned_subsystems) = queue_element.get_first_integer("assigned_subsystems") {
-                            if assigned_subsystems > 0 {
-                                if let Some(my_subsystem_id) = self.subsystem_id {
-                                    if assigned_subsystems & my_subsystem_id == 0 {
-                                        is_processed = false;
-                                    }
-                                } else {
-                                    is_processed = false;
-                                }
-                            }
-                        }
-
-                        if is_processed {
-                            match f(backend, module_context, &mut queue_element, queue_consumer) {
-                                Err(e) => {
-                                    if let PrepareError::Fatal = e {
-                                        warn!("prepare: found fatal error, stop listen queue");
-                                        return;
-                                    }
-                                },
-                                Ok(b) => {
-                                    need_commit = b;
-                                },
-                            }
-                        }
+    // For batch jobs: prepares up to max_records records of the queue with the same batch callbacks as listen_queue and returns
+    // the number of prepared records, without waiting on the notify channel when the queue is drained
+    #[allow(clippy::too_many_arguments)]
+    pub fn run_once<T>(
+        &mut self,
+        max_records: u32,
+        queue_consumer: &mut Consumer,
+        module_context: &mut T,
+        before_batch: &mut fn(&mut Backend, &mut T, batch_size: u32) -> Option<u32>,
+        prepare: &mut fn(&mut Backend, &mut T, &mut Individual, &Consumer) -> Result<bool, PrepareError>,
+        after_batch: &mut fn(&mut Backend, &mut T, prepared_batch_size: u32) -> Result<bool, PrepareError>,
+        backend: &mut Backend,
+    ) -> u32 {
+        let mut processed = 0;
+        while processed < max_records {
+            match self.prepare_batch(queue_consumer, module_context, *before_batch, None, Some(*prepare), *after_batch, backend, Some(max_records - processed)) {
+                Ok((size_batch, prepared_batch_size)) => {
+                    processed += prepared_batch_size;
+                    if size_batch == 0 || prepared_batch_size == 0 {
+                        break;
                     }
-                }
+                },
+                Err(e) => {
+                    warn!("run_once: stop after {} records, err={:?}", processed, e);
+                    break;
+                },
+            }
+        }
+        processed
+    }
 
-                if need_commit {
-                    queue_consumer.commit();
-                }
+    // The next record of the queue, None at the end of the written records or if the record could not be read
+    pub(crate) fn pop_queue_record(&self, queue_consumer: &mut Consumer) -> Option<RawObj> {
+        // пробуем взять из очереди заголовок сообщения
+        if !queue_consumer.pop_header() {
+            return None;
+        }
 
-                self.queue_prepared_count += 1;
+        let mut raw = RawObj::new(vec![0; (queue_consumer.header.msg_length) as usize]);
+
+        // заголовок взят успешно, занесем содержимое сообщения в структуру Individual
+        if let Err(e) = queue_consumer.pop_body(&mut raw.data) {
+            match e {
+                ErrorQueue::FailReadTailMessage => {},
+                ErrorQueue::InvalidChecksum => {
+                    error!("[module] consumer:pop_body: invalid CRC, attempt seek next record");
+                    queue_consumer.seek_next_pos();
+                },
+                _ => {
+                    error!("{} get msg from queue: {}", self.queue_prepared_count, e.as_str());
+                },
+            }
+            return None;
+        }
+        Some(raw)
+    }
 
-                if self.queue_prepared_count % 1000 == 0 {
-                    info!("get from queue, count: {}", self.queue_prepared_count);
+    // false if the element is assigned to other subsystems
+    pub(crate) fn is_assigned_to_subsystem(&self, queue_element: &mut Individual) -> bool {
+        if let Some(assigned_subsystems) = queue_element.get_first_integer("assigned_subsystems") {
+            if assigned_subsystems > 0 {
+                if let Some(my_subsystem_id) = self.subsystem_id {
+                    return assigned_subsystems & my_subsystem_id != 0;
                 }
-                prepared_batch_size += 1;
+                return false;
             }
+        }
+        true
+    }
 
-            if size_batch > 0 {
-                match after_batch(backend, module_context, prepared_batch_size) {
-                    Ok(b) => {
-                        if b {
-                            queue_consumer.commit();
-                        }
-                    },
-                    Err(e) => {
-                        if let PrepareError::Fatal = e {
-                            warn!("after_batch: found fatal error, stop listen queue");
-                            return;
-                        }
-                    },
-                }
+    // Size of the batch found in the queue, Err(Recoverable) if the queue info could not be read
+    pub(crate) fn read_batch_size(&self, queue_consumer: &mut Consumer) -> Result<u32, PrepareError> {
+        // read queue current part info
+        if let Err(e) = queue_consumer.queue.get_info_of_part(queue_consumer.id, true) {
+            error!("{} get_info_of_part {}: {}", self.queue_prepared_count, queue_consumer.id, e.as_str());
+            return Err(PrepareError::Recoverable);
+        }
+
+        let size_batch = queue_consumer.get_batch_size();
+        if size_batch > 0 {
+            debug!("queue: batch size={}", size_batch);
+        }
+        Ok(size_batch)
+    }
+
+    // The number of records to read: the size returned by before_batch, else max_batch_size, else the size of the batch found in the queue
+    pub(crate) fn records_to_read(&self, size_batch: u32, new_size: Option<u32>, max_records: Option<u32>) -> u32 {
+        let max_size_batch = new_size.or(self.max_batch_size).unwrap_or(size_batch);
+        match max_records {
+            Some(m) => max_size_batch.min(m),
+            None => max_size_batch,
+        }
+    }
+
+    // Result of prepare: Err(Fatal) stops listening, otherwise tells if the record needs a commit
+    pub(crate) fn check_prepared(res: Result<bool, PrepareError>) -> Result<bool, PrepareError> {
+        match res {
+            Err(PrepareError::Fatal) => {
+                warn!("prepare: found fatal error, stop listen queue");
+                Err(PrepareError::Fatal)
+            },
+            Err(PrepareError::Recoverable) => Ok(true),
+            Ok(b) => Ok(b),
+        }
+    }
+
+    // Counts the prepared record and commits the position every commit_interval records
+    pub(crate) fn on_record_prepared(&mut self, queue_consumer: &mut Consumer, counter: &mut CommitCounter, need_commit: bool) -> Result<(), PrepareError> {
+        if need_commit && counter.add() {
+            self.commit_or_stop(queue_consumer)?;
+        }
+
+        self.queue_prepared_count += 1;
+
+        if self.queue_prepared_count % 1000 == 0 {
+            info!("get from queue, count: {}", self.queue_prepared_count);
+        }
+        Ok(())
+    }
+
+    // Counts the batch and commits the records the interval left uncommitted
+    pub(crate) fn on_batch_prepared(
+        &mut self,
+        queue_consumer: &mut Consumer,
+        commit_counter: &mut CommitCounter,
+        size_batch: u32,
+        prepared_batch_size: u32,
+    ) -> Result<(), PrepareError> {
+        if size_batch > 0 {
+            self.counters.on_batch(prepared_batch_size);
+        }
+
+        if commit_counter.take_pending() {
+            self.commit_or_stop(queue_consumer)?;
+        }
+        Ok(())
+    }
+
+    // Result of after_batch: Ok(true) commits the position, Err(Fatal) stops listening
+    pub(crate) fn check_after_batch(&mut self, queue_consumer: &mut Consumer, res: Result<bool, PrepareError>) -> Result<(), PrepareError> {
+        match res {
+            Ok(true) => self.commit_or_stop(queue_consumer),
+            Err(PrepareError::Fatal) => {
+                warn!("after_batch: found fatal error, stop listen queue");
+                Err(PrepareError::Fatal)
+            },
+            _ => Ok(()),
+        }
+    }
+
+    // A failed commit is counted by commit_queue, only Err(Fatal) is returned, with stop_on_commit_error
+    fn commit_or_stop(&mut self, queue_consumer: &mut Consumer) -> Result<(), PrepareError> {
+        if let Err(PrepareError::Fatal) = self.commit_queue(queue_consumer) {
+            warn!("commit: found fatal error, stop listen queue");
+            return Err(PrepareError::Fatal);
+        }
+        Ok(())
+    }
+
+    // Result of waiting on the notify channel after a drained batch: a timeout while the queue had records means the notifications
+    // are lost and the channel is reconnected
+    pub(crate) fn on_notify_recv<E: std::fmt::Debug>(&mut self, res: Result<(), E>, size_batch: u32, count_timeout_error: &mut u32) {
+        if let Err(e) = res {
+            debug!("fail recv from queue notify channel, err={:?}", e);
+
+            if *count_timeout_error > 0 && size_batch > 0 {
+                warn!("queue changed but we not received notify message, need reconnect...");
+                self.is_ready_notify_channel = false;
+                self.counters.notify_reconnect_count += 1;
+                *count_timeout_error += 1;
             }
+        } else {
+            *count_timeout_error = 0;
+        }
+    }
 
-            if prepared_batch_size == size_batch {
-                if let Some(s) = &soc {
-                    let wmsg = s.recv();
-                    if let Err(e) = wmsg {
-                        debug!("fail recv from queue notify channel, err={:?}", e);
-
-                        if count_timeout_error > 0 && size_batch > 0 {
-                            warn!("queue changed but we not received notify message, need reconnect...");
-                            self.is_ready_notify_channel = false;
-                            count_timeout_error += 1;
-                        }
-                    } else {
-                        count_timeout_error = 0;
-                    }
-                }
+    // Reads and prepares one batch of the queue with the callbacks of listen_queue, see prepare_batch_with
+    #[allow(clippy::too_many_arguments)]
+    fn prepare_batch<T>(
+        &mut self,
+        queue_consumer: &mut Consumer,
+        module_context: &mut T,
+        before_batch: fn(&mut Backend, &mut T, batch_size: u32) -> Option<u32>,
+        prepare_raw: Option<fn(&mut Backend, &mut T, &RawObj, &Consumer) -> Result<bool, PrepareError>>,
+        prepare_indv: Option<fn(&mut Backend, &mut T, &mut Individual, &Consumer) -> Result<bool, PrepareError>>,
+        after_batch: fn(&mut Backend, &mut T, prepared_batch_size: u32) -> Result<bool, PrepareError>,
+        backend: &mut Backend,
+        max_records: Option<u32>,
+    ) -> Result<(u32, u32), PrepareError> {
+        let mut callbacks = FnCallbacks {
+            backend,
+            module_context,
+            before_batch,
+            prepare_raw,
+            prepare_indv,
+            after_batch,
+        };
+        // the callbacks do not wait, the future is ready when polled
+        block_on(self.prepare_batch_with(queue_consumer, &mut callbacks, max_records))
+    }
+
+    // Reads and prepares one batch of the queue, returns the size of the batch available in the queue and the number of prepared records.
+    // Err(Recoverable) if the queue info could not be read, Err(Fatal) if listening should stop
+    pub(crate) async fn prepare_batch_with<C: BatchCallbacks>(
+        &mut self,
+        queue_consumer: &mut Consumer,
+        callbacks: &mut C,
+        max_records: Option<u32>,
+    ) -> Result<(u32, u32), PrepareError> {
+        let size_batch = self.read_batch_size(queue_consumer)?;
+
+        let new_size = if size_batch > 0 {
+            callbacks.before_batch(size_batch).await
+        } else {
+            None
+        };
+        let max_size_batch = self.records_to_read(size_batch, new_size, max_records);
+
+        let mut prepared_batch_size = 0;
+        let mut commit_counter = CommitCounter::new(self.commit_interval);
+        for _it in 0..max_size_batch {
+            let raw = if let Some(raw) = self.pop_queue_record(queue_consumer) {
+                raw
+            } else {
+                break;
+            };
+
+            let mut need_commit = true;
+
+            if let Some(res) = callbacks.prepare_raw(&raw, queue_consumer).await {
+                need_commit = Module::check_prepared(res)?;
             }
 
-            if let Some(t) = self.max_timeout_between_batches {
-                let delta = prev_batch_time.elapsed().as_millis() as u64;
-                if let Some(c) = self.min_batch_size_to_cancel_timeout {
-                    if prepared_batch_size < c && delta < t {
-                        thread::sleep(time::Duration::from_millis(t - delta));
-                        info!("sleep {} ms", t - delta);
-                    }
-                } else if delta < t {
-                    thread::sleep(time::Duration::from_millis(t - delta));
-                    info!("sleep {} ms", t - delta);
+            if callbacks.prepares_individual() {
+                let mut queue_element = Individual::new_raw(raw);
+                if parse_raw(&mut queue_element).is_ok() && self.is_assigned_to_subsystem(&mut queue_element) {
+                    need_commit = Module::check_prepared(callbacks.prepare(&mut queue_element, queue_consumer).await)?;
                 }
             }
 
-            prev_batch_time = Instant::now();
+            self.on_record_prepared(queue_consumer, &mut commit_counter, need_commit)?;
+            prepared_batch_size += 1;
+        }
+
+        self.on_batch_prepared(queue_consumer, &mut commit_counter, size_batch, prepared_batch_size)?;
+
+        if size_batch > 0 {
+            let res = callbacks.after_batch(prepared_batch_size).await;
+            self.check_after_batch(queue_consumer, res)?;
+        }
+
+        Ok((size_batch, prepared_batch_size))
+    }
+}
+
+// Callbacks of one batch, the sync modules are adapted to it with futures that are ready when polled
+#[allow(async_fn_in_trait)]
+pub(crate) trait BatchCallbacks {
+    async fn before_batch(&mut self, size_batch: u32) -> Option<u32>;
+    // None if the raw record is not prepared
+    async fn prepare_raw(&mut self, _raw: &RawObj, _queue_consumer: &Consumer) -> Option<Result<bool, PrepareError>> {
+        None
+    }
+    // false if the record is not parsed and prepare is not called
+    fn prepares_individual(&self) -> bool {
+        true
+    }
+    async fn prepare(&mut self, queue_element: &mut Individual, queue_consumer: &Consumer) -> Result<bool, PrepareError>;
+    async fn after_batch(&mut self, prepared_batch_size: u32) -> Result<bool, PrepareError>;
+}
+
+// The function callbacks of listen_queue and run_once
+struct FnCallbacks<'a, T> {
+    backend: &'a mut Backend,
+    module_context: &'a mut T,
+    before_batch: fn(&mut Backend, &mut T, batch_size: u32) -> Option<u32>,
+    prepare_raw: Option<fn(&mut Backend, &mut T, &RawObj, &Consumer) -> Result<bool, PrepareError>>,
+    prepare_indv: Option<fn(&mut Backend, &mut T, &mut Individual, &Consumer) -> Result<bool, PrepareError>>,
+    after_batch: fn(&mut Backend, &mut T, prepared_batch_size: u32) -> Result<bool, PrepareError>,
+}
+
+impl<T> BatchCallbacks for FnCallbacks<'_, T> {
+    async fn before_batch(&mut self, size_batch: u32) -> Option<u32> {
+        (self.before_batch)(self.backend, self.module_context, size_batch)
+    }
+
+    async fn prepare_raw(&mut self, raw: &RawObj, queue_consumer: &Consumer) -> Option<Result<bool, PrepareError>> {
+        self.prepare_raw.map(|f| f(self.backend, self.module_context, raw, queue_consumer))
+    }
+
+    fn prepares_individual(&self) -> bool {
+        self.prepare_indv.is_some()
+    }
+
+    async fn prepare(&mut self, queue_element: &mut Individual, queue_consumer: &Consumer) -> Result<bool, PrepareError> {
+        match self.prepare_indv {
+            Some(f) => f(self.backend, self.module_context, queue_element, queue_consumer),
+            None => Ok(true),
         }
     }
+
+    async fn after_batch(&mut self, prepared_batch_size: u32) -> Result<bool, PrepareError> {
+        (self.after_batch)(self.backend, self.module_context, prepared_batch_size)
+    }
 }
 
 pub fn get_inner_binobj_as_individual<'a>(queue_element: &'a mut Individual, field_name: &str, new_indv: &'a mut Individual) -> bool {
@@ -509,6 +880,52 @@ pub fn get_inner_binobj_as_individual<'a>(queue_element: &'a mut Individual, fie
     false
 }
 
+// Counts records that need a commit, without an interval every record is committed
+pub(crate) struct CommitCounter {
+    interval: u32,
+    pending: u32,
+}
+
+impl CommitCounter {
+    pub(crate) fn new(interval: Option<u32>) -> Self {
+        CommitCounter {
+            interval: interval.unwrap_or(1).max(1),
+            pending: 0,
+        }
+    }
+
+    // true if the queue position should be committed now
+    pub(crate) fn add(&mut self) -> bool {
+        self.pending += 1;
+        if self.pending >= self.interval {
+            self.pending = 0;
+            return true;
+        }
+        false
+    }
+
+    // true if records of the batch are left uncommitted
+    pub(crate) fn take_pending(&mut self) -> bool {
+        let is_pending = self.pending > 0;
+        self.pending = 0;
+        is_pending
+    }
+}
+
+// A failed commit leaves the position of the consumer unsaved, the records since the last commit will be read again after restart
+fn check_commit(is_committed: bool, stop_on_commit_error: bool, consumer_name: &str) -> Result<(), PrepareError> {
+    if is_committed {
+        return Ok(());
+    }
+
+    error!("queue consumer [{}]: fail commit position", consumer_name);
+    if stop_on_commit_error {
+        Err(PrepareError::Fatal)
+    } else {
+        Err(PrepareError::Recoverable)
+    }
+}
+
 pub fn get_cmd(queue_element: &mut Individual) -> Option<IndvOp> {
     let wcmd = queue_element.get_first_integer("cmd");
     wcmd?;
@@ -525,6 +942,11 @@ pub fn init_log_with_filter(module_name: &str, filter: Option<&str>) {
 }
 
 pub fn init_log_with_params(module_name: &str, filter: Option<&str>, with_thread_id: bool) {
+    init_log_with_format(module_name, filter, with_thread_id, false);
+}
+
+// with_module_name = true adds the module name to every line, the other init_log functions keep the former format without it
+pub fn init_log_with_format(module_name: &str, filter: Option<&str>, with_thread_id: bool, with_module_name: bool) {
     let var_log_name = module_name.to_owned() + "_LOG";
     match std::env::var_os(&var_log_name) {
         Some(val) => println!("use env var: {}: {:?}", var_log_name, val.to_str()),
@@ -537,19 +959,36 @@ pub fn init_log_with_params(module_name: &str, filter: Option<&str>, with_thread
         env::var(var_log_name).unwrap_or_default()
     };
 
-    if with_thread_id {
-        Builder::new()
-            .format(|buf, record| writeln!(buf, "{} {} [{}] - {}", thread_id::get(), Local::now().format("%Y-%m-%dT%H:%M:%S%.3f"), record.level(), record.args()))
-            .parse_filters(&filters_str)
-            .try_init()
-            .unwrap_or(())
+    let module_name = if with_module_name {
+        Some(module_name.to_owned())
     } else {
-        Builder::new()
-            .format(|buf, record| writeln!(buf, "{} [{}] - {}", Local::now().format("%Y-%m-%dT%H:%M:%S%.3f"), record.level(), record.args()))
-            .parse_filters(&filters_str)
-            .try_init()
-            .unwrap_or(())
+        None
+    };
+
+    Builder::new()
+        .format(move |buf, record| {
+            let thread_id = if with_thread_id {
+                Some(thread_id::get())
+            } else {
+                None
+            };
+            write_log_line(buf, thread_id, module_name.as_deref(), record.level(), record.args())
+        })
+        .parse_filters(&filters_str)
+        .try_init()
+        .unwrap_or(())
+}
+
+// [thread_id] timestamp [level] [module_name] - message
+fn write_log_line<W: Write>(out: &mut W, thread_id: Option<usize>, module_name: Option<&str>, level: log::Level, args: &std::fmt::Arguments) -> std::io::Result<()> {
+    if let Some(id) = thread_id {
+        write!(out, "{} ", id)?;
     }
+    write!(out, "{} [{}] ", Local::now().format("%Y-%m-%dT%H:%M:%S%.3f"), level)?;
+    if let Some(name) = module_name {
+        write!(out, "{} ", name)?;
+    }
+    writeln!(out, "- {}", args)
 }
 
 pub fn get_info_of_module(module_name: &str) -> Option<(i64, i64)> {
@@ -601,3 +1040,221 @@ pub fn wait_module(module_name: &str, wait_op_id: i64) -> i64 {
 
     //-1
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::veda_backend::StaticAzContext;
+    use crate::onto::individual2msgpack::to_msgpack;
+    use crate::storage::memory_storage::MemoryStorage;
+    use v_queue::queue::Queue;
+
+    #[test]
+    fn test_properties_file_read_once() {
+        let file = env::temp_dir().join(format!("veda-{}.properties", std::process::id()));
+        std::fs::write(&file, "max_batch_size=100\n\n[alias]\n").unwrap();
+        let file = file.to_str().unwrap();
+
+        // a cache of its own, the global one is shared with the other tests
+        let cache = PropertiesCache::default();
+        for _ in 0..100 {
+            assert_eq!(cache.load(file).unwrap().get_from(None::<String>, "max_batch_size"), Some("100"));
+        }
+        assert_eq!(cache.load_count.load(Ordering::SeqCst), 1);
+
+        std::fs::write(file, "max_batch_size=200\n\n[alias]\n").unwrap();
+        assert_eq!(cache.load(file).unwrap().get_from(None::<String>, "max_batch_size"), Some("100"));
+        cache.clear();
+        assert_eq!(cache.load(file).unwrap().get_from(None::<String>, "max_batch_size"), Some("200"));
+        assert_eq!(cache.load_count.load(Ordering::SeqCst), 2);
+
+        assert_eq!(Module::get_property_from_file::<u32>(file, "max_batch_size"), Some(200));
+        assert_eq!(Module::get_property_from_file::<String>(file, "not_exists"), None);
+
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn test_missing_properties_file() {
+        let file = env::temp_dir().join(format!("veda-missing-{}.properties", std::process::id()));
+        let file = file.to_str().unwrap();
+
+        let cache = PropertiesCache::default();
+        assert!(cache.load(file).is_err());
+        assert_eq!(cache.load_count.load(Ordering::SeqCst), 0);
+        assert_eq!(Module::get_property_from_file::<u32>(file, "max_batch_size"), None);
+
+        // the failed load did not poison the cache
+        std::fs::write(file, "max_batch_size=100\n\n[alias]\n").unwrap();
+        assert_eq!(cache.load(file).unwrap().get_from(None::<String>, "max_batch_size"), Some("100"));
+        assert_eq!(Module::get_property_from_file::<u32>(file, "max_batch_size"), Some(100));
+
+        std::fs::remove_file(file).unwrap();
+    }
+
+    fn test_module() -> Module {
+        Module::new_for_test()
+    }
+
+    #[test]
+    fn test_queue_base_path() {
+        let base_path = env::temp_dir().join(format!("veda-queue-path-{}", std::process::id())).to_str().unwrap().to_owned();
+
+        let mut queue = Queue::new(&base_path, MAIN_QUEUE_NAME, Mode::ReadWrite).unwrap();
+        queue.push(b"first", MsgType::String).unwrap();
+
+        let mut module = test_module();
+        assert_eq!(module.queue_base_path(), DEFAULT_QUEUE_BASE_PATH);
+        module.set_queue_base_path(&base_path);
+
+        let mut consumer = module.open_queue_consumer().unwrap();
+        assert_eq!(consumer.queue.base_path, base_path);
+        assert!(consumer.pop_header());
+        let mut msg = vec![0; consumer.header.msg_length as usize];
+        consumer.pop_body(&mut msg).unwrap();
+        assert_eq!(msg, b"first");
+
+        drop(consumer);
+        std::fs::remove_dir_all(&base_path).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_run_once_stops_after_max_records() {
+        let base_path = env::temp_dir().join(format!("veda-run-once-{}", std::process::id())).to_str().unwrap().to_owned();
+        let queue_name = "individuals-flow";
+
+        let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+        for n in 0..10 {
+            let mut indv = Individual::default();
+            indv.set_id(&format!("d:doc_{}", n));
+            let mut raw = vec![];
+            to_msgpack(&indv, &mut raw).unwrap();
+            queue.push(&raw, MsgType::Object).unwrap();
+        }
+
+        fn before_batch(_backend: &mut Backend, _ctx: &mut Vec<String>, _size: u32) -> Option<u32> {
+            None
+        }
+        fn prepare(_backend: &mut Backend, ctx: &mut Vec<String>, indv: &mut Individual, _consumer: &Consumer) -> Result<bool, PrepareError> {
+            ctx.push(indv.get_id().to_owned());
+            Ok(true)
+        }
+        fn after_batch(_backend: &mut Backend, _ctx: &mut Vec<String>, _size: u32) -> Result<bool, PrepareError> {
+            Ok(false)
+        }
+
+        let mut consumer = Consumer::new(&base_path, "run_once", queue_name).unwrap();
+        let az = StaticAzContext {
+            granted: vec![],
+        };
+        let mut backend = Backend::new_for_test(Box::new(MemoryStorage::new()), Box::new(az));
+        let mut module = test_module();
+        let mut prepared = vec![];
+
+        let count = module.run_once(
+            5,
+            &mut consumer,
+            &mut prepared,
+            &mut (before_batch as fn(&mut Backend, &mut Vec<String>, u32) -> Option<u32>),
+            &mut (prepare as fn(&mut Backend, &mut Vec<String>, &mut Individual, &Consumer) -> Result<bool, PrepareError>),
+            &mut (after_batch as fn(&mut Backend, &mut Vec<String>, u32) -> Result<bool, PrepareError>),
+            &mut backend,
+        );
+        assert_eq!(count, 5);
+        assert_eq!(prepared, (0..5).map(|n| format!("d:doc_{}", n)).collect::<Vec<String>>());
+        drop(consumer);
+
+        // the position was committed, a new consumer continues after the prepared records
+        let consumer = Consumer::new(&base_path, "run_once", queue_name).unwrap();
+        assert_eq!(consumer.count_popped, 5);
+
+        std::fs::remove_dir_all(&base_path).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_metrics_snapshot() {
+        let mut counters = ModuleCounters::new();
+        counters.on_batch(100);
+        counters.on_batch(20);
+        counters.commit_error_count += 1;
+        thread::sleep(Duration::from_millis(10));
+
+        let metrics = counters.snapshot(120);
+        assert_eq!(metrics.prepared_count, 120);
+        assert_eq!(metrics.batch_count, 2);
+        assert_eq!(metrics.last_batch_size, 20);
+        assert_eq!(metrics.commit_error_count, 1);
+        assert_eq!(metrics.notify_reconnect_count, 0);
+        assert!(metrics.uptime >= Duration::from_millis(10));
+        assert!(metrics.throughput > 0.0);
+    }
+
+    #[test]
+    fn test_commit_interval() {
+        let mut counter = CommitCounter::new(Some(3));
+        let commits: Vec<usize> = (1..=10).filter(|_| counter.add()).collect();
+        assert_eq!(commits, vec![3, 6, 9]);
+        assert!(counter.take_pending());
+        assert!(!counter.take_pending());
+
+        let mut counter = CommitCounter::new(None);
+        assert!((0..5).all(|_| counter.add()));
+        assert!(!counter.take_pending());
+    }
+
+    #[test]
+    fn test_records_to_read() {
+        let mut module = test_module();
+        assert_eq!(module.records_to_read(50, None, None), 50);
+        assert_eq!(module.records_to_read(50, Some(10), None), 10);
+        assert_eq!(module.records_to_read(50, None, Some(5)), 5);
+
+        module.max_batch_size = Some(20);
+        assert_eq!(module.records_to_read(50, None, None), 20);
+        assert_eq!(module.records_to_read(50, Some(30), Some(25)), 25);
+
+        assert!(matches!(Module::check_prepared(Ok(false)), Ok(false)));
+        assert!(matches!(Module::check_prepared(Err(PrepareError::Recoverable)), Ok(true)));
+        assert!(matches!(Module::check_prepared(Err(PrepareError::Fatal)), Err(PrepareError::Fatal)));
+    }
+
+    #[test]
+    fn test_notify_reconnect_is_counted() {
+        let mut module = test_module();
+        module.is_ready_notify_channel = true;
+        let mut count_timeout_error = 1;
+
+        module.on_notify_recv(Err("timeout"), 0, &mut count_timeout_error);
+        assert!(module.is_ready_notify_channel);
+
+        module.on_notify_recv(Err("timeout"), 10, &mut count_timeout_error);
+        assert!(!module.is_ready_notify_channel);
+        assert_eq!(module.metrics_snapshot().notify_reconnect_count, 1);
+
+        module.on_notify_recv(Ok::<(), &str>(()), 10, &mut count_timeout_error);
+        assert_eq!(count_timeout_error, 0);
+    }
+
+    #[test]
+    fn test_check_commit() {
+        assert!(check_commit(true, true, "fulltext_indexer").is_ok());
+        assert!(matches!(check_commit(false, false, "fulltext_indexer"), Err(PrepareError::Recoverable)));
+        assert!(matches!(check_commit(false, true, "fulltext_indexer"), Err(PrepareError::Fatal)));
+    }
+
+    #[test]
+    fn test_log_line_contains_module_name() {
+        let mut out = vec![];
+        write_log_line(&mut out, None, Some("fanout-email"), log::Level::Info, &format_args!("start")).unwrap();
+        write_log_line(&mut out, Some(7), Some("fanout-email"), log::Level::Error, &format_args!("fail send, err={}", 42)).unwrap();
+        write_log_line(&mut out, None, None, log::Level::Warn, &format_args!("legacy")).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with(" [INFO] fanout-email - start"));
+        assert!(lines[1].starts_with("7 "));
+        assert!(lines[1].ends_with(" [ERROR] fanout-email - fail send, err=42"));
+        assert!(lines[2].ends_with(" [WARN] - legacy"));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::module::common::get_first_datetime;
 use crate::onto::datatype::Lang;
 use crate::onto::individual::Individual;
 use crate::v_api::obj::ResultCode;
@@ -6,7 +7,7 @@ use serde_json::Value;
 use std::hash::{Hash, Hasher};
 use std::mem::ManuallyDrop;
 use std::net::IpAddr;
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Utc};
 
 #[derive(Debug, Clone)]
 pub struct Ticket {
@@ -118,7 +119,7 @@ impl Ticket {
     }
 
     pub fn update_from_individual(&mut self, src: &mut Individual) {
-        let when = src.get_first_literal("ticket:when");
+        let when = get_first_datetime(src, "ticket:when");
         let duration = src.get_first_literal("ticket:duration").unwrap_or_default().parse::<i32>().unwrap_or_default();
 
         self.id = src.get_id().to_owned();
@@ -132,6 +133,14 @@ impl Ticket {
             return;
         }
 
+        if when.is_none() {
+            if let Some(v) = src.get_first_literal("ticket:when") {
+                error!("fail parse field [ticket:when] = {}", v);
+                self.user_uri = String::default();
+                return;
+            }
+        }
+
         if !self.user_uri.is_empty() && (when.is_none() || duration < 10) {
             error!("found a session ticket is not complete, we believe that the user has not been found.");
             self.user_uri = String::default();
@@ -139,25 +148,13 @@ impl Ticket {
         }
         let when = when.unwrap();
 
-        if let Ok(t) = NaiveDateTime::parse_from_str(&when, "%Y-%m-%dT%H:%M:%S%.f") {
-            self.start_time = t.and_utc().timestamp();
-            self.end_time = self.start_time + duration as i64;
-        } else {
-            error!("fail parse field [ticket:when] = {}", when);
-            self.user_uri = String::default();
-        }
+        self.start_time = when.timestamp();
+        self.end_time = self.start_time + duration as i64;
     }
 
     pub fn is_ticket_valid(&self, addr: &Option<IpAddr>, is_check_addr: bool) -> ResultCode {
-        if is_check_addr {
-            if let Some(a) = addr {
-                if self.user_addr != a.to_string() {
-                    error!("decline: ticket {}/{} request from {}", self.id, self.user_addr, a.to_string());
-                    return ResultCode::TicketExpired;
-                }
-            } else {
-                return ResultCode::TicketExpired;
-            }
+        if is_check_addr && !ticket_ip_matches(self, *addr, true) {
+            return ResultCode::TicketExpired;
         }
 
         if self.result != ResultCode::Ok {
@@ -175,3 +172,43 @@ impl Ticket {
         ResultCode::Ok
     }
 }
+
+// Checks the source address of a request against the address the ticket is bound to;
+// a request without address passes only when the address is not required
+pub fn ticket_ip_matches(ticket: &Ticket, addr: Option<IpAddr>, require: bool) -> bool {
+    match addr {
+        Some(a) => {
+            if ticket.user_addr != a.to_string() {
+                error!("decline: ticket {}/{} request from {}", ticket.id, ticket.user_addr, a);
+                return false;
+            }
+            true
+        },
+        None => !require,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ticket_ip_matches() {
+        let ticket = Ticket {
+            id: "ticket1".to_owned(),
+            user_uri: "td:User1".to_owned(),
+            user_addr: "10.0.0.1".to_owned(),
+            ..Ticket::default()
+        };
+
+        let same: Option<IpAddr> = "10.0.0.1".parse().ok();
+        let other: Option<IpAddr> = "10.0.0.2".parse().ok();
+
+        assert!(ticket_ip_matches(&ticket, same, true));
+        assert!(ticket_ip_matches(&ticket, same, false));
+        assert!(!ticket_ip_matches(&ticket, other, true));
+        assert!(!ticket_ip_matches(&ticket, other, false));
+        assert!(ticket_ip_matches(&ticket, None, false));
+        assert!(!ticket_ip_matches(&ticket, None, true));
+    }
+}
@@ -1,10 +1,12 @@
+use crate::az_impl::az_lmdb::LmdbAzContext;
 use crate::module::module_impl::Module;
 use crate::module::ticket::Ticket;
 use crate::onto::individual::Individual;
 use crate::search::ft_client::FTClient;
-use crate::storage::common::{StorageId, StorageMode, VStorage};
+use crate::storage::common::{Storage, StorageId, StorageMode, VStorage};
 use crate::v_api::api_client::{AuthClient, IndvOp, MStorageClient};
 use crate::v_api::obj::ResultCode;
+use crate::v_authorization::common::AuthorizationContext;
 use std::env;
 use url::Url;
 
@@ -13,6 +15,8 @@ pub struct Backend {
     pub fts: FTClient,
     pub mstorage_api: MStorageClient,
     pub auth_api: AuthClient,
+    // None until the first authorize, which opens the LMDB index
+    pub az: Option<Box<dyn AuthorizationContext + Send>>,
 }
 
 impl Default for Backend {
@@ -70,9 +74,30 @@ impl Backend {
             fts: ft_client,
             mstorage_api,
             auth_api,
+            az: None,
         }
     }
 
+    // Backend over the given storage and authorization context that does not read veda.properties, the api clients are not connected
+    pub fn new_for_test(storage: Box<dyn Storage + Send>, az: Box<dyn AuthorizationContext + Send>) -> Self {
+        Backend {
+            storage: VStorage::new_custom(storage),
+            fts: FTClient::new(String::default()),
+            mstorage_api: MStorageClient::new(String::default()),
+            auth_api: AuthClient::new(String::default()),
+            az: Some(az),
+        }
+    }
+
+    // Access bits of request_access granted to the user on the uri, 0 if the authorization fails
+    pub fn authorize(&mut self, uri: &str, user_uri: &str, request_access: u8) -> u8 {
+        let az = self.az.get_or_insert_with(|| Box::new(LmdbAzContext::default()));
+        az.authorize(uri, user_uri, request_access, false).unwrap_or_else(|e| {
+            error!("fail authorization {}, uri={}, err={}", user_uri, uri, e);
+            0
+        })
+    }
+
     pub fn get_sys_ticket_id(&mut self) -> Result<String, i32> {
         Module::get_sys_ticket_id_from_db(&mut self.storage)
     }
@@ -195,9 +220,88 @@ pub fn get_storage_with_prop(mode: StorageMode, prop_name: &str) -> VStorage {
         }
     }
 
+    #[cfg(feature = "lmdb_storage")]
     if let Some(db_path) = lmdb_db_path {
         return VStorage::new_lmdb(&db_path, mode, None);
     }
 
+    // the path comes from the properties, so a build without LMDB can only report it
+    #[cfg(not(feature = "lmdb_storage"))]
+    if let Some(db_path) = lmdb_db_path {
+        error!("fail open [LMDB] in {:?} mode, path: {}, the crate is built without lmdb_storage feature", mode, db_path);
+    }
+
     VStorage::none()
 }
+
+#[cfg(test)]
+use v_authorization::common::Trace;
+
+// Grants the listed (uri, user, access) and nothing else
+#[cfg(test)]
+pub(crate) struct StaticAzContext {
+    pub(crate) granted: Vec<(String, String, u8)>,
+}
+
+#[cfg(test)]
+impl AuthorizationContext for StaticAzContext {
+    fn authorize(&mut self, uri: &str, user_uri: &str, request_access: u8, _is_check_for_reload: bool) -> Result<u8, std::io::Error> {
+        Ok(self.granted.iter().filter(|(u, user, _)| u == uri && user == user_uri).fold(0, |res, (_, _, access)| res | (access & request_access)))
+    }
+
+    fn authorize_and_trace(
+        &mut self,
+        uri: &str,
+        user_uri: &str,
+        request_access: u8,
+        is_check_for_reload: bool,
+        _trace: &mut Trace,
+    ) -> Result<u8, std::io::Error> {
+        self.authorize(uri, user_uri, request_access, is_check_for_reload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onto::datatype::Lang;
+    use crate::onto::individual2msgpack::to_msgpack;
+    use crate::storage::memory_storage::MemoryStorage;
+    use v_authorization::common::Access;
+
+    fn put_individual(storage: &mut MemoryStorage, indv: &Individual) {
+        let mut raw = vec![];
+        to_msgpack(indv, &mut raw).unwrap();
+        assert!(storage.put_kv_raw(StorageId::Individuals, indv.get_id(), raw));
+    }
+
+    #[test]
+    fn test_read_from_memory_backend() {
+        let mut storage = MemoryStorage::new();
+
+        let mut author = Individual::default();
+        author.set_id("d:author1");
+        author.add_string("rdfs:label", "Author 1", Lang::none());
+        put_individual(&mut storage, &author);
+
+        let mut doc = Individual::default();
+        doc.set_id("d:doc1");
+        doc.add_uri("v-s:author", "d:author1");
+        put_individual(&mut storage, &doc);
+
+        let read = Access::CanRead as u8;
+        let az = StaticAzContext {
+            granted: vec![("d:doc1".to_owned(), "td:User1".to_owned(), read)],
+        };
+        let mut backend = Backend::new_for_test(Box::new(storage), Box::new(az));
+
+        let mut doc = backend.get_individual_s("d:doc1").unwrap();
+        assert_eq!(doc.get_first_literal("v-s:author"), Some("d:author1".to_owned()));
+        assert_eq!(backend.get_literal_of_link(&mut doc, "v-s:author", "rdfs:label", &mut Individual::default()), Some("Author 1".to_owned()));
+        assert!(backend.get_individual_s("d:doc2").is_none());
+
+        assert_eq!(backend.authorize("d:doc1", "td:User1", read | Access::CanUpdate as u8), read);
+        assert_eq!(backend.authorize("d:doc1", "td:User2", read), 0);
+        assert_eq!(backend.authorize("d:author1", "td:User1", read), 0);
+    }
+}
@@ -1,28 +1,37 @@
-use crate::module::common::sys_sig_listener;
-use crate::module::module_impl::{init_log, Module, PrepareError};
-use crate::onto::individual::{Individual, RawObj};
-use crate::onto::parser::parse_raw;
+use crate::module::common::{lock_consumer, sys_sig_listener};
+use crate::module::module_impl::{init_log, BatchCallbacks, Module, PrepareError, MAIN_QUEUE_NAME};
+use crate::onto::individual::Individual;
 use crossbeam_channel::{select, tick};
+use futures::executor::block_on;
 use nng::{Protocol, Socket};
 use std::time::{Duration, Instant};
 use std::{thread, time};
 use v_queue::consumer::Consumer;
-use v_queue::record::ErrorQueue;
 
+// Only prepare is required, the other callbacks do nothing by default
 pub trait VedaQueueModule {
-    fn before_batch(&mut self, size_batch: u32) -> Option<u32>;
+    // a new size of the batch, None keeps the size found in the queue
+    fn before_batch(&mut self, _size_batch: u32) -> Option<u32> {
+        None
+    }
     fn prepare(&mut self, queue_element: &mut Individual) -> Result<bool, PrepareError>;
-    fn after_batch(&mut self, prepared_batch_size: u32) -> Result<bool, PrepareError>;
-    fn heartbeat(&mut self) -> Result<(), PrepareError>;
-    fn before_start(&mut self);
-    fn before_exit(&mut self);
+    // Ok(true) commits the queue position after the batch
+    fn after_batch(&mut self, _prepared_batch_size: u32) -> Result<bool, PrepareError> {
+        Ok(false)
+    }
+    fn heartbeat(&mut self) -> Result<(), PrepareError> {
+        Ok(())
+    }
+    fn before_start(&mut self) {}
+    fn before_exit(&mut self) {}
 }
 
 impl Module {
     pub fn prepare_queue(&mut self, veda_module: &mut dyn VedaQueueModule) {
         init_log(&self.name);
 
-        let queue_consumer = &mut Consumer::new("./data/queue", &self.name, "individuals-flow").expect("!!!!!!!!! FAIL QUEUE");
+        let _consumer_lock = lock_consumer(self.queue_base_path(), MAIN_QUEUE_NAME, &self.name).unwrap_or_else(|e| panic!("!!!!!!!!! FAIL QUEUE: {}", e));
+        let queue_consumer = &mut self.open_queue_consumer().expect("!!!!!!!!! FAIL QUEUE");
 
         if let Ok(ch) = sys_sig_listener() {
             self.syssig_ch = Some(ch);
@@ -57,142 +66,15 @@ impl Module {
                 soc = s;
             }
 
-            // read queue current part info
-            if let Err(e) = queue_consumer.queue.get_info_of_part(queue_consumer.id, true) {
-                error!("{} get_info_of_part {}: {}", self.queue_prepared_count, queue_consumer.id, e.as_str());
-                continue;
-            }
-
-            let size_batch = queue_consumer.get_batch_size();
-
-            let mut max_size_batch = size_batch;
-            if let Some(m) = self.max_batch_size {
-                max_size_batch = m;
-            }
-
-            if size_batch > 0 {
-                debug!("queue: batch size={}", size_batch);
-                if let Some(new_size) = veda_module.before_batch(size_batch) {
-                    max_size_batch = new_size;
-                }
-            }
-
-            let mut prepared_batch_size = 0;
-            for _it in 0..max_size_batch {
-                // пробуем взять из очереди заголовок сообщения
-                if !queue_consumer.pop_header() {
-                    break;
-                }
-
-                let mut raw = RawObj::new(vec![0; (queue_consumer.header.msg_length) as usize]);
-
-                // заголовок взят успешно, занесем содержимое сообщения в структуру Individual
-                if let Err(e) = queue_consumer.pop_body(&mut raw.data) {
-                    match e {
-                        ErrorQueue::FailReadTailMessage => {
-                            break;
-                        },
-                        ErrorQueue::InvalidChecksum => {
-                            error!("[module] consumer:pop_body: invalid CRC, attempt seek next record");
-                            queue_consumer.seek_next_pos();
-                            break;
-                        },
-                        _ => {
-                            error!("{} get msg from queue: {}", self.queue_prepared_count, e.as_str());
-                            break;
-                        },
-                    }
-                }
-
-                let mut need_commit = true;
-                /*
-                                if let Some(&mut f) = prepare_raw {
-                                    match f(self, module, &raw, queue_consumer) {
-                                        Err(e) => {
-                                            if let PrepareError::Fatal = e {
-                                                warn!("prepare: found fatal error, stop listen queue");
-                                                return;
-                                            }
-                                        }
-                                        Ok(b) => {
-                                            need_commit = b;
-                                        }
-                                    }
-                                }
-                */
-                {
-                    let mut queue_element = Individual::new_raw(raw);
-                    if parse_raw(&mut queue_element).is_ok() {
-                        let mut is_processed = true;
-                        if let Some(assigned_subsystems) = queue_element.get_first_integer("assigned_subsystems") {
-                            if assigned_subsystems > 0 {
-                                if let Some(my_subsystem_id) = self.subsystem_id {
-                                    if assigned_subsystems & my_subsystem_id == 0 {
-                                        is_processed = false;
-                                    }
-                                } else {
-                                    is_processed = false;
-                                }
-                            }
-                        }
-
-                        if is_processed {
-                            match veda_module.prepare(&mut queue_element) {
-                                Err(e) => {
-                                    if let PrepareError::Fatal = e {
-                                        warn!("prepare: found fatal error, stop listen queue");
-                                        return;
-                                    }
-                                },
-                                Ok(b) => {
-                                    need_commit = b;
-                                },
-                            }
-                        }
-                    }
-                }
-
-                if need_commit {
-                    queue_consumer.commit();
-                }
-
-                self.queue_prepared_count += 1;
-
-                if self.queue_prepared_count % 1000 == 0 {
-                    info!("get from queue, count: {}", self.queue_prepared_count);
-                }
-                prepared_batch_size += 1;
-            }
-
-            if size_batch > 0 {
-                match veda_module.after_batch(prepared_batch_size) {
-                    Ok(b) => {
-                        if b {
-                            queue_consumer.commit();
-                        }
-                    },
-                    Err(e) => {
-                        if let PrepareError::Fatal = e {
-                            warn!("after_batch: found fatal error, stop listen queue");
-                            return;
-                        }
-                    },
-                }
-            }
+            let (size_batch, prepared_batch_size) = match self.prepare_module_batch(queue_consumer, veda_module, None) {
+                Ok(sizes) => sizes,
+                Err(PrepareError::Fatal) => return,
+                Err(_) => continue,
+            };
 
             if prepared_batch_size == size_batch {
-                let wmsg = soc.recv();
-                if let Err(e) = wmsg {
-                    debug!("fail recv from queue notify channel, err={:?}", e);
-
-                    if count_timeout_error > 0 && size_batch > 0 {
-                        warn!("queue changed but we not received notify message, need reconnect...");
-                        self.is_ready_notify_channel = false;
-                        count_timeout_error += 1;
-                    }
-                } else {
-                    count_timeout_error = 0;
-                }
+                let res = soc.recv().map(|_| ());
+                self.on_notify_recv(res, size_batch, &mut count_timeout_error);
             }
 
             if let Some(t) = self.max_timeout_between_batches {
@@ -211,4 +93,108 @@ impl Module {
             prev_batch_time = Instant::now();
         }
     }
+
+    // Reads and prepares one batch of the queue, returns the size of the batch found in the queue and the count of prepared records
+    pub(crate) fn prepare_module_batch(
+        &mut self,
+        queue_consumer: &mut Consumer,
+        veda_module: &mut dyn VedaQueueModule,
+        max_records: Option<u32>,
+    ) -> Result<(u32, u32), PrepareError> {
+        block_on(self.prepare_batch_with(queue_consumer, &mut SyncModule(veda_module), max_records))
+    }
+}
+
+struct SyncModule<'a>(&'a mut dyn VedaQueueModule);
+
+impl BatchCallbacks for SyncModule<'_> {
+    async fn before_batch(&mut self, size_batch: u32) -> Option<u32> {
+        self.0.before_batch(size_batch)
+    }
+
+    async fn prepare(&mut self, queue_element: &mut Individual, _queue_consumer: &Consumer) -> Result<bool, PrepareError> {
+        self.0.prepare(queue_element)
+    }
+
+    async fn after_batch(&mut self, prepared_batch_size: u32) -> Result<bool, PrepareError> {
+        self.0.after_batch(prepared_batch_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onto::individual2msgpack::to_msgpack;
+    use std::env;
+    use v_queue::queue::Queue;
+    use v_queue::record::{Mode, MsgType};
+
+    struct CountModule {
+        prepared: Vec<String>,
+    }
+
+    impl VedaQueueModule for CountModule {
+        fn prepare(&mut self, queue_element: &mut Individual) -> Result<bool, PrepareError> {
+            self.prepared.push(queue_element.get_id().to_owned());
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn test_module_with_only_prepare() {
+        let mut count_module = CountModule {
+            prepared: vec![],
+        };
+        let veda_module: &mut dyn VedaQueueModule = &mut count_module;
+
+        veda_module.before_start();
+        assert_eq!(veda_module.before_batch(10), None);
+        let mut indv = Individual::default();
+        indv.set_id("d:doc_1");
+        assert!(matches!(veda_module.prepare(&mut indv), Ok(true)));
+        assert!(matches!(veda_module.after_batch(1), Ok(false)));
+        assert!(veda_module.heartbeat().is_ok());
+        veda_module.before_exit();
+
+        assert_eq!(count_module.prepared, vec!["d:doc_1".to_owned()]);
+    }
+
+    #[test]
+    fn test_prepare_module_batch() {
+        let base_path = env::temp_dir().join(format!("veda-module-batch-{}", std::process::id())).to_str().unwrap().to_owned();
+
+        let mut queue = Queue::new(&base_path, MAIN_QUEUE_NAME, Mode::ReadWrite).unwrap();
+        for n in 0..5 {
+            let mut indv = Individual::default();
+            indv.set_id(&format!("d:doc_{}", n));
+            let mut raw = vec![];
+            to_msgpack(&indv, &mut raw).unwrap();
+            queue.push(&raw, MsgType::Object).unwrap();
+        }
+
+        let mut module = Module::new_for_test();
+        module.set_queue_base_path(&base_path);
+        module.commit_interval = Some(2);
+        let mut consumer = module.open_queue_consumer().unwrap();
+        let mut count_module = CountModule {
+            prepared: vec![],
+        };
+
+        let res = module.prepare_module_batch(&mut consumer, &mut count_module, None);
+
+        assert!(matches!(res, Ok((5, 5))));
+        assert_eq!(count_module.prepared, (0..5).map(|n| format!("d:doc_{}", n)).collect::<Vec<String>>());
+        let metrics = module.metrics_snapshot();
+        assert_eq!(metrics.batch_count, 1);
+        assert_eq!(metrics.last_batch_size, 5);
+        assert_eq!(metrics.prepared_count, 5);
+        drop(consumer);
+
+        // the last record, left by the commit interval, is committed at the end of the batch
+        let consumer = module.open_queue_consumer().unwrap();
+        assert_eq!(consumer.count_popped, 5);
+
+        drop(consumer);
+        std::fs::remove_dir_all(&base_path).unwrap_or_default();
+    }
 }
@@ -1,18 +1,40 @@
 // src/runtime_wrapper/mod.rs
 
-// Убедимся, что включена ровно одна фича
-#[cfg(not(any(feature = "tokio_0_2", feature = "tokio_1")))]
-compile_error!("Either feature \"tokio_0_2\" or \"tokio_1\" must be enabled for this crate.");
-
+// Не больше одной фичи; без них RuntimeWrapper недоступен, а синхронные API собираются как обычно
 #[cfg(all(feature = "tokio_0_2", feature = "tokio_1"))]
 compile_error!("Features \"tokio_0_2\" and \"tokio_1\" cannot be enabled at the same time.");
 
 #[cfg(feature = "tokio_0_2")]
 pub mod tokio_0_2;
 #[cfg(feature = "tokio_0_2")]
-pub use tokio_0_2::RuntimeWrapper;
+pub use tokio_0_2::{spawn_blocking, RuntimeWrapper};
 
 #[cfg(feature = "tokio_1")]
 pub mod tokio_1;
 #[cfg(feature = "tokio_1")]
-pub use tokio_1::RuntimeWrapper;
+pub use tokio_1::{spawn_blocking, RuntimeWrapper};
+
+// Without a runtime there is no blocking pool: the work runs in place on the thread polling the future, so the async APIs
+// built on it (LmdbAzContext::authorize_async, AsyncAzContext, Module::prepare_queue_async) compile and give the same results,
+// but block the executor while LMDB is read or the queue is waited on; a service running them next to other tasks needs
+// the tokio_0_2 or tokio_1 feature
+#[cfg(not(any(feature = "tokio_0_2", feature = "tokio_1")))]
+pub async fn spawn_blocking<F, R>(f: F) -> std::io::Result<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    Ok(f())
+}
+
+#[cfg(all(test, not(any(feature = "tokio_0_2", feature = "tokio_1"))))]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_spawn_blocking_without_runtime() {
+        let thread_id = std::thread::current().id();
+        assert!(block_on(spawn_blocking(move || std::thread::current().id() == thread_id)).unwrap());
+    }
+}
@@ -21,3 +21,12 @@ impl RuntimeWrapper {
         self.runtime.block_on(future)
     }
 }
+
+// Runs blocking work on the blocking thread pool of the runtime
+pub async fn spawn_blocking<F, R>(f: F) -> std::io::Result<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    tokio_dep_0_2::task::spawn_blocking(f).await.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("blocking task failed: {:?}", e)))
+}
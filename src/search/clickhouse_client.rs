@@ -1,7 +1,10 @@
 use crate::az_impl::az_lmdb::LmdbAzContext;
-use crate::search::common::{is_identifier, AuthorizationLevel, FTQuery, QueryResult, ResultFormat};
+use crate::az_impl::context_ext::BatchAuthorizer;
+use crate::onto::individual::Individual;
+use crate::search::common::{identifier_matcher_from_regex, is_identifier, AuthorizationLevel, FTQuery, IdentifierMatcher, QueryResult, ResultFormat};
+use crate::search::sql_params::{parse_sql_query_arguments_with_policy, redact_sql};
+use crate::search::sql_validator::{check_forbidden_names, SqlPolicy};
 use crate::v_api::obj::{OptAuthorize, ResultCode};
-use crate::v_authorization::common::AuthorizationContext;
 use chrono::prelude::*;
 use chrono::DateTime;
 use chrono_tz::Tz;
@@ -9,22 +12,49 @@ use futures::executor::block_on;
 use futures::lock::Mutex;
 use serde_json::json;
 use serde_json::Value;
+use sqlparser::dialect::ClickHouseDialect;
 use std::collections::HashSet;
+use std::future::Future;
+use std::sync::Arc;
 use std::time::*;
 use url::Url;
 use v_authorization::common::Access;
 use v_clickhouse_rs::errors::Error;
-use v_clickhouse_rs::types::{Column, SqlType};
+use v_clickhouse_rs::types::{Block, Column, ColumnType, SqlType};
 use v_clickhouse_rs::types::{FromSql, Row};
 use v_clickhouse_rs::Pool;
 
+// Record of a query sent to ClickHouse by select_with_params, values of the secret params are redacted
+#[derive(Debug, PartialEq)]
+pub struct SqlAuditRecord {
+    pub user: String,
+    // query after parameter substitution, before LIMIT/OFFSET of the page is appended
+    pub sql: String,
+    // query as it is sent to ClickHouse
+    pub paged_sql: String,
+}
+
+pub type SqlAuditSink = Arc<dyn Fn(&SqlAuditRecord) + Send + Sync>;
+
 pub struct CHClient {
     client: Option<Pool>,
     addr: String,
     is_ready: bool,
     az: LmdbAzContext,
+    is_identifier: IdentifierMatcher,
+    subject_column: Option<String>,
+    slow_query_threshold: Option<Duration>,
+    sql_audit: Option<SqlAuditSink>,
+    secret_params: Vec<String>,
+    keyword_delimiters: Vec<char>,
+    sql_policy: SqlPolicy,
 }
 
+// Chars that split a query into words when looking for keywords of data modification
+pub const DEFAULT_KEYWORD_DELIMITERS: &[char] = &[':', '-', ' ', '\t', '\n', '\r', '(', ')', '<', '>', '=', ',', ';', '/', '*'];
+
+const FORBIDDEN_KEYWORDS: &[&str] = &["INSERT", "UPDATE", "DROP", "DELETE", "ALTER", "EXEC"];
+
 impl CHClient {
     pub fn new(client_addr: String) -> CHClient {
         CHClient {
@@ -32,9 +62,67 @@ impl CHClient {
             addr: client_addr,
             is_ready: false,
             az: LmdbAzContext::new(1000),
+            is_identifier: Arc::new(is_identifier),
+            subject_column: None,
+            slow_query_threshold: None,
+            sql_audit: None,
+            secret_params: vec![],
+            keyword_delimiters: DEFAULT_KEYWORD_DELIMITERS.to_vec(),
+            sql_policy: SqlPolicy::default(),
         }
     }
 
+    // Functions and tables a query must not use, e.g. remote, s3 or file; SqlPolicy::default() forbids sleep and url
+    pub fn set_sql_policy(&mut self, policy: SqlPolicy) {
+        self.sql_policy = policy;
+    }
+
+    fn is_forbidden_query(&self, query: &str) -> bool {
+        if let Err(e) = check_forbidden_names(query, &ClickHouseDialect {}, &self.sql_policy) {
+            warn!("reject query [{}]: {}", query, e);
+            return true;
+        }
+        false
+    }
+
+    // Replaces the chars a keyword of data modification can be adjacent to; the set may only extend DEFAULT_KEYWORD_DELIMITERS,
+    // a set without one of them is refused with ErrorKind::InvalidInput and the current set is kept
+    pub fn set_keyword_delimiters(&mut self, delimiters: &[char]) -> Result<(), std::io::Error> {
+        if let Some(d) = DEFAULT_KEYWORD_DELIMITERS.iter().find(|d| !delimiters.contains(d)) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("keyword delimiters must contain the default delimiter {:?}", d)));
+        }
+        self.keyword_delimiters = delimiters.to_vec();
+        Ok(())
+    }
+
+    pub fn set_sql_audit(&mut self, sink: Option<SqlAuditSink>, secret_params: &[&str]) {
+        self.sql_audit = sink;
+        self.secret_params = secret_params.iter().map(|s| s.to_string()).collect();
+    }
+
+    pub fn set_slow_query_threshold(&mut self, threshold: Option<Duration>) {
+        self.slow_query_threshold = threshold;
+    }
+
+    // Column holding the id of the authorization subject of a row; when set, every row is authorized once
+    // against it instead of the first column (select) or every id-like cell (query_select_async)
+    pub fn set_subject_column(&mut self, col_name: Option<&str>) {
+        self.subject_column = col_name.map(|s| s.to_owned());
+    }
+
+    // Replaces the rule that decides which string values of query_select_async results are ids to authorize
+    pub fn set_identifier_matcher<F>(&mut self, matcher: F)
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.is_identifier = Arc::new(matcher);
+    }
+
+    pub fn set_identifier_regex(&mut self, pattern: &str) -> Result<(), regex::Error> {
+        self.is_identifier = identifier_matcher_from_regex(pattern)?;
+        Ok(())
+    }
+
     pub fn connect(&mut self) -> bool {
         info!("Configuration to connect to Clickhouse: {}", self.addr);
         match Url::parse(self.addr.as_ref()) {
@@ -65,9 +153,13 @@ impl CHClient {
 
         let start = Instant::now();
         let mut res = QueryResult::default();
+        let (user, query) = (req.user.clone(), req.query.clone());
 
-        if let Some(c) = &self.client {
-            if let Err(e) = block_on(select_from_clickhouse(req, c, op_auth, &mut res, &mut self.az)) {
+        if self.is_forbidden_query(&query) {
+            res.result_code = ResultCode::BadRequest;
+        } else if let Some(c) = &self.client {
+            if let Err(e) = block_on(select_from_clickhouse(req, c, op_auth, self.subject_column.as_deref(), &self.keyword_delimiters, &mut res, &mut self.az))
+            {
                 error!("fail read from clickhouse: {:?}", e);
                 res.result_code = ResultCode::InternalServerError
             }
@@ -75,20 +167,46 @@ impl CHClient {
 
         res.total_time = start.elapsed().as_millis() as i64;
         res.query_time = res.total_time - res.authorize_time;
+        res.log_if_slow(self.slow_query_threshold, "clickhouse", &user, &query);
         debug!("result={:?}", res);
 
         res
     }
 
+    // Substitutes params into the query template and runs it like select, the final sql is passed to the audit sink
+    pub fn select_with_params(&mut self, mut req: FTQuery, params: &mut Individual, op_auth: OptAuthorize) -> QueryResult {
+        let sql = match parse_sql_query_arguments_with_policy(&req.query, params, "clickhouse", &self.sql_policy) {
+            Ok(sql) => sql,
+            Err(e) => {
+                error!("fail substitute params, query={}, err={:?}", req.query, e);
+                let mut res = QueryResult::default();
+                res.result_code = ResultCode::BadRequest;
+                return res;
+            },
+        };
+
+        if let Some(audit) = &self.sql_audit {
+            let secret_params: Vec<&str> = self.secret_params.iter().map(|s| s.as_str()).collect();
+            audit(&sql_audit_record(&req.user, redact_sql(&sql, params, &secret_params), req.limit, req.from));
+        }
+
+        req.query = sql;
+        self.select(req, op_auth)
+    }
+
     pub async fn select_async(&mut self, req: FTQuery, op_auth: OptAuthorize) -> Result<QueryResult, Error> {
         let start = Instant::now();
         let mut res = QueryResult::default();
+        let (user, query) = (req.user.clone(), req.query.clone());
 
-        if let Some(c) = &self.client {
-            select_from_clickhouse(req, c, op_auth, &mut res, &mut self.az).await?;
+        if self.is_forbidden_query(&query) {
+            res.result_code = ResultCode::BadRequest;
+        } else if let Some(c) = &self.client {
+            select_from_clickhouse(req, c, op_auth, self.subject_column.as_deref(), &self.keyword_delimiters, &mut res, &mut self.az).await?;
         }
         res.total_time = start.elapsed().as_millis() as i64;
         res.query_time = res.total_time - res.authorize_time;
+        res.log_if_slow(self.slow_query_threshold, "clickhouse", &user, &query);
         debug!("result={:?}", res);
 
         Ok(res)
@@ -103,6 +221,7 @@ impl CHClient {
         az: &Mutex<LmdbAzContext>,
     ) -> Result<Value, Error> {
         let mut jres = Value::default();
+        let is_id = &self.is_identifier;
         if let Some(pool) = &self.client {
             let mut client = pool.get_handle().await?;
             let block = client.query(query).fetch_all().await?;
@@ -111,12 +230,25 @@ impl CHClient {
 
             let mut excluded_rows = HashSet::new();
 
+            let mut cell_authorization_level = &authorization_level;
+            if let Some(col_name) = &self.subject_column {
+                if authorization_level != AuthorizationLevel::Query {
+                    let allowed = authorize_rows_by_column(&block, col_name, |id| async move {
+                        let authorized = az.lock().await.authorize_async(&id, user_uri, Access::CanRead as u8, false);
+                        Ok(authorized.await? == Access::CanRead as u8)
+                    })
+                    .await?;
+                    excluded_rows.extend(allowed.iter().enumerate().filter(|(_, a)| !**a).map(|(i, _)| i));
+                    cell_authorization_level = &AuthorizationLevel::Query;
+                }
+            }
+
             if res_format == ResultFormat::Cols {
                 for col in block.columns() {
                     let mut jrow = Value::Array(vec![]);
                     let mut row_count = 0;
                     for row in block.rows() {
-                        if !col_to_json(&row, col, &mut jrow, user_uri, &res_format, &authorization_level, az).await? {
+                        if !col_to_json(&row, col, &mut jrow, user_uri, &res_format, cell_authorization_level, is_id, az).await? {
                             if authorization_level == AuthorizationLevel::RowColumn {
                                 excluded_rows.insert(row_count);
                             }
@@ -132,7 +264,10 @@ impl CHClient {
                 }
                 jres["cols"] = Value::Array(v_cols);
                 let mut jrows = vec![];
-                for row in block.rows() {
+                for (row_count, row) in block.rows().enumerate() {
+                    if excluded_rows.contains(&row_count) {
+                        continue;
+                    }
                     let mut skip_row = false;
                     let mut jrow = if res_format == ResultFormat::Full {
                         Value::from(serde_json::Map::new())
@@ -141,7 +276,7 @@ impl CHClient {
                     };
                     for col in block.columns() {
                         //println!("{} {}", col.name(), col.sql_type());
-                        if !col_to_json(&row, col, &mut jrow, user_uri, &res_format, &authorization_level, az).await? {
+                        if !col_to_json(&row, col, &mut jrow, user_uri, &res_format, cell_authorization_level, is_id, az).await? {
                             skip_row = true;
                             break;
                         }
@@ -154,7 +289,7 @@ impl CHClient {
                 jres["rows"] = Value::Array(jrows);
             }
 
-            if res_format == ResultFormat::Cols && authorization_level == AuthorizationLevel::RowColumn {
+            if res_format == ResultFormat::Cols && !excluded_rows.is_empty() {
                 for (_col_name, col_values) in jres.as_object_mut().unwrap().iter_mut() {
                     if let Value::Array(ref mut rows) = col_values {
                         let mut i = 0;
@@ -173,6 +308,7 @@ impl CHClient {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn cltjs<'a, K: v_clickhouse_rs::types::ColumnType, T: FromSql<'a> + serde::Serialize>(
     row: &'a Row<'_, K>,
     col: &'a Column<K>,
@@ -180,11 +316,13 @@ async fn cltjs<'a, K: v_clickhouse_rs::types::ColumnType, T: FromSql<'a> + serde
     user_uri: &str,
     res_format: &ResultFormat,
     authorization_level: &AuthorizationLevel,
+    is_id: &IdentifierMatcher,
     az: &Mutex<LmdbAzContext>,
 ) -> Result<bool, Error> {
     let v: T = row.get(col.name())?;
     let jv = json!(v);
 
+    #[allow(clippy::too_many_arguments)]
     async fn check_authorization(
         jv: &Value,
         jrow: &mut Value,
@@ -192,11 +330,12 @@ async fn cltjs<'a, K: v_clickhouse_rs::types::ColumnType, T: FromSql<'a> + serde
         user_uri: &str,
         res_format: &ResultFormat,
         authorization_level: &AuthorizationLevel,
+        is_id: &IdentifierMatcher,
         az: &Mutex<LmdbAzContext>,
     ) -> Result<bool, Error> {
         match jv {
             Value::String(vc) => {
-                let authorized = process_authorization(vc, user_uri, authorization_level, az).await?;
+                let authorized = process_authorization(vc, user_uri, authorization_level, is_id, az).await?;
                 if authorized {
                     insert_value(jrow, col_name, jv.clone());
                 } else {
@@ -217,7 +356,7 @@ async fn cltjs<'a, K: v_clickhouse_rs::types::ColumnType, T: FromSql<'a> + serde
                 for item in array {
                     match item {
                         Value::String(vc) => {
-                            let authorized = process_authorization(vc, user_uri, authorization_level, az).await?;
+                            let authorized = process_authorization(vc, user_uri, authorization_level, is_id, az).await?;
                             if authorized {
                                 new_array.push(json!(vc));
                             } else {
@@ -245,11 +384,16 @@ async fn cltjs<'a, K: v_clickhouse_rs::types::ColumnType, T: FromSql<'a> + serde
         }
     }
 
-    async fn process_authorization(vc: &str, user_uri: &str, authorization_level: &AuthorizationLevel, az: &Mutex<LmdbAzContext>) -> Result<bool, Error> {
-        if (authorization_level == &AuthorizationLevel::Cell || authorization_level == &AuthorizationLevel::RowColumn) && is_identifier(vc) {
-            let mut az_lock = az.lock().await;
-            let authorized = az_lock.authorize(vc, user_uri, Access::CanRead as u8, false)?;
-            Ok(authorized == Access::CanRead as u8)
+    async fn process_authorization(
+        vc: &str,
+        user_uri: &str,
+        authorization_level: &AuthorizationLevel,
+        is_id: &IdentifierMatcher,
+        az: &Mutex<LmdbAzContext>,
+    ) -> Result<bool, Error> {
+        if is_authorization_subject(vc, authorization_level, is_id) {
+            let authorized = az.lock().await.authorize_async(vc, user_uri, Access::CanRead as u8, false);
+            Ok(authorized.await? == Access::CanRead as u8)
         } else {
             // Если значение не является идентификатором, считаем, что авторизация не требуется
             Ok(true)
@@ -264,9 +408,14 @@ async fn cltjs<'a, K: v_clickhouse_rs::types::ColumnType, T: FromSql<'a> + serde
         }
     }
 
-    check_authorization(&jv, jrow, col.name(), user_uri, res_format, authorization_level, az).await
+    check_authorization(&jv, jrow, col.name(), user_uri, res_format, authorization_level, is_id, az).await
+}
+
+fn is_authorization_subject(vc: &str, authorization_level: &AuthorizationLevel, is_id: &IdentifierMatcher) -> bool {
+    (authorization_level == &AuthorizationLevel::Cell || authorization_level == &AuthorizationLevel::RowColumn) && is_id(vc)
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn col_to_json<K: v_clickhouse_rs::types::ColumnType>(
     row: &Row<'_, K>,
     col: &Column<K>,
@@ -274,46 +423,47 @@ async fn col_to_json<K: v_clickhouse_rs::types::ColumnType>(
     user_uri: &str,
     res_format: &ResultFormat,
     authorization_level: &AuthorizationLevel,
+    is_id: &IdentifierMatcher,
     az: &Mutex<LmdbAzContext>,
 ) -> Result<bool, v_clickhouse_rs::errors::Error> {
     let mut res = true;
     let sql_type = col.sql_type();
     match sql_type {
         SqlType::UInt8 => {
-            res = cltjs::<K, u8>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+            res = cltjs::<K, u8>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
         },
         SqlType::UInt16 => {
-            res = cltjs::<K, u16>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+            res = cltjs::<K, u16>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
         },
         SqlType::UInt32 => {
-            res = cltjs::<K, u32>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+            res = cltjs::<K, u32>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
         },
         SqlType::UInt64 => {
-            res = cltjs::<K, u64>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+            res = cltjs::<K, u64>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
         },
         SqlType::Int8 => {
-            res = cltjs::<K, i8>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+            res = cltjs::<K, i8>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
         },
         SqlType::Int16 => {
-            res = cltjs::<K, i16>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+            res = cltjs::<K, i16>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
         },
         SqlType::Int32 => {
-            res = cltjs::<K, i32>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+            res = cltjs::<K, i32>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
         },
         SqlType::Int64 => {
-            res = cltjs::<K, i64>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+            res = cltjs::<K, i64>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
         },
         SqlType::String => {
-            res = cltjs::<K, String>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+            res = cltjs::<K, String>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
         },
         SqlType::FixedString(_) => {
-            res = cltjs::<K, String>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+            res = cltjs::<K, String>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
         },
         SqlType::Float32 => {
-            res = cltjs::<K, f32>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+            res = cltjs::<K, f32>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
         },
         SqlType::Float64 => {
-            res = cltjs::<K, f64>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+            res = cltjs::<K, f64>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
         },
         SqlType::Date => {
             let v: DateTime<Tz> = row.get(col.name())?;
@@ -341,40 +491,40 @@ async fn col_to_json<K: v_clickhouse_rs::types::ColumnType>(
         },
         SqlType::Array(ref stype) => match stype {
             SqlType::UInt8 => {
-                res = cltjs::<K, Vec<u8>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Vec<u8>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::UInt16 => {
-                res = cltjs::<K, Vec<u16>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Vec<u16>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::UInt32 => {
-                res = cltjs::<K, Vec<u32>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Vec<u32>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::UInt64 => {
-                res = cltjs::<K, Vec<u64>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Vec<u64>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::Int8 => {
-                res = cltjs::<K, Vec<i8>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Vec<i8>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::Int16 => {
-                res = cltjs::<K, Vec<i16>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Vec<i16>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::Int32 => {
-                res = cltjs::<K, Vec<i32>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Vec<i32>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::Int64 => {
-                res = cltjs::<K, Vec<i64>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Vec<i64>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::String => {
-                res = cltjs::<K, Vec<String>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Vec<String>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::FixedString(_) => {
-                res = cltjs::<K, Vec<String>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Vec<String>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::Float32 => {
-                res = cltjs::<K, Vec<f32>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Vec<f32>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::Float64 => {
-                res = cltjs::<K, Vec<f64>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Vec<f64>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::Date => {
                 let v: Vec<DateTime<Tz>> = row.get(col.name())?;
@@ -418,37 +568,37 @@ async fn col_to_json<K: v_clickhouse_rs::types::ColumnType>(
         },
         SqlType::Nullable(ref inner_type) => match inner_type {
             SqlType::UInt8 => {
-                res = cltjs::<K, Option<u8>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Option<u8>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::UInt16 => {
-                res = cltjs::<K, Option<u16>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Option<u16>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::UInt32 => {
-                res = cltjs::<K, Option<u32>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Option<u32>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::UInt64 => {
-                res = cltjs::<K, Option<u64>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Option<u64>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::Int8 => {
-                res = cltjs::<K, Option<i8>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Option<i8>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::Int16 => {
-                res = cltjs::<K, Option<i16>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Option<i16>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::Int32 => {
-                res = cltjs::<K, Option<i32>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Option<i32>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::Int64 => {
-                res = cltjs::<K, Option<i64>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Option<i64>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::Float32 => {
-                res = cltjs::<K, Option<f32>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Option<f32>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::Float64 => {
-                res = cltjs::<K, Option<f64>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Option<f64>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::String => {
-                res = cltjs::<K, Option<String>>(row, col, jrow, user_uri, res_format, authorization_level, az).await?;
+                res = cltjs::<K, Option<String>>(row, col, jrow, user_uri, res_format, authorization_level, is_id, az).await?;
             },
             SqlType::Date => {
                 let v: DateTime<Tz> = row.get(col.name())?;
@@ -477,41 +627,116 @@ async fn col_to_json<K: v_clickhouse_rs::types::ColumnType>(
     Ok(res)
 }
 
-async fn select_from_clickhouse(req: FTQuery, pool: &Pool, op_auth: OptAuthorize, out_res: &mut QueryResult, az: &mut LmdbAzContext) -> Result<(), Error> {
-    let mut authorized_count = 0;
-    let mut total_count = 0;
-
-    if req
-        .query
-        .to_uppercase()
-        .split([':', '-', ' ', '(', ')', '<', '<', '=', ','].as_ref())
-        .any(|x| x.trim() == "INSERT" || x.trim() == "UPDATE" || x.trim() == "DROP" || x.trim() == "DELETE" || x.trim() == "ALTER" || x.trim() == "EXEC")
-    {
+async fn select_from_clickhouse(
+    req: FTQuery,
+    pool: &Pool,
+    op_auth: OptAuthorize,
+    subject_column: Option<&str>,
+    keyword_delimiters: &[char],
+    out_res: &mut QueryResult,
+    az: &mut LmdbAzContext,
+) -> Result<(), Error> {
+    if has_forbidden_keyword(&req.query, keyword_delimiters) {
         out_res.result_code = ResultCode::BadRequest;
         return Ok(());
     }
 
-    let fq = if req.limit > 0 {
-        format!("{} LIMIT {} OFFSET {}", req.query, req.limit, req.from)
-    } else {
-        format!("{} OFFSET {}", req.query, req.from)
-    };
+    let fq = paged_query(&req.query, req.limit, req.from);
 
     debug!("query={}", fq);
 
     let mut client = pool.get_handle().await?;
     let block = client.query(fq).fetch_all().await?;
+
+    let ids: Vec<Result<String, Error>> = block
+        .rows()
+        .map(|row| -> Result<String, Error> {
+            match subject_column {
+                Some(col_name) => row.get(col_name),
+                None => row.get(row.name(0)?),
+            }
+        })
+        .collect();
+
+    let access = req.request_access();
+    let authorized_ids: Vec<String> = ids.iter().flatten().filter(|id| !id.is_empty()).cloned().collect();
+    let mut authorizer = BatchAuthorizer::new(authorized_ids.iter().map(|id| id.as_str()).collect(), &req.user, access, req.top.max(0) as usize);
+    let authorize = |id: &str| {
+        Ok(if authorizer.is_readable(az, id) {
+            access
+        } else {
+            0
+        })
+    };
+    collect_authorized_rows(ids.into_iter(), &req, op_auth, authorize, out_res)?;
+
+    out_res.estimated = (req.from + block.row_count() as i32) as i64;
+
+    Ok(())
+}
+
+fn has_forbidden_keyword(query: &str, delimiters: &[char]) -> bool {
+    query.to_uppercase().split(delimiters).any(|x| FORBIDDEN_KEYWORDS.contains(&x.trim()))
+}
+
+// ClickHouse does not accept OFFSET without LIMIT, so offset-only paging uses the `LIMIT offset, count` form with the maximum count
+fn paged_query(query: &str, limit: i32, from: i32) -> String {
+    if limit > 0 {
+        format!("{} LIMIT {} OFFSET {}", query, limit, from.max(0))
+    } else if from > 0 {
+        format!("{} LIMIT {}, {}", query, from, u64::MAX)
+    } else {
+        query.to_owned()
+    }
+}
+
+fn sql_audit_record(user: &str, sql: String, limit: i32, from: i32) -> SqlAuditRecord {
+    SqlAuditRecord {
+        user: user.to_owned(),
+        paged_sql: paged_query(&sql, limit, from),
+        sql,
+    }
+}
+
+async fn authorize_rows_by_column<K, F, R>(block: &Block<K>, col_name: &str, mut is_authorized: F) -> Result<Vec<bool>, Error>
+where
+    K: ColumnType,
+    F: FnMut(String) -> R,
+    R: Future<Output = Result<bool, std::io::Error>>,
+{
+    let mut allowed = Vec::with_capacity(block.row_count());
     for row in block.rows() {
+        let id: String = row.get(col_name)?;
+        allowed.push(is_authorized(id).await?);
+    }
+    Ok(allowed)
+}
+
+fn collect_authorized_rows<I, F>(ids: I, req: &FTQuery, op_auth: OptAuthorize, mut authorize: F, out_res: &mut QueryResult) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<String, Error>>,
+    F: FnMut(&str) -> Result<u8, std::io::Error>,
+{
+    let mut authorized_count = 0;
+    let mut total_count = 0;
+
+    for id in ids {
+        let id = id?;
         total_count += 1;
 
-        let id: String = row.get(row.name(0)?)?;
+        if out_res.skip_empty_id(&id) {
+            if req.limit > 0 && total_count >= req.limit {
+                break;
+            }
+            continue;
+        }
 
         if op_auth == OptAuthorize::YES {
             let start = Instant::now();
 
-            match az.authorize(&id, &req.user, Access::CanRead as u8, false) {
+            match authorize(&id) {
                 Ok(res) => {
-                    if res == Access::CanRead as u8 {
+                    if res == req.request_access() {
                         out_res.result.push(id);
                         authorized_count += 1;
 
@@ -533,11 +758,220 @@ async fn select_from_clickhouse(req: FTQuery, pool: &Pool, op_auth: OptAuthorize
     }
 
     out_res.result_code = ResultCode::Ok;
-    out_res.estimated = (req.from + block.row_count() as i32) as i64;
     out_res.count = authorized_count as i64;
     out_res.processed = total_count as i64;
-    out_res.cursor = (req.from + total_count) as i64;
+    out_res.set_cursor(req.from);
     out_res.authorize_time /= 1000;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::common::check_cursor_paging;
+    use crate::search::sql_params::parse_sql_query_arguments;
+
+    #[test]
+    fn test_cursor_advances_across_pages() {
+        check_cursor_paging(|from, page| {
+            let mut req = FTQuery::new_with_user("cfg:Guest", "SELECT id FROM veda_tt.`v-s:Document`");
+            req.limit = 4;
+            req.from = from;
+            let mut res = QueryResult::default();
+            let ids = page.iter().map(|id| Ok(id.to_owned()));
+            let authorize = |id: &str| match id {
+                "d:doc_3" => Ok(0),
+                _ => Ok(Access::CanRead as u8),
+            };
+            collect_authorized_rows(ids, &req, OptAuthorize::YES, authorize, &mut res).unwrap();
+            res
+        });
+    }
+
+    #[test]
+    fn test_empty_subject_id_counts_to_limit() {
+        let mut req = FTQuery::new_with_user("cfg:Guest", "SELECT id FROM veda_tt.`v-s:Document`");
+        req.limit = 3;
+        let ids = ["d:doc_1", "", "d:doc_2", "d:doc_3"].iter().map(|id| Ok(id.to_string()));
+
+        let mut checked = vec![];
+        let mut res = QueryResult::default();
+        collect_authorized_rows(
+            ids,
+            &req,
+            OptAuthorize::YES,
+            |id| {
+                checked.push(id.to_owned());
+                Ok(Access::CanRead as u8)
+            },
+            &mut res,
+        )
+        .unwrap();
+
+        assert_eq!(checked, vec!["d:doc_1".to_owned(), "d:doc_2".to_owned()]);
+        assert_eq!(res.result, checked);
+        assert_eq!(res.skipped_empty_ids, 1);
+        assert_eq!(res.processed, 3);
+        assert_eq!(res.cursor, 3);
+    }
+
+    #[test]
+    fn test_filter_by_update_access() {
+        let rights = [
+            ("d:doc_r", Access::CanRead as u8),
+            ("d:doc_ru", Access::CanRead as u8 | Access::CanUpdate as u8),
+            ("d:doc_u", Access::CanUpdate as u8),
+            ("d:doc_rd", Access::CanRead as u8 | Access::CanDelete as u8),
+        ];
+
+        let mut req = FTQuery::new_with_user("cfg:Guest", "SELECT id FROM veda_tt.`v-s:Document`");
+        req.access = Access::CanUpdate as u8;
+
+        let ids = rights.iter().map(|(id, _)| Ok(id.to_string()));
+        let mut res = QueryResult::default();
+        collect_authorized_rows(
+            ids,
+            &req,
+            OptAuthorize::YES,
+            |id| Ok(rights.iter().find(|(r_id, _)| *r_id == id).map(|(_, access)| access & req.access).unwrap_or(0)),
+            &mut res,
+        )
+        .unwrap();
+
+        assert_eq!(res.result, vec!["d:doc_ru".to_owned(), "d:doc_u".to_owned()]);
+        assert_eq!(res.count, 2);
+    }
+
+    #[test]
+    fn test_sql_policy_rejects_remote() {
+        let mut ch = CHClient::new("tcp://default:@127.0.0.1:1".to_owned());
+        let query = "SELECT id FROM remote('ch2:9000', 'veda_tt', 'docs') WHERE author = {author}";
+        let mut params = Individual::default();
+        params.set_uri("author", "d:author_1");
+
+        // rejected before a connection is made
+        ch.set_sql_policy(SqlPolicy {
+            forbidden_functions: vec!["remote".to_owned(), "s3".to_owned(), "file".to_owned()],
+            ..SqlPolicy::default()
+        });
+        let req = FTQuery::new_with_user("td:User1", query);
+        assert_eq!(ch.select_with_params(req, &mut params, OptAuthorize::YES).result_code, ResultCode::BadRequest);
+        let req = FTQuery::new_with_user("td:User1", "SELECT id FROM s3('https://bucket/docs.csv')");
+        assert_eq!(ch.select(req, OptAuthorize::YES).result_code, ResultCode::BadRequest);
+    }
+
+    #[test]
+    fn test_zero_access_is_checked_as_read() {
+        let rights = [("d:doc_r", Access::CanRead as u8), ("d:doc_none", 0), ("d:doc_u", Access::CanUpdate as u8)];
+
+        let mut req = FTQuery::new_with_user("cfg:Guest", "SELECT id FROM veda_tt.`v-s:Document`");
+        req.access = 0;
+
+        // authorize() returns the granted part of the requested bits, an empty request would be granted for every document
+        let ids = rights.iter().map(|(id, _)| Ok(id.to_string()));
+        let mut res = QueryResult::default();
+        collect_authorized_rows(
+            ids,
+            &req,
+            OptAuthorize::YES,
+            |id| Ok(rights.iter().find(|(r_id, _)| *r_id == id).map(|(_, access)| access & req.request_access()).unwrap_or(0)),
+            &mut res,
+        )
+        .unwrap();
+
+        assert_eq!(res.result, vec!["d:doc_r".to_owned()]);
+        assert_eq!(res.count, 1);
+    }
+
+    #[test]
+    fn test_custom_identifier_matcher() {
+        let default_matcher: IdentifierMatcher = Arc::new(is_identifier);
+        let numeric_matcher = identifier_matcher_from_regex(r"^[0-9]+$").unwrap();
+
+        assert!(is_authorization_subject("d:doc_1", &AuthorizationLevel::Cell, &default_matcher));
+        assert!(!is_authorization_subject("12345", &AuthorizationLevel::Cell, &default_matcher));
+
+        assert!(is_authorization_subject("12345", &AuthorizationLevel::Cell, &numeric_matcher));
+        assert!(is_authorization_subject("12345", &AuthorizationLevel::RowColumn, &numeric_matcher));
+        assert!(!is_authorization_subject("12345", &AuthorizationLevel::Query, &numeric_matcher));
+        assert!(!is_authorization_subject("d:doc_1", &AuthorizationLevel::Cell, &numeric_matcher));
+    }
+
+    #[test]
+    fn test_authorize_by_subject_column() {
+        let block = Block::new().column("title", vec!["d:title_1", "d:title_2", "d:title_3"]).column("doc_id", vec!["d:doc_1", "d:doc_2", "d:doc_3"]);
+
+        let mut checked = vec![];
+        let allowed = block_on(authorize_rows_by_column(&block, "doc_id", |id| {
+            checked.push(id.clone());
+            async move { Ok(id != "d:doc_2") }
+        }))
+        .unwrap();
+
+        assert_eq!(checked, vec!["d:doc_1", "d:doc_2", "d:doc_3"]);
+        assert_eq!(allowed, vec![true, false, true]);
+        assert!(block_on(authorize_rows_by_column(&block, "unknown", |_| async { Ok(true) })).is_err());
+    }
+
+    #[test]
+    fn test_paged_query() {
+        let q = "SELECT id FROM veda_tt.`v-s:Document`";
+
+        assert_eq!(paged_query(q, 10, 20), "SELECT id FROM veda_tt.`v-s:Document` LIMIT 10 OFFSET 20");
+        assert_eq!(paged_query(q, 0, 20), "SELECT id FROM veda_tt.`v-s:Document` LIMIT 20, 18446744073709551615");
+        assert_eq!(paged_query(q, 0, 0), q);
+        assert!(!paged_query(q, 0, 20).contains("OFFSET"));
+    }
+
+    #[test]
+    fn test_sql_audit_record() {
+        use crate::onto::datatype::Lang;
+
+        let query = "SELECT id FROM veda_tt.users WHERE login = {login} AND token = {token}";
+        let mut params = Individual::default();
+        params.set_string("login", "user1", Lang::none());
+        params.set_string("token", "s3cr3t", Lang::none());
+        let sql = parse_sql_query_arguments(query, &mut params, "clickhouse").unwrap();
+
+        let plain = sql_audit_record("td:User1", redact_sql(&sql, &params, &[]), 10, 20);
+        assert!(plain.sql.contains("'s3cr3t'"));
+        assert_eq!(plain.paged_sql, format!("{} LIMIT 10 OFFSET 20", plain.sql));
+
+        let redacted = sql_audit_record("td:User1", redact_sql(&sql, &params, &["token"]), 10, 20);
+        assert_eq!(redacted.sql, plain.sql.replace("s3cr3t", "***"));
+        assert_eq!(redacted.paged_sql, format!("{} LIMIT 10 OFFSET 20", redacted.sql));
+        assert!(!redacted.paged_sql.contains("s3cr3t"));
+    }
+
+    #[test]
+    fn test_keyword_adjacent_to_delimiter() {
+        for d in [';', '\t', '\n', '\r', '*', '/', '>'] {
+            let query = format!("SELECT id FROM docs{}DROP TABLE docs", d);
+            assert!(has_forbidden_keyword(&query, DEFAULT_KEYWORD_DELIMITERS), "delimiter {:?}", d);
+            assert!(!has_forbidden_keyword(&query, &[' ']), "delimiter {:?}", d);
+        }
+
+        assert!(has_forbidden_keyword("select 1>delete", DEFAULT_KEYWORD_DELIMITERS));
+        assert!(!has_forbidden_keyword("SELECT id FROM docs WHERE `v-s:deleted` = 0", DEFAULT_KEYWORD_DELIMITERS));
+        assert!(!has_forbidden_keyword("SELECT dropped, updated_at FROM docs", DEFAULT_KEYWORD_DELIMITERS));
+    }
+
+    #[test]
+    fn test_set_keyword_delimiters() {
+        let mut ch = CHClient::new("tcp://default:@127.0.0.1:1".to_owned());
+
+        let err = ch.set_keyword_delimiters(&[' ', ';']).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(ch.keyword_delimiters, DEFAULT_KEYWORD_DELIMITERS);
+
+        let without_cr: Vec<char> = DEFAULT_KEYWORD_DELIMITERS.iter().copied().filter(|d| *d != '\r').collect();
+        assert!(ch.set_keyword_delimiters(&without_cr).is_err());
+        assert!(has_forbidden_keyword("SELECT id FROM docs\rDROP TABLE docs", &ch.keyword_delimiters));
+
+        let mut extended = DEFAULT_KEYWORD_DELIMITERS.to_vec();
+        extended.push('|');
+        ch.set_keyword_delimiters(&extended).unwrap();
+        assert!(has_forbidden_keyword("SELECT id FROM docs|DROP TABLE docs", &ch.keyword_delimiters));
+    }
+}
@@ -1,11 +1,18 @@
+use crate::module::ticket::Ticket;
+use crate::onto::individual::Individual;
 use crate::onto::onto_index::OntoIndex;
+use crate::onto::resource::{Resource, Value};
 use crate::storage::async_storage::get_individual_from_db;
 use crate::storage::async_storage::AStorage;
 use crate::v_api::obj::ResultCode;
 use futures::lock::Mutex;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use strum_macros::EnumString;
+use v_authorization::common::Access;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct QueryResult {
@@ -13,11 +20,33 @@ pub struct QueryResult {
     pub count: i64,
     pub estimated: i64,
     pub processed: i64,
+    // offset in the source result set of the first row that was not looked at, i.e. the `from` of the next page
     pub cursor: i64,
     pub total_time: i64,
     pub query_time: i64,
     pub authorize_time: i64,
     pub result_code: ResultCode,
+    // matches that were found but hidden because the user has no CanRead on them
+    #[serde(default)]
+    pub denied_count: i64,
+    // ids of the hidden matches, filled only for privileged queries with FTQuery.return_denied
+    #[serde(default)]
+    pub denied: Vec<String>,
+    // estimate of the matches readable by the user: `estimated` scaled by the authorized share of the processed window,
+    // 0 if the backend does not compute it; like `estimated` this is an approximation, not an exact count
+    #[serde(default)]
+    pub estimated_authorized: i64,
+    // matches without a subject id, they are neither authorized nor returned
+    #[serde(default)]
+    pub skipped_empty_ids: i64,
+    // field -> value -> number of documents of this page (`result`) with the value, not of the whole result set; filled only
+    // for FTQuery.facets
+    #[serde(default)]
+    pub facets: HashMap<String, HashMap<String, i64>>,
+    // highlighted excerpt of each document of `result`, in the same order, empty if the document has no matched text;
+    // filled only for FTQuery.with_snippets
+    #[serde(default)]
+    pub snippets: Vec<String>,
 }
 
 impl Default for QueryResult {
@@ -32,10 +61,85 @@ impl Default for QueryResult {
             query_time: 0,
             authorize_time: 0,
             result_code: ResultCode::NotReady,
+            denied_count: 0,
+            denied: vec![],
+            estimated_authorized: 0,
+            skipped_empty_ids: 0,
+            facets: HashMap::new(),
+            snippets: vec![],
         }
     }
 }
 
+impl QueryResult {
+    // All backends count the cursor the same way: rows consumed from the source, authorized or not, are skipped on the next page
+    pub fn set_cursor(&mut self, from: i32) {
+        self.cursor = from as i64 + self.processed;
+    }
+
+    // The guard of every backend before authorization: an empty subject id is counted and must be skipped
+    pub fn skip_empty_id(&mut self, subject_id: &str) -> bool {
+        if subject_id.is_empty() {
+            self.skipped_empty_ids += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Adds an authorized document to the counts of the facet fields, a value repeated in the document is counted once
+    pub fn count_facets(&mut self, indv: &mut Individual, fields: &[String]) {
+        for field in fields {
+            let counts = self.facets.entry(field.to_owned()).or_default();
+            if let Some(resources) = indv.get_resources(field) {
+                let values: HashSet<String> = resources.iter().filter_map(facet_value).collect();
+                for v in values {
+                    *counts.entry(v).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+
+    // Scales the raw estimate by the share of authorized matches among those checked in this window
+    pub fn set_estimated_authorized(&mut self) {
+        let checked = self.count + self.denied_count;
+        self.estimated_authorized = if checked > 0 {
+            (self.estimated as f64 * self.count as f64 / checked as f64).round() as i64
+        } else {
+            self.estimated
+        };
+    }
+
+    // Warns about a query whose total_time exceeds the threshold of the client, returns true if the warning was logged
+    pub fn log_if_slow(&self, threshold: Option<Duration>, backend: &str, user: &str, query: &str) -> bool {
+        match threshold {
+            Some(t) if self.total_time > 0 && self.total_time as u128 > t.as_millis() => {
+                let mut query_head: String = query.chars().take(SLOW_QUERY_MAX_LEN).collect();
+                if query_head.len() < query.len() {
+                    query_head.push_str("...");
+                }
+                warn!(
+                    "slow query: backend={}, user={}, total_time={}, query_time={}, authorize_time={}, count={}, processed={}, query=[{}]",
+                    backend, user, self.total_time, self.query_time, self.authorize_time, self.count, self.processed, query_head
+                );
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+const SLOW_QUERY_MAX_LEN: usize = 256;
+
+fn facet_value(r: &Resource) -> Option<String> {
+    match &r.value {
+        Value::Str(s, _) | Value::Uri(s) => Some(s.to_owned()),
+        Value::Int(i) | Value::Datetime(i) => Some(i.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Num(_, _) | Value::Binary(_) => None,
+    }
+}
+
 #[derive(Debug, PartialEq, EnumString)]
 pub enum ResultFormat {
     #[strum(ascii_case_insensitive)]
@@ -67,9 +171,44 @@ pub struct FTQuery {
     pub top: i32,
     pub limit: i32,
     pub from: i32,
+    // access bits a result must be granted to be returned, Access::CanRead by default
+    #[serde(default = "default_request_access")]
+    pub access: u8,
+    #[serde(default)]
+    pub return_denied: bool,
+    // set only by trusted code, never taken from a deserialized request
+    #[serde(skip)]
+    pub privileged: bool,
+    // fields whose value counts over the returned page are put in QueryResult.facets, empty if no counts are needed; each
+    // returned document is read from the storage to count them
+    #[serde(default)]
+    pub facets: Vec<String>,
+    // put a highlighted excerpt of the matched text of each returned document in QueryResult.snippets
+    #[serde(default)]
+    pub with_snippets: bool,
+}
+
+fn default_request_access() -> u8 {
+    Access::CanRead as u8
+}
+
+const REQUEST_ACCESS_MASK: u8 = Access::CanCreate as u8 | Access::CanRead as u8 | Access::CanUpdate as u8 | Access::CanDelete as u8;
+
+// Access bits a backend authorizes with: only the Can* bits are kept, and a request without them is checked for CanRead,
+// since authorize() grants an empty request to everybody
+pub fn normalize_request_access(access: u8) -> u8 {
+    match access & REQUEST_ACCESS_MASK {
+        0 => Access::CanRead as u8,
+        a => a,
+    }
 }
 
 impl FTQuery {
+    // FTQuery.access as it must be granted, see normalize_request_access
+    pub fn request_access(&self) -> u8 {
+        normalize_request_access(self.access)
+    }
+
     pub fn new_with_user(user: &str, query: &str) -> FTQuery {
         FTQuery {
             ticket: "".to_owned(),
@@ -81,6 +220,11 @@ impl FTQuery {
             top: 10000,
             limit: 10000,
             from: 0,
+            access: Access::CanRead as u8,
+            return_denied: false,
+            privileged: false,
+            facets: vec![],
+            with_snippets: false,
         }
     }
 
@@ -95,9 +239,45 @@ impl FTQuery {
             top: 10000,
             limit: 10000,
             from: 0,
+            access: Access::CanRead as u8,
+            return_denied: false,
+            privileged: false,
+            facets: vec![],
+            with_snippets: false,
         }
     }
 
+    // For a request that carries a ticket, the user is taken from the ticket found by `get_ticket` (e.g. Backend::get_ticket_from_db),
+    // a user given in the request without a ticket is used as is
+    pub fn resolve_user<F>(&mut self, get_ticket: F, addr: Option<IpAddr>, is_check_addr: bool) -> ResultCode
+    where
+        F: FnOnce(&str) -> Ticket,
+    {
+        if self.ticket.is_empty() {
+            return if self.user.is_empty() {
+                ResultCode::TicketNotFound
+            } else {
+                ResultCode::Ok
+            };
+        }
+
+        let ticket = get_ticket(&self.ticket);
+        if ticket.result != ResultCode::Ok || ticket.user_uri.is_empty() {
+            return ResultCode::TicketNotFound;
+        }
+
+        let res = ticket.is_ticket_valid(&addr, is_check_addr);
+        if res == ResultCode::Ok {
+            self.user = ticket.user_uri;
+        }
+        res
+    }
+
+    // Denied ids are returned only to admin tooling, an ordinary request can not switch this on by itself
+    pub fn is_return_denied(&self) -> bool {
+        self.return_denied && self.privileged
+    }
+
     pub fn as_string(&self) -> String {
         let mut s = String::new();
 
@@ -125,10 +305,50 @@ impl FTQuery {
         s.push_str(&self.limit.to_string());
         s.push(',');
         s.push_str(&self.from.to_string());
+        // a request without facets, snippets and with the default access keeps the old form
+        let is_default_access = self.access == Access::CanRead as u8 && !self.return_denied;
+        if !self.facets.is_empty() || self.with_snippets || !is_default_access {
+            s.push(',');
+            s.push_str(&serde_json::to_string(&self.facets).unwrap_or_else(|_| "[]".to_owned()));
+            s.push(',');
+            s.push_str(&self.with_snippets.to_string());
+        }
+        if !is_default_access {
+            s.push(',');
+            s.push_str(&self.access.to_string());
+            s.push(',');
+            s.push_str(&self.return_denied.to_string());
+        }
         s.push(']');
 
         s
     }
+
+    // Reads the form written by as_string, the fields an older form does not have keep their defaults
+    pub fn from_string(s: &str) -> Option<FTQuery> {
+        // without a ticket the first element is the user in the form "UU=<user> preceded by a quote
+        let v: Vec<serde_json::Value> = serde_json::from_str(&s.replacen("[\"\"UU=", "[\"UU=", 1)).ok()?;
+        let text = |i: usize| v.get(i).and_then(|x| x.as_str()).unwrap_or_default().to_owned();
+        let flag = |i: usize| v.get(i).and_then(|x| x.as_bool()).unwrap_or_default();
+        let number = |i: usize, default: i32| v.get(i).and_then(|x| x.as_i64()).map_or(default, |n| n as i32);
+
+        let first = text(0);
+        let mut req = match first.strip_prefix("UU=") {
+            Some(user) => FTQuery::new_with_user(user, &text(1)),
+            None => FTQuery::new_with_ticket(&first, &text(1)),
+        };
+        req.sort = text(2);
+        req.databases = text(3);
+        req.reopen = flag(4);
+        req.top = number(5, req.top);
+        req.limit = number(6, req.limit);
+        req.from = number(7, req.from);
+        req.facets = v.get(8).and_then(|x| serde_json::from_value(x.clone()).ok()).unwrap_or_default();
+        req.with_snippets = flag(9);
+        req.access = v.get(10).and_then(|x| x.as_u64()).and_then(|a| u8::try_from(a).ok()).unwrap_or(req.access);
+        req.return_denied = flag(11);
+        Some(req)
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////
@@ -217,6 +437,14 @@ pub fn is_identifier(str: &str) -> bool {
     REG_URI.is_match(str)
 }
 
+// Decides whether a value is an individual id and therefore must be authorized, is_identifier by default
+pub type IdentifierMatcher = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+pub fn identifier_matcher_from_regex(pattern: &str) -> Result<IdentifierMatcher, regex::Error> {
+    let re = Regex::new(pattern)?;
+    Ok(Arc::new(move |s: &str| re.is_match(s)))
+}
+
 pub fn replace_word(text: &str, a: &str, b: &str) -> String {
     let a_lower = a.to_lowercase();
     let re = Regex::new(r"[\w']+").unwrap();
@@ -242,3 +470,180 @@ pub fn replace_word(text: &str, a: &str, b: &str) -> String {
 
     replaced_text
 }
+
+// Pages over d:doc_0..d:doc_9, four rows a page starting at the cursor of the previous one, d:doc_3 is denied: the cursor must
+// count the denied row as consumed, the same way in every backend
+#[cfg(test)]
+pub(crate) fn check_cursor_paging<F: FnMut(i32, &[String]) -> QueryResult>(mut run_page: F) {
+    let source: Vec<String> = (0..10).map(|n| format!("d:doc_{}", n)).collect();
+
+    let mut from = 0;
+    let mut pages = vec![];
+    loop {
+        let page = &source[(from as usize).min(source.len())..(from as usize + 4).min(source.len())];
+        let res = run_page(from, page);
+        if res.processed == 0 {
+            break;
+        }
+        assert_eq!(res.cursor, (from as i64) + res.processed);
+        assert!(!res.result.iter().any(|id| id == "d:doc_3"));
+        pages.push(res.cursor);
+        from = res.cursor as i32;
+    }
+
+    assert_eq!(pages, vec![4, 8, 10]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_empty_id() {
+        let mut res = QueryResult::default();
+        assert!(!res.skip_empty_id("d:doc_1"));
+        assert!(res.skip_empty_id(""));
+        assert!(res.skip_empty_id(""));
+        assert_eq!(res.skipped_empty_ids, 2);
+    }
+
+    #[test]
+    fn test_log_if_slow() {
+        let threshold = Some(Duration::from_millis(100));
+        let query = format!("'rdf:type' === 'v-s:Document' && 'v-s:description' == '{}'", "x".repeat(1000));
+
+        let mut slow = QueryResult::default();
+        slow.total_time = 350;
+        slow.query_time = 300;
+        slow.authorize_time = 50;
+        assert!(slow.log_if_slow(threshold, "xapian", "td:User1", &query));
+        assert!(!slow.log_if_slow(None, "xapian", "td:User1", &query));
+
+        let mut fast = QueryResult::default();
+        fast.total_time = 20;
+        assert!(!fast.log_if_slow(threshold, "clickhouse", "td:User1", &query));
+    }
+
+    #[test]
+    fn test_request_access() {
+        let mut req = FTQuery::new_with_user("td:User1", "'rdf:type' === 'v-s:Document'");
+        assert_eq!(req.request_access(), Access::CanRead as u8);
+
+        req.access = 0;
+        assert_eq!(req.request_access(), Access::CanRead as u8);
+
+        req.access = Access::CantRead as u8 | Access::CantUpdate as u8;
+        assert_eq!(req.request_access(), Access::CanRead as u8);
+
+        req.access = Access::CanUpdate as u8 | Access::CantDelete as u8;
+        assert_eq!(req.request_access(), Access::CanUpdate as u8);
+    }
+
+    #[test]
+    fn test_as_string() {
+        let mut req = FTQuery::new_with_user("td:User1", "'rdf:type' === 'v-s:Contract'");
+        req.top = 10;
+        assert_eq!(req.as_string(), "[\"\"UU=td:User1\",\"'rdf:type' === 'v-s:Contract'\",\"\",\"\",false,10,10000,0]");
+
+        req.facets = vec!["rdf:type".to_owned()];
+        req.with_snippets = true;
+        assert_eq!(req.as_string(), "[\"\"UU=td:User1\",\"'rdf:type' === 'v-s:Contract'\",\"\",\"\",false,10,10000,0,[\"rdf:type\"],true]");
+
+        req.facets = vec![];
+        req.with_snippets = false;
+        req.access = Access::CanUpdate as u8;
+        assert_eq!(req.as_string(), "[\"\"UU=td:User1\",\"'rdf:type' === 'v-s:Contract'\",\"\",\"\",false,10,10000,0,[],false,4,false]");
+    }
+
+    #[test]
+    fn test_wire_round_trip() {
+        let mut req = FTQuery::new_with_user("td:User1", "'v-s:title' == '\"supply\"'");
+        req.sort = "'v-s:created' desc".to_owned();
+        req.databases = "base".to_owned();
+        req.top = 10;
+        req.limit = 20;
+        req.from = 40;
+        req.access = Access::CanUpdate as u8;
+        req.return_denied = true;
+
+        let res = FTQuery::from_string(&req.as_string()).unwrap();
+        assert_eq!((res.user.as_str(), res.ticket.as_str(), res.query.as_str()), ("td:User1", "", req.query.as_str()));
+        assert_eq!((res.sort.as_str(), res.databases.as_str(), res.reopen), ("'v-s:created' desc", "base", false));
+        assert_eq!((res.top, res.limit, res.from), (10, 20, 40));
+        assert_eq!((res.access, res.return_denied), (Access::CanUpdate as u8, true));
+        // privileged is never taken from the wire
+        assert!(!res.is_return_denied());
+
+        let mut req = FTQuery::new_with_ticket("ticket1", "'rdf:type' === 'v-s:Contract'");
+        req.facets = vec!["rdf:type".to_owned()];
+        req.with_snippets = true;
+        let res = FTQuery::from_string(&req.as_string()).unwrap();
+        assert_eq!((res.ticket.as_str(), res.user.as_str()), ("ticket1", ""));
+        assert_eq!((res.facets, res.with_snippets), (vec!["rdf:type".to_owned()], true));
+
+        // an old request is read with the default access
+        let res = FTQuery::from_string("[\"\"UU=td:User1\",\"'rdf:type' === 'v-s:Contract'\",\"\",\"\",false,10,10000,0]").unwrap();
+        assert_eq!((res.user.as_str(), res.top, res.access, res.return_denied), ("td:User1", 10, Access::CanRead as u8, false));
+        assert!(FTQuery::from_string("not a request").is_none());
+    }
+
+    #[test]
+    fn test_count_facets() {
+        let fields = vec!["rdf:type".to_owned(), "v-s:valid".to_owned()];
+        let mut res = QueryResult::default();
+
+        let mut doc1 = Individual::default();
+        doc1.set_id("d:doc_1");
+        doc1.add_uri("rdf:type", "v-s:Document");
+        doc1.add_uri("rdf:type", "v-s:Contract");
+        doc1.add_uri("rdf:type", "v-s:Contract");
+        doc1.add_bool("v-s:valid", true);
+        res.count_facets(&mut doc1, &fields);
+
+        let mut doc2 = Individual::default();
+        doc2.set_id("d:doc_2");
+        doc2.add_uri("rdf:type", "v-s:Document");
+        res.count_facets(&mut doc2, &fields);
+
+        assert_eq!(res.facets["rdf:type"]["v-s:Document"], 2);
+        assert_eq!(res.facets["rdf:type"]["v-s:Contract"], 1);
+        assert_eq!(res.facets["v-s:valid"].len(), 1);
+        assert_eq!(res.facets["v-s:valid"]["true"], 1);
+    }
+
+    #[test]
+    fn test_resolve_user_from_ticket() {
+        let get_ticket = |id: &str| match id {
+            "valid" | "expired" => Ticket {
+                id: id.to_owned(),
+                user_uri: "td:User1".to_owned(),
+                result: ResultCode::Ok,
+                end_time: if id == "valid" {
+                    chrono::Utc::now().timestamp() + 3600
+                } else {
+                    chrono::Utc::now().timestamp() - 3600
+                },
+                ..Ticket::default()
+            },
+            _ => Ticket::default(),
+        };
+
+        let mut req = FTQuery::new_with_ticket("valid", "'rdf:type' === 'v-s:Document'");
+        assert_eq!(req.resolve_user(get_ticket, None, false), ResultCode::Ok);
+        assert_eq!(req.user, "td:User1");
+
+        let mut req = FTQuery::new_with_ticket("expired", "'rdf:type' === 'v-s:Document'");
+        assert_eq!(req.resolve_user(get_ticket, None, false), ResultCode::TicketExpired);
+        assert!(req.user.is_empty());
+
+        let mut req = FTQuery::new_with_ticket("unknown", "'rdf:type' === 'v-s:Document'");
+        assert_eq!(req.resolve_user(get_ticket, None, false), ResultCode::TicketNotFound);
+
+        let mut req = FTQuery::new_with_ticket("", "'rdf:type' === 'v-s:Document'");
+        assert_eq!(req.resolve_user(get_ticket, None, false), ResultCode::TicketNotFound);
+
+        let mut req = FTQuery::new_with_user("td:User2", "'rdf:type' === 'v-s:Document'");
+        assert_eq!(req.resolve_user(get_ticket, None, false), ResultCode::Ok);
+        assert_eq!(req.user, "td:User2");
+    }
+}
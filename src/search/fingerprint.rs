@@ -0,0 +1,123 @@
+// Normalized form of a VQL or SQL query: whitespace is collapsed and literal values are replaced with `?`,
+// so queries that differ only in values share a fingerprint (used to group metrics and cache entries)
+pub fn fingerprint_query(q: &str) -> String {
+    let is_sql = q.split_whitespace().next().map(|w| w.eq_ignore_ascii_case("select") || w.eq_ignore_ascii_case("with")).unwrap_or(false);
+
+    let src: Vec<char> = q.chars().collect();
+    let mut out = String::with_capacity(q.len());
+    let mut pending_space = false;
+    let mut pos = 0;
+
+    while pos < src.len() {
+        let c = src[pos];
+
+        if c.is_whitespace() {
+            pending_space = true;
+            pos += 1;
+            continue;
+        }
+
+        if pending_space && !out.is_empty() {
+            out.push(' ');
+        }
+        pending_space = false;
+
+        match c {
+            '\'' => {
+                let end = quoted_end(&src, pos, '\'');
+                // in VQL the left side of a comparison is a quoted predicate name, it is a part of the query shape
+                if !is_sql && is_followed_by_vql_op(&src, end) {
+                    out.extend(&src[pos..end]);
+                } else {
+                    out.push('?');
+                }
+                pos = end;
+            },
+            '"' | '`' => {
+                let end = quoted_end(&src, pos, c);
+                out.extend(&src[pos..end]);
+                pos = end;
+            },
+            _ if c.is_ascii_digit() => {
+                // numbers and unquoted dates of VQL ranges
+                let mut end = pos;
+                while end < src.len() && (src[end].is_alphanumeric() || src[end] == '.' || src[end] == '-' || src[end] == ':') {
+                    end += 1;
+                }
+                out.push('?');
+                pos = end;
+            },
+            _ if c.is_alphanumeric() || c == '_' => {
+                let mut end = pos;
+                while end < src.len() && (src[end].is_alphanumeric() || src[end] == '_' || src[end] == ':' || src[end] == '-') {
+                    end += 1;
+                }
+                out.extend(&src[pos..end]);
+                pos = end;
+            },
+            _ => {
+                out.push(c);
+                pos += 1;
+            },
+        }
+    }
+
+    out
+}
+
+// Position after the closing quote; a doubled quote or a backslash escapes it
+fn quoted_end(src: &[char], start: usize, quote: char) -> usize {
+    let mut pos = start + 1;
+    while pos < src.len() {
+        if src[pos] == '\\' {
+            pos += 2;
+            continue;
+        }
+        if src[pos] == quote {
+            if pos + 1 < src.len() && src[pos + 1] == quote {
+                pos += 2;
+                continue;
+            }
+            return pos + 1;
+        }
+        pos += 1;
+    }
+    src.len()
+}
+
+fn is_followed_by_vql_op(src: &[char], mut pos: usize) -> bool {
+    while pos < src.len() && src[pos].is_whitespace() {
+        pos += 1;
+    }
+    match src.get(pos) {
+        Some('=') | Some('>') | Some('<') => true,
+        Some('!') => src.get(pos + 1) == Some(&'='),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vql_fingerprint() {
+        let a = fingerprint_query("'rdf:type' === 'v-s:Document'  &&  'v-s:created' == [2020-01-01T00:00:00, 2020-12-31T23:59:59]");
+        let b = fingerprint_query("'rdf:type' === 'v-s:Contract' && 'v-s:created' == [2021-02-01T00:00:00, 2021-03-31T23:59:59]");
+
+        assert_eq!(a, b);
+        assert_eq!(a, "'rdf:type' === ? && 'v-s:created' == [?, ?]");
+        assert_eq!(fingerprint_query("'v-s:name' == 'Ivanov*'"), "'v-s:name' == ?");
+        assert_ne!(a, fingerprint_query("'rdf:type' === 'v-s:Document' || 'v-s:created' == [2020-01-01T00:00:00, 2020-12-31T23:59:59]"));
+    }
+
+    #[test]
+    fn test_sql_fingerprint() {
+        let a = fingerprint_query("SELECT id FROM veda_tt.`v-s:Document` WHERE `v-s:title_str` = 'it''s first' AND counter > 10");
+        let b = fingerprint_query("SELECT id  FROM veda_tt.`v-s:Document`\n WHERE `v-s:title_str` = 'second' AND counter > 2.5");
+
+        assert_eq!(a, b);
+        assert_eq!(fingerprint_query("SELECT id FROM t WHERE doc_1 = 'x'"), "SELECT id FROM t WHERE doc_1 = ?");
+        assert_ne!(a, fingerprint_query("SELECT id FROM veda_tt.`v-s:Document` WHERE `v-s:title_str` = 'second' OR counter > 2"));
+    }
+}
@@ -94,6 +94,17 @@ impl FTClient {
             res.estimated = v["estimated"].as_i64().unwrap_or_default();
             res.processed = v["processed"].as_i64().unwrap_or_default();
             res.cursor = v["cursor"].as_i64().unwrap_or_default();
+            res.denied_count = v["denied_count"].as_i64().unwrap_or_default();
+            res.skipped_empty_ids = v["skipped_empty_ids"].as_i64().unwrap_or_default();
+            if let Some(jarray) = v["denied"].as_array() {
+                res.denied = jarray.iter().map(|v| v.as_str().unwrap_or_default().to_owned()).collect();
+            }
+            if let Ok(facets) = serde_json::from_value(v["facets"].clone()) {
+                res.facets = facets;
+            }
+            if let Some(jarray) = v["snippets"].as_array() {
+                res.snippets = jarray.iter().map(|v| v.as_str().unwrap_or_default().to_owned()).collect();
+            }
         }
 
         //info!("msg={}", v);
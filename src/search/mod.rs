@@ -1,8 +1,18 @@
+#[cfg(feature = "sparql")]
 mod awc_wrapper;
+#[cfg(feature = "clickhouse")]
 pub mod clickhouse_client;
 pub mod common;
+mod fingerprint;
 pub mod ft_client;
+mod search_impl;
+#[cfg(feature = "sparql")]
 pub mod sparql_client;
+#[cfg(feature = "sparql")]
 pub mod sparql_params;
 mod sql_lex_tree;
 pub mod sql_params;
+pub mod sql_validator;
+
+pub use fingerprint::fingerprint_query;
+pub use search_impl::{execute, search, SearchBackend, SearchError};
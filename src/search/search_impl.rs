@@ -0,0 +1,191 @@
+use crate::ft_xapian::xapian_reader::XapianReader;
+#[cfg(feature = "clickhouse")]
+use crate::search::clickhouse_client::CHClient;
+#[cfg(feature = "sparql")]
+use crate::search::common::PrefixesCache;
+use crate::search::common::{FTQuery, QueryResult};
+#[cfg(feature = "sparql")]
+use crate::search::sparql_client::SparqlClient;
+use crate::v_api::obj::{OptAuthorize, ResultCode};
+use std::fmt;
+use std::io;
+use xapian_rusty::XError;
+
+#[derive(Debug)]
+pub enum SearchError {
+    #[cfg(feature = "clickhouse")]
+    Clickhouse(v_clickhouse_rs::errors::Error),
+    Io(io::Error),
+    Xapian(XError),
+}
+
+impl fmt::Display for SearchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(feature = "clickhouse")]
+            SearchError::Clickhouse(e) => write!(f, "clickhouse: {}", e),
+            SearchError::Io(e) => write!(f, "io: {}", e),
+            SearchError::Xapian(e) => write!(f, "xapian: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SearchError {}
+
+impl SearchError {
+    // The code a failed query is answered with, xapian reports a database changed during the query as DatabaseModifiedError
+    pub fn result_code(&self) -> ResultCode {
+        match self {
+            SearchError::Xapian(XError::Xapian(code)) if *code == -10 || *code == -21 => ResultCode::DatabaseModifiedError,
+            _ => ResultCode::InternalServerError,
+        }
+    }
+}
+
+#[cfg(feature = "clickhouse")]
+impl From<v_clickhouse_rs::errors::Error> for SearchError {
+    fn from(e: v_clickhouse_rs::errors::Error) -> Self {
+        SearchError::Clickhouse(e)
+    }
+}
+
+impl From<io::Error> for SearchError {
+    fn from(e: io::Error) -> Self {
+        SearchError::Io(e)
+    }
+}
+
+impl From<XError> for SearchError {
+    fn from(e: XError) -> Self {
+        SearchError::Xapian(e)
+    }
+}
+
+pub enum SearchBackend<'a> {
+    Xapian(&'a mut XapianReader),
+    #[cfg(feature = "clickhouse")]
+    Clickhouse(&'a mut CHClient),
+    #[cfg(feature = "sparql")]
+    Sparql(&'a mut SparqlClient, &'a PrefixesCache),
+}
+
+// Single entry point over the full text, clickhouse and sparql backends, errors of all of them are reported as SearchError
+pub async fn search(backend: SearchBackend<'_>, request: FTQuery, op_auth: OptAuthorize) -> Result<QueryResult, SearchError> {
+    match backend {
+        SearchBackend::Xapian(xr) => {
+            fn add_out_element(id: &str, ctx: &mut Vec<String>) {
+                ctx.push(id.to_owned());
+            }
+
+            let mut res_out_list = vec![];
+            let mut res = xr.query_use_collect_fn(&request, add_out_element, op_auth, &mut res_out_list).await?;
+            res.result = res_out_list;
+            Ok(res)
+        },
+        #[cfg(feature = "clickhouse")]
+        SearchBackend::Clickhouse(ch) => Ok(ch.select_async(request, op_auth).await?),
+        #[cfg(feature = "sparql")]
+        SearchBackend::Sparql(sparql, prefix_cache) => Ok(sparql.query_select_ids_with_access(&request, op_auth, prefix_cache).await),
+    }
+}
+
+// The same dispatch as search for endpoints that always answer with a QueryResult: an error of the backend is logged
+// and reported in result_code
+pub async fn execute(backend: SearchBackend<'_>, request: FTQuery, op_auth: OptAuthorize) -> QueryResult {
+    into_query_result(search(backend, request, op_auth).await)
+}
+
+fn into_query_result(res: Result<QueryResult, SearchError>) -> QueryResult {
+    match res {
+        Ok(r) => r,
+        Err(e) => {
+            error!("fail search, err={}", e);
+            QueryResult {
+                result_code: e.result_code(),
+                ..Default::default()
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ft_xapian::key2slot::Key2Slot;
+    use futures::executor::block_on;
+    use xapian_rusty::{Document, Stem, TermGenerator, WritableDatabase, CHERT, DB_CREATE_OR_OVERWRITE};
+
+    #[test]
+    fn test_backend_errors_into_search_error() {
+        #[cfg(feature = "clickhouse")]
+        {
+            let e: SearchError = v_clickhouse_rs::errors::Error::from("fail connect").into();
+            assert!(matches!(e, SearchError::Clickhouse(_)));
+        }
+
+        let e: SearchError = io::Error::new(io::ErrorKind::Other, "fail send query").into();
+        assert!(matches!(e, SearchError::Io(_)));
+
+        let e: SearchError = XError::Xapian(-10).into();
+        assert!(matches!(e, SearchError::Xapian(XError::Xapian(-10))));
+        assert_eq!(e.to_string(), "xapian: xapian err=-10");
+    }
+
+    #[test]
+    fn test_errors_into_query_result() {
+        let mut ok = QueryResult::default();
+        ok.result_code = ResultCode::Ok;
+        ok.result = vec!["d:doc_1".to_owned()];
+        assert_eq!(into_query_result(Ok(ok)).result, vec!["d:doc_1".to_owned()]);
+
+        let res = into_query_result(Err(XError::Xapian(-10).into()));
+        assert_eq!(res.result_code, ResultCode::DatabaseModifiedError);
+        assert!(res.result.is_empty());
+
+        assert_eq!(into_query_result(Err(XError::Xapian(-1).into())).result_code, ResultCode::InternalServerError);
+        assert_eq!(into_query_result(Err(io::Error::new(io::ErrorKind::Other, "fail send query").into())).result_code, ResultCode::InternalServerError);
+    }
+
+    #[test]
+    fn test_execute_xapian() {
+        let base_path = std::env::temp_dir().join(format!("search-execute-{}", std::process::id())).to_str().unwrap().to_owned();
+        let _ = std::fs::remove_dir_all(&base_path);
+        std::fs::create_dir_all(&base_path).unwrap();
+
+        let mut key2slot = Key2Slot::new_with_path(&format!("{}/key2slot", base_path));
+        let slot = key2slot.get_slot_and_set_if_not_found("v-s:title");
+
+        let db_path = format!("{}/xapian-search-base", base_path);
+        {
+            let mut wdb = WritableDatabase::new(&db_path, DB_CREATE_OR_OVERWRITE, CHERT).unwrap();
+            let mut tg = TermGenerator::new().unwrap();
+            tg.set_stemmer(&mut Stem::new("english").unwrap()).unwrap();
+            for (id, title) in [("d:doc_1", "supply contract"), ("d:doc_2", "service contract"), ("d:doc_3", "invoice")] {
+                let mut doc = Document::new().unwrap();
+                tg.set_document(&mut doc).unwrap();
+                tg.index_text_with_prefix(title, &format!("X{}X", slot)).unwrap();
+                doc.set_data(id).unwrap();
+                let id_term = format!("Q{}", id);
+                doc.add_boolean_term(&id_term).unwrap();
+                wdb.replace_document(&id_term, &mut doc).unwrap();
+            }
+            wdb.commit().unwrap();
+        }
+
+        let mut xr = XapianReader::new_for_test("english", &base_path, hashmap! { "base".to_owned() => db_path });
+        let mut run = |query: &str, databases: &str| {
+            let mut request = FTQuery::new_with_user("cfg:VedaSystem", query);
+            request.databases = databases.to_owned();
+            block_on(execute(SearchBackend::Xapian(&mut xr), request, OptAuthorize::NO))
+        };
+
+        let mut res = run("'v-s:title' == 'contract'", "base");
+        assert_eq!(res.result_code, ResultCode::Ok);
+        res.result.sort();
+        assert_eq!(res.result, vec!["d:doc_1".to_owned(), "d:doc_2".to_owned()]);
+
+        assert_eq!(run("'v-s:title' == 'contract'", "unknown").result_code, ResultCode::BadRequest);
+
+        let _ = std::fs::remove_dir_all(&base_path);
+    }
+}
@@ -1,17 +1,18 @@
 use crate::az_impl::az_lmdb::LmdbAzContext;
 use crate::module::module_impl::Module;
-use crate::search::common::{get_short_prefix, split_full_prefix, AuthorizationLevel, PrefixesCache, QueryResult, ResultFormat};
-use crate::v_api::obj::ResultCode;
+use crate::search::common::{get_short_prefix, split_full_prefix, AuthorizationLevel, FTQuery, PrefixesCache, QueryResult, ResultFormat};
+use crate::v_api::obj::{OptAuthorize, ResultCode};
 use futures::lock::Mutex;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json::Map;
 use serde_json::{json, Value};
 use std::collections::HashSet;
+use std::future::Future;
 use std::io::{Error, ErrorKind};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use stopwatch::Stopwatch;
-use v_authorization::common::{Access, AuthorizationContext};
+use v_authorization::common::Access;
 
 use super::awc_wrapper::{Client, HeaderValue, ACCEPT, CONTENT_TYPE};
 
@@ -35,6 +36,7 @@ pub struct SparqlClient {
     pub(crate) point: String,
     pub(crate) client: Client,
     pub(crate) az: LmdbAzContext,
+    pub(crate) slow_query_threshold: Option<Duration>,
 }
 
 impl Default for SparqlClient {
@@ -45,13 +47,97 @@ impl Default for SparqlClient {
             point: format!("{}/{}?{}", Module::get_property::<String>("sparql_db").unwrap_or_default(), "query", "default"),
             client,
             az: LmdbAzContext::default(),
+            slow_query_threshold: None,
         }
     }
 }
 
+// SPARQL accepts LIMIT and OFFSET in any combination, they are added after the solution modifiers of the query
+fn paged_query(query: &str, limit: i32, from: i32) -> String {
+    let mut q = query.to_owned();
+    if limit > 0 {
+        q.push_str(&format!(" LIMIT {}", limit));
+    }
+    if from > 0 {
+        q.push_str(&format!(" OFFSET {}", from));
+    }
+    q
+}
+
+// `from` is the OFFSET the bindings were selected with, the cursor points to the binding following the last consumed one;
+// the bindings after `top` readable ones are not consumed, 0 is no limit. The time spent in is_readable is the authorize_time
+async fn collect_authorized_bindings<F, R>(
+    bindings: Vec<Value>,
+    var: &str,
+    from: i32,
+    top: i32,
+    prefix_cache: &PrefixesCache,
+    mut is_readable: F,
+    qres: &mut QueryResult,
+) where
+    F: FnMut(String) -> R,
+    R: Future<Output = bool>,
+{
+    let mut auth_sw = Stopwatch::new();
+    for el in bindings {
+        if top > 0 && qres.result.len() >= top as usize {
+            break;
+        }
+        qres.processed += 1;
+
+        let r = &el[var];
+        // an unbound variable has no id, like an empty uri
+        let subject_id = if r.is_null() {
+            Some("")
+        } else if r["type"] == "uri" {
+            r["value"].as_str()
+        } else {
+            None
+        };
+
+        if let Some(v) = subject_id {
+            if qres.skip_empty_id(v) {
+                continue;
+            }
+
+            let iri = split_full_prefix(v);
+
+            let fullprefix = iri.0;
+            let prefix = get_short_prefix(fullprefix, prefix_cache);
+            let short_iri = format!("{prefix}:{}", iri.1);
+
+            auth_sw.start();
+            let readable = is_readable(short_iri.clone()).await;
+            auth_sw.stop();
+            if readable {
+                qres.result.push(short_iri);
+            }
+        }
+    }
+
+    qres.authorize_time = auth_sw.elapsed_ms();
+
+    qres.result_code = ResultCode::Ok;
+    qres.count = qres.result.len() as i64;
+    qres.set_cursor(from);
+}
+
 impl SparqlClient {
+    pub fn set_slow_query_threshold(&mut self, threshold: Option<Duration>) {
+        self.slow_query_threshold = threshold;
+    }
+
     pub async fn query_select_ids(&mut self, user_uri: &str, query: String, prefix_cache: &PrefixesCache) -> QueryResult {
+        self.query_select_ids_with_access(&FTQuery::new_with_user(user_uri, &query), OptAuthorize::YES, prefix_cache).await
+    }
+
+    // The query is paged by req.from and req.limit, at most req.top readable ids are returned and the ids are checked for req.access;
+    // the cursor of the result is the offset of the next page in the result set of the query
+    pub async fn query_select_ids_with_access(&mut self, req: &FTQuery, op_auth: OptAuthorize, prefix_cache: &PrefixesCache) -> QueryResult {
+        let (user_uri, access) = (req.user.as_str(), req.request_access());
+        let query = paged_query(&req.query, req.limit, req.from);
         let total_time = Instant::now();
+        let slow_query_text = self.slow_query_threshold.map(|_| query.clone());
 
         #[cfg(feature = "awc_2")]
         let res_req =
@@ -78,31 +164,29 @@ impl SparqlClient {
                     let var = &v.head.vars[0];
                     debug!("vars:{var:?}");
 
-                    qres.count = v.results.bindings.len() as i64;
-
-                    qres.result_code = ResultCode::Ok;
-
-                    let mut auth_sw = Stopwatch::new();
-                    for el in v.results.bindings {
-                        let r = &el[var];
-                        if r["type"] == "uri" {
-                            if let Some(v) = r["value"].as_str() {
-                                let iri = split_full_prefix(v);
-
-                                let fullprefix = iri.0;
-                                let prefix = get_short_prefix(fullprefix, &prefix_cache);
-                                let short_iri = format!("{prefix}:{}", iri.1);
-
-                                auth_sw.start();
-                                if self.az.authorize(&short_iri, user_uri, Access::CanRead as u8, true).unwrap_or(0) == Access::CanRead as u8 {
-                                    qres.result.push(short_iri);
+                    let az = &self.az;
+                    collect_authorized_bindings(
+                        v.results.bindings,
+                        var,
+                        req.from,
+                        req.top,
+                        prefix_cache,
+                        |short_iri| {
+                            let authorized = if op_auth == OptAuthorize::YES {
+                                Some(az.authorize_async(&short_iri, user_uri, access, true))
+                            } else {
+                                None
+                            };
+                            async move {
+                                match authorized {
+                                    Some(authorized) => authorized.await.unwrap_or(0) == access,
+                                    None => true,
                                 }
-                                auth_sw.stop();
                             }
-                        }
-                    }
-                    qres.processed = qres.result.len() as i64;
-                    qres.authorize_time = auth_sw.elapsed_ms();
+                        },
+                        &mut qres,
+                    )
+                    .await;
                 },
                 Err(e) => {
                     error!("{:?}", e);
@@ -112,6 +196,9 @@ impl SparqlClient {
 
         qres.total_time = total_time.elapsed().as_millis() as i64;
         qres.query_time = qres.total_time - qres.authorize_time;
+        if let Some(q) = slow_query_text {
+            qres.log_if_slow(self.slow_query_threshold, "sparql", user_uri, &q);
+        }
 
         qres
     }
@@ -181,7 +268,8 @@ impl SparqlClient {
                                     let prefix = get_short_prefix(iri.0, prefix_cache);
                                     let short_iri = format!("{prefix}:{}", iri.1);
 
-                                    if az.lock().await.authorize(&short_iri, user_uri, Access::CanRead as u8, false).unwrap_or(0) != Access::CanRead as u8 {
+                                    let authorized = az.lock().await.authorize_async(&short_iri, user_uri, Access::CanRead as u8, false);
+                                    if authorized.await.unwrap_or(0) != Access::CanRead as u8 {
                                         is_authorized = false;
                                         if authorization_level == AuthorizationLevel::Cell {
                                             json!("v-s:NotAuthorized")
@@ -272,3 +360,155 @@ impl SparqlClient {
         Ok(jres)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::common::check_cursor_paging;
+    use futures::executor::block_on;
+    use futures::future::ready;
+    use std::sync::Arc;
+
+    fn test_prefix_cache() -> PrefixesCache {
+        let (full2short_r, mut full2short_w) = evmap::new();
+        let (short2full_r, mut short2full_w) = evmap::new();
+        full2short_w.insert("http://semantic-machines.com/veda/veda-data/".to_owned(), "d".to_owned());
+        short2full_w.insert("d".to_owned(), "http://semantic-machines.com/veda/veda-data/".to_owned());
+        full2short_w.refresh();
+        short2full_w.refresh();
+        PrefixesCache {
+            full2short_r,
+            full2short_w: Arc::new(Mutex::new(full2short_w)),
+            short2full_r,
+            short2full_w: Arc::new(Mutex::new(short2full_w)),
+        }
+    }
+
+    #[test]
+    fn test_cursor_advances_across_pages() {
+        let prefix_cache = test_prefix_cache();
+
+        check_cursor_paging(|from, page| {
+            let bindings: Vec<Value> =
+                page.iter().map(|id| json!({ "s": { "type": "uri", "value": id.replace("d:", "http://semantic-machines.com/veda/veda-data/") } })).collect();
+            let mut qres = QueryResult::default();
+            block_on(collect_authorized_bindings(bindings, "s", from, 0, &prefix_cache, |id| ready(id != "d:doc_3"), &mut qres));
+            qres
+        });
+    }
+
+    // answers a query with the bindings of doc_0..doc_9 selected by its LIMIT and OFFSET
+    #[cfg(any(all(feature = "awc_2", feature = "tokio_0_2"), all(feature = "awc_3", feature = "tokio_1")))]
+    fn answer_page(mut stream: std::net::TcpStream) -> std::io::Result<()> {
+        use std::io::{BufRead, Read, Write};
+
+        let mut reader = std::io::BufReader::new(stream.try_clone()?);
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line == "\r\n" {
+                break;
+            }
+            if let Some(v) = line.to_lowercase().strip_prefix("content-length:") {
+                content_length = v.trim().parse().unwrap_or(0);
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+
+        let query = String::from_utf8_lossy(&body).to_string();
+        let param = |name: &str| query.split_whitespace().skip_while(|w| *w != name).nth(1).and_then(|v| v.parse::<usize>().ok());
+        let bindings: Vec<Value> = (0..10)
+            .skip(param("OFFSET").unwrap_or(0))
+            .take(param("LIMIT").unwrap_or(10))
+            .map(|n| json!({ "s": { "type": "uri", "value": format!("http://semantic-machines.com/veda/veda-data/doc_{}", n) } }))
+            .collect();
+        let body = json!({ "head": { "vars": ["s"] }, "results": { "bindings": bindings } }).to_string();
+        let head = "HTTP/1.1 200 OK\r\nContent-Type: application/sparql-results+json\r\nConnection: close";
+        write!(stream, "{}\r\nContent-Length: {}\r\n\r\n{}", head, body.len(), body)
+    }
+
+    #[cfg(any(all(feature = "awc_2", feature = "tokio_0_2"), all(feature = "awc_3", feature = "tokio_1")))]
+    #[test]
+    fn test_search_pages_by_cursor() {
+        use crate::runtime_wrapper::RuntimeWrapper;
+        use crate::search::search_impl::{search, SearchBackend};
+        #[cfg(feature = "tokio_0_2")]
+        use tokio_dep_0_2::task::LocalSet;
+        #[cfg(feature = "tokio_1")]
+        use tokio_dep_1::task::LocalSet;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = answer_page(stream);
+            }
+        });
+
+        let az_path = std::env::temp_dir().join(format!("sparql-search-az-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&az_path);
+        let mut az = LmdbAzContext::open_writable(az_path.to_str().unwrap()).unwrap();
+        for n in (0..10).filter(|n| *n != 3) {
+            az.put(&format!("Pd:doc_{}", n), "td:User1;R;").unwrap();
+        }
+
+        let mut sparql = SparqlClient {
+            point: format!("http://{}/query", addr),
+            client: Client::default(),
+            az,
+            slow_query_threshold: None,
+        };
+        let prefix_cache = test_prefix_cache();
+        let mut rt = RuntimeWrapper::new();
+        let local = LocalSet::new();
+
+        check_cursor_paging(|from, _page| {
+            let mut req = FTQuery::new_with_user("td:User1", "SELECT ?s WHERE { ?s a <v-s:Document> }");
+            req.from = from;
+            req.limit = 4;
+            local.block_on(&mut rt.runtime, search(SearchBackend::Sparql(&mut sparql, &prefix_cache), req, OptAuthorize::YES)).unwrap()
+        });
+
+        let _ = std::fs::remove_dir_all(&az_path);
+    }
+
+    #[test]
+    fn test_paged_query() {
+        let q = "SELECT ?s WHERE { ?s a <v-s:Document> }";
+        assert_eq!(paged_query(q, 4, 8), format!("{} LIMIT 4 OFFSET 8", q));
+        assert_eq!(paged_query(q, 0, 8), format!("{} OFFSET 8", q));
+        assert_eq!(paged_query(q, 0, 0), q);
+    }
+
+    #[test]
+    fn test_unbound_subject_is_skipped_as_empty() {
+        let prefix_cache = test_prefix_cache();
+        let bindings = vec![
+            json!({ "s": { "type": "uri", "value": "http://semantic-machines.com/veda/veda-data/doc_1" } }),
+            json!({ "s": { "type": "uri", "value": "" } }),
+            json!({}),
+            json!({ "s": { "type": "literal", "value": "text" } }),
+        ];
+
+        let mut checked = vec![];
+        let mut qres = QueryResult::default();
+        block_on(collect_authorized_bindings(
+            bindings,
+            "s",
+            0,
+            0,
+            &prefix_cache,
+            |id| {
+                checked.push(id);
+                ready(true)
+            },
+            &mut qres,
+        ));
+
+        assert_eq!(checked, vec!["d:doc_1".to_owned()]);
+        assert_eq!(qres.result, checked);
+        assert_eq!(qres.skipped_empty_ids, 2);
+        assert_eq!(qres.processed, 4);
+    }
+}
@@ -1,6 +1,7 @@
 use crate::onto::individual::Individual;
 use crate::onto::resource::Resource;
 use crate::onto::resource::Value::{Bool, Datetime, Int, Num, Str, Uri};
+use crate::search::sql_validator::{SqlPolicy, SqlValidationError};
 use chrono::{TimeZone, Utc};
 use sqlparser::ast::TableFactor::UNNEST;
 use sqlparser::ast::{
@@ -10,79 +11,79 @@ use sqlparser::ast::{
 use std::io;
 use std::io::{Error, ErrorKind};
 
-pub fn tr_statement(f: &mut Statement, args_map: &mut Individual) -> io::Result<()> {
+pub fn tr_statement(f: &mut Statement, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
     if let Statement::Query(ref mut s) = f {
-        tr_query(s, args_map)?;
+        tr_query(s, args_map, policy)?;
         Ok(())
     } else {
         Err(Error::new(ErrorKind::Unsupported, "Query forbidden".to_string()))
     }
 }
 
-fn tr_query(f: &mut Query, args_map: &mut Individual) -> io::Result<()> {
+fn tr_query(f: &mut Query, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
     if let Some(with) = &mut f.with {
-        tr_with(with, args_map)?;
+        tr_with(with, args_map, policy)?;
     }
-    tr_set_expr(&mut f.body, args_map)?;
+    tr_set_expr(&mut f.body, args_map, policy)?;
     if !f.order_by.is_empty() {
         for x in f.order_by.iter_mut() {
-            tr_order_by_expr(x, args_map)?;
+            tr_order_by_expr(x, args_map, policy)?;
         }
     }
     if let Some(ref mut limit) = f.limit {
-        tr_expr(limit, args_map)?;
+        tr_expr(limit, args_map, policy)?;
     }
     if let Some(ref mut offset) = f.offset {
-        tr_offset(offset, args_map)?;
+        tr_offset(offset, args_map, policy)?;
     }
     if let Some(ref mut fetch) = f.fetch {
-        tr_fetch(fetch, args_map)?;
+        tr_fetch(fetch, args_map, policy)?;
     }
     Ok(())
 }
 
-fn tr_offset(f: &mut Offset, args_map: &mut Individual) -> io::Result<()> {
-    tr_expr(&mut f.value, args_map)?;
+fn tr_offset(f: &mut Offset, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
+    tr_expr(&mut f.value, args_map, policy)?;
     Ok(())
 }
 
-fn tr_fetch(f: &mut Fetch, args_map: &mut Individual) -> io::Result<()> {
+fn tr_fetch(f: &mut Fetch, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
     if let Some(ref mut quantity) = f.quantity {
-        tr_expr(quantity, args_map)?;
+        tr_expr(quantity, args_map, policy)?;
     }
     Ok(())
 }
 
-fn tr_order_by_expr(f: &mut OrderByExpr, args_map: &mut Individual) -> io::Result<()> {
-    tr_expr(&mut f.expr, args_map)?;
+fn tr_order_by_expr(f: &mut OrderByExpr, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
+    tr_expr(&mut f.expr, args_map, policy)?;
     Ok(())
 }
 
-fn tr_with(f: &mut With, args_map: &mut Individual) -> io::Result<()> {
+fn tr_with(f: &mut With, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
     for x in f.cte_tables.iter_mut() {
-        tr_cte(x, args_map)?;
+        tr_cte(x, args_map, policy)?;
     }
     Ok(())
 }
 
-fn tr_cte(f: &mut Cte, args_map: &mut Individual) -> io::Result<()> {
-    tr_query(&mut f.query, args_map)?;
+fn tr_cte(f: &mut Cte, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
+    tr_query(&mut f.query, args_map, policy)?;
     Ok(())
 }
 
-fn tr_set_expr(f: &mut SetExpr, args_map: &mut Individual) -> io::Result<()> {
+fn tr_set_expr(f: &mut SetExpr, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
     match f {
         SetExpr::Select(s) => {
-            tr_select(s, args_map)?;
+            tr_select(s, args_map, policy)?;
         },
         SetExpr::Query(q) => {
-            tr_query(q, args_map)?;
+            tr_query(q, args_map, policy)?;
         },
         SetExpr::Values(v) => {
-            tr_values(v, args_map)?;
+            tr_values(v, args_map, policy)?;
         },
         SetExpr::Insert(v) => {
-            tr_statement(v, args_map)?;
+            tr_statement(v, args_map, policy)?;
         },
         SetExpr::SetOperation {
             ref mut left,
@@ -90,29 +91,29 @@ fn tr_set_expr(f: &mut SetExpr, args_map: &mut Individual) -> io::Result<()> {
             op: _,
             all: _,
         } => {
-            tr_set_expr(left, args_map)?;
-            tr_set_expr(right, args_map)?;
+            tr_set_expr(left, args_map, policy)?;
+            tr_set_expr(right, args_map, policy)?;
         },
     }
     Ok(())
 }
 
-fn tr_values(f: &mut Values, args_map: &mut Individual) -> io::Result<()> {
+fn tr_values(f: &mut Values, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
     for row in f.0.iter_mut() {
         for x in row.iter_mut() {
-            tr_expr(x, args_map)?;
+            tr_expr(x, args_map, policy)?;
         }
     }
     Ok(())
 }
 
-fn tr_expr(f: &mut Expr, args_map: &mut Individual) -> io::Result<()> {
+fn tr_expr(f: &mut Expr, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
     match f {
         Expr::MapAccess {
             column,
             keys,
         } => {
-            tr_expr(column, args_map)?;
+            tr_expr(column, args_map, policy)?;
             for k in keys {
                 match k {
                     Expr::Value(v) => {
@@ -121,7 +122,7 @@ fn tr_expr(f: &mut Expr, args_map: &mut Individual) -> io::Result<()> {
                         }
                     },
                     _ => {
-                        tr_expr(k, args_map)?;
+                        tr_expr(k, args_map, policy)?;
                     },
                 }
             }
@@ -132,9 +133,9 @@ fn tr_expr(f: &mut Expr, args_map: &mut Individual) -> io::Result<()> {
             list,
             negated: _,
         } => {
-            tr_expr(expr, args_map)?;
+            tr_expr(expr, args_map, policy)?;
             for x in list.iter_mut() {
-                tr_expr(x, args_map)?;
+                tr_expr(x, args_map, policy)?;
             }
         },
         Expr::InSubquery {
@@ -142,16 +143,16 @@ fn tr_expr(f: &mut Expr, args_map: &mut Individual) -> io::Result<()> {
             subquery,
             negated: _,
         } => {
-            tr_expr(expr, args_map)?;
-            tr_query(subquery, args_map)?;
+            tr_expr(expr, args_map, policy)?;
+            tr_query(subquery, args_map, policy)?;
         },
         Expr::InUnnest {
             expr,
             array_expr,
             negated: _,
         } => {
-            tr_expr(expr, args_map)?;
-            tr_expr(array_expr, args_map)?;
+            tr_expr(expr, args_map, policy)?;
+            tr_expr(array_expr, args_map, policy)?;
         },
         Expr::Between {
             expr,
@@ -159,63 +160,63 @@ fn tr_expr(f: &mut Expr, args_map: &mut Individual) -> io::Result<()> {
             low,
             high,
         } => {
-            tr_expr(expr, args_map)?;
-            tr_expr(low, args_map)?;
-            tr_expr(high, args_map)?;
+            tr_expr(expr, args_map, policy)?;
+            tr_expr(low, args_map, policy)?;
+            tr_expr(high, args_map, policy)?;
         },
         Expr::BinaryOp {
             left,
             op: _,
             right,
         } => {
-            tr_expr(left, args_map)?;
-            tr_expr(right, args_map)?;
+            tr_expr(left, args_map, policy)?;
+            tr_expr(right, args_map, policy)?;
         },
         Expr::AnyOp(expr) => {
-            tr_expr(expr, args_map)?;
+            tr_expr(expr, args_map, policy)?;
         },
         Expr::AllOp(expr) => {
-            tr_expr(expr, args_map)?;
+            tr_expr(expr, args_map, policy)?;
         },
         Expr::UnaryOp {
             op: _,
             expr,
         } => {
-            tr_expr(expr, args_map)?;
+            tr_expr(expr, args_map, policy)?;
         },
         Expr::Cast {
             expr,
             data_type: _,
         } => {
-            tr_expr(expr, args_map)?;
+            tr_expr(expr, args_map, policy)?;
         },
         Expr::TryCast {
             expr,
             data_type: _,
         } => {
-            tr_expr(expr, args_map)?;
+            tr_expr(expr, args_map, policy)?;
         },
         Expr::Extract {
             field: _,
             expr,
         } => {
-            tr_expr(expr, args_map)?;
+            tr_expr(expr, args_map, policy)?;
         },
         Expr::Position {
             expr,
             r#in,
         } => {
-            tr_expr(expr, args_map)?;
-            tr_expr(r#in, args_map)?;
+            tr_expr(expr, args_map, policy)?;
+            tr_expr(r#in, args_map, policy)?;
         },
         Expr::Collate {
             expr,
             collation: _,
         } => {
-            tr_expr(expr, args_map)?;
+            tr_expr(expr, args_map, policy)?;
         },
         Expr::Nested(ast) => {
-            tr_expr(ast, args_map)?;
+            tr_expr(ast, args_map, policy)?;
         },
         Expr::Value(v) => {
             let v_s = v.to_string();
@@ -227,7 +228,7 @@ fn tr_expr(f: &mut Expr, args_map: &mut Individual) -> io::Result<()> {
             }
         },
         Expr::Function(ref mut fun) => {
-            tr_function(fun, args_map)?;
+            tr_function(fun, args_map, policy)?;
         },
         Expr::Case {
             operand,
@@ -236,47 +237,47 @@ fn tr_expr(f: &mut Expr, args_map: &mut Individual) -> io::Result<()> {
             else_result,
         } => {
             if let Some(operand) = operand {
-                tr_expr(operand, args_map)?;
+                tr_expr(operand, args_map, policy)?;
             }
             for (c, r) in conditions.iter_mut().zip(results) {
-                tr_expr(c, args_map)?;
-                tr_expr(r, args_map)?;
+                tr_expr(c, args_map, policy)?;
+                tr_expr(r, args_map, policy)?;
             }
 
             if let Some(else_result) = else_result {
-                tr_expr(else_result, args_map)?;
+                tr_expr(else_result, args_map, policy)?;
             }
         },
         //Expr::Exists(s) => {
-        //    tr_query(s, args_map)?;
+        //    tr_query(s, args_map, policy)?;
         //},
         Expr::Subquery(s) => {
-            tr_query(s, args_map)?;
+            tr_query(s, args_map, policy)?;
         },
         Expr::ListAgg(listagg) => {
-            tr_list_agg(listagg, args_map)?;
+            tr_list_agg(listagg, args_map, policy)?;
         },
         Expr::GroupingSets(sets) => {
             for set in sets {
                 for x in set.iter_mut() {
-                    tr_expr(x, args_map)?;
+                    tr_expr(x, args_map, policy)?;
                 }
             }
         },
         Expr::Cube(sets) => {
             for set in sets {
                 for x in set.iter_mut() {
-                    tr_expr(x, args_map)?;
+                    tr_expr(x, args_map, policy)?;
                 }
             }
         },
         Expr::Rollup(ref mut sets) => {
             for set in sets.iter_mut() {
                 if set.len() == 1 {
-                    tr_expr(&mut set[0], args_map)?;
+                    tr_expr(&mut set[0], args_map, policy)?;
                 } else {
                     for x in set.iter_mut() {
-                        tr_expr(x, args_map)?;
+                        tr_expr(x, args_map, policy)?;
                     }
                 }
             }
@@ -286,48 +287,48 @@ fn tr_expr(f: &mut Expr, args_map: &mut Individual) -> io::Result<()> {
             substring_from,
             substring_for,
         } => {
-            tr_expr(expr, args_map)?;
+            tr_expr(expr, args_map, policy)?;
             if let Some(ref mut from_part) = substring_from {
-                tr_expr(from_part, args_map)?;
+                tr_expr(from_part, args_map, policy)?;
             }
             if let Some(ref mut from_part) = substring_for {
-                tr_expr(from_part, args_map)?;
+                tr_expr(from_part, args_map, policy)?;
             }
         },
         Expr::IsDistinctFrom(ref mut a, ref mut b) => {
-            tr_expr(a, args_map)?;
-            tr_expr(b, args_map)?;
+            tr_expr(a, args_map, policy)?;
+            tr_expr(b, args_map, policy)?;
         },
         Expr::IsNotDistinctFrom(ref mut a, ref mut b) => {
-            tr_expr(a, args_map)?;
-            tr_expr(b, args_map)?;
+            tr_expr(a, args_map, policy)?;
+            tr_expr(b, args_map, policy)?;
         },
         Expr::Trim {
             ref mut expr,
             trim_where: _,
             trim_what: _,
         } => {
-            tr_expr(expr, args_map)?;
+            tr_expr(expr, args_map, policy)?;
         },
         Expr::Tuple(exprs) => {
             for x in exprs.iter_mut() {
-                tr_expr(x, args_map)?;
+                tr_expr(x, args_map, policy)?;
             }
         },
         Expr::ArrayIndex {
             ref mut obj,
             indexes,
         } => {
-            tr_expr(obj, args_map)?;
+            tr_expr(obj, args_map, policy)?;
 
             for i in indexes.iter_mut() {
-                tr_expr(i, args_map)?;
+                tr_expr(i, args_map, policy)?;
             }
             return Ok(());
         },
         Expr::Array(ref mut set) => {
             for x in set.elem.iter_mut() {
-                tr_expr(x, args_map)?;
+                tr_expr(x, args_map, policy)?;
             }
         },
         Expr::JsonAccess {
@@ -335,192 +336,202 @@ fn tr_expr(f: &mut Expr, args_map: &mut Individual) -> io::Result<()> {
             operator: _,
             ref mut right,
         } => {
-            tr_expr(left, args_map)?;
-            tr_expr(right, args_map)?;
+            tr_expr(left, args_map, policy)?;
+            tr_expr(right, args_map, policy)?;
         },
         Expr::CompositeAccess {
             ref mut expr,
             key: _,
         } => {
-            tr_expr(expr, args_map)?;
+            tr_expr(expr, args_map, policy)?;
         },
         _ => {},
     }
     Ok(())
 }
 
-fn tr_list_agg(f: &mut ListAgg, args_map: &mut Individual) -> io::Result<()> {
-    tr_expr(&mut f.expr, args_map)?;
+fn tr_list_agg(f: &mut ListAgg, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
+    tr_expr(&mut f.expr, args_map, policy)?;
 
     if let Some(ref mut separator) = f.separator {
-        tr_expr(separator, args_map)?;
+        tr_expr(separator, args_map, policy)?;
     }
     if let Some(ref mut on_overflow) = f.on_overflow {
-        tr_list_agg_on_overflow(on_overflow, args_map)?;
+        tr_list_agg_on_overflow(on_overflow, args_map, policy)?;
     }
     if !f.within_group.is_empty() {
         for x in f.within_group.iter_mut() {
-            tr_order_by_expr(x, args_map)?;
+            tr_order_by_expr(x, args_map, policy)?;
         }
     }
     Ok(())
 }
 
-fn tr_list_agg_on_overflow(f: &mut ListAggOnOverflow, args_map: &mut Individual) -> io::Result<()> {
+fn tr_list_agg_on_overflow(f: &mut ListAggOnOverflow, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
     if let ListAggOnOverflow::Truncate {
         filler: Some(filler),
         with_count: _,
     } = f
     {
-        tr_expr(filler, args_map)?;
+        tr_expr(filler, args_map, policy)?;
     }
 
     Ok(())
 }
 
-fn tr_select_item(f: &mut SelectItem, args_map: &mut Individual) -> io::Result<()> {
+fn tr_select_item(f: &mut SelectItem, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
     match f {
         SelectItem::UnnamedExpr(ref mut expr) => {
-            tr_expr(expr, args_map)?;
+            tr_expr(expr, args_map, policy)?;
         },
         SelectItem::ExprWithAlias {
             ref mut expr,
             alias: _,
         } => {
-            tr_expr(expr, args_map)?;
+            tr_expr(expr, args_map, policy)?;
         },
         _ => {},
     }
     Ok(())
 }
 
-fn tr_select(f: &mut Select, args_map: &mut Individual) -> io::Result<()> {
+fn tr_select(f: &mut Select, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
     if let Some(ref mut top) = f.top {
-        tr_top(top, args_map)?;
+        tr_top(top, args_map, policy)?;
     }
     for x in f.projection.iter_mut() {
-        tr_select_item(x, args_map)?;
+        tr_select_item(x, args_map, policy)?;
     }
 
     if !f.from.is_empty() {
         for x in f.from.iter_mut() {
-            tr_table_with_joins(x, args_map)?;
+            tr_table_with_joins(x, args_map, policy)?;
         }
     }
     if !f.lateral_views.is_empty() {
         for lv in f.lateral_views.iter_mut() {
-            tr_lateral_view(lv, args_map)?;
+            tr_lateral_view(lv, args_map, policy)?;
         }
     }
     if let Some(ref mut selection) = f.selection {
-        tr_expr(selection, args_map)?;
+        tr_expr(selection, args_map, policy)?;
     }
     if !f.group_by.is_empty() {
         for x in f.group_by.iter_mut() {
-            tr_expr(x, args_map)?;
+            tr_expr(x, args_map, policy)?;
         }
     }
     if !f.cluster_by.is_empty() {
         for x in f.cluster_by.iter_mut() {
-            tr_expr(x, args_map)?;
+            tr_expr(x, args_map, policy)?;
         }
     }
     if !f.distribute_by.is_empty() {
         for x in f.distribute_by.iter_mut() {
-            tr_expr(x, args_map)?;
+            tr_expr(x, args_map, policy)?;
         }
     }
     if !f.sort_by.is_empty() {
         for x in f.sort_by.iter_mut() {
-            tr_expr(x, args_map)?;
+            tr_expr(x, args_map, policy)?;
         }
     }
     if let Some(ref mut having) = f.having {
-        tr_expr(having, args_map)?;
+        tr_expr(having, args_map, policy)?;
     }
     if let Some(ref mut qualify) = f.qualify {
-        tr_expr(qualify, args_map)?;
+        tr_expr(qualify, args_map, policy)?;
     }
     Ok(())
 }
 
-fn tr_top(f: &mut Top, args_map: &mut Individual) -> io::Result<()> {
+fn tr_top(f: &mut Top, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
     if let Some(ref mut quantity) = f.quantity {
-        tr_expr(quantity, args_map)?;
+        tr_expr(quantity, args_map, policy)?;
     }
     Ok(())
 }
 
-fn tr_table_with_joins(f: &mut TableWithJoins, args_map: &mut Individual) -> io::Result<()> {
-    tr_table_factor(&mut f.relation, args_map)?;
+fn tr_table_with_joins(f: &mut TableWithJoins, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
+    tr_table_factor(&mut f.relation, args_map, policy)?;
     for join in f.joins.iter_mut() {
-        tr_join(join, args_map)?;
+        tr_join(join, args_map, policy)?;
     }
     Ok(())
 }
 
-fn tr_join_constraint(f: &mut JoinConstraint, args_map: &mut Individual) -> io::Result<()> {
+fn tr_join_constraint(f: &mut JoinConstraint, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
     if let JoinConstraint::On(ref mut expr) = f {
-        tr_expr(expr, args_map)?;
+        tr_expr(expr, args_map, policy)?;
     }
     Ok(())
 }
 
-fn tr_join(f: &mut Join, args_map: &mut Individual) -> io::Result<()> {
+fn tr_join(f: &mut Join, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
     match &mut f.join_operator {
         JoinOperator::Inner(ref mut constraint) => {
-            tr_table_factor(&mut f.relation, args_map)?;
-            tr_join_constraint(constraint, args_map)?;
+            tr_table_factor(&mut f.relation, args_map, policy)?;
+            tr_join_constraint(constraint, args_map, policy)?;
         },
         JoinOperator::LeftOuter(constraint) => {
-            tr_table_factor(&mut f.relation, args_map)?;
-            tr_join_constraint(constraint, args_map)?;
+            tr_table_factor(&mut f.relation, args_map, policy)?;
+            tr_join_constraint(constraint, args_map, policy)?;
         },
         JoinOperator::RightOuter(constraint) => {
-            tr_table_factor(&mut f.relation, args_map)?;
-            tr_join_constraint(constraint, args_map)?;
+            tr_table_factor(&mut f.relation, args_map, policy)?;
+            tr_join_constraint(constraint, args_map, policy)?;
         },
         JoinOperator::FullOuter(constraint) => {
-            tr_join_constraint(constraint, args_map)?;
-            tr_table_factor(&mut f.relation, args_map)?;
+            tr_join_constraint(constraint, args_map, policy)?;
+            tr_table_factor(&mut f.relation, args_map, policy)?;
         },
         JoinOperator::CrossJoin => {
-            tr_table_factor(&mut f.relation, args_map)?;
+            tr_table_factor(&mut f.relation, args_map, policy)?;
         },
         JoinOperator::CrossApply => {
-            tr_table_factor(&mut f.relation, args_map)?;
+            tr_table_factor(&mut f.relation, args_map, policy)?;
         },
         JoinOperator::OuterApply => {
-            tr_table_factor(&mut f.relation, args_map)?;
+            tr_table_factor(&mut f.relation, args_map, policy)?;
         },
     }
     Ok(())
 }
 
-fn tr_table_factor(f: &mut TableFactor, args_map: &mut Individual) -> io::Result<()> {
+fn tr_table_factor(f: &mut TableFactor, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
     match f {
-        &mut UNNEST {
+        UNNEST {
+            array_expr,
             ..
-        } => todo!(),
+        } => {
+            tr_expr(array_expr, args_map, policy)?;
+        },
         TableFactor::Table {
             name,
             alias: _,
             args,
             with_hints,
         } => {
-            if name.to_string().as_str() == "url" {
-                return Err(Error::new(ErrorKind::Unsupported, format!("Table [{}] forbidden", name)));
+            // unquoted, a forbidden table is found also under a database name or in backticks
+            let table = name.0.iter().map(|i| i.value.as_str()).collect::<Vec<&str>>().join(".");
+            let last = name.0.last().map(|i| i.value.as_str()).unwrap_or_default();
+            if is_forbidden(&policy.forbidden_tables, &table) || is_forbidden(&policy.forbidden_tables, last) {
+                return Err(Error::new(ErrorKind::Unsupported, SqlValidationError::ForbiddenTable(table)));
+            }
+            // a table function in FROM, e.g. remote(...)
+            if args.is_some() && (is_forbidden(&policy.forbidden_functions, &table) || is_forbidden(&policy.forbidden_functions, last)) {
+                return Err(Error::new(ErrorKind::Unsupported, SqlValidationError::ForbiddenFunction(table)));
             }
 
             if let Some(a) = args {
                 for x in a.iter_mut() {
-                    tr_function_arg(x, args_map)?;
+                    tr_function_arg(x, args_map, policy)?;
                 }
             }
 
             if !with_hints.is_empty() {
                 for x in with_hints.iter_mut() {
-                    tr_expr(x, args_map)?;
+                    tr_expr(x, args_map, policy)?;
                 }
             }
         },
@@ -529,84 +540,85 @@ fn tr_table_factor(f: &mut TableFactor, args_map: &mut Individual) -> io::Result
             subquery,
             alias: _,
         } => {
-            tr_query(subquery, args_map)?;
+            tr_query(subquery, args_map, policy)?;
         },
         TableFactor::TableFunction {
             expr,
             alias: _,
         } => {
-            tr_expr(expr, args_map)?;
+            tr_expr(expr, args_map, policy)?;
         },
         TableFactor::NestedJoin {
             table_with_joins,
             alias: _,
         } => {
-            tr_table_with_joins(table_with_joins, args_map)?;
+            tr_table_with_joins(table_with_joins, args_map, policy)?;
         },
     }
     Ok(())
 }
 
-fn tr_function_arg(f: &mut FunctionArg, args_map: &mut Individual) -> io::Result<()> {
+fn tr_function_arg(f: &mut FunctionArg, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
     match f {
         FunctionArg::Named {
             name: _,
             arg,
         } => {
-            tr_function_arg_expr(arg, args_map)?;
+            tr_function_arg_expr(arg, args_map, policy)?;
         },
         FunctionArg::Unnamed(unnamed_arg) => {
-            tr_function_arg_expr(unnamed_arg, args_map)?;
+            tr_function_arg_expr(unnamed_arg, args_map, policy)?;
         },
     }
     Ok(())
 }
 
-fn tr_function_arg_expr(f: &mut FunctionArgExpr, args_map: &mut Individual) -> io::Result<()> {
+fn tr_function_arg_expr(f: &mut FunctionArgExpr, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
     if let FunctionArgExpr::Expr(expr) = f {
-        tr_expr(expr, args_map)?;
+        tr_expr(expr, args_map, policy)?;
     }
     Ok(())
 }
 
-fn tr_function(f: &mut Function, args_map: &mut Individual) -> io::Result<()> {
-    match f.name.to_string().as_str() {
-        "sleep" | "url" => {
-            return Err(Error::new(ErrorKind::Unsupported, format!("Function [{}] forbidden", f.name)));
-        },
-        _ => {},
+fn tr_function(f: &mut Function, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
+    if is_forbidden(&policy.forbidden_functions, &f.name.to_string()) {
+        return Err(Error::new(ErrorKind::Unsupported, SqlValidationError::ForbiddenFunction(f.name.to_string())));
     }
 
     for x in f.args.iter_mut() {
-        tr_function_arg(x, args_map)?;
+        tr_function_arg(x, args_map, policy)?;
     }
 
     if let Some(ref mut o) = f.over {
-        tr_window_spec(o, args_map)?;
+        tr_window_spec(o, args_map, policy)?;
     }
     Ok(())
 }
 
-fn tr_window_spec(f: &mut WindowSpec, args_map: &mut Individual) -> io::Result<()> {
+fn tr_window_spec(f: &mut WindowSpec, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
     if !f.partition_by.is_empty() {
         for x in f.partition_by.iter_mut() {
-            tr_expr(x, args_map)?;
+            tr_expr(x, args_map, policy)?;
         }
     }
     if !f.order_by.is_empty() {
         for x in f.order_by.iter_mut() {
-            tr_order_by_expr(x, args_map)?;
+            tr_order_by_expr(x, args_map, policy)?;
         }
     }
 
     Ok(())
 }
 
-fn tr_lateral_view(f: &mut LateralView, args_map: &mut Individual) -> io::Result<()> {
-    tr_expr(&mut f.lateral_view, args_map)?;
+fn tr_lateral_view(f: &mut LateralView, args_map: &mut Individual, policy: &SqlPolicy) -> io::Result<()> {
+    tr_expr(&mut f.lateral_view, args_map, policy)?;
     Ok(())
 }
 
+fn is_forbidden(names: &[String], name: &str) -> bool {
+    names.iter().any(|x| x.eq_ignore_ascii_case(name))
+}
+
 fn resource_val_to_sql_val(ri: Option<&Resource>) -> io::Result<sqlparser::ast::Value> {
     if let Some(r) = ri {
         return match &r.value {
@@ -626,3 +638,71 @@ fn resource_val_to_sql_val(ri: Option<&Resource>) -> io::Result<sqlparser::ast::
     }
     Ok(sqlparser::ast::Value::Null)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlparser::dialect::{GenericDialect, MySqlDialect};
+    use sqlparser::parser::Parser;
+
+    #[test]
+    fn test_params_in_cte() {
+        let query = "WITH docs AS (SELECT id, '{label}' AS label FROM t WHERE author = '{author}'), \
+                     nested AS (WITH inner_docs AS (SELECT id FROM docs WHERE label <> '{label}') SELECT id FROM inner_docs) \
+                     SELECT id FROM docs WHERE id IN (SELECT id FROM nested) AND number > '{number}'";
+
+        let mut args = Individual::default();
+        args.set_uri("author", "d:author_1");
+        args.set_uri("label", "draft");
+        args.set_integer("number", 7);
+
+        let mut ast = Parser::parse_sql(&MySqlDialect {}, query).unwrap();
+        tr_statement(&mut ast[0], &mut args, &SqlPolicy::default()).unwrap();
+        let sql = ast[0].to_string();
+
+        assert!(!sql.contains('{'), "sql={}", sql);
+        assert!(sql.contains("SELECT id, 'draft' AS label FROM t WHERE author = 'd:author_1'"), "sql={}", sql);
+        assert!(sql.contains("label <> 'draft'"), "sql={}", sql);
+        assert!(sql.contains("number > 7"), "sql={}", sql);
+    }
+
+    #[test]
+    fn test_params_in_unnest() {
+        let query = "SELECT x FROM UNNEST(['{id1}', '{id2}']) AS x WHERE x <> '{skip}'";
+
+        let mut args = Individual::default();
+        args.set_uri("id1", "d:doc_1");
+        args.set_uri("id2", "d:doc_2");
+        args.set_uri("skip", "d:doc_3");
+
+        let mut ast = Parser::parse_sql(&GenericDialect {}, query).unwrap();
+        tr_statement(&mut ast[0], &mut args, &SqlPolicy::default()).unwrap();
+
+        assert_eq!(ast[0].to_string(), "SELECT x FROM UNNEST(['d:doc_1', 'd:doc_2']) AS x WHERE x <> 'd:doc_3'");
+    }
+
+    #[test]
+    fn test_custom_forbidden_list() {
+        let policy = SqlPolicy {
+            forbidden_functions: vec!["remote".to_owned(), "file".to_owned()],
+            forbidden_tables: vec!["system.users".to_owned()],
+            ..SqlPolicy::default()
+        };
+        let tr = |query: &str, policy: &SqlPolicy| {
+            let mut args = Individual::default();
+            args.set_uri("author", "d:author_1");
+            let mut ast = Parser::parse_sql(&MySqlDialect {}, query).unwrap();
+            tr_statement(&mut ast[0], &mut args, policy).map_err(|e| (e.kind(), e.to_string()))
+        };
+
+        let err = tr("SELECT id FROM remote('ch2:9000', 'veda_tt', 'docs') WHERE author = '{author}'", &policy).unwrap_err();
+        assert_eq!(err, (ErrorKind::Unsupported, "Function [remote] forbidden".to_owned()));
+        assert_eq!(tr("SELECT REMOTE(1)", &policy).unwrap_err(), (ErrorKind::Unsupported, "Function [REMOTE] forbidden".to_owned()));
+        assert_eq!(tr("SELECT name FROM system.users", &policy).unwrap_err(), (ErrorKind::Unsupported, "Table [system.users] forbidden".to_owned()));
+
+        // the custom list replaces the default one
+        assert!(tr("SELECT SLEEP(3)", &policy).is_ok());
+        assert_eq!(tr("SELECT SLEEP(3)", &SqlPolicy::default()).unwrap_err(), (ErrorKind::Unsupported, "Function [SLEEP] forbidden".to_owned()));
+        assert!(tr("SELECT id FROM remote('ch2:9000', 'veda_tt', 'docs')", &SqlPolicy::default()).is_ok());
+    }
+}
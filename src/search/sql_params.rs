@@ -1,14 +1,148 @@
 use crate::onto::individual::Individual;
-use crate::onto::resource::Value;
+use crate::onto::resource::{Resource, Value};
 use crate::search::sql_lex_tree::tr_statement;
+use crate::search::sql_validator::{check_forbidden_names, SqlPolicy};
 use klickhouse::query_parser::parse_query_arguments;
 use regex::Regex;
 use sqlparser::dialect::AnsiDialect;
+use sqlparser::dialect::ClickHouseDialect;
 use sqlparser::dialect::MySqlDialect;
-use sqlparser::parser::Parser;
+use sqlparser::ast::Statement;
+use sqlparser::parser::{Parser, ParserError};
+use std::collections::{HashMap, VecDeque};
 use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+const STATEMENT_CACHE_SIZE: usize = 256;
+
+// Parsed query templates keyed by dialect and query text, the oldest template is evicted when the cache is full
+struct StatementCache {
+    statements: HashMap<(String, String), Statement>,
+    order: VecDeque<(String, String)>,
+}
+
+lazy_static! {
+    static ref STATEMENT_CACHE: Mutex<StatementCache> = Mutex::new(StatementCache {
+        statements: HashMap::new(),
+        order: VecDeque::new(),
+    });
+}
+
+static SQL_PARSE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// Returns a copy of the first statement of the query, substitution of params changes the copy and never the cached tree
+fn parse_statement(query: &str, dialect: &str) -> Result<Option<Statement>, ParserError> {
+    let key = (dialect.to_owned(), query.to_owned());
+
+    if let Some(st) = STATEMENT_CACHE.lock().unwrap().statements.get(&key) {
+        return Ok(Some(st.clone()));
+    }
+
+    SQL_PARSE_COUNT.fetch_add(1, Ordering::Relaxed);
+    let ast = match dialect {
+        "mysql" => Parser::parse_sql(&MySqlDialect {}, query)?,
+        _ => Parser::parse_sql(&AnsiDialect {}, query)?,
+    };
+
+    let st = if let Some(st) = ast.into_iter().next() {
+        st
+    } else {
+        return Ok(None);
+    };
+
+    let mut cache = STATEMENT_CACHE.lock().unwrap();
+    if !cache.statements.contains_key(&key) {
+        if cache.order.len() >= STATEMENT_CACHE_SIZE {
+            if let Some(old) = cache.order.pop_front() {
+                cache.statements.remove(&old);
+            }
+        }
+        cache.order.push_back(key.clone());
+        cache.statements.insert(key, st.clone());
+    }
+
+    Ok(Some(st))
+}
+
+pub const REDACTED_VALUE: &str = "***";
+
+// The executed clickhouse sql with every literal equal to a value of a secret param replaced with '***': the secret values are
+// rendered as the substitution renders them and compared with whole quoted strings and bare words of the sql, so a literal
+// of the template equal to a secret is hidden as well; the sign of a negative number is kept
+pub fn redact_sql(sql: &str, params: &Individual, secret_params: &[&str]) -> String {
+    let secrets: Vec<String> = secret_params
+        .iter()
+        .filter_map(|name| params.obj.resources.get(*name))
+        .flatten()
+        .filter_map(|r| to_clickhouse_value(r).ok())
+        .map(|v| parse_query_arguments("$1", &[v]).trim_start_matches('-').to_owned())
+        .collect();
+    if secrets.is_empty() {
+        return sql.to_owned();
+    }
+
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_' || c == '.';
+    let mut out = String::with_capacity(sql.len());
+    let mut chars = sql.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let mut end = sql.len();
+        if c == '\'' {
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        chars.next();
+                    },
+                    '\'' if chars.peek().map(|(_, c)| *c) == Some('\'') => {
+                        chars.next();
+                    },
+                    '\'' => {
+                        end = i + 1;
+                        break;
+                    },
+                    _ => {},
+                }
+            }
+        } else if is_word_char(c) {
+            while let Some(&(i, c)) = chars.peek() {
+                if !is_word_char(c) {
+                    end = i;
+                    break;
+                }
+                chars.next();
+            }
+        } else {
+            out.push(c);
+            continue;
+        }
+
+        let token = &sql[start..end];
+        if secrets.iter().any(|s| s == token) {
+            out.push_str(&format!("'{}'", REDACTED_VALUE));
+        } else {
+            out.push_str(token);
+        }
+    }
+    out
+}
+
+fn to_clickhouse_value(r: &Resource) -> Result<klickhouse::Value, Error> {
+    match &r.value {
+        Value::Uri(v) | Value::Str(v, _) => Ok(klickhouse::Value::string(v)),
+        Value::Int(v) => Ok(klickhouse::Value::Int64(*v)),
+        Value::Bool(v) => Ok(klickhouse::Value::UInt8(*v as u8)),
+        Value::Num(_m, _d) => Ok(klickhouse::Value::Float64(r.get_float())),
+        Value::Datetime(v) => Ok(klickhouse::Value::DateTime(klickhouse::DateTime(klickhouse::Tz::UTC, *v as u32))),
+        _ => Err(Error::new(ErrorKind::Other, format!("Unsupported value type {:?}", r.value))),
+    }
+}
 
 pub fn parse_sql_query_arguments(query: &str, params: &mut Individual, dialect: &str) -> Result<String, Error> {
+    parse_sql_query_arguments_with_policy(query, params, dialect, &SqlPolicy::default())
+}
+
+// Functions and tables of the policy are rejected with ErrorKind::Unsupported while the parsed query is walked
+pub fn parse_sql_query_arguments_with_policy(query: &str, params: &mut Individual, dialect: &str, policy: &SqlPolicy) -> Result<String, Error> {
     match dialect {
         "clickhouse" => {
             let re = Regex::new(r"(\{[^\}]+\}|'\{[^\}]+\}')").unwrap();
@@ -31,15 +165,7 @@ pub fn parse_sql_query_arguments(query: &str, params: &mut Individual, dialect:
             for arg_name in &arg_names {
                 //info!("@arg_name={}", arg_name);
                 if let Some(res) = params.obj.resources.get(arg_name) {
-                    let arg_value = match &res[0].value {
-                        Value::Uri(v) | Value::Str(v, _) => klickhouse::Value::string(v),
-                        Value::Int(v) => klickhouse::Value::Int64(*v),
-                        Value::Bool(v) => klickhouse::Value::UInt8(*v as u8),
-                        Value::Num(_m, _d) => klickhouse::Value::Float64(res[0].get_float()),
-                        Value::Datetime(v) => klickhouse::Value::DateTime(klickhouse::DateTime(klickhouse::Tz::UTC, *v as u32)),
-                        _ => return Err(Error::new(ErrorKind::Other, format!("Unsupported value type {:?}", res[0].value))),
-                    };
-                    arg_values.push(arg_value);
+                    arg_values.push(to_clickhouse_value(&res[0])?);
                 } else {
                     return Err(Error::new(ErrorKind::Other, format!("Variable {} not found in params", arg_name)));
                 }
@@ -48,25 +174,22 @@ pub fn parse_sql_query_arguments(query: &str, params: &mut Individual, dialect:
             let res_query = parse_query_arguments(&res_query, &arg_values);
             //info!("@res_query={:?}", res_query);
 
+            // substituted values are quoted literals, the names are checked in the query that is going to be sent
+            check_forbidden_names(&res_query, &ClickHouseDialect {}, policy).map_err(|e| Error::new(ErrorKind::Unsupported, e.to_string()))?;
+
             return Ok(res_query);
         },
         "mysql" | _ => {
-            let lex_tree = match dialect {
-                "mysql" => Parser::parse_sql(&MySqlDialect {}, query),
-                _ => Parser::parse_sql(&AnsiDialect {}, query),
-            };
-
-            match lex_tree {
-                Ok(mut ast) => {
-                    if let Some(el) = ast.iter_mut().next() {
-                        tr_statement(el, params)?;
-                        debug!("NEW: {}", el);
-                        return match dialect {
-                            "mysql" => Ok(el.to_string()),
-                            _ => Err(Error::new(ErrorKind::Other, "unknown SQL dialect")),
-                        };
-                    }
+            match parse_statement(query, dialect) {
+                Ok(Some(mut el)) => {
+                    tr_statement(&mut el, params, policy)?;
+                    debug!("NEW: {}", el);
+                    return match dialect {
+                        "mysql" => Ok(el.to_string()),
+                        _ => Err(Error::new(ErrorKind::Other, "unknown SQL dialect")),
+                    };
                 },
+                Ok(None) => {},
                 Err(e) => {
                     error!("{:?}", e);
                 },
@@ -75,3 +198,66 @@ pub fn parse_sql_query_arguments(query: &str, params: &mut Individual, dialect:
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onto::datatype::Lang;
+    use crate::onto::individual::Individual;
+
+    #[test]
+    fn test_template_parsed_once() {
+        let query = "SELECT id FROM `v-s:Document` WHERE `v-s:author` = '{author}' AND `v-s:number` = '{number}'";
+
+        let parse_count = SQL_PARSE_COUNT.load(Ordering::Relaxed);
+
+        let mut results = vec![];
+        for n in 0..3 {
+            let mut params = Individual::default();
+            params.set_uri("author", &format!("d:author_{}", n));
+            params.set_integer("number", n);
+            results.push(parse_sql_query_arguments(query, &mut params, "mysql").unwrap());
+        }
+
+        assert_eq!(SQL_PARSE_COUNT.load(Ordering::Relaxed) - parse_count, 1);
+        assert!(results[0].contains("'d:author_0'") && results[0].contains("= 0"));
+        assert!(results[2].contains("'d:author_2'") && results[2].contains("= 2"));
+        assert!(!results[2].contains("d:author_0"));
+    }
+
+    #[test]
+    fn test_redact_secret_params() {
+        let query = "SELECT id FROM veda_tt.users WHERE login = {login} AND token = {token} AND pin = {pin} AND note != 's3cr3tx' AND n > 7-{pin}";
+
+        let mut params = Individual::default();
+        params.set_string("login", "user1", Lang::none());
+        params.set_string("token", "s3'cr3t", Lang::none());
+        params.set_integer("pin", 7);
+
+        let sql = parse_sql_query_arguments(query, &mut params, "clickhouse").unwrap();
+        assert!(sql.contains("'user1'") && sql.contains("'s3\\'cr3t'") && sql.contains("pin = 7"), "sql={}", sql);
+
+        let audit_sql = redact_sql(&sql, &params, &["token", "pin"]);
+        assert_eq!(audit_sql, "SELECT id FROM veda_tt.users WHERE login = 'user1' AND token = '***' AND pin = '***' AND note != 's3cr3tx' AND n > '***'-'***'");
+        assert_eq!(redact_sql(&sql, &params, &[]), sql);
+        assert_eq!(redact_sql(&sql, &params, &["unknown"]), sql);
+    }
+
+    #[test]
+    fn test_clickhouse_policy() {
+        let query = "SELECT id FROM remote('ch2:9000', 'veda_tt', 'docs') WHERE author = {author}";
+        let mut params = Individual::default();
+        params.set_uri("author", "d:author_1");
+
+        let policy = SqlPolicy {
+            forbidden_functions: vec!["remote".to_owned(), "s3".to_owned(), "file".to_owned()],
+            ..SqlPolicy::default()
+        };
+        let err = parse_sql_query_arguments_with_policy(query, &mut params, "clickhouse", &policy).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        assert_eq!(err.to_string(), "Function [remote] forbidden");
+
+        let sql = parse_sql_query_arguments(query, &mut params, "clickhouse").unwrap();
+        assert!(sql.contains("'d:author_1'"), "sql={}", sql);
+    }
+}
@@ -0,0 +1,203 @@
+use crate::onto::individual::Individual;
+use crate::search::sql_lex_tree::tr_statement;
+use sqlparser::ast::Statement;
+use sqlparser::dialect::{Dialect, MySqlDialect};
+use sqlparser::keywords::Keyword;
+use sqlparser::parser::Parser;
+use sqlparser::tokenizer::{Token, Tokenizer, Whitespace};
+use std::fmt;
+
+pub(crate) const FORBIDDEN_FUNCTIONS: &[&str] = &["sleep", "url"];
+pub(crate) const FORBIDDEN_TABLES: &[&str] = &["url"];
+
+// Safety rules of user queries; the default is the policy applied by parse_sql_query_arguments
+#[derive(Debug, Clone)]
+pub struct SqlPolicy {
+    pub forbidden_functions: Vec<String>,
+    pub forbidden_tables: Vec<String>,
+    pub allow_multiple_statements: bool,
+    pub allow_comments: bool,
+    pub read_only: bool,
+}
+
+impl Default for SqlPolicy {
+    fn default() -> Self {
+        SqlPolicy {
+            forbidden_functions: FORBIDDEN_FUNCTIONS.iter().map(|s| s.to_string()).collect(),
+            forbidden_tables: FORBIDDEN_TABLES.iter().map(|s| s.to_string()).collect(),
+            allow_multiple_statements: false,
+            allow_comments: false,
+            read_only: true,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SqlValidationError {
+    Parse(String),
+    Empty,
+    MultipleStatements(usize),
+    NotReadOnly(String),
+    Comment,
+    ForbiddenFunction(String),
+    ForbiddenTable(String),
+}
+
+impl fmt::Display for SqlValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SqlValidationError::Parse(e) => write!(f, "fail parse query: {}", e),
+            SqlValidationError::Empty => write!(f, "query is empty"),
+            SqlValidationError::MultipleStatements(n) => write!(f, "only one statement allowed, found {}", n),
+            SqlValidationError::NotReadOnly(s) => write!(f, "only SELECT allowed, found [{}]", s),
+            SqlValidationError::Comment => write!(f, "comments are not allowed"),
+            SqlValidationError::ForbiddenFunction(name) => write!(f, "Function [{}] forbidden", name),
+            SqlValidationError::ForbiddenTable(name) => write!(f, "Table [{}] forbidden", name),
+        }
+    }
+}
+
+impl std::error::Error for SqlValidationError {}
+
+// Runs only the safety checks over the query, parameters are not substituted
+pub fn validate_sql(query: &str, policy: &SqlPolicy) -> Result<(), SqlValidationError> {
+    let dialect = MySqlDialect {};
+
+    let tokens = Tokenizer::new(&dialect, query).tokenize().map_err(|e| SqlValidationError::Parse(e.message))?;
+    let statements = Parser::parse_sql(&dialect, query).map_err(|e| SqlValidationError::Parse(e.to_string()))?;
+
+    if statements.is_empty() {
+        return Err(SqlValidationError::Empty);
+    }
+    if statements.len() > 1 && !policy.allow_multiple_statements {
+        return Err(SqlValidationError::MultipleStatements(statements.len()));
+    }
+    if policy.read_only {
+        if let Some(st) = statements.iter().find(|st| !matches!(st, Statement::Query(_))) {
+            return Err(SqlValidationError::NotReadOnly(st.to_string().split_whitespace().next().unwrap_or_default().to_owned()));
+        }
+    }
+
+    let has_comment =
+        tokens.iter().any(|t| matches!(t, Token::Whitespace(Whitespace::SingleLineComment { .. }) | Token::Whitespace(Whitespace::MultiLineComment(_))));
+    if has_comment && !policy.allow_comments {
+        return Err(SqlValidationError::Comment);
+    }
+
+    check_parsed_names(&statements, tokens, policy)
+}
+
+// Only the functions and tables of the policy are checked, so the query of any dialect is accepted while the tokenizer reads it,
+// e.g. a ClickHouse query that the parser does not support
+pub fn check_forbidden_names(query: &str, dialect: &dyn Dialect, policy: &SqlPolicy) -> Result<(), SqlValidationError> {
+    let tokens = Tokenizer::new(dialect, query).tokenize().map_err(|e| SqlValidationError::Parse(e.message))?;
+    match Parser::parse_sql(dialect, query) {
+        Ok(statements) => check_parsed_names(&statements, tokens, policy),
+        Err(_) => scan_forbidden_names(tokens, policy),
+    }
+}
+
+// The names of a query are found by walking its tree the same way the parameters are substituted, so every table of
+// a FROM list or of a subquery is checked; other statements are left to the token scan
+fn check_parsed_names(statements: &[Statement], tokens: Vec<Token>, policy: &SqlPolicy) -> Result<(), SqlValidationError> {
+    for st in statements.iter().filter(|st| matches!(st, Statement::Query(_))) {
+        if let Err(e) = tr_statement(&mut st.clone(), &mut Individual::default(), policy) {
+            let msg = e.to_string();
+            return Err(match e.into_inner().map(|e| e.downcast::<SqlValidationError>()) {
+                Some(Ok(e)) => *e,
+                _ => SqlValidationError::Parse(msg),
+            });
+        }
+    }
+
+    if statements.iter().all(|st| matches!(st, Statement::Query(_))) {
+        Ok(())
+    } else {
+        scan_forbidden_names(tokens, policy)
+    }
+}
+
+fn scan_forbidden_names(tokens: Vec<Token>, policy: &SqlPolicy) -> Result<(), SqlValidationError> {
+    let tokens: Vec<Token> = tokens.into_iter().filter(|t| !matches!(t, Token::Whitespace(_))).collect();
+
+    let is_forbidden = |list: &[String], name: &str| list.iter().any(|f| f.eq_ignore_ascii_case(name));
+
+    for (idx, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Word(w) if w.keyword == Keyword::FROM || w.keyword == Keyword::JOIN => {
+                let mut name = vec![];
+                let mut pos = idx + 1;
+                while let Some(Token::Word(part)) = tokens.get(pos) {
+                    name.push(part.value.as_str());
+                    if tokens.get(pos + 1) != Some(&Token::Period) {
+                        break;
+                    }
+                    pos += 2;
+                }
+                if let Some(last) = name.last() {
+                    let full = name.join(".");
+                    if is_forbidden(&policy.forbidden_tables, last) || is_forbidden(&policy.forbidden_tables, &full) {
+                        return Err(SqlValidationError::ForbiddenTable(full));
+                    }
+                }
+            },
+            Token::Word(w) if tokens.get(idx + 1) == Some(&Token::LParen) => {
+                if is_forbidden(&policy.forbidden_functions, &w.value) {
+                    return Err(SqlValidationError::ForbiddenFunction(w.value.to_owned()));
+                }
+            },
+            _ => {},
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlparser::dialect::ClickHouseDialect;
+
+    #[test]
+    fn test_validate_sql() {
+        let policy = SqlPolicy::default();
+
+        assert_eq!(validate_sql("SELECT id, count(*) FROM veda_tt.`v-s:Document` WHERE `v-s:author` = '{author}' GROUP BY id", &policy), Ok(()));
+
+        assert!(matches!(validate_sql("SELECT id FROM", &policy), Err(SqlValidationError::Parse(_))));
+        assert_eq!(validate_sql("SELECT 1; SELECT 2", &policy), Err(SqlValidationError::MultipleStatements(2)));
+        assert_eq!(validate_sql("DROP TABLE veda_tt.docs", &policy), Err(SqlValidationError::NotReadOnly("DROP".to_owned())));
+        assert_eq!(validate_sql("SELECT id FROM docs -- WHERE deleted = 0", &policy), Err(SqlValidationError::Comment));
+        assert_eq!(validate_sql("SELECT id FROM docs /* hint */", &policy), Err(SqlValidationError::Comment));
+        assert_eq!(validate_sql("SELECT SLEEP(3)", &policy), Err(SqlValidationError::ForbiddenFunction("SLEEP".to_owned())));
+        let url_forbidden = Err(SqlValidationError::ForbiddenTable("url".to_owned()));
+        assert_eq!(validate_sql("SELECT id FROM url", &policy), url_forbidden);
+        assert_eq!(validate_sql("SELECT a.id FROM docs a, url", &policy), url_forbidden);
+        assert_eq!(validate_sql("SELECT id FROM docs JOIN `url` ON 1 = 1", &policy), url_forbidden);
+        assert_eq!(validate_sql("SELECT id FROM docs WHERE id IN (SELECT id FROM (SELECT id FROM url))", &policy), url_forbidden);
+
+        let custom = SqlPolicy {
+            forbidden_tables: vec!["system.users".to_owned()],
+            allow_comments: true,
+            ..SqlPolicy::default()
+        };
+        assert_eq!(validate_sql("SELECT name FROM system.users", &custom), Err(SqlValidationError::ForbiddenTable("system.users".to_owned())));
+        assert_eq!(validate_sql("SELECT id FROM docs -- comment", &custom), Ok(()));
+    }
+
+    #[test]
+    fn test_check_forbidden_names_of_clickhouse_query() {
+        let policy = SqlPolicy {
+            forbidden_functions: vec!["remote".to_owned(), "s3".to_owned()],
+            ..SqlPolicy::default()
+        };
+        let dialect = ClickHouseDialect {};
+
+        let query = "SELECT id FROM remote('ch2:9000', 'veda_tt', 'docs') ARRAY JOIN tags AS tag SETTINGS max_threads = 1";
+        assert_eq!(check_forbidden_names(query, &dialect, &policy), Err(SqlValidationError::ForbiddenFunction("remote".to_owned())));
+        assert_eq!(check_forbidden_names(query, &dialect, &SqlPolicy::default()), Ok(()));
+        assert_eq!(check_forbidden_names("SELECT id FROM docs -- remote(x)", &dialect, &policy), Ok(()));
+        let url_forbidden = Err(SqlValidationError::ForbiddenTable("url".to_owned()));
+        assert_eq!(check_forbidden_names("SELECT id FROM docs, url", &dialect, &SqlPolicy::default()), url_forbidden);
+    }
+}
@@ -1,7 +1,10 @@
 use crate::az_impl::az_lmdb::LmdbAzContext;
 use crate::onto::individual::Individual;
 use crate::onto::parser::parse_raw;
-use crate::storage::common::{Storage, StorageId};
+#[cfg(feature = "lmdb_storage")]
+use crate::storage::common::Storage;
+use crate::storage::common::StorageId;
+#[cfg(feature = "lmdb_storage")]
 use crate::storage::lmdb_storage::LMDBStorage;
 use crate::v_api::obj::ResultCode;
 use crate::v_authorization::common::{Access, AuthorizationContext, Trace};
@@ -16,6 +19,7 @@ pub const TICKETS_SPACE_ID: i32 = 513;
 
 pub struct AStorage {
     pub tt: Option<Client>,
+    #[cfg(feature = "lmdb_storage")]
     pub lmdb: Option<Mutex<LMDBStorage>>,
 }
 
@@ -86,6 +90,7 @@ pub async fn get_individual_use_storage_id(
         }
         return Ok((iraw, ResultCode::UnprocessableEntity));
     }
+    #[cfg(feature = "lmdb_storage")]
     if let Some(lmdb) = &db.lmdb {
         let mut iraw = Individual::default();
         let res = lmdb.lock().await.get_individual_from_db(storage_id, uri, &mut iraw);
@@ -1,9 +1,12 @@
 use crate::onto::individual::Individual;
+#[cfg(feature = "lmdb_storage")]
 use crate::storage::lmdb_storage::LMDBStorage;
 use crate::storage::memory_storage::MemoryStorage;
 use crate::storage::remote_storage_client::StorageROClient;
+#[cfg(any(feature = "tokio_0_2", feature = "tokio_1"))]
 use crate::storage::tt_storage::TTStorage;
 use crate::v_api::obj::ResultCode;
+use std::sync::Arc;
 
 #[derive(Eq, PartialEq, Debug, Clone)]
 pub enum StorageMode {
@@ -18,26 +21,54 @@ pub enum StorageId {
     Az,
 }
 
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum WriteKind {
+    Put,
+    Remove,
+}
+
+// Called after a successful write to the storage
+pub type WriteObserver = Arc<dyn Fn(StorageId, &str, WriteKind) + Send + Sync>;
+
 pub trait Storage {
     fn get_individual_from_db(&mut self, storage: StorageId, id: &str, iraw: &mut Individual) -> ResultCode;
+    // Results in the order of ids; LMDB reads all of them in one transaction
+    fn get_individuals_from_db(&mut self, storage: StorageId, ids: &[&str]) -> Vec<(String, ResultCode, Individual)> {
+        ids.iter()
+            .map(|id| {
+                let mut iraw = Individual::default();
+                let res = self.get_individual_from_db(storage.clone(), id, &mut iraw);
+                (id.to_string(), res, iraw)
+            })
+            .collect()
+    }
     fn get_v(&mut self, storage: StorageId, key: &str) -> Option<String>;
     fn get_raw(&mut self, storage: StorageId, key: &str) -> Vec<u8>;
     fn put_kv(&mut self, storage: StorageId, key: &str, val: &str) -> bool;
     fn put_kv_raw(&mut self, storage: StorageId, key: &str, val: Vec<u8>) -> bool;
     fn remove(&mut self, storage: StorageId, key: &str) -> bool;
     fn count(&mut self, storage: StorageId) -> usize;
+    // Keys starting with the prefix together with their values in key order; None if the backend can not enumerate
+    // keys (Tarantool, remote). LMDB and memory read all the matching pairs into memory before the first one is returned
+    fn iter_prefix(&mut self, _storage: StorageId, _prefix: &str) -> Option<Box<dyn Iterator<Item = (String, Vec<u8>)>>> {
+        None
+    }
 }
 
 pub(crate) enum EStorage {
+    #[cfg(feature = "lmdb_storage")]
     Lmdb(LMDBStorage),
+    #[cfg(any(feature = "tokio_0_2", feature = "tokio_1"))]
     Tt(TTStorage),
     Remote(StorageROClient),
     Memory(MemoryStorage),
+    Custom(Box<dyn Storage + Send>),
     None,
 }
 
 pub struct VStorage {
     storage: EStorage,
+    on_write: Option<WriteObserver>,
 }
 
 impl VStorage {
@@ -48,6 +79,7 @@ impl VStorage {
     pub fn none() -> VStorage {
         VStorage {
             storage: EStorage::None,
+            on_write: None,
         }
     }
 
@@ -55,20 +87,32 @@ impl VStorage {
         info!("Trying to connect to [remote], addr: {}", addr);
         VStorage {
             storage: EStorage::Remote(StorageROClient::new(addr)),
+            on_write: None,
         }
     }
 
+    #[cfg(any(feature = "tokio_0_2", feature = "tokio_1"))]
     pub fn new_tt(tt_uri: String, login: &str, pass: &str) -> VStorage {
         info!("Trying to connect to [Tarantool], addr: {}", tt_uri);
         VStorage {
             storage: EStorage::Tt(TTStorage::new(tt_uri, login, pass)),
+            on_write: None,
         }
     }
 
+    // The Tarantool client needs the tokio runtime, without it there is no storage
+    #[cfg(not(any(feature = "tokio_0_2", feature = "tokio_1")))]
+    pub fn new_tt(tt_uri: String, _login: &str, _pass: &str) -> VStorage {
+        error!("fail connect to [Tarantool], addr: {}, the crate is built without tokio_0_2 or tokio_1 feature", tt_uri);
+        VStorage::none()
+    }
+
+    #[cfg(feature = "lmdb_storage")]
     pub fn new_lmdb(db_path: &str, mode: StorageMode, max_read_counter_reopen: Option<u64>) -> VStorage {
         info!("Trying to connect to [LMDB], path: {}", db_path);
         VStorage {
             storage: EStorage::Lmdb(LMDBStorage::new(db_path, mode, max_read_counter_reopen)),
+            on_write: None,
         }
     }
 
@@ -76,85 +120,180 @@ impl VStorage {
         info!("Creating in-memory storage");
         VStorage {
             storage: EStorage::Memory(MemoryStorage::new()),
+            on_write: None,
+        }
+    }
+
+    // Storage given by the caller, e.g. a stub in tests
+    pub fn new_custom(storage: Box<dyn Storage + Send>) -> VStorage {
+        VStorage {
+            storage: EStorage::Custom(storage),
+            on_write: None,
+        }
+    }
+
+    // Observes put_kv, put_kv_raw and remove made through this VStorage, None (the default) turns it off
+    pub fn set_on_write(&mut self, on_write: Option<WriteObserver>) {
+        self.on_write = on_write;
+    }
+
+    fn notify_write(&self, storage: StorageId, key: &str, kind: WriteKind) {
+        if let Some(on_write) = &self.on_write {
+            on_write(storage, key, kind);
         }
     }
 
     pub fn get_individual(&mut self, id: &str, iraw: &mut Individual) -> ResultCode {
         match &mut self.storage {
+            #[cfg(any(feature = "tokio_0_2", feature = "tokio_1"))]
             EStorage::Tt(s) => s.get_individual_from_db(StorageId::Individuals, id, iraw),
+            #[cfg(feature = "lmdb_storage")]
             EStorage::Lmdb(s) => s.get_individual_from_db(StorageId::Individuals, id, iraw),
             EStorage::Remote(s) => s.get_individual_from_db(StorageId::Individuals, id, iraw),
             EStorage::Memory(s) => s.get_individual_from_db(StorageId::Individuals, id, iraw),
+            EStorage::Custom(s) => s.get_individual_from_db(StorageId::Individuals, id, iraw),
             _ => ResultCode::NotReady,
         }
     }
 
     pub fn get_individual_from_db(&mut self, storage: StorageId, id: &str, iraw: &mut Individual) -> ResultCode {
         match &mut self.storage {
+            #[cfg(any(feature = "tokio_0_2", feature = "tokio_1"))]
             EStorage::Tt(s) => s.get_individual_from_db(storage, id, iraw),
+            #[cfg(feature = "lmdb_storage")]
             EStorage::Lmdb(s) => s.get_individual_from_db(storage, id, iraw),
             EStorage::Remote(s) => s.get_individual_from_db(storage, id, iraw),
             EStorage::Memory(s) => s.get_individual_from_db(storage, id, iraw),
+            EStorage::Custom(s) => s.get_individual_from_db(storage, id, iraw),
             _ => ResultCode::NotReady,
         }
     }
 
+    pub fn get_individuals_from_db(&mut self, storage: StorageId, ids: &[&str]) -> Vec<(String, ResultCode, Individual)> {
+        match &mut self.storage {
+            #[cfg(any(feature = "tokio_0_2", feature = "tokio_1"))]
+            EStorage::Tt(s) => s.get_individuals_from_db(storage, ids),
+            #[cfg(feature = "lmdb_storage")]
+            EStorage::Lmdb(s) => s.get_individuals_from_db(storage, ids),
+            EStorage::Remote(s) => ids
+                .iter()
+                .map(|id| {
+                    let mut iraw = Individual::default();
+                    let res = s.get_individual_from_db(storage.clone(), id, &mut iraw);
+                    (id.to_string(), res, iraw)
+                })
+                .collect(),
+            EStorage::Memory(s) => s.get_individuals_from_db(storage, ids),
+            EStorage::Custom(s) => s.get_individuals_from_db(storage, ids),
+            _ => ids.iter().map(|id| (id.to_string(), ResultCode::NotReady, Individual::default())).collect(),
+        }
+    }
+
     pub fn get_value(&mut self, storage: StorageId, id: &str) -> Option<String> {
         match &mut self.storage {
+            #[cfg(any(feature = "tokio_0_2", feature = "tokio_1"))]
             EStorage::Tt(s) => s.get_v(storage, id),
+            #[cfg(feature = "lmdb_storage")]
             EStorage::Lmdb(s) => s.get_v(storage, id),
             EStorage::Remote(_s) => None,
             EStorage::Memory(s) => s.get_v(storage, id),
+            EStorage::Custom(s) => s.get_v(storage, id),
             _ => None,
         }
     }
 
     pub fn get_raw_value(&mut self, storage: StorageId, id: &str) -> Vec<u8> {
         match &mut self.storage {
+            #[cfg(any(feature = "tokio_0_2", feature = "tokio_1"))]
             EStorage::Tt(s) => s.get_raw(storage, id),
+            #[cfg(feature = "lmdb_storage")]
             EStorage::Lmdb(s) => s.get_raw(storage, id),
             EStorage::Remote(_s) => Default::default(),
             EStorage::Memory(s) => s.get_raw(storage, id),
+            EStorage::Custom(s) => s.get_raw(storage, id),
             _ => Default::default(),
         }
     }
 
     pub fn put_kv(&mut self, storage: StorageId, key: &str, val: &str) -> bool {
-        match &mut self.storage {
+        let storage_id = storage.clone();
+        let res = match &mut self.storage {
+            #[cfg(any(feature = "tokio_0_2", feature = "tokio_1"))]
             EStorage::Tt(s) => s.put_kv(storage, key, val),
+            #[cfg(feature = "lmdb_storage")]
             EStorage::Lmdb(s) => s.put_kv(storage, key, val),
             EStorage::Remote(_s) => false,
             EStorage::Memory(s) => s.put_kv(storage, key, val),
+            EStorage::Custom(s) => s.put_kv(storage, key, val),
             _ => false,
+        };
+
+        if res {
+            self.notify_write(storage_id, key, WriteKind::Put);
         }
+        res
     }
 
     pub fn put_kv_raw(&mut self, storage: StorageId, key: &str, val: Vec<u8>) -> bool {
-        match &mut self.storage {
+        let storage_id = storage.clone();
+        let res = match &mut self.storage {
+            #[cfg(any(feature = "tokio_0_2", feature = "tokio_1"))]
             EStorage::Tt(s) => s.put_kv_raw(storage, key, val),
+            #[cfg(feature = "lmdb_storage")]
             EStorage::Lmdb(s) => s.put_kv_raw(storage, key, val),
             EStorage::Remote(_s) => false,
             EStorage::Memory(s) => s.put_kv_raw(storage, key, val),
+            EStorage::Custom(s) => s.put_kv_raw(storage, key, val),
             _ => false,
+        };
+
+        if res {
+            self.notify_write(storage_id, key, WriteKind::Put);
         }
+        res
     }
 
     pub fn remove(&mut self, storage: StorageId, key: &str) -> bool {
-        match &mut self.storage {
+        let storage_id = storage.clone();
+        let res = match &mut self.storage {
+            #[cfg(any(feature = "tokio_0_2", feature = "tokio_1"))]
             EStorage::Tt(s) => s.remove(storage, key),
+            #[cfg(feature = "lmdb_storage")]
             EStorage::Lmdb(s) => s.remove(storage, key),
             EStorage::Remote(_s) => false,
             EStorage::Memory(s) => s.remove(storage, key),
+            EStorage::Custom(s) => s.remove(storage, key),
             _ => false,
+        };
+
+        if res {
+            self.notify_write(storage_id, key, WriteKind::Remove);
+        }
+        res
+    }
+
+    pub fn iter_prefix(&mut self, storage: StorageId, prefix: &str) -> Option<Box<dyn Iterator<Item = (String, Vec<u8>)>>> {
+        match &mut self.storage {
+            #[cfg(any(feature = "tokio_0_2", feature = "tokio_1"))]
+            EStorage::Tt(s) => s.iter_prefix(storage, prefix),
+            #[cfg(feature = "lmdb_storage")]
+            EStorage::Lmdb(s) => s.iter_prefix(storage, prefix),
+            EStorage::Remote(_s) => None,
+            EStorage::Memory(s) => s.iter_prefix(storage, prefix),
+            EStorage::Custom(s) => s.iter_prefix(storage, prefix),
+            _ => None,
         }
     }
 
     pub fn count(&mut self, storage: StorageId) -> usize {
         match &mut self.storage {
+            #[cfg(any(feature = "tokio_0_2", feature = "tokio_1"))]
             EStorage::Tt(s) => s.count(storage),
+            #[cfg(feature = "lmdb_storage")]
             EStorage::Lmdb(s) => s.count(storage),
             EStorage::Remote(s) => s.count(storage),
             EStorage::Memory(s) => s.count(storage),
+            EStorage::Custom(s) => s.count(storage),
             _ => 0,
         }
     }
@@ -185,12 +324,50 @@ mod tests {
         assert_eq!(storage.get_raw_value(StorageId::Individuals, "raw_key"), raw_data);
     }
 
+    #[test]
+    fn test_on_write() {
+        let mut storage = VStorage::new_memory();
+        let writes = Arc::new(std::sync::Mutex::new(vec![]));
+        let writes_clone = writes.clone();
+        storage.set_on_write(Some(Arc::new(move |storage, key, kind| writes_clone.lock().unwrap().push((storage, key.to_owned(), kind)))));
+
+        assert!(storage.put_kv_raw(StorageId::Individuals, "d:doc1", vec![1, 2, 3]));
+        assert!(storage.put_kv(StorageId::Tickets, "ticket1", "value"));
+        assert!(storage.remove(StorageId::Individuals, "d:doc1"));
+
+        assert_eq!(
+            *writes.lock().unwrap(),
+            vec![
+                (StorageId::Individuals, "d:doc1".to_owned(), WriteKind::Put),
+                (StorageId::Tickets, "ticket1".to_owned(), WriteKind::Put),
+                (StorageId::Individuals, "d:doc1".to_owned(), WriteKind::Remove)
+            ]
+        );
+
+        // failed writes are not observed
+        let mut none = VStorage::none();
+        let writes_clone = writes.clone();
+        none.set_on_write(Some(Arc::new(move |storage, key, kind| writes_clone.lock().unwrap().push((storage, key.to_owned(), kind)))));
+        assert!(!none.put_kv(StorageId::Individuals, "d:doc2", "value"));
+        assert_eq!(writes.lock().unwrap().len(), 3);
+    }
+
     #[test]
     fn test_empty_storage() {
         let storage = VStorage::none();
         assert!(storage.is_empty());
     }
 
+    #[test]
+    fn test_iter_prefix_support() {
+        let mut storage = VStorage::new_memory();
+        assert!(storage.put_kv(StorageId::Individuals, "d:doc1", "value"));
+        assert_eq!(storage.iter_prefix(StorageId::Individuals, "d:").map(|it| it.count()), Some(1));
+
+        assert!(VStorage::none().iter_prefix(StorageId::Individuals, "d:").is_none());
+        assert!(VStorage::new_remote("127.0.0.1:0").iter_prefix(StorageId::Individuals, "d:").is_none());
+    }
+
     #[test]
     fn test_individual_operations() {
         let mut storage = VStorage::new_memory();
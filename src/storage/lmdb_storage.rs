@@ -11,8 +11,12 @@ pub struct LMDBStorage {
     individuals_db: LmdbInstance,
     tickets_db: LmdbInstance,
     az_db: LmdbInstance,
+    parse_failure_handler: Option<ParseFailureHandler>,
 }
 
+// Receives the id and the undecoded bytes of an individual that get_individual_from_db failed to parse
+pub type ParseFailureHandler = Box<dyn FnMut(&str, &[u8]) + Send>;
+
 pub struct LmdbInstance {
     max_read_counter: u64,
     path: String,
@@ -101,6 +105,53 @@ impl LmdbInstance {
         }
     }
 
+    // Keys starting with the prefix with their values; they are read in one transaction and returned as a snapshot,
+    // so the environment may be reopened while the caller iterates. The snapshot holds all the matching pairs in memory,
+    // a short prefix over a large database costs as much as the data it matches
+    pub fn iter_prefix(&mut self, prefix: &str) -> Box<dyn Iterator<Item = (String, Vec<u8>)>> {
+        if self.db_env.is_err() {
+            self.open();
+        }
+
+        let mut items = vec![];
+        if let (Ok(env), Ok(handle)) = (&self.db_env, &self.db_handle) {
+            match env.get_reader() {
+                Ok(txn) => {
+                    let db = txn.bind(handle);
+                    let cursor_result = db.new_cursor();
+                    match cursor_result {
+                        Ok(mut cursor) => {
+                            let mut res = if prefix.is_empty() {
+                                cursor.to_first()
+                            } else {
+                                cursor.to_gte_key(&prefix)
+                            };
+                            while res.is_ok() {
+                                match cursor.get::<Vec<u8>, Vec<u8>>() {
+                                    Ok((key, val)) => {
+                                        if !key.starts_with(prefix.as_bytes()) {
+                                            break;
+                                        }
+                                        items.push((String::from_utf8_lossy(&key).into_owned(), val));
+                                    },
+                                    Err(e) => {
+                                        error!("LMDB: fail read cursor, path=[{}], err={}", self.path, e);
+                                        break;
+                                    },
+                                }
+                                res = cursor.to_next_item();
+                            }
+                        },
+                        Err(e) => error!("LMDB: fail create cursor, path=[{}], err={}", self.path, e),
+                    }
+                },
+                Err(e) => error!("LMDB: fail to create transaction for prefix read, path=[{}], err={}", self.path, e),
+            }
+        }
+
+        Box::new(items.into_iter())
+    }
+
     pub fn open(&mut self) {
         let env_builder = if self.mode == StorageMode::ReadOnly {
             EnvBuilder::new().flags(EnvCreateNoLock | EnvCreateReadOnly | EnvCreateNoMetaSync | EnvCreateNoSync)
@@ -125,19 +176,41 @@ impl LmdbInstance {
 
     fn get_individual(&mut self, uri: &str, iraw: &mut Individual) -> ResultCode {
         if let Some(val) = self.get::<&[u8]>(uri) {
-            iraw.set_raw(val);
-
-            return if parse_raw(iraw).is_ok() {
-                ResultCode::Ok
-            } else {
-                error!("LMDB: fail parse binobj, path=[{}], len={}, uri=[{}]", self.path, iraw.get_raw_len(), uri);
-                ResultCode::UnprocessableEntity
-            };
+            return self.parse_individual(uri, val, iraw);
         }
 
         ResultCode::NotReady
     }
 
+    // All uris are read in one read transaction, the results are in the order of uris
+    fn get_individuals(&mut self, uris: &[&str]) -> Vec<(String, ResultCode, Individual)> {
+        let vals = self.get_multiple::<&[u8]>(uris);
+
+        uris.iter()
+            .zip(vals)
+            .map(|(uri, val)| {
+                let mut iraw = Individual::default();
+                let res = if let Some(val) = val {
+                    self.parse_individual(uri, val, &mut iraw)
+                } else {
+                    ResultCode::NotReady
+                };
+                (uri.to_string(), res, iraw)
+            })
+            .collect()
+    }
+
+    fn parse_individual(&self, uri: &str, val: &[u8], iraw: &mut Individual) -> ResultCode {
+        iraw.set_raw(val);
+
+        if parse_raw(iraw).is_ok() {
+            ResultCode::Ok
+        } else {
+            error!("LMDB: fail parse binobj, path=[{}], len={}, uri=[{}]", self.path, iraw.get_raw_len(), uri);
+            ResultCode::UnprocessableEntity
+        }
+    }
+
     fn get_v(&mut self, key: &str) -> Option<String> {
         self.get::<String>(key)
     }
@@ -147,6 +220,11 @@ impl LmdbInstance {
     }
 
     pub fn get<T: FromMdbValue>(&mut self, key: &str) -> Option<T> {
+        self.get_multiple(&[key]).pop().flatten()
+    }
+
+    // Reads the keys in one read transaction, None for the keys that are not found or failed to read
+    pub fn get_multiple<T: FromMdbValue>(&mut self, keys: &[&str]) -> Vec<Option<T>> {
         if self.db_env.is_err() {
             self.open();
         }
@@ -165,38 +243,37 @@ impl LmdbInstance {
                         Ok(txn) => {
                             let db = txn.bind(handle);
 
-                            match db.get::<T>(&key) {
-                                Ok(val) => {
-                                    return Some(val);
-                                },
-                                Err(e) => match e {
-                                    MdbError::NotFound => {
-                                        return None;
+                            return keys
+                                .iter()
+                                .map(|key| match db.get::<T>(key) {
+                                    Ok(val) => Some(val),
+                                    Err(e) => match e {
+                                        MdbError::NotFound => None,
+                                        _ => {
+                                            error!("LMDB: db.get failed for key=[{}], path=[{}], err={:?}", key, self.path, e);
+                                            None
+                                        },
                                     },
-                                    _ => {
-                                        error!("LMDB: db.get failed for key=[{}], path=[{}], err={:?}", key, self.path, e);
-                                        return None;
-                                    },
-                                },
-                            }
+                                })
+                                .collect();
                         },
                         Err(e) => match e {
                             MdbError::Other(c, _) => {
                                 if c == -30785 {
                                     is_need_reopen = true;
                                 } else {
-                                    error!("LMDB: failed to create transaction for key=[{}], path=[{}], err={}", key, self.path, e);
-                                    return None;
+                                    error!("LMDB: failed to create transaction for key=[{}], path=[{}], err={}", keys.join(", "), self.path, e);
+                                    return keys.iter().map(|_| None).collect();
                                 }
                             },
                             _ => {
-                                error!("LMDB: failed to create transaction for key=[{}], path=[{}], err={}", key, self.path, e);
+                                error!("LMDB: failed to create transaction for key=[{}], path=[{}], err={}", keys.join(", "), self.path, e);
                             },
                         },
                     },
                     Err(e) => {
-                        error!("LMDB: db handle error for key=[{}], path=[{}], err={}", key, self.path, e);
-                        return None;
+                        error!("LMDB: db handle error for key=[{}], path=[{}], err={}", keys.join(", "), self.path, e);
+                        return keys.iter().map(|_| None).collect();
                     },
                 },
                 Err(e) => match e {
@@ -204,19 +281,19 @@ impl LmdbInstance {
                         is_need_reopen = true;
                     },
                     _ => {
-                        error!("LMDB: db environment error for key=[{}], path=[{}], err={}", key, self.path, e);
-                        return None;
+                        error!("LMDB: db environment error for key=[{}], path=[{}], err={}", keys.join(", "), self.path, e);
+                        return keys.iter().map(|_| None).collect();
                     },
                 },
             }
 
             if is_need_reopen {
-                warn!("db {} reopen for key=[{}]", self.path, key);
+                warn!("db {} reopen for key=[{}]", self.path, keys.join(", "));
                 self.open();
             }
         }
 
-        None
+        keys.iter().map(|_| None).collect()
     }
 
     pub fn count(&mut self) -> usize {
@@ -266,7 +343,19 @@ impl LmdbInstance {
         0
     }
 
+    // Writes to an instance opened in ReadOnly mode are refused here, before LMDB answers them with an access error
+    fn check_writable(&self, op: &str, key: &str) -> bool {
+        if self.mode == StorageMode::ReadOnly {
+            error!("LMDB: {} key=[{}] refused, path=[{}] is opened read only", op, key, self.path);
+            return false;
+        }
+        true
+    }
+
     pub fn remove(&mut self, key: &str) -> bool {
+        if !self.check_writable("remove", key) {
+            return false;
+        }
         if self.db_env.is_err() {
             self.open();
         }
@@ -274,6 +363,9 @@ impl LmdbInstance {
     }
 
     pub fn put<T: ToMdbValue>(&mut self, key: &str, val: T) -> bool {
+        if !self.check_writable("put", key) {
+            return false;
+        }
         if self.db_env.is_err() {
             self.open();
         }
@@ -302,9 +394,16 @@ impl LMDBStorage {
                 mode: mode.clone(),
                 ..Default::default()
             },
+            parse_failure_handler: None,
         }
     }
 
+    // By default a corrupt individual is only logged and answered with UnprocessableEntity,
+    // the handler additionally gets its raw bytes, e.g. to save them for recovery
+    pub fn set_parse_failure_handler(&mut self, handler: Option<ParseFailureHandler>) {
+        self.parse_failure_handler = handler;
+    }
+
     fn get_db_instance(&mut self, storage: &StorageId) -> &mut LmdbInstance {
         match storage {
             StorageId::Individuals => &mut self.individuals_db,
@@ -324,7 +423,27 @@ impl LMDBStorage {
 impl Storage for LMDBStorage {
     fn get_individual_from_db(&mut self, storage: StorageId, uri: &str, iraw: &mut Individual) -> ResultCode {
         let db_instance = self.get_db_instance(&storage);
-        db_instance.get_individual(uri, iraw)
+        let res = db_instance.get_individual(uri, iraw);
+
+        if res == ResultCode::UnprocessableEntity {
+            if let Some(handler) = &mut self.parse_failure_handler {
+                handler(uri, &iraw.raw.data);
+            }
+        }
+        res
+    }
+
+    fn get_individuals_from_db(&mut self, storage: StorageId, uris: &[&str]) -> Vec<(String, ResultCode, Individual)> {
+        let res = self.get_db_instance(&storage).get_individuals(uris);
+
+        if let Some(handler) = &mut self.parse_failure_handler {
+            for (uri, code, iraw) in res.iter() {
+                if *code == ResultCode::UnprocessableEntity {
+                    handler(uri, &iraw.raw.data);
+                }
+            }
+        }
+        res
     }
 
     fn get_v(&mut self, storage: StorageId, key: &str) -> Option<String> {
@@ -340,18 +459,27 @@ impl Storage for LMDBStorage {
 
     fn put_kv(&mut self, storage: StorageId, key: &str, val: &str) -> bool {
         let db_instance = self.get_db_instance(&storage);
+        if !db_instance.check_writable("put", key) {
+            return false;
+        }
 
         put_kv_lmdb(&db_instance.db_env, &db_instance.db_handle, key, val.as_bytes(), &db_instance.path)
     }
 
     fn put_kv_raw(&mut self, storage: StorageId, key: &str, val: Vec<u8>) -> bool {
         let db_instance = self.get_db_instance(&storage);
+        if !db_instance.check_writable("put", key) {
+            return false;
+        }
 
         put_kv_lmdb(&db_instance.db_env, &db_instance.db_handle, key, val.as_slice(), &db_instance.path)
     }
 
     fn remove(&mut self, storage: StorageId, key: &str) -> bool {
         let db_instance = self.get_db_instance(&storage);
+        if !db_instance.check_writable("remove", key) {
+            return false;
+        }
 
         remove_from_lmdb(&db_instance.db_env, &db_instance.db_handle, key, &db_instance.path)
     }
@@ -360,6 +488,10 @@ impl Storage for LMDBStorage {
         let db_instance = self.get_db_instance(&storage);
         db_instance.count()
     }
+
+    fn iter_prefix(&mut self, storage: StorageId, prefix: &str) -> Option<Box<dyn Iterator<Item = (String, Vec<u8>)>>> {
+        Some(self.get_db_instance(&storage).iter_prefix(prefix))
+    }
 }
 
 fn remove_from_lmdb(db_env: &Result<Environment, MdbError>, db_handle: &Result<DbHandle, MdbError>, key: &str, path: &str) -> bool {
@@ -457,3 +589,117 @@ fn grow_db(db_env: &Result<Environment, MdbError>, path: &str) -> bool {
     }
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onto::individual2msgpack::to_msgpack;
+    use std::env;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_write_to_read_only_instance_is_refused() {
+        let path = env::temp_dir().join(format!("lmdb-read-only-{}", std::process::id())).to_str().unwrap().to_owned();
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        let mut rw = LmdbInstance::new(&path, StorageMode::ReadWrite);
+        assert!(rw.put("key1", "value1".as_bytes()));
+
+        let mut ro = LmdbInstance::new(&path, StorageMode::ReadOnly);
+        assert_eq!(ro.get::<String>("key1"), Some("value1".to_owned()));
+        assert!(!ro.put("key1", "value2".as_bytes()));
+        assert!(!ro.remove("key1"));
+        assert_eq!(ro.get::<String>("key1"), Some("value1".to_owned()));
+
+        assert!(rw.remove("key1"));
+        fs::remove_dir_all(&path).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_iter_prefix() {
+        let path = env::temp_dir().join(format!("lmdb-iter-prefix-{}", std::process::id())).to_str().unwrap().to_owned();
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        let mut db = LmdbInstance::new(&path, StorageMode::ReadWrite);
+        for key in ["d:b", "d:a", "cfg:a", "d", "da:a", "e:a"] {
+            assert!(db.put(key, key.as_bytes()));
+        }
+
+        let found: Vec<(String, Vec<u8>)> = db.iter_prefix("d:").collect();
+        assert_eq!(found, vec![("d:a".to_owned(), b"d:a".to_vec()), ("d:b".to_owned(), b"d:b".to_vec())]);
+        assert_eq!(db.iter_prefix("x:").count(), 0);
+        assert_eq!(db.iter_prefix("").count(), 6);
+
+        // the snapshot outlives a reopen of the environment
+        let mut it = db.iter_prefix("d");
+        db.open();
+        assert_eq!(it.next().map(|(k, _)| k), Some("d".to_owned()));
+        assert_eq!(it.count(), 3);
+
+        fs::remove_dir_all(&path).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_get_individuals_from_db() {
+        let path = env::temp_dir().join(format!("lmdb-get-individuals-{}", std::process::id())).to_str().unwrap().to_owned();
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(path.clone() + "/lmdb-individuals/").unwrap();
+
+        let mut storage = LMDBStorage::new(&path, StorageMode::ReadWrite, None);
+        for id in ["d:doc1", "d:doc2"] {
+            let mut indv = Individual::default();
+            indv.set_id(id);
+            let mut raw = vec![];
+            to_msgpack(&indv, &mut raw).unwrap();
+            assert!(storage.put_kv_raw(StorageId::Individuals, id, raw));
+        }
+        assert!(storage.put_kv_raw(StorageId::Individuals, "d:broken", b"\xff\xff\xffcorrupt".to_vec()));
+
+        let uris = ["d:doc2", "d:absent", "d:broken", "d:doc1"];
+        let res = storage.get_individuals_from_db(StorageId::Individuals, &uris);
+
+        let codes: Vec<(&str, ResultCode)> = res.iter().map(|(uri, code, _)| (uri.as_str(), *code)).collect();
+        assert_eq!(
+            codes,
+            vec![("d:doc2", ResultCode::Ok), ("d:absent", ResultCode::NotReady), ("d:broken", ResultCode::UnprocessableEntity), ("d:doc1", ResultCode::Ok)]
+        );
+        assert_eq!(res[0].2.get_id(), "d:doc2");
+        assert_eq!(res[3].2.get_id(), "d:doc1");
+
+        // the same as one by one
+        for (uri, code, _) in res.iter() {
+            let mut indv = Individual::default();
+            assert_eq!(storage.get_individual_from_db(StorageId::Individuals, uri, &mut indv), *code);
+        }
+
+        fs::remove_dir_all(&path).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_raw_of_corrupt_individual() {
+        let path = env::temp_dir().join(format!("lmdb-corrupt-{}", std::process::id())).to_str().unwrap().to_owned();
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(path.clone() + "/lmdb-individuals/").unwrap();
+
+        let corrupt = b"\xff\xff\xffcorrupt".to_vec();
+        let mut storage = LMDBStorage::new(&path, StorageMode::ReadWrite, None);
+        assert!(storage.put_kv_raw(StorageId::Individuals, "d:broken", corrupt.clone()));
+
+        // without the handler the error is returned as before
+        let mut indv = Individual::default();
+        assert_eq!(storage.get_individual_from_db(StorageId::Individuals, "d:broken", &mut indv), ResultCode::UnprocessableEntity);
+
+        let failed = Arc::new(Mutex::new(vec![]));
+        let failed_clone = failed.clone();
+        storage.set_parse_failure_handler(Some(Box::new(move |id, raw| failed_clone.lock().unwrap().push((id.to_owned(), raw.to_vec())))));
+
+        let mut indv = Individual::default();
+        assert_eq!(storage.get_individual_from_db(StorageId::Individuals, "d:broken", &mut indv), ResultCode::UnprocessableEntity);
+        assert_eq!(*failed.lock().unwrap(), vec![("d:broken".to_owned(), corrupt)]);
+
+        fs::remove_dir_all(&path).unwrap_or_default();
+    }
+}
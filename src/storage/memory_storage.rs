@@ -119,6 +119,16 @@ impl Storage for MemoryStorage {
             0
         }
     }
+
+    fn iter_prefix(&mut self, storage: StorageId, prefix: &str) -> Option<Box<dyn Iterator<Item = (String, Vec<u8>)>>> {
+        let mut items: Vec<(String, Vec<u8>)> = if let Ok(map) = self.get_storage(storage).read() {
+            map.iter().filter(|(k, _)| k.starts_with(prefix)).map(|(k, v)| (k.clone(), v.clone())).collect()
+        } else {
+            vec![]
+        };
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+        Some(Box::new(items.into_iter()))
+    }
 }
 
 #[cfg(test)]
@@ -1,5 +1,7 @@
 pub mod common;
+#[cfg(feature = "lmdb_storage")]
 pub mod lmdb_storage;
+#[cfg(any(feature = "tokio_0_2", feature = "tokio_1"))]
 mod tt_storage;
 pub mod tt_wrapper;
 
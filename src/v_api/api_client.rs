@@ -1,12 +1,17 @@
 use crate::onto::individual::Individual;
+use crate::v_api::messages::result_message;
 use crate::v_api::obj::ResultCode;
 use nng::options::{Options, RecvTimeout, SendTimeout};
 use nng::{Message, Protocol, Socket};
+use serde::Deserialize;
 use serde_json::json;
 use serde_json::Value;
 use std::fmt;
 use std::net::IpAddr;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub const ALL_MODULES: i64 = 0;
 
@@ -31,6 +36,11 @@ impl ApiError {
             info: info.to_owned(),
         }
     }
+
+    // Text for the client, the details in info are for logs
+    pub fn message(&self, locale: Option<&str>) -> &'static str {
+        result_message(self.result, locale)
+    }
 }
 
 impl Default for ApiError {
@@ -122,16 +132,44 @@ pub struct NngClient {
     soc: Socket,
     addr: String,
     is_ready: bool,
+    protocol: Protocol,
+    state: Arc<NngClientState>,
+}
+
+// Shared by the clones of a client, they all use the same socket
+#[derive(Default)]
+struct NngClientState {
+    closed: AtomicBool,
+    in_flight: AtomicUsize,
 }
 
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
 impl NngClient {
     pub fn new(name: &str, addr: String) -> NngClient {
-        NngClient {
+        NngClient::new_with_protocol(name, addr, Protocol::Req0).unwrap()
+    }
+
+    // Only protocols where the reply comes back over the same socket are accepted: Req0 and Pair
+    pub fn new_with_protocol(name: &str, addr: String, protocol: Protocol) -> Result<NngClient, ApiError> {
+        if !matches!(protocol, Protocol::Req0 | Protocol::Pair0 | Protocol::Pair1) {
+            return Err(ApiError::new(ResultCode::BadRequest, &format!("nng {}: protocol {:?} does not support request/reply", name, protocol)));
+        }
+
+        let soc = Socket::new(protocol).map_err(|e| ApiError::new(ResultCode::InternalServerError, &format!("nng {}: fail create socket, err={}", name, e)))?;
+
+        Ok(NngClient {
             name: name.to_owned(),
-            soc: Socket::new(Protocol::Req0).unwrap(),
+            soc,
             addr,
             is_ready: false,
-        }
+            protocol,
+            state: Arc::new(NngClientState::default()),
+        })
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
     }
 
     pub fn connect(&mut self) -> bool {
@@ -156,7 +194,46 @@ impl NngClient {
         self.is_ready
     }
 
+    // Waits up to CLOSE_TIMEOUT for requests of the clones of this client to complete, then closes the socket shared by all of them
+    pub fn close(self) {
+        self.close_with_timeout(CLOSE_TIMEOUT)
+    }
+
+    pub fn close_with_timeout(mut self, timeout: Duration) {
+        self.shutdown(timeout)
+    }
+
+    fn shutdown(&mut self, timeout: Duration) {
+        if self.state.closed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let start = Instant::now();
+        while self.state.in_flight.load(Ordering::SeqCst) > 0 && start.elapsed() < timeout {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let in_flight = self.state.in_flight.load(Ordering::SeqCst);
+        if in_flight > 0 {
+            warn!("nng {}: close with {} requests in flight", self.name, in_flight);
+        }
+
+        self.soc.close();
+        self.is_ready = false;
+        info!("nng {}: closed", self.name);
+    }
+
     pub(crate) fn req_recv(&mut self, query: Value) -> Result<Value, ApiError> {
+        if self.state.closed.load(Ordering::SeqCst) {
+            return Err(ApiError::new(ResultCode::NotReady, &format!("nng {}: client is closed", self.name)));
+        }
+
+        self.state.in_flight.fetch_add(1, Ordering::SeqCst);
+        let res = self.send_recv(query);
+        self.state.in_flight.fetch_sub(1, Ordering::SeqCst);
+        res
+    }
+
+    fn send_recv(&mut self, query: Value) -> Result<Value, ApiError> {
         if !self.is_ready {
             self.connect();
         }
@@ -190,6 +267,14 @@ impl NngClient {
     }
 }
 
+impl Drop for NngClient {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.state) == 1 {
+            self.shutdown(Duration::default());
+        }
+    }
+}
+
 pub struct AuthClient {
     client: NngClient,
 }
@@ -335,43 +420,108 @@ impl MStorageClient {
     }
 
     pub fn update_form_json(&mut self, query: Value) -> Result<OpResult, ApiError> {
-        let json: Value = self.client.req_recv(query)?;
+        parse_op_result(self.client.req_recv(query)?)
+    }
+}
 
-        if let Some(t) = json["type"].as_str() {
-            if t != "OpResult" {
-                return Err(ApiError::new(ResultCode::BadRequest, &format!("api:update - expecten \"type\" = \"OpResult\", found {}", t)));
-            }
-        } else {
-            return Err(ApiError::new(ResultCode::BadRequest, "api:update - not found \"type\""));
-        }
+// Reply of the main module to an update: {"type":"OpResult","data":[{"result":..,"op_id":..}]}, or only {"type":"OpResult","result":..}
+#[derive(Deserialize, Debug)]
+pub struct OpResultEnvelope {
+    pub r#type: String,
+    pub data: Option<Vec<OpResultItem>>,
+    pub result: Option<i64>,
+}
 
-        if let Some(arr) = json["data"].as_array() {
-            if arr.len() != 1 {
-                return Err(ApiError::new(ResultCode::BadRequest, "api:update - invalid \"data\" section"));
-            }
+#[derive(Deserialize, Debug)]
+pub struct OpResultItem {
+    pub result: i64,
+    pub op_id: i64,
+}
 
-            if let Some(res) = arr[0]["result"].as_i64() {
-                if let Some(op_id) = arr[0]["op_id"].as_i64() {
-                    return Ok(OpResult {
-                        result: ResultCode::from_i64(res),
-                        op_id,
-                    });
-                }
-            } else {
+fn parse_op_result(json: Value) -> Result<OpResult, ApiError> {
+    let envelope: OpResultEnvelope = match serde_json::from_value(json) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(ApiError::new(ResultCode::BadRequest, &format!("api:update - invalid reply, err={}", e)));
+        },
+    };
+
+    if envelope.r#type != "OpResult" {
+        return Err(ApiError::new(ResultCode::BadRequest, &format!("api:update - expecten \"type\" = \"OpResult\", found {}", envelope.r#type)));
+    }
+
+    match (envelope.data, envelope.result) {
+        (Some(data), _) => {
+            if data.len() != 1 {
                 return Err(ApiError::new(ResultCode::BadRequest, "api:update - invalid \"data\" section"));
             }
-        } else {
-            return if let Some(res) = json["result"].as_i64() {
-                Ok(OpResult {
-                    result: ResultCode::from_i64(res),
-                    op_id: 0,
-                })
-            } else {
-                error!("api:update - not found \"data\"");
-                return Err(ApiError::new(ResultCode::BadRequest, "api:update - not found \"data\""));
-            };
+            Ok(OpResult {
+                result: ResultCode::from_i64(data[0].result),
+                op_id: data[0].op_id,
+            })
+        },
+        (None, Some(res)) => Ok(OpResult {
+            result: ResultCode::from_i64(res),
+            op_id: 0,
+        }),
+        (None, None) => {
+            error!("api:update - not found \"data\"");
+            Err(ApiError::new(ResultCode::BadRequest, "api:update - not found \"data\""))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_op_result() {
+        let res = parse_op_result(json!({"type": "OpResult", "data": [{"result": 200, "op_id": 42}]})).unwrap();
+        assert_eq!(res.result, ResultCode::Ok);
+        assert_eq!(res.op_id, 42);
+
+        let res = parse_op_result(json!({"type": "OpResult", "result": 200})).unwrap();
+        assert_eq!((res.result, res.op_id), (ResultCode::Ok, 0));
+
+        let e = parse_op_result(json!({"type": "Ticket", "data": [{"result": 200, "op_id": 42}]})).unwrap_err();
+        assert_eq!(e.result, ResultCode::BadRequest);
+        assert!(e.info.contains("found Ticket"));
+
+        for malformed in [
+            json!({"data": [{"result": 200, "op_id": 42}]}),
+            json!({"type": "OpResult", "data": {"result": 200}}),
+            json!({"type": "OpResult", "data": [{"result": "200", "op_id": 42}]}),
+            json!({"type": "OpResult", "data": []}),
+            json!({"type": "OpResult"}),
+            json!("OpResult"),
+        ] {
+            assert_eq!(parse_op_result(malformed.clone()).unwrap_err().result, ResultCode::BadRequest, "reply={}", malformed);
+        }
+    }
+
+    #[test]
+    fn test_client_protocol() {
+        assert_eq!(NngClient::new("test client", "tcp://127.0.0.1:8088".to_owned()).protocol(), Protocol::Req0);
+
+        let client = NngClient::new_with_protocol("test client", "tcp://127.0.0.1:8088".to_owned(), Protocol::Pair1).unwrap();
+        assert_eq!(client.protocol(), Protocol::Pair1);
+
+        for protocol in [Protocol::Pub0, Protocol::Sub0, Protocol::Push0, Protocol::Pull0, Protocol::Rep0] {
+            let e = NngClient::new_with_protocol("test client", "tcp://127.0.0.1:8088".to_owned(), protocol).err().unwrap();
+            assert_eq!(e.result, ResultCode::BadRequest);
         }
+    }
+
+    #[test]
+    fn test_closed_client_rejects_requests() {
+        let client = NngClient::new("test client", "tcp://127.0.0.1:8088".to_owned());
+        let mut other = client.clone();
+
+        client.close_with_timeout(Duration::from_millis(100));
 
-        Err(ApiError::new(ResultCode::BadRequest, "api:update - unknown"))
+        let e = other.req_recv(json!({"function": "get_ticket_trusted"})).unwrap_err();
+        assert_eq!(e.result, ResultCode::NotReady);
+        assert!(e.info.contains("client is closed"), "err={}", e);
     }
 }
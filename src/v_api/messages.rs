@@ -0,0 +1,65 @@
+use crate::v_api::obj::ResultCode;
+
+// Catalog of user-facing messages keyed by the result code; English is used for an unknown or absent locale.
+// Details for logs stay in ApiError.info and in the log messages
+pub fn result_message(code: ResultCode, locale: Option<&str>) -> &'static str {
+    match locale.map(|l| l.split(['-', '_']).next().unwrap_or_default().to_ascii_lowercase()).as_deref() {
+        Some("ru") => message_ru(code),
+        _ => message_en(code),
+    }
+}
+
+fn message_en(code: ResultCode) -> &'static str {
+    match code {
+        ResultCode::Ok => "Ok",
+        ResultCode::BadRequest => "Bad request",
+        ResultCode::Forbidden => "Access denied",
+        ResultCode::NotFound => "Not found",
+        ResultCode::TooManyRequests => "Too many requests",
+        ResultCode::InvalidPassword => "Invalid password",
+        ResultCode::PasswordExpired => "Password expired",
+        ResultCode::TicketNotFound => "Session not found, please log in",
+        ResultCode::TicketExpired => "Session expired, please log in again",
+        ResultCode::NotAuthorized => "Authorization check failed",
+        ResultCode::AuthenticationFailed => "Authentication failed",
+        ResultCode::NotReady => "Service is not ready",
+        ResultCode::ServiceUnavailable => "Service unavailable",
+        ResultCode::ConnectError => "Connection error",
+        _ => "Internal error",
+    }
+}
+
+fn message_ru(code: ResultCode) -> &'static str {
+    match code {
+        ResultCode::Ok => "Успешно",
+        ResultCode::BadRequest => "Некорректный запрос",
+        ResultCode::Forbidden => "Доступ запрещен",
+        ResultCode::NotFound => "Не найдено",
+        ResultCode::TooManyRequests => "Слишком много запросов",
+        ResultCode::InvalidPassword => "Неверный пароль",
+        ResultCode::PasswordExpired => "Срок действия пароля истек",
+        ResultCode::TicketNotFound => "Сессия не найдена, выполните вход",
+        ResultCode::TicketExpired => "Сессия истекла, выполните вход повторно",
+        ResultCode::NotAuthorized => "Ошибка при проверке авторизации",
+        ResultCode::AuthenticationFailed => "Ошибка аутентификации",
+        ResultCode::NotReady => "Сервис не готов",
+        ResultCode::ServiceUnavailable => "Сервис недоступен",
+        ResultCode::ConnectError => "Ошибка соединения",
+        _ => "Внутренняя ошибка",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_result_message_locales() {
+        assert_eq!(result_message(ResultCode::TicketExpired, None), "Session expired, please log in again");
+        assert_eq!(result_message(ResultCode::TicketExpired, Some("en-US")), "Session expired, please log in again");
+        assert_eq!(result_message(ResultCode::TicketExpired, Some("ru_RU")), "Сессия истекла, выполните вход повторно");
+        assert_eq!(result_message(ResultCode::NotAuthorized, Some("RU")), "Ошибка при проверке авторизации");
+        assert_eq!(result_message(ResultCode::NotAuthorized, Some("de")), "Authorization check failed");
+        assert_eq!(result_message(ResultCode::DiskFull, Some("ru")), "Внутренняя ошибка");
+    }
+}
@@ -1,2 +1,3 @@
 pub mod api_client;
+pub mod messages;
 pub mod obj;
@@ -125,7 +125,7 @@ pub enum ResultCode {
     ConnectError = 4000,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum OptAuthorize {
     NO,
     YES,
@@ -206,6 +206,28 @@ impl ResultCode {
             _ => ResultCode::Zero,
         }
     }
+
+    // Status of an http response carrying this code: a missing or expired ticket asks the client to log in again (401),
+    // codes specific to veda are mapped to the nearest standard status
+    pub fn to_http_status(&self) -> u16 {
+        match self {
+            ResultCode::TicketNotFound | ResultCode::TicketExpired | ResultCode::NotAuthorized | ResultCode::AuthenticationFailed => 401,
+            ResultCode::ChangePasswordForbidden => 403,
+            ResultCode::SecretExpired
+            | ResultCode::EmptyPassword
+            | ResultCode::NewPasswordIsEqualToOld
+            | ResultCode::InvalidPassword
+            | ResultCode::InvalidSecret
+            | ResultCode::PasswordExpired
+            | ResultCode::InvalidIdentifier => 400,
+            ResultCode::TooManyRequestsChangePassword => 429,
+            ResultCode::NotReady | ResultCode::ConnectError => 503,
+            ResultCode::DuplicateKey | ResultCode::DatabaseModifiedError => 409,
+            ResultCode::SizeTooLarge => 413,
+            ResultCode::Zero | ResultCode::FailOpenTransaction | ResultCode::FailCommit | ResultCode::FailStore | ResultCode::DiskFull => 500,
+            _ => *self as u16,
+        }
+    }
 }
 
 pub fn generate_unique_uri(prefix: &str, postfix: &str) -> String {
@@ -216,3 +238,19 @@ pub fn generate_unique_uri(prefix: &str, postfix: &str) -> String {
 
     format!("{}{}{}", prefix, nanoid!(24, &alphabet), postfix)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_http_status() {
+        assert_eq!(ResultCode::Ok.to_http_status(), 200);
+        assert_eq!(ResultCode::TicketNotFound.to_http_status(), 401);
+        assert_eq!(ResultCode::TicketExpired.to_http_status(), 401);
+        assert_eq!(ResultCode::Forbidden.to_http_status(), 403);
+        assert_eq!(ResultCode::NotReady.to_http_status(), 503);
+        assert_eq!(ResultCode::DiskFull.to_http_status(), 500);
+        assert_eq!(ResultCode::from_i64(471), ResultCode::TicketExpired);
+    }
+}